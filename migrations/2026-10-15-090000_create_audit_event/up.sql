@@ -0,0 +1,19 @@
+create table audit_event (
+    id uuid default gen_random_uuid(),
+
+    request_id uuid not null,
+    subject text not null,
+    bucket text not null,
+    set text,
+    object text,
+    method text not null,
+    authz_action text not null,
+    authz_decision text not null,
+    response_status int4 not null,
+
+    created_at timestamptz not null default now(),
+
+    primary key (id)
+);
+
+create index audit_event_created_at_id_idx on audit_event (created_at, id);
@@ -0,0 +1,5 @@
+drop index if exists audit_event_operation_created_at_idx;
+
+alter table audit_event
+    drop column if exists operation,
+    drop column if exists authz_duration_ms;
@@ -0,0 +1,5 @@
+alter table audit_event
+    add column operation text,
+    add column authz_duration_ms int4;
+
+create index audit_event_operation_created_at_idx on audit_event (operation, created_at);
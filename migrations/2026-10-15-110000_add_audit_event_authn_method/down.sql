@@ -0,0 +1,2 @@
+alter table audit_event
+    drop column if exists authn_method;
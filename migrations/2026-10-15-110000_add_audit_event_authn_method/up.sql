@@ -0,0 +1,2 @@
+alter table audit_event
+    add column authn_method text;
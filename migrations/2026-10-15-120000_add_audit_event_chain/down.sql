@@ -0,0 +1,5 @@
+drop index if exists audit_event_chain_seq_idx;
+
+alter table audit_event
+    drop column if exists chain_seq,
+    drop column if exists prev_hash;
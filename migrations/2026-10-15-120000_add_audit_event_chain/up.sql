@@ -0,0 +1,5 @@
+alter table audit_event
+    add column chain_seq bigint,
+    add column prev_hash text;
+
+create unique index audit_event_chain_seq_idx on audit_event (chain_seq);
@@ -0,0 +1,13 @@
+create table api_key (
+    id uuid default gen_random_uuid(),
+
+    key_hash text not null,
+    account_id text not null,
+    scope text[],
+    expires_at timestamptz,
+    created_by text not null,
+
+    created_at timestamptz not null default now(),
+
+    primary key (id)
+);
@@ -0,0 +1,67 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::{SecondsFormat, Utc};
+use log::{error, info};
+
+use crate::app::config::FailoverWebhookConfig;
+use crate::s3::FailoverHook;
+
+////////////////////////////////////////////////////////////////////////////////
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Serialize)]
+struct FailoverAlert<'a> {
+    event: &'static str,
+    backend: &'a str,
+    endpoint: &'a str,
+    at: String,
+}
+
+/// Posts failovers of backends to the webhook in background, so calls routed to the standby
+/// backend aren't delayed by the alert.
+pub(crate) fn failover_hook(config: FailoverWebhookConfig) -> FailoverHook {
+    let config = Arc::new(config);
+    Arc::new(move |backend: &str, endpoint: &str| {
+        let config = config.clone();
+        let backend = backend.to_owned();
+        let endpoint = endpoint.to_owned();
+        std::thread::spawn(move || match notify(&config, &backend, &endpoint) {
+            Ok(()) => info!("Failover alert is sent, backend = '{}'", backend),
+            Err(err) => error!(
+                "Error sending the failover alert, backend = '{}': {:#}",
+                backend, err
+            ),
+        });
+    })
+}
+
+fn notify(config: &FailoverWebhookConfig, backend: &str, endpoint: &str) -> anyhow::Result<()> {
+    let alert = FailoverAlert {
+        event: "s3_failover",
+        backend,
+        endpoint,
+        at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+    };
+
+    let client = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()
+        .context("failed to create an HTTP client")?;
+    let mut req = client.post(&config.url).json(&alert);
+    if let Some(ref secret) = config.secret {
+        req = req.bearer_auth(secret);
+    }
+
+    let resp = req.send().context("failed to call the failover webhook")?;
+    if !resp.status().is_success() {
+        return Err(anyhow::format_err!(
+            "failover webhook responded with status = {}",
+            resp.status()
+        ));
+    }
+
+    Ok(())
+}
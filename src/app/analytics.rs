@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{format_err, Context};
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::db::audit_event::{ActivityQuery, GroupColumn, GroupCount};
+use crate::db::ConnectionPool;
+
+////////////////////////////////////////////////////////////////////////////////
+
+const SIGN_OPERATION: &str = "sign";
+
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+const GROUP_LIMIT: i64 = 100;
+
+const TOP_OBJECTS_LIMIT: i64 = 10;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Window {
+    Hour,
+    Day,
+    Week,
+}
+
+impl Window {
+    pub(crate) fn parse(value: Option<&str>) -> anyhow::Result<Self> {
+        match value {
+            Some("1h") => Ok(Window::Hour),
+            None | Some("24h") => Ok(Window::Day),
+            Some("7d") => Ok(Window::Week),
+            Some(value) => Err(format_err!(
+                "invalid window = '{}', expected one of: 1h, 24h, 7d",
+                value
+            )),
+        }
+    }
+
+    fn duration(self) -> chrono::Duration {
+        match self {
+            Window::Hour => chrono::Duration::hours(1),
+            Window::Day => chrono::Duration::hours(24),
+            Window::Week => chrono::Duration::days(7),
+        }
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Window::Hour => write!(fmt, "1h"),
+            Window::Day => write!(fmt, "24h"),
+            Window::Week => write!(fmt, "7d"),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ActivityCount {
+    key: String,
+    count: i64,
+}
+
+impl From<GroupCount> for ActivityCount {
+    fn from(value: GroupCount) -> Self {
+        Self {
+            key: value.key,
+            count: value.count,
+        }
+    }
+}
+
+/// Statistics of the Sign endpoint drawn from the audit log.
+#[derive(Clone, Debug, Response)]
+pub(crate) struct SignActivity {
+    window: String,
+    from: String,
+    computed_at: String,
+    total: i64,
+    error_rate: f64,
+    avg_authz_latency_ms: Option<f64>,
+    by_subject: Vec<ActivityCount>,
+    by_bucket: Vec<ActivityCount>,
+    by_method: Vec<ActivityCount>,
+    top_objects: Vec<ActivityCount>,
+}
+
+impl SignActivity {
+    fn read(db: &ConnectionPool, window: Window, now: DateTime<Utc>) -> anyhow::Result<Self> {
+        let conn = db.get().context("failed to get a db connection")?;
+        let from = now - window.duration();
+        let q = ActivityQuery::new(SIGN_OPERATION, from);
+
+        let group = |column, limit| {
+            q.group_by(&conn, column, limit)
+                .map(|counts| counts.into_iter().map(ActivityCount::from).collect())
+                .context("failed to aggregate audit events")
+        };
+
+        let totals = q
+            .totals(&conn)
+            .context("failed to aggregate audit events")?;
+        Ok(Self {
+            window: window.to_string(),
+            from: from.to_rfc3339_opts(SecondsFormat::Secs, true),
+            computed_at: now.to_rfc3339_opts(SecondsFormat::Secs, true),
+            total: totals.total,
+            error_rate: error_rate(totals.total, totals.errors),
+            avg_authz_latency_ms: totals.avg_authz_duration_ms,
+            by_subject: group(GroupColumn::Subject, GROUP_LIMIT)?,
+            by_bucket: group(GroupColumn::Bucket, GROUP_LIMIT)?,
+            by_method: group(GroupColumn::Method, GROUP_LIMIT)?,
+            top_objects: group(GroupColumn::Object, TOP_OBJECTS_LIMIT)?,
+        })
+    }
+}
+
+fn error_rate(total: i64, errors: i64) -> f64 {
+    if total > 0 {
+        errors as f64 / total as f64
+    } else {
+        0.0
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Statistics are computed at most once a minute per window on each instance.
+pub(crate) struct SignActivityCache {
+    inner: Mutex<HashMap<Window, (Instant, SignActivity)>>,
+}
+
+impl fmt::Debug for SignActivityCache {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("SignActivityCache").finish()
+    }
+}
+
+impl SignActivityCache {
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn get(&self, db: &ConnectionPool, window: Window) -> anyhow::Result<SignActivity> {
+        if let Some(activity) = self.cached(window, Instant::now()) {
+            return Ok(activity);
+        }
+
+        let activity = SignActivity::read(db, window, Utc::now())?;
+        self.inner
+            .lock()
+            .expect("Sign activity cache lock is poisoned")
+            .insert(window, (Instant::now(), activity.clone()));
+        Ok(activity)
+    }
+
+    fn cached(&self, window: Window, now: Instant) -> Option<SignActivity> {
+        let inner = self
+            .inner
+            .lock()
+            .expect("Sign activity cache lock is poisoned");
+        match inner.get(&window) {
+            Some((computed_at, activity)) if now.duration_since(*computed_at) < CACHE_TTL => {
+                Some(activity.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_window() {
+        assert_eq!(Window::parse(None).unwrap(), Window::Day);
+        assert_eq!(Window::parse(Some("1h")).unwrap(), Window::Hour);
+        assert_eq!(Window::parse(Some("7d")).unwrap().to_string(), "7d");
+        assert!(Window::parse(Some("30d")).is_err());
+    }
+
+    #[test]
+    fn cached_activity_expires() {
+        let cache = SignActivityCache::new();
+        let activity = SignActivity {
+            window: "1h".into(),
+            from: "2020-01-01T09:00:00Z".into(),
+            computed_at: "2020-01-01T10:00:00Z".into(),
+            total: 4,
+            error_rate: error_rate(4, 1),
+            avg_authz_latency_ms: Some(12.5),
+            by_subject: vec![],
+            by_bucket: vec![],
+            by_method: vec![],
+            top_objects: vec![],
+        };
+        let computed_at = Instant::now();
+        cache
+            .inner
+            .lock()
+            .unwrap()
+            .insert(Window::Hour, (computed_at, activity));
+
+        let cached = cache.cached(Window::Hour, computed_at + Duration::from_secs(59));
+        assert_eq!(cached.map(|activity| activity.error_rate), Some(0.25));
+        assert!(cache.cached(Window::Day, computed_at).is_none());
+        assert!(cache
+            .cached(Window::Hour, computed_at + CACHE_TTL)
+            .is_none());
+    }
+}
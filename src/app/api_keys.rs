@@ -0,0 +1,517 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{format_err, Context};
+use chrono::{DateTime, Utc};
+use futures::Async;
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::pbkdf2_hmac;
+use svc_authn::AccountId;
+use uuid::Uuid;
+
+use crate::app::config::ApiKeysConfig;
+use crate::app::util::{AuthnMethod, Subject, TokenScope};
+use crate::db::{api_key, ConnectionPool};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+const HASH_ITERATIONS: usize = 100_000;
+const SALT_LENGTH: usize = 16;
+const HASH_LENGTH: usize = 32;
+const SECRET_LENGTH: usize = 32;
+
+/// Keys are hashed once a minute at most, so that requests don't pay for the key derivation.
+const VERIFIED_KEY_TTL: Duration = Duration::from_secs(60);
+/// Keys failed the verification are remembered as long as the verified ones, up to the limit.
+const MAX_REJECTED_KEYS: usize = 10_000;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Hashes the API key with PBKDF2-HMAC-SHA256 and a random salt:
+/// `pbkdf2-sha256$<iterations>$<salt>$<hash>`, the salt and the hash are hex encoded
+/// since keys of the configuration are lowercased on loading.
+pub(crate) fn hash_key(key: &str) -> anyhow::Result<String> {
+    let mut salt = [0; SALT_LENGTH];
+    openssl::rand::rand_bytes(&mut salt).context("failed to generate a salt")?;
+    let hash = derive(key, &salt, HASH_ITERATIONS)?;
+
+    Ok(format!(
+        "{}${}${}${}",
+        HASH_SCHEME,
+        HASH_ITERATIONS,
+        to_hex(&salt),
+        to_hex(&hash)
+    ))
+}
+
+/// Whether the key matches the hash, hashes of unknown formats match nothing.
+pub(crate) fn verify_key(key: &str, hash: &str) -> bool {
+    let parts = hash.split('$').collect::<Vec<&str>>();
+    let (iterations, salt, expected) = match parts.as_slice() {
+        [HASH_SCHEME, iterations, salt, expected] => (iterations, salt, expected),
+        _ => return false,
+    };
+    match (
+        iterations.parse::<usize>(),
+        from_hex(salt),
+        from_hex(expected),
+    ) {
+        (Ok(iterations), Some(salt), Some(expected)) if iterations > 0 => {
+            derive(key, &salt, iterations)
+                .map(|hash| hash.len() == expected.len() && openssl::memcmp::eq(&hash, &expected))
+                .unwrap_or(false)
+        }
+        _ => false,
+    }
+}
+
+/// Keys are derived on a thread of the pool allowed to block, so that other requests of
+/// the worker aren't held up, and in place outside of the pool, e.g. in tests.
+fn verify_key_blocking(key: &str, hash: &str) -> Result<bool, String> {
+    match tokio_threadpool::blocking(|| verify_key(key, hash)) {
+        Ok(Async::Ready(verified)) => Ok(verified),
+        // Every thread allowed to block is busy with other keys
+        Ok(Async::NotReady) => Err(String::from("too many api keys are being verified")),
+        Err(_) => Ok(verify_key(key, hash)),
+    }
+}
+
+/// Keys of the database are looked up and verified on a thread of the pool allowed to block
+/// as well, returns `None` if the key isn't found or doesn't match its hash.
+fn find_key_blocking(
+    db: &ConnectionPool,
+    id: Uuid,
+    key: &str,
+) -> Result<Option<api_key::Object>, String> {
+    let find = || {
+        let conn = db
+            .get()
+            .map_err(|_| String::from("db connection is unavailable"))?;
+        let object = api_key::FindQuery::new(id)
+            .execute(&conn)
+            .map_err(|err| format!("failed to find the api key: {}", err))?;
+        Ok(object.filter(|object| verify_key(key, &object.key_hash)))
+    };
+
+    match tokio_threadpool::blocking(find) {
+        Ok(Async::Ready(found)) => found,
+        Ok(Async::NotReady) => Err(String::from("too many api keys are being verified")),
+        Err(_) => find(),
+    }
+}
+
+fn derive(key: &str, salt: &[u8], iterations: usize) -> anyhow::Result<Vec<u8>> {
+    let mut hash = vec![0; HASH_LENGTH];
+    pbkdf2_hmac(
+        key.as_bytes(),
+        salt,
+        iterations,
+        MessageDigest::sha256(),
+        &mut hash,
+    )
+    .context("failed to hash the key")?;
+    Ok(hash)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) || !value.is_ascii() {
+        return None;
+    }
+
+    (0..value.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&value[idx..idx + 2], 16).ok())
+        .collect()
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Account of the API key along with its expiry and scope.
+#[derive(Clone, Debug)]
+struct ApiKeyGrant {
+    account_id: AccountId,
+    expires_at: Option<DateTime<Utc>>,
+    scope: Option<TokenScope>,
+}
+
+impl ApiKeyGrant {
+    fn parse(
+        account_id: AccountId,
+        expires_at: Option<&str>,
+        scope: Option<&[String]>,
+    ) -> anyhow::Result<Self> {
+        let expires_at = expires_at
+            .map(|value| {
+                DateTime::parse_from_rfc3339(value)
+                    .map(|value| value.with_timezone(&Utc))
+                    .map_err(|err| format_err!("invalid expires_at = '{}': {}", value, err))
+            })
+            .transpose()?;
+        let scope = scope.map(TokenScope::parse).transpose()?;
+
+        Ok(Self {
+            account_id,
+            expires_at,
+            scope,
+        })
+    }
+
+    fn subject(&self) -> Result<Subject, String> {
+        if let Some(expires_at) = self.expires_at {
+            if expires_at <= Utc::now() {
+                return Err(String::from("api key is expired"));
+            }
+        }
+
+        let mut subject = Subject::authenticated_by(self.account_id.clone(), AuthnMethod::ApiKey);
+        subject.set_scope(self.scope.clone());
+        Ok(subject)
+    }
+}
+
+/// Keys verified recently along with the time of their verification and their identifiers
+/// in the database, keyed by SHA-256 digests of keys.
+type VerifiedKeys = HashMap<[u8; 32], (Instant, Option<Uuid>, ApiKeyGrant)>;
+
+/// Keys failed the verification recently along with the time of their verification,
+/// keyed by SHA-256 digests of keys.
+type RejectedKeys = HashMap<[u8; 32], Instant>;
+
+/// API key created through the management API, the key itself isn't stored.
+#[derive(Debug)]
+pub(crate) struct CreatedApiKey {
+    pub(crate) id: Uuid,
+    pub(crate) key: String,
+    pub(crate) key_hash: String,
+    pub(crate) expires_at: Option<DateTime<Utc>>,
+}
+
+/// API keys of the configuration along with the ones created through the management API
+/// and stored in the database. Keys of both are prefixed with their identifiers,
+/// `<id>.<secret>`, so that they are looked up rather than matched against every hash.
+#[derive(Clone)]
+pub(crate) struct ApiKeyStore {
+    keys: Arc<HashMap<String, (String, ApiKeyGrant)>>,
+    db: Option<ConnectionPool>,
+    verified: Arc<Mutex<VerifiedKeys>>,
+    rejected: Arc<Mutex<RejectedKeys>>,
+}
+
+impl fmt::Debug for ApiKeyStore {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ApiKeyStore")
+            .field("keys", &self.keys.len())
+            .field("db", &self.db.is_some())
+            .finish()
+    }
+}
+
+impl ApiKeyStore {
+    pub(crate) fn new(config: &ApiKeysConfig, db: Option<ConnectionPool>) -> anyhow::Result<Self> {
+        let keys = config
+            .keys
+            .iter()
+            .map(|(id, entry)| {
+                if id.contains('.') {
+                    return Err(format_err!("invalid api key id = '{}'", id));
+                }
+                let grant = ApiKeyGrant::parse(
+                    entry.account_id.clone(),
+                    entry.expires_at.as_deref(),
+                    entry.scope.as_deref(),
+                )
+                .with_context(|| format!("invalid api key = '{}'", id))?;
+                // Keys of the configuration are lowercased on loading
+                Ok((id.to_lowercase(), (entry.hash.to_owned(), grant)))
+            })
+            .collect::<anyhow::Result<HashMap<_, _>>>()?;
+
+        Ok(Self {
+            keys: Arc::new(keys),
+            db,
+            verified: Arc::new(Mutex::new(HashMap::new())),
+            rejected: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Subject of the API key, fails if the key is unknown or expired.
+    pub(crate) fn authenticate(&self, key: &str) -> Result<Subject, String> {
+        let digest = openssl::sha::sha256(key.as_bytes());
+        let cached = {
+            let mut verified = self.verified.lock().expect("Api key lock is poisoned");
+            verified.retain(|_, (verified_at, _, _)| verified_at.elapsed() < VERIFIED_KEY_TTL);
+            verified.get(&digest).map(|(_, _, grant)| grant.clone())
+        };
+
+        let grant = match cached {
+            Some(grant) => grant,
+            None => {
+                let rejected = {
+                    let mut rejected = self.rejected.lock().expect("Api key lock is poisoned");
+                    rejected.retain(|_, rejected_at| rejected_at.elapsed() < VERIFIED_KEY_TTL);
+                    rejected.contains_key(&digest)
+                };
+                if rejected {
+                    return Err(String::from("unknown api key"));
+                }
+
+                let (id, grant) = match self.lookup(key)? {
+                    Some(found) => found,
+                    None => {
+                        let mut rejected = self.rejected.lock().expect("Api key lock is poisoned");
+                        if rejected.len() >= MAX_REJECTED_KEYS {
+                            rejected.clear();
+                        }
+                        rejected.insert(digest, Instant::now());
+                        return Err(String::from("unknown api key"));
+                    }
+                };
+                self.verified
+                    .lock()
+                    .expect("Api key lock is poisoned")
+                    .insert(digest, (Instant::now(), id, grant.clone()));
+                grant
+            }
+        };
+
+        grant.subject()
+    }
+
+    /// Keys without an identifier are unknown, so no key is verified against more than one hash.
+    fn lookup(&self, key: &str) -> Result<Option<(Option<Uuid>, ApiKeyGrant)>, String> {
+        let id = match key.split_once('.') {
+            Some((id, _)) => id,
+            None => return Ok(None),
+        };
+
+        if let Some((hash, grant)) = self.keys.get(&id.to_lowercase()) {
+            return Ok(if verify_key_blocking(key, hash)? {
+                Some((None, grant.clone()))
+            } else {
+                None
+            });
+        }
+
+        if let (Ok(id), Some(db)) = (id.parse::<Uuid>(), self.db.as_ref()) {
+            if let Some(object) = find_key_blocking(db, id, key)? {
+                let account_id = object
+                    .account_id
+                    .parse::<AccountId>()
+                    .map_err(|err| format!("invalid account of the api key: {}", err))?;
+                let grant = ApiKeyGrant {
+                    account_id,
+                    expires_at: object.expires_at,
+                    scope: object
+                        .scope
+                        .as_deref()
+                        .map(TokenScope::parse)
+                        .transpose()
+                        .map_err(|err| format!("invalid scope of the api key: {}", err))?,
+                };
+                return Ok(Some((Some(id), grant)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Creates a key of the account and stores its hash, the key is returned only once.
+    pub(crate) fn create(
+        &self,
+        account_id: &AccountId,
+        expires_at: Option<&str>,
+        scope: Option<&[String]>,
+        created_by: &AccountId,
+    ) -> anyhow::Result<CreatedApiKey> {
+        let db = self
+            .db
+            .as_ref()
+            .ok_or_else(|| format_err!("api keys can't be created without the database"))?;
+        let grant = ApiKeyGrant::parse(account_id.clone(), expires_at, scope)?;
+
+        let id = Uuid::new_v4();
+        let mut secret = [0; SECRET_LENGTH];
+        openssl::rand::rand_bytes(&mut secret).context("failed to generate a key")?;
+        let key = format!(
+            "{}.{}",
+            id,
+            base64::encode_config(&secret, base64::URL_SAFE_NO_PAD)
+        );
+        let key_hash = hash_key(&key)?;
+
+        let conn = db.get().context("db connection is unavailable")?;
+        api_key::InsertQuery::new(
+            id,
+            &key_hash,
+            &account_id.to_string(),
+            &created_by.to_string(),
+        )
+        .scope(scope)
+        .expires_at(grant.expires_at)
+        .execute(&conn)
+        .context("failed to store the api key")?;
+
+        Ok(CreatedApiKey {
+            id,
+            key,
+            key_hash,
+            expires_at: grant.expires_at,
+        })
+    }
+
+    /// The key created through the management API, `None` if it's not found.
+    pub(crate) fn find(&self, id: Uuid) -> anyhow::Result<Option<api_key::Object>> {
+        let db = self
+            .db
+            .as_ref()
+            .ok_or_else(|| format_err!("api keys can't be read without the database"))?;
+        let conn = db.get().context("db connection is unavailable")?;
+        api_key::FindQuery::new(id)
+            .execute(&conn)
+            .context("failed to find the api key")
+    }
+
+    /// Deletes the key created through the management API, returns `false` if it's not found.
+    pub(crate) fn delete(&self, id: Uuid) -> anyhow::Result<bool> {
+        let db = self
+            .db
+            .as_ref()
+            .ok_or_else(|| format_err!("api keys can't be deleted without the database"))?;
+        let conn = db.get().context("db connection is unavailable")?;
+        let deleted = api_key::DeleteQuery::new(id)
+            .execute(&conn)
+            .context("failed to delete the api key")?;
+
+        // The key must not be accepted from now on
+        self.verified
+            .lock()
+            .expect("Api key lock is poisoned")
+            .retain(|_, (_, key_id, _)| *key_id != Some(id));
+        Ok(deleted > 0)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::app::config::ApiKeyConfig;
+
+    #[test]
+    fn hash_and_verify_keys() {
+        let hash = hash_key("secret-key").unwrap();
+        assert!(hash.starts_with("pbkdf2-sha256$100000$"));
+        // Keys of the configuration are lowercased on loading
+        assert_eq!(hash.to_lowercase(), hash);
+        assert!(verify_key("secret-key", &hash));
+        assert!(!verify_key("secret-kez", &hash));
+        assert_ne!(hash_key("secret-key").unwrap(), hash);
+
+        let hash = "pbkdf2-sha256$100000$3ead6fd9ecfaaad858b38b8cc9ed7bee$b11bf03ee4b9a165abec06034dd07284a442a0532f3479e59db2fa0168d1c6bd";
+        assert!(verify_key("uploader-key", hash));
+        assert!(!verify_key("signer-key", hash));
+
+        // Hashes of unknown formats match nothing
+        assert!(!verify_key("secret-key", "secret-key"));
+        assert!(!verify_key("secret-key", "bcrypt$10$salt$hash"));
+        assert!(!verify_key(
+            "secret-key",
+            "pbkdf2-sha256$0$73616c74$68617368"
+        ));
+        assert!(!verify_key(
+            "secret-key",
+            "pbkdf2-sha256$1000$73616c7$68617368"
+        ));
+    }
+
+    fn key_config(key: &str, account_id: &str, expires_at: Option<&str>) -> ApiKeyConfig {
+        ApiKeyConfig {
+            hash: hash_key(key).unwrap(),
+            account_id: AccountId::new(account_id, "svc.example.org"),
+            expires_at: expires_at.map(ToOwned::to_owned),
+            scope: None,
+        }
+    }
+
+    #[test]
+    fn authenticate_configured_keys() {
+        let mut config = ApiKeysConfig::default();
+        config.keys.insert(
+            "uploader".into(),
+            key_config("uploader.secret", "uploader", None),
+        );
+        config.keys.insert(
+            "signer".into(),
+            ApiKeyConfig {
+                scope: Some(vec!["sign:bucket=videos.example.org/*:method=GET".into()]),
+                ..key_config("signer.secret", "signer", Some("2100-01-01T00:00:00Z"))
+            },
+        );
+        config.keys.insert(
+            "expired".into(),
+            key_config("expired.secret", "expired", Some("2000-01-01T00:00:00Z")),
+        );
+        let store = ApiKeyStore::new(&config, None).unwrap();
+
+        let subject = store.authenticate("uploader.secret").unwrap();
+        assert_eq!(subject.to_string(), "uploader.svc.example.org");
+        assert_eq!(subject.authn_method(), AuthnMethod::ApiKey);
+        assert!(subject
+            .check_sign_scope("videos.example.org", "a.mp4", "PUT")
+            .is_ok());
+
+        // Verified keys are served from the cache
+        assert!(store.authenticate("uploader.secret").is_ok());
+        assert_eq!(store.verified.lock().unwrap().len(), 1);
+
+        let subject = store.authenticate("signer.secret").unwrap();
+        assert!(subject
+            .check_sign_scope("videos.example.org", "a.mp4", "GET")
+            .is_ok());
+        assert!(subject
+            .check_sign_scope("videos.example.org", "a.mp4", "PUT")
+            .is_err());
+
+        assert_eq!(
+            store.authenticate("expired.secret").unwrap_err(),
+            "api key is expired"
+        );
+
+        // Keys without an identifier, of unknown ones, or failed the verification are remembered
+        for key in &["uploader-secret", "unknown.secret", "uploader.other"] {
+            assert_eq!(store.authenticate(key).unwrap_err(), "unknown api key");
+            assert_eq!(store.authenticate(key).unwrap_err(), "unknown api key");
+        }
+        assert_eq!(store.rejected.lock().unwrap().len(), 3);
+
+        // Keys of the database aren't found without it
+        let key = format!("{}.secret", Uuid::new_v4());
+        assert!(store.authenticate(&key).is_err());
+    }
+
+    #[test]
+    fn reject_invalid_configured_keys() {
+        let mut config = ApiKeysConfig::default();
+        config.keys.insert(
+            "signer".into(),
+            key_config("signer.secret", "signer", Some("tomorrow")),
+        );
+        assert!(ApiKeyStore::new(&config, None).is_err());
+
+        let mut config = ApiKeysConfig::default();
+        config.keys.insert(
+            "signer.v2".into(),
+            key_config("signer.v2.secret", "signer", None),
+        );
+        assert!(ApiKeyStore::new(&config, None).is_err());
+    }
+}
@@ -0,0 +1,1075 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{format_err, Context};
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::Future;
+use http::{Response, StatusCode};
+use log::{error, info};
+use svc_authn::AccountId;
+use uuid::Uuid;
+
+use crate::app::cost::{CostAttribution, CostRecord};
+use crate::app::export::AuditExporter;
+use crate::app::util::AuthnMethod;
+use crate::db::{audit_event, ConnectionPool};
+use crate::tower_web::Error;
+
+////////////////////////////////////////////////////////////////////////////////
+
+const REPORT_PAGE_SIZE: i64 = 1000;
+
+const REPORT_COLUMNS: &[&str] = &[
+    "request_id",
+    "timestamp",
+    "subject",
+    "bucket",
+    "set",
+    "object",
+    "method",
+    "authz_action",
+    "authz_decision",
+    "response_status",
+    "operation",
+    "authz_duration_ms",
+    "authn_method",
+];
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Access event to be recorded in the audit log.
+#[derive(Clone, Debug)]
+pub(crate) struct AuditEntry {
+    request_id: Uuid,
+    started_at: Instant,
+    subject: String,
+    bucket: String,
+    set: Option<String>,
+    object: Option<String>,
+    method: String,
+    action: String,
+    success_status: StatusCode,
+    operation: Option<String>,
+    authn_method: Option<String>,
+    cost_center: Option<String>,
+    // Set once the authorization completes
+    authz_duration: Arc<Mutex<Option<Duration>>>,
+    // Set once the size of the transferred object is known
+    size: Arc<Mutex<Option<u64>>>,
+}
+
+impl AuditEntry {
+    pub(crate) fn new(
+        sub: &AccountId,
+        bucket: &str,
+        method: &str,
+        action: &str,
+        success_status: StatusCode,
+    ) -> Self {
+        Self {
+            request_id: Uuid::new_v4(),
+            started_at: Instant::now(),
+            subject: sub.to_string(),
+            bucket: bucket.to_owned(),
+            set: None,
+            object: None,
+            method: method.to_owned(),
+            action: action.to_owned(),
+            success_status,
+            operation: None,
+            authn_method: None,
+            cost_center: None,
+            authz_duration: Arc::new(Mutex::new(None)),
+            size: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Name of the endpoint the event is produced by, e.g. `sign`.
+    pub(crate) fn operation(self, value: &str) -> Self {
+        Self {
+            operation: Some(value.to_owned()),
+            ..self
+        }
+    }
+
+    /// Mechanism the subject of the request has been authenticated by.
+    pub(crate) fn authn_method(self, value: AuthnMethod) -> Self {
+        Self {
+            authn_method: Some(value.to_string()),
+            ..self
+        }
+    }
+
+    /// Cost center the operation is attributed to, the bucket is used if it's absent.
+    pub(crate) fn cost_center(self, value: Option<&str>) -> Self {
+        Self {
+            cost_center: value.map(ToOwned::to_owned),
+            ..self
+        }
+    }
+
+    /// Size of the object transferred by the operation.
+    pub(crate) fn size(self, value: u64) -> Self {
+        self.size_recorder().record(value);
+        self
+    }
+
+    /// Records the size of the object once it's known, e.g. retrieved along with its version.
+    pub(crate) fn size_recorder(&self) -> SizeRecorder {
+        SizeRecorder(self.size.clone())
+    }
+
+    /// Measures the duration of the authorization, from now until the future completes.
+    pub(crate) fn time_authz<F: Future>(
+        &self,
+        f: F,
+    ) -> impl Future<Item = F::Item, Error = F::Error> {
+        let started_at = Instant::now();
+        let authz_duration = self.authz_duration.clone();
+
+        f.then(move |result| {
+            *authz_duration
+                .lock()
+                .expect("Audit authz duration lock is poisoned") = Some(started_at.elapsed());
+            result
+        })
+    }
+
+    fn authz_duration_ms(&self) -> Option<i32> {
+        self.authz_duration
+            .lock()
+            .expect("Audit authz duration lock is poisoned")
+            .map(|duration| duration.as_millis().min(i32::MAX as u128) as i32)
+    }
+
+    pub(crate) fn set(self, value: &str) -> Self {
+        Self {
+            set: Some(value.to_owned()),
+            ..self
+        }
+    }
+
+    pub(crate) fn object(self, value: &str) -> Self {
+        Self {
+            object: Some(value.to_owned()),
+            ..self
+        }
+    }
+
+    fn insert_query(&self, status: StatusCode) -> audit_event::InsertQuery<'_> {
+        audit_event::InsertQuery::new(
+            self.request_id,
+            &self.subject,
+            &self.bucket,
+            self.set.as_deref(),
+            self.object.as_deref(),
+            &self.method,
+            &self.action,
+            decision(status),
+            i32::from(status.as_u16()),
+        )
+        .operation(self.operation.as_deref())
+        .authz_duration_ms(self.authz_duration_ms())
+        .authn_method(self.authn_method.as_deref())
+    }
+
+    fn cost_record(&self) -> CostRecord<'_> {
+        CostRecord {
+            request_id: self.request_id,
+            subject: &self.subject,
+            bucket: &self.bucket,
+            method: &self.method,
+            action: &self.action,
+            size: *self.size.lock().expect("Audit size lock is poisoned"),
+            cost_center: self.cost_center.as_deref(),
+        }
+    }
+
+    /// Line of NDJSON files the audit log is exported to, along with the position
+    /// of the event in the hash chain if it's been recorded in the database.
+    fn export_record(&self, pending: &PendingEvent, event: Option<&audit_event::Object>) -> String {
+        let status = pending.status;
+        serde_json::json!({
+            "request_id": self.request_id.to_string(),
+            "recorded_at": pending.recorded_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "subject": self.subject,
+            "bucket": self.bucket,
+            "set": self.set,
+            "object": self.object,
+            "method": self.method,
+            "action": self.action,
+            "decision": decision(status),
+            "status": status.as_u16(),
+            "operation": self.operation,
+            "authn_method": self.authn_method,
+            "cost_center": self.cost_center,
+            "size": *self.size.lock().expect("Audit size lock is poisoned"),
+            "authz_duration_ms": self.authz_duration_ms(),
+            "duration_ms": pending.duration_ms,
+            "chain_seq": event.and_then(|event| event.chain_seq),
+            "prev_hash": event.and_then(|event| event.prev_hash.as_deref()),
+        })
+        .to_string()
+    }
+
+    fn log(&self, status: StatusCode) {
+        info!(
+            target: "storage::access",
+            request_id:% = self.request_id,
+            subject = self.subject.as_str(),
+            bucket = self.bucket.as_str(),
+            set = self.set.as_deref(),
+            object = self.object.as_deref(),
+            method = self.method.as_str(),
+            action = self.action.as_str(),
+            authn_method = self.authn_method.as_deref(),
+            status = status.as_u16(),
+            duration_ms = self.started_at.elapsed().as_millis() as u64;
+            "{} {} {}", self.method, self.bucket, status.as_u16()
+        );
+    }
+}
+
+fn decision(status: StatusCode) -> &'static str {
+    if status == StatusCode::FORBIDDEN {
+        "deny"
+    } else {
+        "allow"
+    }
+}
+
+/// Event waiting to be appended to the hash chain and exported.
+struct PendingEvent {
+    entry: AuditEntry,
+    status: StatusCode,
+    recorded_at: DateTime<Utc>,
+    duration_ms: u64,
+    exporter: Option<AuditExporter>,
+}
+
+impl PendingEvent {
+    fn new(entry: &AuditEntry, status: StatusCode, exporter: Option<AuditExporter>) -> Self {
+        Self {
+            entry: entry.clone(),
+            status,
+            recorded_at: Utc::now(),
+            duration_ms: entry.started_at.elapsed().as_millis() as u64,
+            exporter,
+        }
+    }
+
+    fn export(&self, event: Option<&audit_event::Object>) {
+        if let Some(ref exporter) = self.exporter {
+            exporter.push(self.entry.export_record(self, event));
+        }
+    }
+}
+
+/// Events waiting for the writer at most, events are dropped once there are more of them.
+const WRITER_QUEUE_CAPACITY: usize = 10_000;
+
+/// Events appended to the hash chain within a single transaction at most.
+const WRITER_BATCH_SIZE: usize = 100;
+
+/// Appends events to the hash chain on a dedicated thread, so that requests never wait
+/// for the lock of the chain serializing appends across instances.
+#[derive(Clone)]
+struct AuditWriter {
+    tx: mpsc::SyncSender<PendingEvent>,
+}
+
+impl AuditWriter {
+    fn spawn(db: ConnectionPool) -> anyhow::Result<Self> {
+        let (tx, rx) = mpsc::sync_channel::<PendingEvent>(WRITER_QUEUE_CAPACITY);
+
+        std::thread::Builder::new()
+            .name("audit-writer".to_owned())
+            .spawn(move || {
+                while let Ok(first) = rx.recv() {
+                    let mut batch = vec![first];
+                    while batch.len() < WRITER_BATCH_SIZE {
+                        match rx.try_recv() {
+                            Ok(pending) => batch.push(pending),
+                            Err(_) => break,
+                        }
+                    }
+
+                    match append(&db, &batch) {
+                        Ok(events) => {
+                            for (pending, event) in batch.iter().zip(events.iter()) {
+                                pending.export(Some(event));
+                            }
+                        }
+                        Err(err) => {
+                            error!("Error recording {} audit events: {:#}", batch.len(), err);
+                            for pending in &batch {
+                                pending.export(None);
+                            }
+                        }
+                    }
+                }
+            })
+            .context("failed to spawn an audit writer thread")?;
+
+        Ok(Self { tx })
+    }
+
+    fn push(&self, pending: PendingEvent) {
+        match self.tx.try_send(pending) {
+            Ok(()) => (),
+            Err(mpsc::TrySendError::Full(pending)) => {
+                error!(
+                    "Error recording an audit event: the queue is full, request_id = '{}'",
+                    pending.entry.request_id
+                );
+                pending.export(None);
+            }
+            Err(mpsc::TrySendError::Disconnected(pending)) => {
+                error!("Error recording an audit event: the writer has stopped");
+                pending.export(None);
+            }
+        }
+    }
+}
+
+/// Appends the events to the hash chain, appends are serialized across instances.
+fn append(db: &ConnectionPool, batch: &[PendingEvent]) -> anyhow::Result<Vec<audit_event::Object>> {
+    use diesel::Connection;
+
+    let conn = db.get().context("failed to get a db connection")?;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        audit_event::lock_chain(&conn)?;
+        let (mut seq, mut prev_hash) = match audit_event::chain_head(&conn)? {
+            Some(head) => (head.chain_seq.unwrap_or(0) + 1, chain_hash(&head)),
+            None => (1, GENESIS_HASH.to_owned()),
+        };
+        let mut events = Vec::with_capacity(batch.len());
+        for pending in batch {
+            let event = pending
+                .entry
+                .insert_query(pending.status)
+                .chain(seq, &prev_hash)
+                .execute(&conn)?;
+            seq += 1;
+            prev_hash = chain_hash(&event);
+            events.push(event);
+        }
+        Ok(events)
+    })
+    .context("failed to insert audit events")
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct SizeRecorder(Arc<Mutex<Option<u64>>>);
+
+impl SizeRecorder {
+    pub(crate) fn record(&self, value: u64) {
+        *self.0.lock().expect("Audit size lock is poisoned") = Some(value);
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Audit log of access events stored in the database, disabled without one.
+/// Costs of successful operations are attributed to their subjects if cost attribution is enabled.
+/// Events are also exported to S3 if the exporter is set, regardless of the database.
+#[derive(Clone)]
+pub(crate) struct AuditLog {
+    db: Option<ConnectionPool>,
+    writer: Option<AuditWriter>,
+    costs: Option<CostAttribution>,
+    exporter: Option<AuditExporter>,
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("AuditLog")
+            .field("enabled", &self.db.is_some())
+            .field("costs", &self.costs)
+            .field("exporter", &self.exporter)
+            .finish()
+    }
+}
+
+impl AuditLog {
+    pub(crate) fn new(db: Option<ConnectionPool>) -> anyhow::Result<Self> {
+        let writer = db.clone().map(AuditWriter::spawn).transpose()?;
+        Ok(Self {
+            db,
+            writer,
+            costs: None,
+            exporter: None,
+        })
+    }
+
+    pub(crate) fn set_exporter(&mut self, exporter: AuditExporter) -> &mut Self {
+        self.exporter = Some(exporter);
+        self
+    }
+
+    pub(crate) fn set_cost_attribution(&mut self, costs: CostAttribution) -> &mut Self {
+        self.costs = Some(costs);
+        self
+    }
+
+    pub(crate) fn costs(&self) -> Option<&CostAttribution> {
+        self.costs.as_ref()
+    }
+
+    pub(crate) fn db(&self) -> Option<&ConnectionPool> {
+        self.db.as_ref()
+    }
+
+    /// Records the outcome of the authorized operation,
+    /// `403` responses are recorded as denied, any other as allowed.
+    /// The outcome is logged with `storage::access` target even if the audit log is disabled.
+    pub(crate) fn observe<F, T>(
+        &self,
+        entry: AuditEntry,
+        f: F,
+    ) -> impl Future<Item = Result<T, Error>, Error = ()>
+    where
+        F: Future<Item = Result<T, Error>, Error = ()>,
+    {
+        let log = self.clone();
+
+        f.map(move |result| {
+            let status = match result {
+                Ok(_) => entry.success_status,
+                Err(ref err) => err.status_code(),
+            };
+            log.record(&entry, status);
+
+            result
+        })
+    }
+
+    /// Records the outcome of an operation performed outside of a request, e.g. by a background job.
+    pub(crate) fn record(&self, entry: &AuditEntry, status: StatusCode) {
+        entry.log(status);
+
+        if let Some(ref costs) = self.costs {
+            if status.is_success() || status.is_redirection() {
+                costs.record(&entry.cost_record());
+            }
+        }
+
+        // Events are exported once they're appended to the hash chain
+        let pending = PendingEvent::new(entry, status, self.exporter.clone());
+        match self.writer {
+            Some(ref writer) => writer.push(pending),
+            None => pending.export(None),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Filter of events included into an access review report.
+#[derive(Clone, Debug)]
+pub(crate) struct ReportFilter {
+    pub(crate) from: DateTime<Utc>,
+    pub(crate) to: DateTime<Utc>,
+    pub(crate) bucket: Option<String>,
+    pub(crate) subject: Option<String>,
+}
+
+impl ReportFilter {
+    pub(crate) fn new(from: &str, to: &str) -> anyhow::Result<Self> {
+        let parse = |value: &str| {
+            DateTime::parse_from_rfc3339(value)
+                .map(|value| value.with_timezone(&Utc))
+                .map_err(|err| format_err!("invalid timestamp = '{}': {}", value, err))
+        };
+
+        let (from, to) = (parse(from)?, parse(to)?);
+        if from >= to {
+            return Err(format_err!("'from' must precede 'to'"));
+        }
+
+        Ok(Self {
+            from,
+            to,
+            bucket: None,
+            subject: None,
+        })
+    }
+
+    pub(crate) fn range(&self) -> Duration {
+        (self.to - self.from).to_std().unwrap_or_default()
+    }
+
+    /// Reads a page of events following the event identified by the cursor.
+    pub(crate) fn page(
+        &self,
+        db: &ConnectionPool,
+        after: Option<(DateTime<Utc>, Uuid)>,
+        limit: i64,
+    ) -> anyhow::Result<Vec<audit_event::Object>> {
+        let conn = db.get().context("failed to get a db connection")?;
+
+        audit_event::ListQuery::new(self.from, self.to, limit)
+            .bucket(self.bucket.as_deref())
+            .subject(self.subject.as_deref())
+            .after(after)
+            .execute(&conn)
+            .context("failed to list audit events")
+    }
+
+    fn all(&self, db: &ConnectionPool) -> anyhow::Result<Vec<audit_event::Object>> {
+        let mut events = Vec::new();
+        let mut after = None;
+
+        loop {
+            let page = self.page(db, after, REPORT_PAGE_SIZE)?;
+            let last = page.last().map(|event| (event.created_at, event.id));
+            let done = (page.len() as i64) < REPORT_PAGE_SIZE;
+            events.extend(page);
+
+            match last {
+                Some(last) if !done => after = Some(last),
+                _ => return Ok(events),
+            }
+        }
+    }
+}
+
+/// Hash of the first event of the chain preceding it.
+pub(crate) const GENESIS_HASH: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Fields of an event covered by the hash, in the order they're serialized.
+#[derive(Serialize)]
+struct ChainRecord<'a> {
+    id: String,
+    request_id: String,
+    created_at: String,
+    subject: &'a str,
+    bucket: &'a str,
+    set: Option<&'a str>,
+    object: Option<&'a str>,
+    method: &'a str,
+    authz_action: &'a str,
+    authz_decision: &'a str,
+    response_status: i32,
+    operation: Option<&'a str>,
+    authz_duration_ms: Option<i32>,
+    authn_method: Option<&'a str>,
+    chain_seq: Option<i64>,
+    prev_hash: Option<&'a str>,
+}
+
+/// SHA-256 of the JSON representation of the event, including its link to the preceding one.
+pub(crate) fn chain_hash(event: &audit_event::Object) -> String {
+    let record = ChainRecord {
+        id: event.id.to_string(),
+        request_id: event.request_id.to_string(),
+        created_at: event
+            .created_at
+            .to_rfc3339_opts(SecondsFormat::Micros, true),
+        subject: &event.subject,
+        bucket: &event.bucket,
+        set: event.set.as_deref(),
+        object: event.object.as_deref(),
+        method: &event.method,
+        authz_action: &event.authz_action,
+        authz_decision: &event.authz_decision,
+        response_status: event.response_status,
+        operation: event.operation.as_deref(),
+        authz_duration_ms: event.authz_duration_ms,
+        authn_method: event.authn_method.as_deref(),
+        chain_seq: event.chain_seq,
+        prev_hash: event.prev_hash.as_deref(),
+    };
+    let data = serde_json::to_vec(&record).expect("Error serializing an audit event");
+
+    openssl::sha::sha256(&data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Deleted events are reported as gaps in positions of the chain,
+/// modified ones by hashes their successors are linked with.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum ChainIssue {
+    Gap {
+        chain_seq: i64,
+        missing: i64,
+    },
+    HashMismatch {
+        chain_seq: i64,
+        expected: String,
+        actual: Option<String>,
+    },
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ChainReport {
+    pub(crate) verified: usize,
+    pub(crate) issues: Vec<ChainIssue>,
+    /// Position to continue the verification from, if there could be more events.
+    pub(crate) next: Option<i64>,
+}
+
+/// Verifies `count` events of the hash chain starting from the position `from`.
+pub(crate) fn verify(db: &ConnectionPool, from: i64, count: i64) -> anyhow::Result<ChainReport> {
+    let conn = db.get().context("failed to get a db connection")?;
+    // The preceding event is read along with the rest to verify the link of the first one
+    let anchored = if from > 1 { 1 } else { 0 };
+    let events = audit_event::ChainQuery::new(from - anchored, count + anchored)
+        .execute(&conn)
+        .context("failed to read audit events")?;
+
+    let mut report = verify_chain(from, &events);
+    if events.len() as i64 == count + anchored {
+        report.next = events
+            .last()
+            .and_then(|event| event.chain_seq)
+            .map(|seq| seq + 1);
+    }
+    Ok(report)
+}
+
+/// Recomputes the hash chain of events starting from the position `from`, ordered by positions.
+/// The event preceding `from` is expected to be the first one unless `from` is the beginning
+/// of the chain, it anchors the chain and isn't counted as verified.
+pub(crate) fn verify_chain(from: i64, events: &[audit_event::Object]) -> ChainReport {
+    let mut report = ChainReport::default();
+    let mut expected_seq = (from - 1).max(1);
+    let mut expected_hash = if expected_seq == 1 {
+        Some(GENESIS_HASH.to_owned())
+    } else {
+        None
+    };
+
+    for event in events {
+        let seq = match event.chain_seq {
+            Some(val) => val,
+            None => continue,
+        };
+        if seq > expected_seq {
+            report.issues.push(ChainIssue::Gap {
+                chain_seq: expected_seq,
+                missing: seq - expected_seq,
+            });
+        } else if let Some(expected) = expected_hash {
+            if event.prev_hash.as_deref() != Some(expected.as_str()) {
+                report.issues.push(ChainIssue::HashMismatch {
+                    chain_seq: seq,
+                    expected,
+                    actual: event.prev_hash.clone(),
+                });
+            }
+        }
+
+        if seq >= from {
+            report.verified += 1;
+        }
+        expected_seq = seq + 1;
+        expected_hash = Some(chain_hash(event));
+    }
+
+    report
+}
+
+pub(crate) fn encode_position(event: &audit_event::Object) -> String {
+    format!(
+        "{}|{}",
+        event
+            .created_at
+            .to_rfc3339_opts(SecondsFormat::Micros, true),
+        event.id
+    )
+}
+
+pub(crate) fn decode_position(value: &str) -> anyhow::Result<(DateTime<Utc>, Uuid)> {
+    let (created_at, id) = value
+        .split_once('|')
+        .ok_or_else(|| format_err!("invalid cursor"))?;
+    let created_at = DateTime::parse_from_rfc3339(created_at)
+        .context("invalid cursor")?
+        .with_timezone(&Utc);
+    let id = Uuid::parse_str(id).map_err(|_| format_err!("invalid cursor"))?;
+    Ok((created_at, id))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ReportFormat {
+    Json,
+    Csv,
+}
+
+impl ReportFormat {
+    pub(crate) fn parse(value: Option<&str>) -> anyhow::Result<Self> {
+        match value {
+            None | Some("json") => Ok(ReportFormat::Json),
+            Some("csv") => Ok(ReportFormat::Csv),
+            Some(value) => Err(format_err!(
+                "invalid format = '{}', expected one of: json, csv",
+                value
+            )),
+        }
+    }
+}
+
+fn report_row(event: &audit_event::Object) -> Vec<String> {
+    vec![
+        event.request_id.to_string(),
+        event
+            .created_at
+            .to_rfc3339_opts(SecondsFormat::Millis, true),
+        event.subject.clone(),
+        event.bucket.clone(),
+        event.set.clone().unwrap_or_default(),
+        event.object.clone().unwrap_or_default(),
+        event.method.clone(),
+        event.authz_action.clone(),
+        event.authz_decision.clone(),
+        event.response_status.to_string(),
+        event.operation.clone().unwrap_or_default(),
+        event
+            .authz_duration_ms
+            .map(|value| value.to_string())
+            .unwrap_or_default(),
+        event.authn_method.clone().unwrap_or_default(),
+    ]
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Renders the report, the cursor of the next page is passed in the `next-cursor` header.
+pub(crate) fn report(
+    events: &[audit_event::Object],
+    next_cursor: Option<&str>,
+    format: ReportFormat,
+) -> Response<String> {
+    let (content_type, body) = match format {
+        ReportFormat::Csv => {
+            let mut body = REPORT_COLUMNS.join(",");
+            body.push('\n');
+            for event in events {
+                let row = report_row(event)
+                    .iter()
+                    .map(|value| csv_field(value))
+                    .collect::<Vec<String>>();
+                body.push_str(&row.join(","));
+                body.push('\n');
+            }
+            ("text/csv", body)
+        }
+        ReportFormat::Json => {
+            let events = events
+                .iter()
+                .map(|event| {
+                    let mut row = serde_json::Map::new();
+                    for (column, value) in REPORT_COLUMNS.iter().zip(report_row(event)) {
+                        row.insert(column.to_string(), serde_json::Value::String(value));
+                    }
+                    row.insert(
+                        "response_status".to_owned(),
+                        serde_json::Value::from(event.response_status),
+                    );
+                    row.insert("operation".to_owned(), serde_json::json!(event.operation));
+                    row.insert(
+                        "authz_duration_ms".to_owned(),
+                        serde_json::json!(event.authz_duration_ms),
+                    );
+                    row.insert(
+                        "authn_method".to_owned(),
+                        serde_json::json!(event.authn_method),
+                    );
+                    serde_json::Value::Object(row)
+                })
+                .collect::<Vec<serde_json::Value>>();
+            let body = serde_json::json!({
+                "events": events,
+                "next_cursor": next_cursor,
+                "has_more": next_cursor.is_some(),
+            });
+            ("application/json", body.to_string())
+        }
+    };
+
+    let mut builder = Response::builder();
+    builder.header("content-type", content_type);
+    if let Some(cursor) = next_cursor {
+        builder.header("next-cursor", cursor);
+    }
+    builder.status(StatusCode::OK).body(body).unwrap()
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+enum JobState {
+    Pending,
+    Completed(Vec<audit_event::Object>),
+    Failed(String),
+}
+
+struct Job {
+    created_at: Instant,
+    state: JobState,
+}
+
+/// Access review reports generated in background, kept in memory of the instance for the ttl.
+pub(crate) struct ReportJobs {
+    ttl: Duration,
+    inner: Arc<Mutex<HashMap<Uuid, Job>>>,
+}
+
+impl fmt::Debug for ReportJobs {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ReportJobs")
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+impl ReportJobs {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            inner: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Starts generating the report in a separate thread, returns the identifier of the job.
+    pub(crate) fn start(&self, db: ConnectionPool, filter: ReportFilter) -> Uuid {
+        let id = Uuid::new_v4();
+        let ttl = self.ttl;
+        {
+            let mut inner = self.inner.lock().expect("Report jobs lock is poisoned");
+            inner.retain(|_, job| job.created_at.elapsed() < ttl);
+            inner.insert(
+                id,
+                Job {
+                    created_at: Instant::now(),
+                    state: JobState::Pending,
+                },
+            );
+        }
+
+        let inner = self.inner.clone();
+        std::thread::spawn(move || {
+            let state = match filter.all(&db) {
+                Ok(events) => JobState::Completed(events),
+                Err(err) => {
+                    error!("Error generating an access review report: {:#}", err);
+                    JobState::Failed(format!("{:#}", err))
+                }
+            };
+
+            let mut inner = inner.lock().expect("Report jobs lock is poisoned");
+            if let Some(job) = inner.get_mut(&id) {
+                job.state = state;
+            }
+        });
+
+        id
+    }
+
+    /// Renders the report of the completed job, `None` if the job is unknown or expired.
+    pub(crate) fn report(
+        &self,
+        id: Uuid,
+        format: ReportFormat,
+    ) -> Option<Result<Response<String>, String>> {
+        let inner = self.inner.lock().expect("Report jobs lock is poisoned");
+        let job = inner
+            .get(&id)
+            .filter(|job| job.created_at.elapsed() < self.ttl)?;
+
+        Some(match job.state {
+            JobState::Pending => Ok(job_response(id, "pending")),
+            JobState::Completed(ref events) => Ok(report(events, None, format)),
+            JobState::Failed(ref err) => Err(err.to_owned()),
+        })
+    }
+}
+
+/// `202 Accepted` response referring to the job generating the report.
+pub(crate) fn job_response(id: Uuid, status: &str) -> Response<String> {
+    let body = serde_json::json!({ "job_id": id.to_string(), "status": status });
+
+    Response::builder()
+        .header("content-type", "application/json")
+        .status(StatusCode::ACCEPTED)
+        .body(body.to_string())
+        .unwrap()
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> audit_event::Object {
+        audit_event::Object {
+            id: Uuid::new_v4(),
+            request_id: Uuid::new_v4(),
+            subject: "alice.usr.example.org".into(),
+            bucket: "data.example.org".into(),
+            set: Some("foo".into()),
+            object: Some("bar, \"baz\"".into()),
+            method: "GET".into(),
+            authz_action: "read".into(),
+            authz_decision: "allow".into(),
+            response_status: 303,
+            created_at: "2020-01-01T10:00:00.123456Z".parse().unwrap(),
+            operation: None,
+            authz_duration_ms: None,
+            authn_method: None,
+            chain_seq: None,
+            prev_hash: None,
+        }
+    }
+
+    fn chain(len: i64) -> Vec<audit_event::Object> {
+        let mut events: Vec<audit_event::Object> = Vec::new();
+        for seq in 1..=len {
+            let prev_hash = match events.last() {
+                Some(prev) => chain_hash(prev),
+                None => GENESIS_HASH.to_owned(),
+            };
+            events.push(audit_event::Object {
+                chain_seq: Some(seq),
+                prev_hash: Some(prev_hash),
+                ..event()
+            });
+        }
+        events
+    }
+
+    #[test]
+    fn chain_hash_fields() {
+        let event = event();
+        let hash = chain_hash(&event);
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, chain_hash(&event));
+
+        let modified = audit_event::Object {
+            response_status: 200,
+            ..event
+        };
+        assert_ne!(hash, chain_hash(&modified));
+    }
+
+    #[test]
+    fn verify_chain_issues() {
+        let events = chain(5);
+        let report = verify_chain(1, &events);
+        assert_eq!(report.verified, 5);
+        assert!(report.issues.is_empty());
+        // The preceding event anchors the hash of the first one
+        let report = verify_chain(3, &events[1..]);
+        assert_eq!(report.verified, 3);
+        assert!(report.issues.is_empty());
+
+        let mut modified = chain(5);
+        modified[1].subject = "mallory.usr.example.org".into();
+        let report = verify_chain(1, &modified);
+        assert_eq!(
+            report.issues,
+            vec![ChainIssue::HashMismatch {
+                chain_seq: 3,
+                expected: chain_hash(&modified[1]),
+                actual: modified[2].prev_hash.clone(),
+            }]
+        );
+
+        let mut deleted = chain(5);
+        deleted.remove(2);
+        let report = verify_chain(1, &deleted);
+        assert_eq!(report.verified, 4);
+        assert_eq!(
+            report.issues,
+            vec![ChainIssue::Gap {
+                chain_seq: 3,
+                missing: 1,
+            }]
+        );
+        let report = verify_chain(4, &deleted[2..]);
+        assert_eq!(
+            report.issues,
+            vec![ChainIssue::Gap {
+                chain_seq: 3,
+                missing: 1,
+            }]
+        );
+
+        let mut genesis = chain(2);
+        genesis[0].prev_hash = Some("ff".into());
+        let report = verify_chain(1, &genesis);
+        assert_eq!(
+            report.issues[0],
+            ChainIssue::HashMismatch {
+                chain_seq: 1,
+                expected: GENESIS_HASH.to_owned(),
+                actual: Some("ff".into()),
+            }
+        );
+        // The link of the next event covers the modified one
+        assert_eq!(report.issues.len(), 2);
+    }
+
+    #[test]
+    fn position_roundtrip() {
+        let event = event();
+        let position = decode_position(&encode_position(&event)).unwrap();
+        assert_eq!(position, (event.created_at, event.id));
+        assert!(decode_position("not a cursor").is_err());
+    }
+
+    #[test]
+    fn report_csv() {
+        let resp = report(&[event()], None, ReportFormat::Csv);
+        let mut lines = resp.body().lines();
+        assert_eq!(lines.next().unwrap(), REPORT_COLUMNS.join(","));
+        let row = lines.next().unwrap();
+        assert!(row.contains(",foo,\"bar, \"\"baz\"\"\",GET,read,allow,303"));
+        assert_eq!(resp.headers()["content-type"], "text/csv");
+    }
+
+    #[test]
+    fn export_record_fields() {
+        let sub = AccountId::new("alice", "usr.example.org");
+        let entry = AuditEntry::new(&sub, "data.example.org", "GET", "read", StatusCode::OK)
+            .set("foo")
+            .object("bar")
+            .size(1024);
+        let pending = PendingEvent::new(&entry, StatusCode::FORBIDDEN, None);
+
+        let record: serde_json::Value =
+            serde_json::from_str(&entry.export_record(&pending, None)).unwrap();
+        assert_eq!(record["subject"], "alice.usr.example.org");
+        assert_eq!(record["set"], "foo");
+        assert_eq!(record["decision"], "deny");
+        assert_eq!(record["status"], 403);
+        assert_eq!(record["size"], 1024);
+        assert_eq!(record["request_id"], entry.request_id.to_string());
+        assert!(record["prev_hash"].is_null());
+    }
+
+    #[test]
+    fn report_filter_range() {
+        let filter =
+            ReportFilter::new("2020-01-01T00:00:00Z", "2020-01-02T00:00:00+00:00").unwrap();
+        assert_eq!(filter.range(), Duration::from_secs(86400));
+        assert!(ReportFilter::new("2020-01-02T00:00:00Z", "2020-01-01T00:00:00Z").is_err());
+        assert!(ReportFilter::new("yesterday", "2020-01-01T00:00:00Z").is_err());
+    }
+}
@@ -0,0 +1,835 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use anyhow::{format_err, Context};
+use chrono::{SecondsFormat, Utc};
+use futures::sync::{mpsc as async_mpsc, oneshot};
+use futures::{future, Future, Stream};
+use log::{error, info, warn};
+use r2d2_redis::{r2d2, redis, RedisConnectionManager};
+use svc_authn::{AccountId, Authenticable};
+use tokio::timer::Timeout;
+use wasmtime::{Instance, TypedFunc};
+
+use crate::app::config::WasmPolicyConfig;
+use crate::app::sqs::SqsPublisher;
+use crate::app::wasm::{Sandbox, SandboxLimits};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+pub(crate) enum AuthzError {
+    Policy(String),
+    Backend(Box<svc_authz::Error>),
+}
+
+impl fmt::Display for AuthzError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthzError::Policy(detail) => write!(fmt, "[Forbidden] {}", detail),
+            AuthzError::Backend(err) => fmt::Display::fmt(err, fmt),
+        }
+    }
+}
+
+const ENFORCED_ACTIONS: &[&str] = &["admin", "delegate"];
+
+type AuthzFuture = Box<dyn Future<Item = Result<(), AuthzError>, Error = ()> + Send>;
+
+/// Subject of intents authorized by [`Authz`].
+pub(crate) trait IntentSubject: Authenticable {
+    /// Intents of bearers of delegation tokens are authorized by the grant of the token only.
+    fn is_delegated(&self) -> bool {
+        false
+    }
+}
+
+impl IntentSubject for AccountId {}
+
+/// How intents are authorized, configured by `authz.mode` and overridden per route.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum AuthzMode {
+    /// Denied intents are rejected.
+    #[default]
+    Enforce,
+    /// Intents aren't authorized at all, they're only published for asynchronous audit.
+    AuditOnly,
+    /// Denied intents are published for asynchronous audit, but aren't rejected.
+    PermissiveAudit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum AuthzDecision {
+    Unchecked,
+    Denied,
+}
+
+/// Authorization event published in `audit-only` and `permissive-audit` modes.
+#[derive(Debug, Serialize)]
+struct AuthzEvent {
+    time: String,
+    mode: AuthzMode,
+    decision: AuthzDecision,
+    audience: String,
+    subject: String,
+    object: Vec<String>,
+    action: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+impl AuthzEvent {
+    fn new(
+        mode: AuthzMode,
+        decision: AuthzDecision,
+        audience: &str,
+        subject: &AccountId,
+        object: &[&str],
+        action: &str,
+    ) -> Self {
+        Self {
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            mode,
+            decision,
+            audience: audience.to_owned(),
+            subject: subject.to_string(),
+            object: object.iter().map(|value| (*value).to_owned()).collect(),
+            action: action.to_owned(),
+            reason: None,
+        }
+    }
+}
+
+/// Authorization events are published to the queue if it's configured, or logged otherwise.
+#[derive(Clone, Debug)]
+struct AuthzEvents {
+    queue: Option<SqsPublisher>,
+}
+
+impl AuthzEvents {
+    fn publish(&self, event: &AuthzEvent) {
+        let message = match serde_json::to_string(event) {
+            Ok(message) => message,
+            Err(err) => {
+                error!("Error serializing an authz event: {}", err);
+                return;
+            }
+        };
+
+        match self.queue {
+            Some(ref queue) => queue.publish(message),
+            None => info!(target: "storage::authz", "{}", message),
+        }
+    }
+}
+
+/// Authz clients of the audiences, the intents are evaluated by the WASM policy first
+/// and only those allowed by the policy are sent to the authz backend.
+#[derive(Clone, Debug)]
+pub(crate) struct Authz {
+    inner: Arc<svc_authz::ClientMap>,
+    policy: Option<WasmPolicy>,
+    mode: AuthzMode,
+    /// Mode of the routes reading objects and signing requests unless overridden,
+    /// the rest of the routes are always enforced.
+    routes_mode: AuthzMode,
+    events: AuthzEvents,
+}
+
+impl Authz {
+    pub(crate) fn new(
+        inner: svc_authz::ClientMap,
+        policy: Option<WasmPolicy>,
+        mode: AuthzMode,
+        queue: Option<SqsPublisher>,
+    ) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            policy,
+            mode: AuthzMode::Enforce,
+            routes_mode: mode,
+            events: AuthzEvents { queue },
+        }
+    }
+
+    pub(crate) fn client_map(&self) -> &svc_authz::ClientMap {
+        &self.inner
+    }
+
+    /// Returns the client authorizing intents of the read or sign route in its mode,
+    /// `authz.mode` unless it's overridden.
+    pub(crate) fn with_mode(&self, mode: Option<AuthzMode>) -> Self {
+        let mut authz = self.clone();
+        authz.mode = mode.unwrap_or(self.routes_mode);
+        authz
+    }
+
+    /// Administrative and delegation intents, as well as those of anonymous subjects,
+    /// are enforced in any mode.
+    fn mode_of<A>(&self, subject: &A, action: &str) -> AuthzMode
+    where
+        A: Authenticable,
+    {
+        let anonymous = subject.as_account_id().label() == "anonymous";
+        if anonymous || ENFORCED_ACTIONS.contains(&action) {
+            AuthzMode::Enforce
+        } else {
+            self.mode
+        }
+    }
+
+    pub(crate) fn authorize<A>(
+        &self,
+        audience: &str,
+        subject: &A,
+        object: Vec<&str>,
+        action: &str,
+    ) -> AuthzFuture
+    where
+        A: IntentSubject,
+    {
+        // Rights of the account the token is issued to are never lent to its bearer
+        if subject.is_delegated() {
+            let detail = format!(
+                "the intent of the subject = '{}' isn't granted by the delegation token",
+                subject.as_account_id()
+            );
+            return Box::new(future::ok(Err(AuthzError::Policy(detail))));
+        }
+
+        let mode = self.mode_of(subject, action);
+        match mode {
+            AuthzMode::Enforce => self.check(audience, subject, object, action),
+            AuthzMode::AuditOnly => {
+                let event = AuthzEvent::new(
+                    mode,
+                    AuthzDecision::Unchecked,
+                    audience,
+                    subject.as_account_id(),
+                    &object,
+                    action,
+                );
+                self.events.publish(&event);
+                Box::new(future::ok(Ok(())))
+            }
+            AuthzMode::PermissiveAudit => {
+                let mut event = AuthzEvent::new(
+                    mode,
+                    AuthzDecision::Denied,
+                    audience,
+                    subject.as_account_id(),
+                    &object,
+                    action,
+                );
+                let events = self.events.clone();
+                Box::new(
+                    self.check(audience, subject, object, action)
+                        .map(move |result| {
+                            if let Err(err) = result {
+                                warn!(
+                                    target: "storage::authz",
+                                    "The intent is allowed in the permissive audit mode despite the error: {}",
+                                    err
+                                );
+                                event.reason = Some(err.to_string());
+                                events.publish(&event);
+                            }
+
+                            Ok(())
+                        }),
+                )
+            }
+        }
+    }
+
+    /// Intents already granted by resource scopes of the access token of the subject aren't sent
+    /// to the authorization backend, the rest of them are authorized as usual.
+    pub(crate) fn authorize_unless_granted<A>(
+        &self,
+        granted: bool,
+        audience: &str,
+        subject: &A,
+        object: Vec<&str>,
+        action: &str,
+    ) -> AuthzFuture
+    where
+        A: IntentSubject,
+    {
+        if granted {
+            return Box::new(future::ok(Ok(())));
+        }
+
+        self.authorize(audience, subject, object, action)
+    }
+
+    fn check<A>(&self, audience: &str, subject: &A, object: Vec<&str>, action: &str) -> AuthzFuture
+    where
+        A: Authenticable,
+    {
+        let policy = match self.policy {
+            Some(ref policy) => policy,
+            None => {
+                return Box::new(
+                    self.inner
+                        .authorize(audience, subject, object, action)
+                        .map(|result| result.map_err(|err| AuthzError::Backend(Box::new(err)))),
+                )
+            }
+        };
+
+        let inner = self.inner.clone();
+        let audience = audience.to_owned();
+        let subject = subject.as_account_id().to_owned();
+        let object = object
+            .into_iter()
+            .map(str::to_owned)
+            .collect::<Vec<String>>();
+        let action = action.to_owned();
+
+        Box::new(
+            policy
+                .evaluate(&subject, &object, &action)
+                .and_then(move |allowed| {
+                    if !allowed {
+                        let detail = format!(
+                            "the intent (subject = '{}', object = '{:?}', action = '{}') has been denied by the wasm policy",
+                            subject, object, action
+                        );
+                        return future::Either::A(future::ok(Err(AuthzError::Policy(detail))));
+                    }
+
+                    let object = object.iter().map(String::as_str).collect();
+                    future::Either::B(
+                        inner
+                            .authorize(&audience, &subject, object, &action)
+                            .map(|result| result.map_err(|err| AuthzError::Backend(Box::new(err)))),
+                    )
+                }),
+        )
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+enum Command {
+    Evaluate {
+        subject: String,
+        object: Vec<String>,
+        action: String,
+        tx: oneshot::Sender<anyhow::Result<bool>>,
+    },
+    Reload,
+}
+
+/// Authorization policy compiled to WebAssembly, run in a sandbox limiting each evaluation.
+///
+/// Besides `memory` and `alloc` exports of the sandbox, the module must export
+/// `authorize(subject_ptr: i32, subject_len: i32, object_ptr: i32, object_len: i32,
+/// action_ptr: i32, action_len: i32) -> i32` returning a non-zero value if the intent is allowed.
+/// Strings are UTF-8 encoded, elements of the object are separated by `\0`.
+///
+/// The module is instantiated and evaluated on a dedicated thread, requests are evaluated in order.
+/// Intents which aren't evaluated in time are denied.
+#[derive(Clone)]
+pub(crate) struct WasmPolicy {
+    path: PathBuf,
+    timeout: Duration,
+    tx: async_mpsc::UnboundedSender<Command>,
+}
+
+impl fmt::Debug for WasmPolicy {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("WasmPolicy")
+            .field("path", &self.path)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl WasmPolicy {
+    pub(crate) fn load(config: &WasmPolicyConfig) -> anyhow::Result<Self> {
+        let (tx, rx) = async_mpsc::unbounded();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let path = config.path.clone();
+        let limits = SandboxLimits {
+            fuel: config.fuel,
+            max_memory_bytes: config.max_memory_bytes,
+        };
+
+        std::thread::Builder::new()
+            .name("wasm-policy".to_owned())
+            .spawn(move || {
+                // Reloaded policies are instantiated in stores of their own
+                let load =
+                    |path: &Path| Sandbox::load("wasm policy", path, limits, authorize_export);
+                let mut policy = match load(&path) {
+                    Ok(val) => {
+                        let _ = ready_tx.send(Ok(()));
+                        val
+                    }
+                    Err(err) => {
+                        let _ = ready_tx.send(Err(err));
+                        return;
+                    }
+                };
+
+                for command in rx.wait() {
+                    match command {
+                        // The intent has been denied on timeout while it's been queued
+                        Ok(Command::Evaluate { ref tx, .. }) if tx.is_canceled() => {}
+                        Ok(Command::Evaluate {
+                            subject,
+                            object,
+                            action,
+                            tx,
+                        }) => {
+                            let _ = tx.send(authorize(&policy, &subject, &object, &action));
+                        }
+                        Ok(Command::Reload) => match load(policy.path()) {
+                            Ok(val) => {
+                                policy = val;
+                                info!(
+                                    "WASM policy is reloaded, path = '{}'",
+                                    policy.path().display()
+                                );
+                            }
+                            Err(err) => error!(
+                                "Error reloading the WASM policy, the previous one is kept: {:#}",
+                                err
+                            ),
+                        },
+                        Err(()) => break,
+                    }
+                }
+            })
+            .context("failed to spawn a wasm policy thread")?;
+
+        ready_rx
+            .recv()
+            .context("wasm policy thread has stopped")??;
+
+        Ok(Self {
+            path: config.path.clone(),
+            timeout: Duration::from_millis(config.timeout_ms),
+            tx,
+        })
+    }
+
+    pub(crate) fn reload(&self) {
+        if self.tx.unbounded_send(Command::Reload).is_err() {
+            error!("Error reloading the WASM policy: the policy thread has stopped");
+        }
+    }
+
+    /// Evaluates the intent, an error or a timeout of the policy denies it.
+    pub(crate) fn evaluate(
+        &self,
+        subject: &AccountId,
+        object: &[String],
+        action: &str,
+    ) -> impl Future<Item = bool, Error = ()> {
+        let (tx, rx) = oneshot::channel();
+        let command = Command::Evaluate {
+            subject: subject.to_string(),
+            object: object.to_vec(),
+            action: action.to_owned(),
+            tx,
+        };
+        if self.tx.unbounded_send(command).is_err() {
+            error!("Error evaluating the WASM policy: the policy thread has stopped");
+        }
+
+        let timeout = self.timeout;
+        Timeout::new(rx, timeout).then(move |result| {
+            let allowed = result
+                .map_err(|err| {
+                    if err.is_elapsed() {
+                        format_err!("the policy has timed out after {:?}", timeout)
+                    } else if err.is_inner() {
+                        format_err!("the policy thread has stopped")
+                    } else {
+                        format_err!("the timer has failed")
+                    }
+                })
+                .and_then(|result| result)
+                .unwrap_or_else(|err| {
+                    error!("Error evaluating the WASM policy: {:#}", err);
+                    false
+                });
+
+            Ok(allowed)
+        })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+type AuthorizeFunc = TypedFunc<(i32, i32, i32, i32, i32, i32), i32>;
+
+fn authorize_export(instance: &Instance) -> anyhow::Result<AuthorizeFunc> {
+    instance
+        .get_typed_func("authorize")
+        .context("invalid 'authorize' export of wasm policy")
+}
+
+fn authorize(
+    policy: &Sandbox<AuthorizeFunc>,
+    subject: &str,
+    object: &[String],
+    action: &str,
+) -> anyhow::Result<bool> {
+    let object = object.join("\0");
+    policy.call(|instance| {
+        let (subject_ptr, subject_len) = instance.write(subject.as_bytes())?;
+        let (object_ptr, object_len) = instance.write(object.as_bytes())?;
+        let (action_ptr, action_len) = instance.write(action.as_bytes())?;
+
+        let result = instance.exports().call((
+            subject_ptr,
+            subject_len,
+            object_ptr,
+            object_len,
+            action_ptr,
+            action_len,
+        ))?;
+        Ok(result != 0)
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) type CachePool = Arc<r2d2::Pool<RedisConnectionManager>>;
+
+const CACHE_SCAN_COUNT: usize = 1000;
+
+/// Cached decisions matching the selector, unset parts match any value.
+#[derive(Debug, Extract)]
+pub(crate) struct CacheSelector {
+    pub(crate) subject: Option<String>,
+    // Elements of the object joined by `/`, e.g. `buckets/data.example.org/sets/foo`
+    pub(crate) object: Option<String>,
+    pub(crate) action: Option<String>,
+}
+
+enum CacheKeys {
+    Exact(String),
+    Pattern(String),
+}
+
+impl CacheSelector {
+    // Decisions are cached by svc-authz with `intent::SUBJECT::OBJECT::ACTION` keys
+    fn keys(&self) -> CacheKeys {
+        match (&self.subject, &self.object, &self.action) {
+            (Some(subject), Some(object), Some(action)) => {
+                CacheKeys::Exact(format!("intent::{}::{}::{}", subject, object, action))
+            }
+            (subject, object, action) => {
+                let part = |value: &Option<String>| match value {
+                    Some(value) => escape_pattern(value),
+                    None => "*".to_owned(),
+                };
+                CacheKeys::Pattern(format!(
+                    "intent::{}::{}::{}",
+                    part(subject),
+                    part(object),
+                    part(action)
+                ))
+            }
+        }
+    }
+}
+
+fn escape_pattern(value: &str) -> String {
+    let mut acc = String::with_capacity(value.len());
+    for c in value.chars() {
+        if let '*' | '?' | '[' | ']' | '\\' = c {
+            acc.push('\\');
+        }
+        acc.push(c);
+    }
+    acc
+}
+
+/// Removes cached authz decisions, so that changes of policies are enforced immediately.
+#[derive(Clone)]
+pub(crate) struct CacheInvalidator {
+    pool: CachePool,
+}
+
+impl fmt::Debug for CacheInvalidator {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("CacheInvalidator").finish()
+    }
+}
+
+impl CacheInvalidator {
+    pub(crate) fn new(pool: CachePool) -> Self {
+        Self { pool }
+    }
+
+    /// Removes decisions matching the selector, returns the number of removed decisions.
+    pub(crate) fn invalidate(&self, selector: &CacheSelector) -> anyhow::Result<u64> {
+        let mut conn = self
+            .pool
+            .get()
+            .context("failed to get a cache connection")?;
+
+        let pattern = match selector.keys() {
+            CacheKeys::Exact(key) => {
+                return redis::cmd("DEL")
+                    .arg(key)
+                    .query::<u64>(&mut *conn)
+                    .context("failed to delete a cached decision");
+            }
+            CacheKeys::Pattern(pattern) => pattern,
+        };
+
+        let mut cursor = 0u64;
+        let mut removed = 0;
+        loop {
+            let (next, keys) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(CACHE_SCAN_COUNT)
+                .query::<(u64, Vec<String>)>(&mut *conn)
+                .context("failed to scan cached decisions")?;
+
+            if !keys.is_empty() {
+                removed += redis::cmd("DEL")
+                    .arg(keys)
+                    .query::<u64>(&mut *conn)
+                    .context("failed to delete cached decisions")?;
+            }
+
+            match next {
+                0 => return Ok(removed),
+                next => cursor = next,
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::delegation::DelegationGrant;
+    use crate::app::util::Subject;
+    use crate::app::wasm;
+    use wasmtime::Module;
+
+    #[test]
+    fn audit_modes_apply_to_routes() {
+        let me = AccountId::new("storage", "svc.example.org");
+        let inner = svc_authz::ClientMap::new(&me, None, Default::default()).unwrap();
+        let authz = Authz::new(inner, None, AuthzMode::AuditOnly, None);
+        let john = AccountId::new("john", "usr.example.net");
+        let anonymous = AccountId::new("anonymous", "usr.example.net");
+
+        assert_eq!(authz.mode_of(&john, "read"), AuthzMode::Enforce);
+        let route = authz.with_mode(None);
+        assert_eq!(route.mode_of(&john, "read"), AuthzMode::AuditOnly);
+        assert_eq!(route.mode_of(&john, "admin"), AuthzMode::Enforce);
+        assert_eq!(route.mode_of(&john, "delegate"), AuthzMode::Enforce);
+        assert_eq!(route.mode_of(&anonymous, "read"), AuthzMode::Enforce);
+        let route = authz.with_mode(Some(AuthzMode::PermissiveAudit));
+        assert_eq!(route.mode_of(&john, "read"), AuthzMode::PermissiveAudit);
+    }
+
+    #[test]
+    fn delegated_intents_are_denied_in_any_mode() {
+        let me = AccountId::new("storage", "svc.example.org");
+        let inner = svc_authz::ClientMap::new(&me, None, Default::default()).unwrap();
+        let authz = Authz::new(inner, None, AuthzMode::AuditOnly, None);
+        let grant = DelegationGrant {
+            bucket: "reports.example.org".into(),
+            allowed_objects: "2026/*".into(),
+            allowed_actions: vec!["read".into()],
+            issued_to: AccountId::new("partner", "example.net"),
+            expires_at: 1_600_000_300,
+            namespace: None,
+        };
+        let subject = Subject::delegated(grant);
+        let zobj = vec!["buckets", "reports.example.org", "objects", "2025/a.pdf"];
+
+        let result = authz.authorize("example.net", &subject, zobj.clone(), "read");
+        assert!(matches!(result.wait(), Ok(Err(AuthzError::Policy(_)))));
+        let result = authz.with_mode(Some(AuthzMode::PermissiveAudit)).authorize(
+            "example.net",
+            &subject,
+            zobj,
+            "read",
+        );
+        assert!(matches!(result.wait(), Ok(Err(AuthzError::Policy(_)))));
+    }
+
+    #[test]
+    fn authz_event_json() {
+        let subject = AccountId::new("john", "usr.example.net");
+        let mut event = AuthzEvent::new(
+            AuthzMode::PermissiveAudit,
+            AuthzDecision::Denied,
+            "example.net",
+            &subject,
+            &["buckets", "data.example.net", "objects", "a.txt"],
+            "read",
+        );
+        event.time = String::from("2024-03-05T07:30:00.000Z");
+        event.reason = Some(String::from("[Forbidden] denied"));
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "time": "2024-03-05T07:30:00.000Z",
+                "mode": "permissive-audit",
+                "decision": "denied",
+                "audience": "example.net",
+                "subject": "john.usr.example.net",
+                "object": ["buckets", "data.example.net", "objects", "a.txt"],
+                "action": "read",
+                "reason": "[Forbidden] denied",
+            })
+        );
+
+        event.mode = AuthzMode::AuditOnly;
+        event.decision = AuthzDecision::Unchecked;
+        event.reason = None;
+        let value = serde_json::to_value(&event).unwrap();
+        assert_eq!(value["mode"], "audit-only");
+        assert_eq!(value["decision"], "unchecked");
+        assert!(value.get("reason").is_none());
+    }
+
+    #[test]
+    fn cache_selector_keys() {
+        let selector = |subject: Option<&str>, object: Option<&str>, action: Option<&str>| {
+            let selector = CacheSelector {
+                subject: subject.map(ToOwned::to_owned),
+                object: object.map(ToOwned::to_owned),
+                action: action.map(ToOwned::to_owned),
+            };
+            match selector.keys() {
+                CacheKeys::Exact(key) => (true, key),
+                CacheKeys::Pattern(pattern) => (false, pattern),
+            }
+        };
+
+        assert_eq!(
+            selector(
+                Some("john.usr.example.net"),
+                Some("buckets/x/sets/y"),
+                Some("read")
+            ),
+            (
+                true,
+                "intent::john.usr.example.net::buckets/x/sets/y::read".to_owned()
+            )
+        );
+        assert_eq!(
+            selector(Some("john.usr.example.net"), None, None),
+            (false, "intent::john.usr.example.net::*::*".to_owned())
+        );
+        assert_eq!(
+            selector(None, Some("buckets/x/sets/[y]*"), None),
+            (false, "intent::*::buckets/x/sets/\\[y\\]\\*::*".to_owned())
+        );
+        assert_eq!(
+            selector(None, None, None),
+            (false, "intent::*::*::*".to_owned())
+        );
+    }
+
+    // Allows reading anything, but only the object owned by the subject could be updated
+    const POLICY: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (global $next (mut i32) (i32.const 0))
+            (func (export "alloc") (param $len i32) (result i32)
+                (local $ptr i32)
+                (local.set $ptr (global.get $next))
+                (global.set $next (i32.add (global.get $next) (local.get $len)))
+                (local.get $ptr))
+            (func (export "authorize")
+                (param $sub i32) (param $sub_len i32)
+                (param $obj i32) (param $obj_len i32)
+                (param $act i32) (param $act_len i32)
+                (result i32)
+                (global.set $next (i32.const 0))
+                ;; action = "read"
+                (if (i32.and
+                        (i32.eq (local.get $act_len) (i32.const 4))
+                        (i32.eq (i32.load (local.get $act)) (i32.const 0x64616572)))
+                    (then (return (i32.const 1))))
+                ;; the first byte of the object is the same as of the subject
+                (i32.and
+                    (i32.gt_s (local.get $obj_len) (i32.const 0))
+                    (i32.eq (i32.load8_u (local.get $sub)) (i32.load8_u (local.get $obj))))))
+    "#;
+
+    const LIMITS: SandboxLimits = SandboxLimits {
+        fuel: 1_000_000,
+        max_memory_bytes: 16 * 1024 * 1024,
+    };
+
+    fn policy(wat: &str) -> anyhow::Result<Sandbox<AuthorizeFunc>> {
+        let module = Module::new(&wasm::engine().unwrap(), wat).unwrap();
+        Sandbox::new(
+            "wasm policy",
+            module,
+            Path::new("policy.wasm"),
+            LIMITS,
+            authorize_export,
+        )
+    }
+
+    #[test]
+    fn policy_authorize() {
+        let policy = policy(POLICY).unwrap();
+
+        let object = |values: &[&str]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        let sub = "john.usr.example.net";
+        assert!(authorize(&policy, sub, &object(&["buckets", "x"]), "read").unwrap());
+        assert!(!authorize(&policy, sub, &object(&["buckets", "x"]), "update").unwrap());
+        assert!(authorize(&policy, sub, &object(&["john"]), "update").unwrap());
+        assert!(!authorize(&policy, sub, &[], "delete").unwrap());
+    }
+
+    #[test]
+    fn policy_rejects_imports() {
+        assert!(policy(
+            r#"(module (import "wasi_snapshot_preview1" "fd_write" (func (param i32 i32 i32 i32) (result i32))))"#,
+        )
+        .is_err());
+        assert!(policy(r#"(module (memory (export "memory") 1))"#).is_err());
+    }
+
+    #[test]
+    fn policy_limits() {
+        // Policies running out of fuel fail to evaluate the intent, the next one is evaluated anew
+        let policy = policy(
+            r#"
+            (module
+                (memory (export "memory") 1)
+                (func (export "alloc") (param $len i32) (result i32)
+                    (i32.const 0))
+                (func (export "authorize")
+                    (param $sub i32) (param $sub_len i32)
+                    (param $obj i32) (param $obj_len i32)
+                    (param $act i32) (param $act_len i32)
+                    (result i32)
+                    (if (i32.eqz (local.get $obj_len)) (then (loop $forever (br $forever))))
+                    (i32.const 1)))
+            "#,
+        )
+        .unwrap();
+        let object = vec!["buckets".to_owned()];
+        assert!(authorize(&policy, "john.usr.example.net", &[], "read").is_err());
+        assert!(authorize(&policy, "john.usr.example.net", &object, "read").unwrap());
+    }
+}
@@ -0,0 +1,711 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+
+use anyhow::format_err;
+use futures::future::{self, Loop};
+use futures::{stream, Future, Stream};
+use log::{error, info};
+
+use crate::s3::{
+    BucketCorsRule, BucketLifecycleRule, BucketNotifications, Client, IntelligentTieringConfig,
+    NotificationTarget, StoredIntelligentTieringConfig, EXPIRY_LIFECYCLE_RULE_ID,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const MAX_LIFECYCLE_RULES: usize = 1000;
+const MAX_INTELLIGENT_TIERING_CONFIGS: usize = 1000;
+const MAX_TAGS: usize = 50;
+const LIFECYCLE_STORAGE_CLASSES: &[&str] = &[
+    "STANDARD_IA",
+    "ONEZONE_IA",
+    "INTELLIGENT_TIERING",
+    "GLACIER_IR",
+    "GLACIER",
+    "DEEP_ARCHIVE",
+];
+/// Access tiers of Intelligent-Tiering along with the allowed range of days without access.
+const ACCESS_TIERS: &[(&str, i64, i64)] = &[
+    ("ARCHIVE_ACCESS", 90, 730),
+    ("DEEP_ARCHIVE_ACCESS", 180, 730),
+];
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = anyhow::Error> + Send>;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Configuration of a bucket changed at once, each of the present sections replaces
+/// the corresponding configuration of the bucket, empty ones remove it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BucketConfigPayload {
+    cors: Option<Vec<BucketCorsRule>>,
+    lifecycle: Option<Vec<BucketLifecycleRule>>,
+    notifications: Option<BucketNotifications>,
+    intelligent_tiering: Option<Vec<IntelligentTieringConfig>>,
+    tags: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Section {
+    Cors,
+    Lifecycle,
+    Notifications,
+    IntelligentTiering,
+    Tags,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SectionStatus {
+    Applied,
+    Failed,
+    RolledBack,
+    RollbackFailed,
+    /// The section isn't applied because of the failure of a previous one.
+    Skipped,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct SectionReport {
+    pub(crate) section: Section,
+    pub(crate) status: SectionStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) detail: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct BucketConfigReport {
+    /// Whether all the sections are applied.
+    pub(crate) committed: bool,
+    pub(crate) sections: Vec<SectionReport>,
+}
+
+/// Validated changes of the bucket configuration in the order they're applied.
+#[derive(Debug)]
+pub(crate) struct BucketConfigChanges {
+    changes: Vec<Change>,
+}
+
+#[derive(Debug)]
+enum Change {
+    Cors(Vec<BucketCorsRule>),
+    Lifecycle(Vec<BucketLifecycleRule>),
+    Notifications(BucketNotifications),
+    IntelligentTiering(Vec<IntelligentTieringConfig>),
+    Tags(BTreeMap<String, String>),
+}
+
+/// Configuration of the section before the change, it's restored on rollback.
+#[derive(Debug)]
+enum Snapshot {
+    Cors(Option<Vec<BucketCorsRule>>),
+    Lifecycle(Option<Vec<rusoto_s3::LifecycleRule>>),
+    Notifications(rusoto_s3::NotificationConfiguration),
+    IntelligentTiering(Vec<StoredIntelligentTieringConfig>),
+    Tags(BTreeMap<String, String>),
+}
+
+struct Progress {
+    pending: std::vec::IntoIter<(Change, Snapshot)>,
+    applied: Vec<(Change, Snapshot)>,
+}
+
+enum TieringOperation {
+    Put(String, String),
+    Delete(String),
+}
+
+impl BucketConfigChanges {
+    /// Stages the changes of the payload, none of them is applied if any is invalid.
+    pub(crate) fn stage(payload: BucketConfigPayload) -> anyhow::Result<Self> {
+        let mut changes = Vec::new();
+        if let Some(rules) = payload.cors {
+            if !rules.is_empty() {
+                super::validate_bucket_cors(&rules)?;
+            }
+            changes.push(Change::Cors(rules));
+        }
+        if let Some(rules) = payload.lifecycle {
+            validate_lifecycle(&rules)?;
+            changes.push(Change::Lifecycle(rules));
+        }
+        if let Some(notifications) = payload.notifications {
+            validate_notifications(&notifications)?;
+            changes.push(Change::Notifications(notifications));
+        }
+        if let Some(configs) = payload.intelligent_tiering {
+            validate_intelligent_tiering(&configs)?;
+            changes.push(Change::IntelligentTiering(configs));
+        }
+        if let Some(tags) = payload.tags {
+            validate_tags(&tags)?;
+            changes.push(Change::Tags(tags));
+        }
+
+        if changes.is_empty() {
+            return Err(format_err!(
+                "at least one of: cors, lifecycle, notifications, intelligent_tiering, tags must be present"
+            ));
+        }
+
+        Ok(Self { changes })
+    }
+
+    /// Takes snapshots of the changed sections, then applies the changes one by one.
+    /// Once a change fails, it's rolled back as it may be partially applied, then the applied
+    /// ones are rolled back in the reverse order.
+    /// The error is returned only if the snapshots can't be taken, nothing is changed then.
+    pub(crate) fn apply(
+        self,
+        s3: Arc<Client>,
+        bucket: String,
+    ) -> impl Future<Item = BucketConfigReport, Error = anyhow::Error> + Send {
+        let snapshots = self
+            .changes
+            .iter()
+            .map(|change| snapshot(&s3, &bucket, change.section()))
+            .collect::<Vec<BoxFuture<Snapshot>>>();
+
+        let changes = self.changes;
+        future::join_all(snapshots).and_then(move |snapshots| {
+            let progress = Progress {
+                pending: changes
+                    .into_iter()
+                    .zip(snapshots)
+                    .collect::<Vec<(Change, Snapshot)>>()
+                    .into_iter(),
+                applied: Vec::new(),
+            };
+
+            let (s3_, bucket_) = (s3.clone(), bucket.clone());
+            future::loop_fn(progress, move |mut progress| {
+                match progress.pending.next() {
+                    None => future::Either::A(future::ok(Loop::Break((progress, None)))),
+                    Some((change, snapshot)) => {
+                        future::Either::B(apply_change(&s3_, &bucket_, &change, &snapshot).then(
+                            move |result| match result {
+                                Ok(()) => {
+                                    progress.applied.push((change, snapshot));
+                                    Ok(Loop::Continue(progress))
+                                }
+                                Err(err) => {
+                                    Ok(Loop::Break((progress, Some((change, snapshot, err)))))
+                                }
+                            },
+                        ))
+                    }
+                }
+            })
+            .and_then(move |(progress, failure)| match failure {
+                None => future::Either::A(future::ok(BucketConfigReport {
+                    committed: true,
+                    sections: progress
+                        .applied
+                        .iter()
+                        .map(|(change, _)| SectionReport {
+                            section: change.section(),
+                            status: SectionStatus::Applied,
+                            detail: None,
+                        })
+                        .collect(),
+                })),
+                Some((change, snapshot, err)) => {
+                    future::Either::B(rollback(s3, bucket, progress, (change, snapshot), err))
+                }
+            })
+        })
+    }
+}
+
+fn rollback(
+    s3: Arc<Client>,
+    bucket: String,
+    progress: Progress,
+    (failed, snapshot): (Change, Snapshot),
+    err: anyhow::Error,
+) -> impl Future<Item = BucketConfigReport, Error = anyhow::Error> + Send {
+    let section = failed.section();
+    error!(
+        "Error applying {:?} configuration of bucket = '{}', rolling back: {:#}",
+        section, bucket, err
+    );
+
+    // Some of the operations of the failed change may have succeeded, e.g. of intelligent tiering
+    let failure = restore(&s3, &bucket, &failed, snapshot).then(move |result| {
+        Ok::<_, anyhow::Error>(match result {
+            Ok(()) => SectionReport {
+                section,
+                status: SectionStatus::Failed,
+                detail: Some(format!("{:#}", err)),
+            },
+            Err(rollback_err) => {
+                error!(
+                    "Error rolling back {:?} configuration: {:#}",
+                    section, rollback_err
+                );
+                SectionReport {
+                    section,
+                    status: SectionStatus::RollbackFailed,
+                    detail: Some(format!("{:#}, rollback failed: {:#}", err, rollback_err)),
+                }
+            }
+        })
+    });
+    let skipped = progress
+        .pending
+        .map(|(change, _)| SectionReport {
+            section: change.section(),
+            status: SectionStatus::Skipped,
+            detail: None,
+        })
+        .collect::<Vec<SectionReport>>();
+
+    let applied = stream::iter_ok(progress.applied.into_iter().rev())
+        .and_then(move |(change, snapshot)| {
+            let section = change.section();
+            restore(&s3, &bucket, &change, snapshot).then(move |result| {
+                Ok(match result {
+                    Ok(()) => {
+                        info!("{:?} configuration of bucket is rolled back", section);
+                        SectionReport {
+                            section,
+                            status: SectionStatus::RolledBack,
+                            detail: None,
+                        }
+                    }
+                    Err(err) => {
+                        error!("Error rolling back {:?} configuration: {:#}", section, err);
+                        SectionReport {
+                            section,
+                            status: SectionStatus::RollbackFailed,
+                            detail: Some(format!("{:#}", err)),
+                        }
+                    }
+                })
+            })
+        })
+        .collect();
+
+    failure
+        .and_then(move |failure| applied.map(move |sections| (failure, sections)))
+        .map(move |(failure, mut sections)| {
+            sections.reverse();
+            sections.push(failure);
+            sections.extend(skipped);
+            BucketConfigReport {
+                committed: false,
+                sections,
+            }
+        })
+}
+
+impl Change {
+    fn section(&self) -> Section {
+        match self {
+            Change::Cors(_) => Section::Cors,
+            Change::Lifecycle(_) => Section::Lifecycle,
+            Change::Notifications(_) => Section::Notifications,
+            Change::IntelligentTiering(_) => Section::IntelligentTiering,
+            Change::Tags(_) => Section::Tags,
+        }
+    }
+}
+
+fn snapshot(s3: &Client, bucket: &str, section: Section) -> BoxFuture<Snapshot> {
+    match section {
+        Section::Cors => Box::new(s3.get_bucket_cors(bucket).map(Snapshot::Cors)),
+        Section::Lifecycle => Box::new(s3.get_bucket_lifecycle(bucket).map(Snapshot::Lifecycle)),
+        Section::Notifications => Box::new(
+            s3.get_bucket_notifications(bucket)
+                .map(Snapshot::Notifications),
+        ),
+        Section::IntelligentTiering => Box::new(
+            s3.list_bucket_intelligent_tiering(bucket)
+                .map(Snapshot::IntelligentTiering),
+        ),
+        Section::Tags => Box::new(s3.get_bucket_tags(bucket).map(Snapshot::Tags)),
+    }
+}
+
+fn apply_change(s3: &Client, bucket: &str, change: &Change, snapshot: &Snapshot) -> BoxFuture<()> {
+    match (change, snapshot) {
+        (Change::Cors(rules), _) if rules.is_empty() => Box::new(s3.delete_bucket_cors(bucket)),
+        (Change::Cors(rules), _) => Box::new(s3.put_bucket_cors(bucket, rules.clone())),
+        (Change::Lifecycle(rules), Snapshot::Lifecycle(current)) => {
+            // The expiry rule is managed by the application, it's kept as is
+            let rules = rules
+                .iter()
+                .cloned()
+                .map(rusoto_s3::LifecycleRule::from)
+                .chain(
+                    current
+                        .iter()
+                        .flatten()
+                        .filter(|rule| rule.id.as_deref() == Some(EXPIRY_LIFECYCLE_RULE_ID))
+                        .cloned(),
+                )
+                .collect::<Vec<rusoto_s3::LifecycleRule>>();
+            restore_lifecycle(s3, bucket, rules)
+        }
+        (Change::Notifications(notifications), _) => {
+            Box::new(s3.put_bucket_notifications(bucket, notifications.clone().into()))
+        }
+        (Change::IntelligentTiering(configs), Snapshot::IntelligentTiering(current)) => {
+            let ids = configs
+                .iter()
+                .map(|config| config.id.as_str())
+                .collect::<HashSet<&str>>();
+            let operations = current
+                .iter()
+                .filter(|config| !ids.contains(config.id.as_str()))
+                .map(|config| TieringOperation::Delete(config.id.clone()))
+                .chain(
+                    configs
+                        .iter()
+                        .map(|config| TieringOperation::Put(config.id.clone(), config.to_xml())),
+                )
+                .collect();
+            apply_tiering(s3, bucket, operations)
+        }
+        (Change::Tags(tags), _) if tags.is_empty() => Box::new(s3.delete_bucket_tags(bucket)),
+        (Change::Tags(tags), _) => Box::new(s3.set_bucket_tags(bucket, tags.clone())),
+        (change, _) => Box::new(future::err(format_err!(
+            "snapshot doesn't match {:?} configuration",
+            change.section()
+        ))),
+    }
+}
+
+fn restore(s3: &Client, bucket: &str, change: &Change, snapshot: Snapshot) -> BoxFuture<()> {
+    match (change, snapshot) {
+        (_, Snapshot::Cors(None)) => Box::new(s3.delete_bucket_cors(bucket)),
+        (_, Snapshot::Cors(Some(rules))) => Box::new(s3.put_bucket_cors(bucket, rules)),
+        (_, Snapshot::Lifecycle(rules)) => restore_lifecycle(s3, bucket, rules.unwrap_or_default()),
+        (_, Snapshot::Notifications(config)) => {
+            Box::new(s3.put_bucket_notifications(bucket, config))
+        }
+        (Change::IntelligentTiering(configs), Snapshot::IntelligentTiering(stored)) => {
+            // Configurations created by the change are deleted, the stored ones are put back
+            let ids = stored
+                .iter()
+                .map(|config| config.id.clone())
+                .collect::<HashSet<String>>();
+            let operations = configs
+                .iter()
+                .filter(|config| !ids.contains(&config.id))
+                .map(|config| TieringOperation::Delete(config.id.clone()))
+                .chain(
+                    stored
+                        .into_iter()
+                        .map(|config| TieringOperation::Put(config.id, config.xml)),
+                )
+                .collect();
+            apply_tiering(s3, bucket, operations)
+        }
+        (_, Snapshot::IntelligentTiering(_)) => Box::new(future::err(format_err!(
+            "change doesn't match intelligent tiering configuration"
+        ))),
+        (_, Snapshot::Tags(tags)) if tags.is_empty() => Box::new(s3.delete_bucket_tags(bucket)),
+        (_, Snapshot::Tags(tags)) => Box::new(s3.set_bucket_tags(bucket, tags)),
+    }
+}
+
+/// The backend rejects the empty lifecycle configuration, it's deleted instead.
+fn restore_lifecycle(
+    s3: &Client,
+    bucket: &str,
+    rules: Vec<rusoto_s3::LifecycleRule>,
+) -> BoxFuture<()> {
+    if rules.is_empty() {
+        Box::new(s3.delete_bucket_lifecycle(bucket))
+    } else {
+        Box::new(s3.put_bucket_lifecycle(bucket, rules))
+    }
+}
+
+fn apply_tiering(s3: &Client, bucket: &str, operations: Vec<TieringOperation>) -> BoxFuture<()> {
+    let (s3, bucket) = (s3.clone(), bucket.to_owned());
+    Box::new(
+        stream::iter_ok(operations).for_each(move |operation| -> BoxFuture<()> {
+            match operation {
+                TieringOperation::Put(id, xml) => {
+                    Box::new(s3.put_bucket_intelligent_tiering(&bucket, &id, xml))
+                }
+                TieringOperation::Delete(id) => {
+                    Box::new(s3.delete_bucket_intelligent_tiering(&bucket, &id))
+                }
+            }
+        }),
+    )
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn validate_lifecycle(rules: &[BucketLifecycleRule]) -> anyhow::Result<()> {
+    if rules.len() > MAX_LIFECYCLE_RULES {
+        return Err(format_err!(
+            "invalid number of lifecycle rules = '{}', it must not exceed {}",
+            rules.len(),
+            MAX_LIFECYCLE_RULES
+        ));
+    }
+
+    let mut ids = HashSet::new();
+    for rule in rules {
+        if rule.id.is_empty() || rule.id.len() > 255 {
+            return Err(format_err!(
+                "invalid id = '{}' of lifecycle rule, it must be from 1 to 255 characters",
+                rule.id
+            ));
+        }
+        if rule.id == EXPIRY_LIFECYCLE_RULE_ID {
+            return Err(format_err!(
+                "lifecycle rule = '{}' is managed by the application",
+                rule.id
+            ));
+        }
+        if !ids.insert(rule.id.as_str()) {
+            return Err(format_err!("duplicate lifecycle rule = '{}'", rule.id));
+        }
+
+        if rule.expiration_days.is_none()
+            && rule.transitions.is_empty()
+            && rule.noncurrent_version_expiration_days.is_none()
+            && rule.abort_incomplete_multipart_upload_days.is_none()
+        {
+            return Err(format_err!(
+                "lifecycle rule = '{}' must have at least one action",
+                rule.id
+            ));
+        }
+
+        let days = rule
+            .expiration_days
+            .iter()
+            .chain(rule.transitions.iter().map(|transition| &transition.days))
+            .chain(rule.noncurrent_version_expiration_days.iter())
+            .chain(rule.abort_incomplete_multipart_upload_days.iter());
+        for days in days {
+            if *days < 1 {
+                return Err(format_err!(
+                    "invalid days = '{}' of lifecycle rule = '{}', they must be positive",
+                    days,
+                    rule.id
+                ));
+            }
+        }
+
+        if let Some(transition) = rule.transitions.iter().find(|transition| {
+            !LIFECYCLE_STORAGE_CLASSES.contains(&transition.storage_class.as_str())
+        }) {
+            return Err(format_err!(
+                "invalid storage class = '{}' of lifecycle rule = '{}', it must be one of: {}",
+                transition.storage_class,
+                rule.id,
+                LIFECYCLE_STORAGE_CLASSES.join(", ")
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_notifications(notifications: &BucketNotifications) -> anyhow::Result<()> {
+    let targets = notifications
+        .topics
+        .iter()
+        .chain(notifications.queues.iter())
+        .chain(notifications.lambda_functions.iter());
+    for target in targets {
+        validate_notification_target(target)?;
+    }
+
+    Ok(())
+}
+
+fn validate_notification_target(target: &NotificationTarget) -> anyhow::Result<()> {
+    if !target.arn.starts_with("arn:") {
+        return Err(format_err!(
+            "invalid destination = '{}' of notifications, it must be an ARN",
+            target.arn
+        ));
+    }
+
+    if target.events.is_empty() {
+        return Err(format_err!(
+            "notifications to '{}' must have at least one event",
+            target.arn
+        ));
+    }
+
+    if let Some(event) = target.events.iter().find(|event| !event.starts_with("s3:")) {
+        return Err(format_err!(
+            "invalid event = '{}' of notifications to '{}', it must start with 's3:'",
+            event,
+            target.arn
+        ));
+    }
+
+    Ok(())
+}
+
+fn validate_intelligent_tiering(configs: &[IntelligentTieringConfig]) -> anyhow::Result<()> {
+    if configs.len() > MAX_INTELLIGENT_TIERING_CONFIGS {
+        return Err(format_err!(
+            "invalid number of intelligent tiering configurations = '{}', it must not exceed {}",
+            configs.len(),
+            MAX_INTELLIGENT_TIERING_CONFIGS
+        ));
+    }
+
+    let mut ids = HashSet::new();
+    for config in configs {
+        if config.id.is_empty() || config.id.len() > 64 {
+            return Err(format_err!(
+                "invalid id = '{}' of intelligent tiering configuration, it must be from 1 to 64 characters",
+                config.id
+            ));
+        }
+        if !ids.insert(config.id.as_str()) {
+            return Err(format_err!(
+                "duplicate intelligent tiering configuration = '{}'",
+                config.id
+            ));
+        }
+
+        if config.tierings.is_empty() || config.tierings.len() > ACCESS_TIERS.len() {
+            return Err(format_err!(
+                "intelligent tiering configuration = '{}' must have from 1 to {} tierings",
+                config.id,
+                ACCESS_TIERS.len()
+            ));
+        }
+
+        let mut tiers = HashSet::new();
+        for tiering in &config.tierings {
+            let (_, min_days, max_days) = ACCESS_TIERS
+                .iter()
+                .find(|(tier, _, _)| *tier == tiering.access_tier)
+                .ok_or_else(|| {
+                    format_err!(
+                        "invalid access tier = '{}' of intelligent tiering configuration = '{}', it must be one of: ARCHIVE_ACCESS, DEEP_ARCHIVE_ACCESS",
+                        tiering.access_tier,
+                        config.id
+                    )
+                })?;
+            if !tiers.insert(tiering.access_tier.as_str()) {
+                return Err(format_err!(
+                    "duplicate access tier = '{}' of intelligent tiering configuration = '{}'",
+                    tiering.access_tier,
+                    config.id
+                ));
+            }
+            if tiering.days < *min_days || tiering.days > *max_days {
+                return Err(format_err!(
+                    "invalid days = '{}' of access tier = '{}', they must be from {} to {}",
+                    tiering.days,
+                    tiering.access_tier,
+                    min_days,
+                    max_days
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_tags(tags: &BTreeMap<String, String>) -> anyhow::Result<()> {
+    if tags.len() > MAX_TAGS {
+        return Err(format_err!(
+            "invalid number of tags = '{}', it must not exceed {}",
+            tags.len(),
+            MAX_TAGS
+        ));
+    }
+
+    for (key, value) in tags {
+        if key.is_empty() || key.chars().count() > 128 || key.starts_with("aws:") {
+            return Err(format_err!(
+                "invalid tag key = '{}', it must be from 1 to 128 characters and not start with 'aws:'",
+                key
+            ));
+        }
+        if value.chars().count() > 256 {
+            return Err(format_err!(
+                "value of tag = '{}' must not exceed 256 characters",
+                key
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stage(body: &str) -> anyhow::Result<BucketConfigChanges> {
+        BucketConfigChanges::stage(serde_json::from_str(body)?)
+    }
+
+    #[test]
+    fn stage_changes_in_order() {
+        let changes = stage(
+            r#"{
+                "tags": {"team": "media"},
+                "cors": [],
+                "lifecycle": [{"id": "tmp", "prefix": "tmp/", "expiration_days": 7}],
+                "intelligent_tiering": [
+                    {"id": "archive", "tierings": [{"access_tier": "ARCHIVE_ACCESS", "days": 90}]}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let sections = changes
+            .changes
+            .iter()
+            .map(Change::section)
+            .collect::<Vec<Section>>();
+        assert_eq!(
+            sections,
+            vec![
+                Section::Cors,
+                Section::Lifecycle,
+                Section::IntelligentTiering,
+                Section::Tags
+            ]
+        );
+    }
+
+    #[test]
+    fn stage_invalid_changes() {
+        assert!(stage("{}").is_err());
+        assert!(stage(r#"{"unknown": []}"#).is_err());
+        assert!(stage(r#"{"lifecycle": [{"id": "tmp", "prefix": "tmp/"}]}"#).is_err());
+        assert!(
+            stage(r#"{"lifecycle": [{"id": "storage-expiry", "expiration_days": 7}]}"#).is_err()
+        );
+        assert!(stage(
+            r#"{"lifecycle": [{"id": "cold", "transitions": [{"days": 30, "storage_class": "COLD"}]}]}"#
+        )
+        .is_err());
+        assert!(stage(
+            r#"{"intelligent_tiering": [{"id": "archive", "tierings": [{"access_tier": "ARCHIVE_ACCESS", "days": 30}]}]}"#
+        )
+        .is_err());
+        assert!(stage(
+            r#"{"notifications": {"queues": [{"arn": "arn:aws:sqs:us-east-1:1:uploads", "events": ["ObjectCreated:*"]}]}}"#
+        )
+        .is_err());
+        assert!(stage(r#"{"tags": {"aws:team": "media"}}"#).is_err());
+        // Nothing is staged if any of the sections is invalid
+        assert!(stage(r#"{"tags": {"team": "media"}, "cors": [{"allowed_origins": [], "allowed_methods": ["GET"]}]}"#).is_err());
+    }
+}
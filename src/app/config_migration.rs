@@ -0,0 +1,165 @@
+use std::convert::TryFrom;
+
+use anyhow::format_err;
+use log::info;
+use serde_json::Value;
+
+////////////////////////////////////////////////////////////////////////////////
+
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// Migrations of the configuration file, the one at index `i` transforms the configuration
+/// of schema version `i + 1` into the one of version `i + 2`. Changes of the configuration
+/// that aren't backward compatible must be accompanied by a migration.
+const MIGRATIONS: &[&dyn ConfigMigrator] = &[];
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Transforms the configuration of the schema version into the one of the next version,
+/// e.g. renames a field or adds a default value.
+pub(crate) trait ConfigMigrator {
+    fn apply(&self, old_version: u32, config: Value) -> anyhow::Result<Value>;
+}
+
+/// Brings the configuration of the older schema version up to the current one, configurations
+/// of versions newer than the current one are rejected.
+pub(crate) fn migrate(config: Value) -> anyhow::Result<Value> {
+    migrate_with(config, MIGRATIONS)
+}
+
+fn migrate_with(mut config: Value, migrations: &[&dyn ConfigMigrator]) -> anyhow::Result<Value> {
+    let current = migrations.len() as u32 + 1;
+    let version = schema_version(&config)?;
+    if version > current {
+        return Err(format_err!(
+            "schema_version = {} of the configuration is newer than the supported one = {}, upgrade the application",
+            version,
+            current
+        ));
+    }
+
+    for old_version in version..current {
+        let migration = migrations[old_version as usize - 1];
+        config = migration.apply(old_version, config).map_err(|err| {
+            err.context(format!(
+                "failed to migrate the configuration from schema_version = {}",
+                old_version
+            ))
+        })?;
+        info!(
+            "Configuration is migrated from schema_version = {} to {}",
+            old_version,
+            old_version + 1
+        );
+    }
+
+    match config {
+        Value::Object(ref mut fields) => {
+            fields.insert(SCHEMA_VERSION_KEY.to_owned(), Value::from(current));
+        }
+        _ => return Err(format_err!("configuration must be a table")),
+    }
+
+    Ok(config)
+}
+
+/// Versions overridden by environment variables are strings.
+fn schema_version(config: &Value) -> anyhow::Result<u32> {
+    let value = config
+        .get(SCHEMA_VERSION_KEY)
+        .ok_or_else(|| format_err!("schema_version of the configuration is required"))?;
+    let version = match value {
+        Value::Number(number) => number.as_u64(),
+        Value::String(string) => string.parse::<u64>().ok(),
+        _ => None,
+    };
+
+    version
+        .and_then(|version| u32::try_from(version).ok())
+        .filter(|version| *version >= 1)
+        .ok_or_else(|| format_err!("invalid schema_version = {} of the configuration", value))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Version 2 has renamed `log.format` to `log.style`.
+    struct RenameLogFormat;
+
+    impl ConfigMigrator for RenameLogFormat {
+        fn apply(&self, old_version: u32, mut config: Value) -> anyhow::Result<Value> {
+            assert_eq!(old_version, 1);
+            if let Some(log) = config.get_mut("log").and_then(Value::as_object_mut) {
+                if let Some(format) = log.remove("format") {
+                    log.insert("style".to_owned(), format);
+                }
+            }
+            Ok(config)
+        }
+    }
+
+    /// Version 3 has made `log.level` required.
+    struct AddLogLevel;
+
+    impl ConfigMigrator for AddLogLevel {
+        fn apply(&self, old_version: u32, mut config: Value) -> anyhow::Result<Value> {
+            assert_eq!(old_version, 2);
+            let log = config
+                .as_object_mut()
+                .ok_or_else(|| format_err!("invalid configuration"))?
+                .entry("log")
+                .or_insert_with(|| json!({}));
+            log.as_object_mut()
+                .ok_or_else(|| format_err!("invalid log section"))?
+                .entry("level")
+                .or_insert_with(|| json!("info"));
+            Ok(config)
+        }
+    }
+
+    const TEST_MIGRATIONS: &[&dyn ConfigMigrator] = &[&RenameLogFormat, &AddLogLevel];
+
+    #[test]
+    fn migrate_consecutive_versions() {
+        let config = json!({"schema_version": 1, "id": "storage", "log": {"format": "json"}});
+        assert_eq!(
+            migrate_with(config, TEST_MIGRATIONS).unwrap(),
+            json!({"schema_version": 3, "id": "storage", "log": {"style": "json", "level": "info"}})
+        );
+
+        let config = json!({"schema_version": "2", "log": {"style": "text", "level": "debug"}});
+        assert_eq!(
+            migrate_with(config, TEST_MIGRATIONS).unwrap(),
+            json!({"schema_version": 3, "log": {"style": "text", "level": "debug"}})
+        );
+
+        let config = json!({"schema_version": 3, "id": "storage"});
+        assert_eq!(
+            migrate_with(config.clone(), TEST_MIGRATIONS).unwrap(),
+            config
+        );
+    }
+
+    #[test]
+    fn reject_unsupported_versions() {
+        for config in &[
+            json!({"id": "storage"}),
+            json!({"schema_version": 0}),
+            json!({"schema_version": "one"}),
+            json!({"schema_version": 4}),
+        ] {
+            assert!(
+                migrate_with(config.clone(), TEST_MIGRATIONS).is_err(),
+                "{}",
+                config
+            );
+        }
+
+        assert!(migrate(json!({"schema_version": 1})).is_ok());
+        assert!(migrate(json!({"schema_version": MIGRATIONS.len() + 2})).is_err());
+    }
+}
@@ -0,0 +1,311 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::format_err;
+use futures::{future, Future, Poll};
+use http::header::{self, HeaderName};
+use http::{Method, Request, Response};
+use tower_service::Service;
+use tower_web::middleware::cors::{AllowedOrigins, CorsBuilder, CorsMiddleware, CorsService};
+use tower_web::middleware::Middleware;
+use tower_web::util::http::HttpService;
+
+use crate::app::Cors;
+
+////////////////////////////////////////////////////////////////////////////////
+
+const ALLOW_METHODS: &[Method] = &[
+    Method::GET,
+    Method::POST,
+    Method::PUT,
+    Method::PATCH,
+    Method::DELETE,
+];
+
+const ALLOW_HEADERS: &[HeaderName] = &[
+    header::AUTHORIZATION,
+    header::CACHE_CONTROL,
+    header::CONTENT_LENGTH,
+    header::CONTENT_TYPE,
+    header::IF_MATCH,
+    header::IF_MODIFIED_SINCE,
+    header::IF_NONE_MATCH,
+    header::IF_UNMODIFIED_SINCE,
+    header::RANGE,
+];
+
+/// CORS policy of requests with paths matching the pattern, unset options fall back to the global ones.
+#[derive(Debug, Deserialize)]
+pub(crate) struct CorsRoute {
+    path_pattern: String,
+    #[serde(default)]
+    disabled: bool,
+    #[serde(deserialize_with = "crate::serde::optional_allowed_origins")]
+    #[serde(default)]
+    allow_origins: Option<AllowedOrigins>,
+    allow_methods: Option<Vec<String>>,
+    allow_headers: Option<Vec<String>>,
+    max_age: Option<u64>,
+    allow_credentials: Option<bool>,
+}
+
+fn cors_builder(global: &Cors, route: Option<&CorsRoute>) -> anyhow::Result<CorsBuilder> {
+    let allow_methods = match route.and_then(|route| route.allow_methods.as_ref()) {
+        Some(methods) => methods
+            .iter()
+            .map(|method| {
+                Method::from_bytes(method.as_bytes())
+                    .map_err(|_| format_err!("invalid CORS method = '{}'", method))
+            })
+            .collect::<anyhow::Result<Vec<Method>>>()?,
+        None => ALLOW_METHODS.to_vec(),
+    };
+    let allow_headers = match route.and_then(|route| route.allow_headers.as_ref()) {
+        Some(headers) => headers
+            .iter()
+            .map(|name| {
+                HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| format_err!("invalid CORS header = '{}'", name))
+            })
+            .collect::<anyhow::Result<Vec<HeaderName>>>()?,
+        None => ALLOW_HEADERS.to_vec(),
+    };
+    let allow_origins = route
+        .and_then(|route| route.allow_origins.clone())
+        .unwrap_or_else(|| global.allow_origins.clone());
+    let max_age = route
+        .and_then(|route| route.max_age)
+        .map(Duration::from_secs)
+        .unwrap_or(global.max_age);
+    let allow_credentials = route
+        .and_then(|route| route.allow_credentials)
+        .unwrap_or(true);
+
+    Ok(CorsBuilder::new()
+        .allow_origins(allow_origins)
+        .allow_methods(allow_methods)
+        .allow_headers(allow_headers)
+        .allow_credentials(allow_credentials)
+        .max_age(max_age))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Applies the CORS policy of the first route with `path_pattern` matching the path of the request,
+/// or the global one. Requests to routes with CORS disabled are passed through as is.
+pub(crate) struct PerRouteCorsMiddleware {
+    routes: Vec<(String, Option<CorsMiddleware>)>,
+    default: CorsMiddleware,
+}
+
+impl PerRouteCorsMiddleware {
+    pub(crate) fn new(config: &Cors) -> anyhow::Result<Self> {
+        let routes = config
+            .routes
+            .iter()
+            .map(|route| {
+                let cors = if route.disabled {
+                    None
+                } else {
+                    Some(cors_builder(config, Some(route))?.build())
+                };
+                Ok((route.path_pattern.clone(), cors))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            routes,
+            default: cors_builder(config, None)?.build(),
+        })
+    }
+}
+
+impl<S> Middleware<S> for PerRouteCorsMiddleware
+where
+    S: HttpService,
+{
+    type Request = Request<S::RequestBody>;
+    type Response = Response<Option<S::ResponseBody>>;
+    type Error = S::Error;
+    type Service = PerRouteCorsService<S>;
+
+    fn wrap(&self, service: S) -> Self::Service {
+        let inner = SharedService::new(service);
+        let routes = self
+            .routes
+            .iter()
+            .map(|(pattern, cors)| {
+                let cors = cors.as_ref().map(|cors| cors.wrap(inner.clone()));
+                (pattern.clone(), cors)
+            })
+            .collect();
+
+        PerRouteCorsService {
+            routes,
+            default: self.default.wrap(inner.clone()),
+            inner,
+        }
+    }
+}
+
+pub(crate) struct PerRouteCorsService<S> {
+    inner: SharedService<S>,
+    routes: Vec<(String, Option<CorsService<SharedService<S>>>)>,
+    default: CorsService<SharedService<S>>,
+}
+
+impl<S> Service for PerRouteCorsService<S>
+where
+    S: HttpService,
+{
+    type Request = Request<S::RequestBody>;
+    type Response = Response<Option<S::ResponseBody>>;
+    type Error = S::Error;
+    type Future = future::Either<
+        <CorsService<SharedService<S>> as Service>::Future,
+        future::Map<S::Future, fn(Response<S::ResponseBody>) -> Self::Response>,
+    >;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, request: Self::Request) -> Self::Future {
+        let path = request.uri().path().to_owned();
+        let cors = match self
+            .routes
+            .iter_mut()
+            .find(|(pattern, _)| crate::app::util::wildcard_match(pattern, &path))
+        {
+            Some((_, cors)) => cors.as_mut(),
+            None => Some(&mut self.default),
+        };
+
+        match cors {
+            Some(cors) => future::Either::A(cors.call(request)),
+            None => future::Either::B(
+                self.inner
+                    .call(request)
+                    .map(passthrough as fn(Response<S::ResponseBody>) -> Self::Response),
+            ),
+        }
+    }
+}
+
+fn passthrough<B>(response: Response<B>) -> Response<Option<B>> {
+    response.map(Some)
+}
+
+/// The inner service shared by CORS services of all routes, or by pending requests.
+pub(crate) struct SharedService<S>(Arc<Mutex<S>>);
+
+impl<S> SharedService<S> {
+    pub(crate) fn new(service: S) -> Self {
+        SharedService(Arc::new(Mutex::new(service)))
+    }
+}
+
+impl<S> Clone for SharedService<S> {
+    fn clone(&self) -> Self {
+        SharedService(self.0.clone())
+    }
+}
+
+impl<S> Service for SharedService<S>
+where
+    S: HttpService,
+{
+    type Request = Request<S::RequestBody>;
+    type Response = Response<S::ResponseBody>;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.0
+            .lock()
+            .expect("Inner service lock is poisoned")
+            .poll_http_ready()
+    }
+
+    fn call(&mut self, request: Self::Request) -> Self::Future {
+        self.0
+            .lock()
+            .expect("Inner service lock is poisoned")
+            .call_http(request)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use http::StatusCode;
+    use tower_web::util::buf_stream::{self, Empty};
+
+    type Body = Empty<Option<[u8; 1]>, ()>;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = Request<Body>;
+        type Response = Response<Body>;
+        type Error = ();
+        type Future = future::FutureResult<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(futures::Async::Ready(()))
+        }
+
+        fn call(&mut self, _request: Self::Request) -> Self::Future {
+            future::ok(Response::new(buf_stream::empty()))
+        }
+    }
+
+    fn request(path: &str, origin: &str) -> Request<Body> {
+        Request::builder()
+            .uri(path)
+            .header(header::ORIGIN, origin)
+            .body(buf_stream::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn per_route_cors_policies() {
+        let config = serde_json::from_value::<Cors>(serde_json::json!({
+            "allow_origins": ["https://app.example.org"],
+            "max_age": 86400,
+            "routes": [
+                {"path_pattern": "/healthz", "disabled": true},
+                {"path_pattern": "/api/*/sign", "allow_origins": ["https://uploader.example.org"]},
+            ],
+        }))
+        .unwrap();
+        let mut service = PerRouteCorsMiddleware::new(&config).unwrap().wrap(Echo);
+        let mut call = |path, origin| service.call(request(path, origin)).wait().unwrap();
+
+        let resp = call("/api/v2/sign", "https://uploader.example.org");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://uploader.example.org"
+        );
+        assert_eq!(
+            call("/api/v2/sign", "https://app.example.org").status(),
+            StatusCode::FORBIDDEN
+        );
+
+        let resp = call("/api/v2/buckets/foo/sets/bar", "https://app.example.org");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let resp = call("/healthz", "https://other.example.org");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!resp
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+}
@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{format_err, Context};
+use chrono::NaiveDate;
+use log::{error, info};
+use r2d2_redis::redis::PipelineCommands;
+use r2d2_redis::{r2d2, redis, RedisConnectionManager};
+use uuid::Uuid;
+
+use crate::app::config::{CostAttributionConfig, CostPricesConfig};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const KEY_PREFIX: &str = "storage.cost";
+
+const BYTES_PER_GIB: u128 = 1 << 30;
+
+const REQUESTS_FIELD_PREFIX: &str = "requests:";
+
+const COST_CENTER_FIELD_PREFIX: &str = "cost_center:";
+
+const BYTES_FIELD: &str = "bytes";
+
+const COST_FIELD: &str = "cost_micro_cents";
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Billable type of an S3 operation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum CostOperation {
+    Get,
+    Head,
+    Put,
+    List,
+    Delete,
+}
+
+impl CostOperation {
+    /// Operations are inferred from the method of the request,
+    /// listings are told apart by the authorized action.
+    pub(crate) fn new(method: &str, action: &str) -> Option<Self> {
+        match (method, action) {
+            (_, "list") => Some(CostOperation::List),
+            ("GET", _) => Some(CostOperation::Get),
+            ("HEAD", _) => Some(CostOperation::Head),
+            ("PUT", _) | ("POST", _) => Some(CostOperation::Put),
+            ("DELETE", _) => Some(CostOperation::Delete),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            CostOperation::Get => "get",
+            CostOperation::Head => "head",
+            CostOperation::Put => "put",
+            CostOperation::List => "list",
+            CostOperation::Delete => "delete",
+        }
+    }
+
+    /// Estimated cost in micro-cents of the request along with the transfer of `size` bytes.
+    pub(crate) fn estimate(self, prices: &CostPricesConfig, size: Option<u64>) -> u64 {
+        let (request, transfer_per_gib) = match self {
+            CostOperation::Get => (prices.get_request, prices.transfer_out_per_gib),
+            CostOperation::Head => (prices.head_request, 0),
+            CostOperation::Put => (prices.put_request, prices.transfer_in_per_gib),
+            CostOperation::List => (prices.list_request, 0),
+            CostOperation::Delete => (prices.delete_request, 0),
+        };
+        let transfer = u128::from(size.unwrap_or(0)) * u128::from(transfer_per_gib) / BYTES_PER_GIB;
+
+        request.saturating_add(transfer.min(u128::from(u64::MAX)) as u64)
+    }
+}
+
+impl fmt::Display for CostOperation {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(self.as_str())
+    }
+}
+
+/// Operation attributed to the subject.
+#[derive(Debug)]
+pub(crate) struct CostRecord<'a> {
+    pub(crate) request_id: Uuid,
+    pub(crate) subject: &'a str,
+    pub(crate) bucket: &'a str,
+    pub(crate) method: &'a str,
+    pub(crate) action: &'a str,
+    pub(crate) size: Option<u64>,
+    /// Operations are attributed to the bucket unless the subject has a cost center.
+    pub(crate) cost_center: Option<&'a str>,
+}
+
+/// Costs of the subject aggregated over a day.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub(crate) struct CostSummary {
+    subject: String,
+    requests: HashMap<String, u64>,
+    bytes: u64,
+    cost_micro_cents: u64,
+    cost_centers: HashMap<String, u64>,
+}
+
+impl CostSummary {
+    fn from_fields(subject: &str, fields: HashMap<String, u64>) -> Self {
+        let mut summary = Self {
+            subject: subject.to_owned(),
+            ..Default::default()
+        };
+
+        for (field, value) in fields {
+            if let Some(operation) = field.strip_prefix(REQUESTS_FIELD_PREFIX) {
+                summary.requests.insert(operation.to_owned(), value);
+            } else if let Some(cost_center) = field.strip_prefix(COST_CENTER_FIELD_PREFIX) {
+                summary.cost_centers.insert(cost_center.to_owned(), value);
+            } else if field == BYTES_FIELD {
+                summary.bytes = value;
+            } else if field == COST_FIELD {
+                summary.cost_micro_cents = value;
+            }
+        }
+
+        summary
+    }
+}
+
+/// Increments of fields of costs of subjects, keyed by the day and the subject.
+#[derive(Debug, Default, PartialEq)]
+struct PendingCosts(HashMap<(NaiveDate, String), HashMap<String, u64>>);
+
+impl PendingCosts {
+    fn add(
+        &mut self,
+        date: NaiveDate,
+        subject: &str,
+        operation: CostOperation,
+        size: Option<u64>,
+        cost: u64,
+        cost_center: &str,
+    ) {
+        let fields = self.0.entry((date, subject.to_owned())).or_default();
+        let mut incr = |field: String, value: u64| {
+            let entry = fields.entry(field).or_insert(0);
+            *entry = entry.saturating_add(value);
+        };
+        incr(format!("{}{}", REQUESTS_FIELD_PREFIX, operation), 1);
+        incr(BYTES_FIELD.to_owned(), size.unwrap_or(0));
+        incr(COST_FIELD.to_owned(), cost);
+        incr(format!("{}{}", COST_CENTER_FIELD_PREFIX, cost_center), cost);
+    }
+}
+
+/// Interval costs aggregated in memory are flushed to Redis at.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Logs estimated costs of operations and aggregates them per subject per day in Redis,
+/// so they can be charged back to teams or users. Costs are aggregated in memory and
+/// flushed to Redis in background, so that requests don't wait for Redis.
+#[derive(Clone)]
+pub(crate) struct CostAttribution {
+    pool: Arc<r2d2::Pool<RedisConnectionManager>>,
+    prices: Arc<CostPricesConfig>,
+    retention_secs: usize,
+    pending: Arc<Mutex<PendingCosts>>,
+}
+
+impl fmt::Debug for CostAttribution {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("CostAttribution")
+            .field("prices", &self.prices)
+            .field("retention_secs", &self.retention_secs)
+            .finish()
+    }
+}
+
+impl CostAttribution {
+    pub(crate) fn new(config: &CostAttributionConfig, redis_url: &str) -> anyhow::Result<Self> {
+        let costs = Self {
+            pool: svc_authz::cache::create_pool2(
+                redis_url,
+                config.pool_size,
+                None,
+                config.pool_timeout_secs,
+            ),
+            prices: Arc::new(config.prices.clone()),
+            retention_secs: (config.retention_days * 24 * 60 * 60) as usize,
+            pending: Arc::new(Mutex::new(PendingCosts::default())),
+        };
+
+        let flusher = costs.clone();
+        std::thread::Builder::new()
+            .name("cost-attribution".to_owned())
+            .spawn(move || loop {
+                std::thread::sleep(FLUSH_INTERVAL);
+                flusher.flush();
+            })
+            .context("failed to spawn a cost attribution thread")?;
+
+        Ok(costs)
+    }
+
+    /// Operations other than the known ones aren't attributed, failures to aggregate are logged.
+    pub(crate) fn record(&self, record: &CostRecord) {
+        let operation = match CostOperation::new(record.method, record.action) {
+            Some(val) => val,
+            None => return,
+        };
+        let cost = operation.estimate(&self.prices, record.size);
+        let cost_center = record.cost_center.unwrap_or(record.bucket);
+
+        info!(
+            target: "storage::cost",
+            request_id:% = record.request_id,
+            subject = record.subject,
+            operation = operation.as_str(),
+            bucket = record.bucket,
+            size = record.size,
+            cost_micro_cents = cost,
+            cost_center = cost_center;
+            "{} {} {}", operation, record.bucket, cost
+        );
+
+        let date = chrono::Utc::now().naive_utc().date();
+        self.pending
+            .lock()
+            .expect("Cost attribution lock is poisoned")
+            .add(
+                date,
+                record.subject,
+                operation,
+                record.size,
+                cost,
+                cost_center,
+            );
+    }
+
+    /// Costs that fail to be flushed are dropped, so they don't pile up while Redis is unavailable.
+    fn flush(&self) {
+        let pending = std::mem::take(
+            &mut *self
+                .pending
+                .lock()
+                .expect("Cost attribution lock is poisoned"),
+        );
+        if pending.0.is_empty() {
+            return;
+        }
+
+        let subjects = pending.0.len();
+        if let Err(err) = self.aggregate(pending) {
+            error!(
+                "Error aggregating costs of {} subjects: {:#}",
+                subjects, err
+            );
+        }
+    }
+
+    fn aggregate(&self, pending: PendingCosts) -> anyhow::Result<()> {
+        let mut conn = self
+            .pool
+            .get()
+            .context("failed to get a redis connection")?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for ((date, subject), fields) in pending.0 {
+            let key = subject_key(date, &subject);
+            let subjects = subjects_key(date);
+            for (field, value) in fields {
+                pipe.hincr(&key, field, value).ignore();
+            }
+            pipe.expire(&key, self.retention_secs)
+                .ignore()
+                .sadd(&subjects, &subject)
+                .ignore()
+                .expire(&subjects, self.retention_secs)
+                .ignore();
+        }
+
+        pipe.query::<()>(&mut *conn)
+            .context("failed to aggregate costs")
+    }
+
+    /// Costs of the subject, or of all subjects, over the day.
+    pub(crate) fn summary(
+        &self,
+        date: NaiveDate,
+        subject: Option<&str>,
+    ) -> anyhow::Result<Vec<CostSummary>> {
+        let mut conn = self
+            .pool
+            .get()
+            .context("failed to get a redis connection")?;
+        let mut subjects = match subject {
+            Some(subject) => vec![subject.to_owned()],
+            None => redis::cmd("SMEMBERS")
+                .arg(subjects_key(date))
+                .query::<Vec<String>>(&mut *conn)
+                .context("failed to list subjects")?,
+        };
+        subjects.sort();
+
+        let mut summaries = Vec::with_capacity(subjects.len());
+        for subject in subjects {
+            let fields = redis::cmd("HGETALL")
+                .arg(subject_key(date, &subject))
+                .query::<HashMap<String, u64>>(&mut *conn)
+                .context("failed to read costs")?;
+            if !fields.is_empty() {
+                summaries.push(CostSummary::from_fields(&subject, fields));
+            }
+        }
+
+        Ok(summaries)
+    }
+}
+
+pub(crate) fn parse_date(value: &str) -> anyhow::Result<NaiveDate> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|err| format_err!("invalid date = '{}': {}", value, err))
+}
+
+fn subject_key(date: NaiveDate, subject: &str) -> String {
+    format!("{}.{}.{}", KEY_PREFIX, date.format("%Y-%m-%d"), subject)
+}
+
+fn subjects_key(date: NaiveDate) -> String {
+    format!("{}.{}.subjects", KEY_PREFIX, date.format("%Y-%m-%d"))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cost_operation_new() {
+        assert_eq!(CostOperation::new("GET", "read"), Some(CostOperation::Get));
+        assert_eq!(CostOperation::new("GET", "list"), Some(CostOperation::List));
+        assert_eq!(
+            CostOperation::new("POST", "update"),
+            Some(CostOperation::Put)
+        );
+        assert_eq!(
+            CostOperation::new("DELETE", "delete"),
+            Some(CostOperation::Delete)
+        );
+        assert_eq!(CostOperation::new("OPTIONS", "read"), None);
+    }
+
+    #[test]
+    fn cost_operation_estimate() {
+        let prices = CostPricesConfig::default();
+        assert_eq!(CostOperation::Get.estimate(&prices, None), 40);
+        assert_eq!(
+            CostOperation::Get.estimate(&prices, Some(1 << 30)),
+            9_000_040
+        );
+        assert_eq!(CostOperation::Head.estimate(&prices, Some(1 << 30)), 40);
+        assert_eq!(CostOperation::Put.estimate(&prices, Some(1 << 30)), 500);
+        assert_eq!(CostOperation::Delete.estimate(&prices, None), 0);
+        assert_eq!(
+            CostOperation::Get.estimate(&prices, Some(u64::MAX)),
+            154_618_822_656_000_039
+        );
+
+        // Estimates exceeding the range saturate
+        let prices = CostPricesConfig {
+            transfer_out_per_gib: u64::MAX,
+            ..CostPricesConfig::default()
+        };
+        assert_eq!(
+            CostOperation::Get.estimate(&prices, Some(u64::MAX)),
+            u64::MAX
+        );
+    }
+
+    #[test]
+    fn cost_summary_from_fields() {
+        let fields = vec![
+            ("requests:get", 3),
+            ("requests:put", 1),
+            ("bytes", 1024),
+            ("cost_micro_cents", 620),
+            ("cost_center:video", 600),
+            ("cost_center:uploads.example.org", 20),
+        ]
+        .into_iter()
+        .map(|(field, value)| (field.to_owned(), value))
+        .collect::<HashMap<String, u64>>();
+
+        let summary = CostSummary::from_fields("john.usr.example.org", fields);
+        assert_eq!(summary.subject, "john.usr.example.org");
+        assert_eq!(summary.requests.get("get"), Some(&3));
+        assert_eq!(summary.requests.get("put"), Some(&1));
+        assert_eq!(summary.bytes, 1024);
+        assert_eq!(summary.cost_micro_cents, 620);
+        assert_eq!(summary.cost_centers.get("video"), Some(&600));
+    }
+
+    #[test]
+    fn pending_costs_add() {
+        let date = NaiveDate::from_ymd(2024, 1, 1);
+        let mut pending = PendingCosts::default();
+        pending.add(date, "john", CostOperation::Get, Some(1024), 40, "video");
+        pending.add(date, "john", CostOperation::Get, None, 40, "video");
+        pending.add(date, "jane", CostOperation::Put, Some(10), 500, "uploads");
+
+        let fields = &pending.0[&(date, "john".to_owned())];
+        assert_eq!(fields["requests:get"], 2);
+        assert_eq!(fields["bytes"], 1024);
+        assert_eq!(fields["cost_micro_cents"], 80);
+        assert_eq!(fields["cost_center:video"], 80);
+        assert_eq!(pending.0[&(date, "jane".to_owned())]["requests:put"], 1);
+        assert_eq!(pending.0.len(), 2);
+    }
+
+    #[test]
+    fn parse_dates() {
+        assert_eq!(
+            parse_date("2024-01-01").unwrap(),
+            NaiveDate::from_ymd(2024, 1, 1)
+        );
+        assert!(parse_date("2024-13-01").is_err());
+        assert!(parse_date("yesterday").is_err());
+    }
+}
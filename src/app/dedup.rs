@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{format_err, Context};
+use futures::{future, stream, Future, Stream};
+use log::{error, info};
+use r2d2_redis::redis::PipelineCommands;
+use r2d2_redis::{r2d2, redis, RedisConnectionManager};
+
+use crate::app::config::DeduplicationConfig;
+use crate::s3::inventory::{self, InventoryRecord};
+use crate::s3::{Client, ClientRouter};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const KEY_PREFIX: &str = "storage.duplicates";
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Objects of the bucket of the same size and ETag, the canonical one is the first by key.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct DuplicateGroup {
+    pub(crate) etag: String,
+    pub(crate) size: u64,
+    /// Storage taken by all the objects but the canonical one.
+    pub(crate) wasted_bytes: u64,
+    pub(crate) canonical_key: String,
+    pub(crate) duplicate_keys: Vec<String>,
+}
+
+impl DuplicateGroup {
+    fn new(etag: String, size: u64, mut keys: Vec<String>) -> Self {
+        keys.sort();
+        let canonical_key = keys.remove(0);
+        Self {
+            etag,
+            size,
+            wasted_bytes: size.saturating_mul(keys.len() as u64),
+            canonical_key,
+            duplicate_keys: keys,
+        }
+    }
+}
+
+/// Groups objects listed in inventory reports by their size and ETag, empty objects are skipped.
+#[derive(Debug, Default)]
+struct DuplicateFinder {
+    objects: HashMap<(u64, String), Vec<String>>,
+}
+
+impl DuplicateFinder {
+    fn add(&mut self, record: InventoryRecord) {
+        if record.size > 0 && !record.etag.is_empty() {
+            self.objects
+                .entry((record.size, record.etag))
+                .or_default()
+                .push(record.key);
+        }
+    }
+
+    /// Groups of more than one object, the most wasteful first.
+    fn finish(self) -> Vec<DuplicateGroup> {
+        let mut groups = self
+            .objects
+            .into_iter()
+            .filter(|(_, keys)| keys.len() > 1)
+            .map(|((size, etag), keys)| DuplicateGroup::new(etag, size, keys))
+            .collect::<Vec<_>>();
+        groups.sort_by(|a, b| {
+            b.wasted_bytes
+                .cmp(&a.wasted_bytes)
+                .then_with(|| a.etag.cmp(&b.etag))
+        });
+        groups
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// The latest duplicate groups of the bucket.
+#[derive(Debug, Response)]
+pub(crate) struct DuplicatesReport {
+    bucket: String,
+    /// Manifest of the inventory report the groups are found in, `None` if the bucket hasn't been scanned yet.
+    manifest: Option<String>,
+    groups: Vec<DuplicateGroup>,
+}
+
+/// Duplicate groups of buckets stored in Redis: keys of objects by their ETag
+/// (the canonical key first) along with a sorted set of ETags by wasted space.
+#[derive(Clone)]
+pub(crate) struct DuplicateIndex {
+    pool: Arc<r2d2::Pool<RedisConnectionManager>>,
+    ttl_secs: usize,
+}
+
+impl fmt::Debug for DuplicateIndex {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("DuplicateIndex")
+            .field("ttl_secs", &self.ttl_secs)
+            .finish()
+    }
+}
+
+impl DuplicateIndex {
+    pub(crate) fn new(config: &DeduplicationConfig, redis_url: &str) -> Self {
+        Self {
+            pool: svc_authz::cache::create_pool2(
+                redis_url,
+                config.pool_size,
+                None,
+                config.pool_timeout_secs,
+            ),
+            // Groups outlive a missed scan or two
+            ttl_secs: (config.interval_secs * 3) as usize,
+        }
+    }
+
+    /// Replaces groups of the bucket.
+    fn store(&self, bucket: &str, manifest: &str, groups: &[DuplicateGroup]) -> anyhow::Result<()> {
+        let mut conn = self
+            .pool
+            .get()
+            .context("failed to get a redis connection")?;
+        let (keys, wasted, manifest_key) = bucket_keys(bucket);
+
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .del(&[&keys, &wasted])
+            .ignore()
+            .set(&manifest_key, manifest)
+            .ignore()
+            .expire(&manifest_key, self.ttl_secs)
+            .ignore();
+        if !groups.is_empty() {
+            let items = groups
+                .iter()
+                .map(|group| {
+                    let mut keys = vec![&group.canonical_key];
+                    keys.extend(&group.duplicate_keys);
+                    let keys = serde_json::to_string(&keys).expect("Error serializing object keys");
+                    (group.etag.as_str(), keys)
+                })
+                .collect::<Vec<_>>();
+            let scores = groups
+                .iter()
+                .map(|group| (group.wasted_bytes, group.etag.as_str()))
+                .collect::<Vec<_>>();
+            pipe.hset_multiple(&keys, &items)
+                .ignore()
+                .expire(&keys, self.ttl_secs)
+                .ignore()
+                .zadd_multiple(&wasted, &scores)
+                .ignore()
+                .expire(&wasted, self.ttl_secs)
+                .ignore();
+        }
+
+        pipe.query::<()>(&mut *conn)
+            .context("failed to store duplicate groups")
+    }
+
+    /// The most wasteful groups of the bucket, up to `limit`.
+    pub(crate) fn top(&self, bucket: &str, limit: usize) -> anyhow::Result<DuplicatesReport> {
+        let mut conn = self
+            .pool
+            .get()
+            .context("failed to get a redis connection")?;
+        let (keys, wasted, manifest_key) = bucket_keys(bucket);
+
+        let manifest = redis::cmd("GET")
+            .arg(&manifest_key)
+            .query::<Option<String>>(&mut *conn)
+            .context("failed to read the manifest of duplicates")?;
+        let scores = redis::cmd("ZREVRANGE")
+            .arg(&wasted)
+            .arg(0)
+            .arg(limit as isize - 1)
+            .arg("WITHSCORES")
+            .query::<Vec<(String, u64)>>(&mut *conn)
+            .context("failed to read wasted space of duplicates")?;
+
+        let mut groups = Vec::with_capacity(scores.len());
+        if !scores.is_empty() {
+            let etags = scores
+                .iter()
+                .map(|(etag, _)| etag.as_str())
+                .collect::<Vec<_>>();
+            let values = redis::cmd("HMGET")
+                .arg(&keys)
+                .arg(etags)
+                .query::<Vec<Option<String>>>(&mut *conn)
+                .context("failed to read keys of duplicates")?;
+
+            for ((etag, wasted_bytes), value) in scores.into_iter().zip(values) {
+                // The group could have been replaced between the reads
+                let mut keys = match value {
+                    Some(value) => serde_json::from_str::<Vec<String>>(&value)
+                        .context("invalid keys of duplicates")?,
+                    None => continue,
+                };
+                if keys.len() < 2 {
+                    return Err(format_err!("invalid keys of duplicates, etag = '{}'", etag));
+                }
+
+                let canonical_key = keys.remove(0);
+                groups.push(DuplicateGroup {
+                    etag,
+                    size: wasted_bytes / keys.len() as u64,
+                    wasted_bytes,
+                    canonical_key,
+                    duplicate_keys: keys,
+                });
+            }
+        }
+
+        Ok(DuplicatesReport {
+            bucket: bucket.to_owned(),
+            manifest,
+            groups,
+        })
+    }
+}
+
+fn bucket_keys(bucket: &str) -> (String, String, String) {
+    (
+        format!("{}.{}.keys", KEY_PREFIX, bucket),
+        format!("{}.{}.wasted", KEY_PREFIX, bucket),
+        format!("{}.{}.manifest", KEY_PREFIX, bucket),
+    )
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Finds duplicate objects of the configured buckets by their latest inventory reports
+/// every `interval_secs` in background, rather than hashing objects on every upload.
+pub(crate) fn spawn(s3: Arc<ClientRouter>, index: DuplicateIndex, config: DeduplicationConfig) {
+    if config.buckets.is_empty() {
+        return;
+    }
+
+    std::thread::spawn(move || {
+        let mut rt =
+            tokio::runtime::Runtime::new().expect("Error creating a deduplication runtime");
+
+        let interval = Duration::from_secs(config.interval_secs.max(1));
+        loop {
+            for bucket in &config.buckets {
+                let scan = scan_bucket(
+                    s3.client(),
+                    bucket.to_owned(),
+                    config.inventory_id.clone(),
+                    config.max_file_bytes,
+                );
+                match rt
+                    .block_on(scan)
+                    .and_then(|(manifest, groups)| {
+                        index.store(bucket, &manifest, &groups).map(|()| groups)
+                    }) {
+                    Ok(groups) => info!(
+                        "Duplicate objects are found, bucket = '{}', groups = {}, wasted_bytes = {}",
+                        bucket,
+                        groups.len(),
+                        groups.iter().map(|group| group.wasted_bytes).sum::<u64>()
+                    ),
+                    Err(err) => error!(
+                        "Error finding duplicate objects, bucket = '{}': {:#}",
+                        bucket, err
+                    ),
+                }
+            }
+
+            std::thread::sleep(interval);
+        }
+    });
+}
+
+/// Groups objects of the latest report of the bucket's inventory configuration,
+/// returns the key of the manifest of the report along with the groups.
+fn scan_bucket(
+    s3: Arc<Client>,
+    bucket: String,
+    inventory_id: Option<String>,
+    max_file_bytes: usize,
+) -> impl Future<Item = (String, Vec<DuplicateGroup>), Error = anyhow::Error> {
+    let source = s3.bucket_name(&bucket);
+    let manifest_source = source.clone();
+
+    s3.list_bucket_inventory_configurations(&bucket)
+        .and_then(move |configs| {
+            // The configured inventory or the first enabled one
+            let config = configs
+                .into_iter()
+                .find(|config| match inventory_id {
+                    Some(ref id) => &config.id == id,
+                    None => config.enabled,
+                })
+                .ok_or_else(|| format_err!("inventory configuration is not found"))?;
+            let prefix =
+                inventory::report_prefix(config.destination_prefix.as_deref(), &source, &config.id);
+            Ok((config.destination_bucket, prefix))
+        })
+        .and_then(move |(destination, prefix)| {
+            s3.list_folders(&destination, &prefix)
+                .and_then(move |folders| {
+                    let manifest = inventory::latest_manifest(&folders)
+                        .ok_or_else(|| format_err!("no inventory reports under '{}'", prefix))?;
+                    Ok((s3, destination, manifest))
+                })
+        })
+        .and_then(move |(s3, destination, manifest_key)| {
+            s3.get_object(&destination, &manifest_key, max_file_bytes)
+                .and_then(move |manifest| {
+                    let manifest = manifest
+                        .ok_or_else(|| format_err!("manifest '{}' is not found", manifest_key))?;
+                    let manifest = inventory::parse_manifest(&manifest)?;
+                    // Reports of other buckets may share the destination prefix
+                    if manifest.source_bucket != manifest_source {
+                        return Err(format_err!(
+                            "manifest '{}' is of another bucket = '{}'",
+                            manifest_key,
+                            manifest.source_bucket
+                        ));
+                    }
+                    Ok((s3, destination, manifest_key, manifest))
+                })
+        })
+        .and_then(move |(s3, destination, manifest_key, manifest)| {
+            let manifest = Arc::new(manifest);
+            let files = manifest.files.clone();
+
+            // Files are downloaded one at a time, only groups are kept in memory
+            stream::iter_ok(files)
+                .and_then(move |file| {
+                    let manifest = manifest.clone();
+                    s3.get_object(&destination, &file, max_file_bytes)
+                        .and_then(move |data| {
+                            let data =
+                                data.ok_or_else(|| format_err!("file '{}' is not found", file))?;
+                            inventory::parse_file(&manifest, &data)
+                                .with_context(|| format!("failed to parse file '{}'", file))
+                        })
+                })
+                .fold(DuplicateFinder::default(), |mut finder, records| {
+                    records.into_iter().for_each(|record| finder.add(record));
+                    future::ok::<_, anyhow::Error>(finder)
+                })
+                .map(move |finder| (manifest_key, finder.finish()))
+        })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(key: &str, size: u64, etag: &str) -> InventoryRecord {
+        InventoryRecord {
+            key: key.to_owned(),
+            size,
+            etag: etag.to_owned(),
+        }
+    }
+
+    #[test]
+    fn find_duplicates() {
+        let mut finder = DuplicateFinder::default();
+        for record in vec![
+            record("c", 10, "e1"),
+            record("a", 10, "e1"),
+            record("b", 10, "e1"),
+            record("big.1", 100, "e2"),
+            record("big.2", 100, "e2"),
+            // Same ETag, different size
+            record("d", 20, "e1"),
+            record("unique", 10, "e3"),
+            record("empty.1", 0, "d41d8cd9"),
+            record("empty.2", 0, "d41d8cd9"),
+        ] {
+            finder.add(record);
+        }
+
+        assert_eq!(
+            finder.finish(),
+            vec![
+                DuplicateGroup {
+                    etag: "e2".to_owned(),
+                    size: 100,
+                    wasted_bytes: 100,
+                    canonical_key: "big.1".to_owned(),
+                    duplicate_keys: vec!["big.2".to_owned()],
+                },
+                DuplicateGroup {
+                    etag: "e1".to_owned(),
+                    size: 10,
+                    wasted_bytes: 20,
+                    canonical_key: "a".to_owned(),
+                    duplicate_keys: vec!["b".to_owned(), "c".to_owned()],
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use std::str::FromStr;
+
+use anyhow::{format_err, Context};
+use chrono::{DateTime, TimeZone, Utc};
+use jsonwebtoken::{Algorithm, Header, Validation};
+use svc_authn::AccountId;
+
+use crate::app::config::DelegationConfig;
+use crate::app::util::wildcard_match;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Actions on objects which could be delegated.
+pub(crate) const DELEGATED_ACTIONS: &[&str] = &["read"];
+
+/// Access to objects of a bucket granted by a subject allowed to delegate it, the holder
+/// of the token is authorized by the grant rather than by the authz backend.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct DelegationGrant {
+    pub(crate) bucket: String,
+    pub(crate) allowed_objects: String,
+    pub(crate) allowed_actions: Vec<String>,
+    pub(crate) issued_to: AccountId,
+    pub(crate) expires_at: i64,
+    /// Namespace of the tenant the bucket belongs to, see multitenancy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) namespace: Option<String>,
+}
+
+impl DelegationGrant {
+    pub(crate) fn allows(&self, bucket: &str, object: &str, action: &str) -> bool {
+        self.bucket == bucket
+            && wildcard_match(&self.allowed_objects, object)
+            && self.allowed_actions.iter().any(|val| val == action)
+    }
+}
+
+/// Claims of delegation tokens, the issuer is the application.
+#[derive(Debug, Deserialize, Serialize)]
+struct DelegationClaims {
+    iss: String,
+    #[serde(flatten)]
+    grant: DelegationGrant,
+}
+
+/// Issues delegation tokens signed with the private key of the application and verifies them.
+pub(crate) struct Delegation {
+    issuer: String,
+    algorithm: Algorithm,
+    private_key: Vec<u8>,
+    public_key: Vec<u8>,
+    max_expires_in: i64,
+}
+
+impl std::fmt::Debug for Delegation {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("Delegation")
+            .field("issuer", &self.issuer)
+            .field("algorithm", &self.algorithm)
+            .field("max_expires_in", &self.max_expires_in)
+            .finish()
+    }
+}
+
+impl Delegation {
+    pub(crate) fn new(issuer: &AccountId, config: &DelegationConfig) -> anyhow::Result<Self> {
+        let algorithm = Algorithm::from_str(&config.algorithm)
+            .map_err(|_| format_err!("invalid algorithm = '{}'", config.algorithm))?;
+        let read = |path: &std::path::Path| {
+            std::fs::read(path).with_context(|| format!("failed to read key = '{:?}'", path))
+        };
+
+        Ok(Self {
+            issuer: issuer.to_string(),
+            algorithm,
+            private_key: read(&config.private_key)?,
+            public_key: read(&config.public_key)?,
+            max_expires_in: config.max_expires_in as i64,
+        })
+    }
+
+    /// Tokens live for the maximum lifetime unless a shorter one is requested.
+    pub(crate) fn expires_at(
+        &self,
+        expires_in: Option<u64>,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<DateTime<Utc>> {
+        let expires_in = match expires_in {
+            None => self.max_expires_in,
+            Some(val) if val > 0 && val as i64 <= self.max_expires_in => val as i64,
+            Some(val) => {
+                return Err(format_err!(
+                    "invalid expires_in = '{}', expected from 1 to {} seconds",
+                    val,
+                    self.max_expires_in
+                ))
+            }
+        };
+
+        Ok(now + chrono::Duration::seconds(expires_in))
+    }
+
+    pub(crate) fn issue(&self, grant: DelegationGrant) -> anyhow::Result<String> {
+        let claims = DelegationClaims {
+            iss: self.issuer.clone(),
+            grant,
+        };
+        jsonwebtoken::encode(&Header::new(self.algorithm), &claims, &self.private_key)
+            .map_err(|err| format_err!("failed to sign a delegation token: {}", err))
+    }
+
+    pub(crate) fn verify(&self, token: &str, now: i64) -> anyhow::Result<DelegationGrant> {
+        let mut validation = Validation::new(self.algorithm);
+        validation.validate_exp = false;
+        let claims = jsonwebtoken::decode::<DelegationClaims>(token, &self.public_key, &validation)
+            .map_err(|err| format_err!("invalid delegation token: {}", err))?
+            .claims;
+
+        if claims.iss != self.issuer {
+            return Err(format_err!(
+                "issuer = '{}' of the delegation token is not allowed",
+                claims.iss
+            ));
+        }
+        if claims.grant.expires_at <= now {
+            return Err(format_err!(
+                "delegation token has expired at '{}'",
+                Utc.timestamp(claims.grant.expires_at, 0).to_rfc3339()
+            ));
+        }
+
+        Ok(claims.grant)
+    }
+
+    /// Verifies the token sent along with credentials of the `bearer`, if any, it's to be
+    /// issued to the bearer.
+    pub(crate) fn verify_bearer(
+        &self,
+        token: &str,
+        bearer: Option<&AccountId>,
+        now: i64,
+    ) -> anyhow::Result<DelegationGrant> {
+        let grant = self.verify(token, now)?;
+        match bearer {
+            Some(bearer) if *bearer != grant.issued_to => Err(format_err!(
+                "delegation token is issued to = '{}', not to the bearer = '{}'",
+                grant.issued_to,
+                bearer
+            )),
+            _ => Ok(grant),
+        }
+    }
+}
+
+/// Allowed actions of a grant, `read` by default.
+pub(crate) fn parse_allowed_actions(values: Option<Vec<String>>) -> anyhow::Result<Vec<String>> {
+    let values = values.unwrap_or_else(|| vec![String::from("read")]);
+    if values.is_empty() {
+        return Err(format_err!("allowed_actions must not be empty"));
+    }
+    match values
+        .iter()
+        .find(|val| !DELEGATED_ACTIONS.contains(&val.as_str()))
+    {
+        Some(val) => Err(format_err!(
+            "action = '{}' can't be delegated, expected one of: {}",
+            val,
+            DELEGATED_ACTIONS.join(", ")
+        )),
+        None => Ok(values),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delegation(secret: &str) -> Delegation {
+        Delegation {
+            issuer: String::from("storage.svc.example.org"),
+            algorithm: Algorithm::HS256,
+            private_key: secret.as_bytes().to_vec(),
+            public_key: secret.as_bytes().to_vec(),
+            max_expires_in: 3600,
+        }
+    }
+
+    fn grant() -> DelegationGrant {
+        DelegationGrant {
+            bucket: "reports.example.org".into(),
+            allowed_objects: "2026/*".into(),
+            allowed_actions: vec!["read".into()],
+            issued_to: AccountId::new("partner", "example.net"),
+            expires_at: 1_600_000_300,
+            namespace: None,
+        }
+    }
+
+    #[test]
+    fn token_roundtrip() {
+        let token = delegation("secret").issue(grant()).unwrap();
+        assert_eq!(
+            delegation("secret").verify(&token, 1_600_000_000).unwrap(),
+            grant()
+        );
+        assert!(delegation("other").verify(&token, 1_600_000_000).is_err());
+        assert!(delegation("secret").verify(&token, 1_600_000_300).is_err());
+
+        let foreign = Delegation {
+            issuer: String::from("other.svc.example.org"),
+            ..delegation("secret")
+        };
+        assert!(foreign.verify(&token, 1_600_000_000).is_err());
+        assert!(delegation("secret")
+            .verify("not a token", 1_600_000_000)
+            .is_err());
+    }
+
+    #[test]
+    fn token_bearers() {
+        let delegation = delegation("secret");
+        let token = delegation.issue(grant()).unwrap();
+        let partner = AccountId::new("partner", "example.net");
+        let other = AccountId::new("other", "example.net");
+
+        assert_eq!(
+            delegation
+                .verify_bearer(&token, None, 1_600_000_000)
+                .unwrap(),
+            grant()
+        );
+        assert_eq!(
+            delegation
+                .verify_bearer(&token, Some(&partner), 1_600_000_000)
+                .unwrap(),
+            grant()
+        );
+        assert!(delegation
+            .verify_bearer(&token, Some(&other), 1_600_000_000)
+            .is_err());
+        assert!(delegation
+            .verify_bearer(&token, Some(&partner), 1_600_000_300)
+            .is_err());
+    }
+
+    #[test]
+    fn grant_restrictions() {
+        let grant = grant();
+        assert!(grant.allows("reports.example.org", "2026/q1.pdf", "read"));
+        assert!(!grant.allows("reports.example.org", "2025/q1.pdf", "read"));
+        assert!(!grant.allows("reports.example.org", "2026/q1.pdf", "update"));
+        assert!(!grant.allows("other.example.org", "2026/q1.pdf", "read"));
+    }
+
+    #[test]
+    fn token_expiry() {
+        let now = Utc.timestamp(1_600_000_000, 0);
+        let delegation = delegation("secret");
+        assert_eq!(
+            delegation.expires_at(None, now).unwrap().timestamp(),
+            1_600_003_600
+        );
+        assert_eq!(
+            delegation.expires_at(Some(60), now).unwrap().timestamp(),
+            1_600_000_060
+        );
+        assert!(delegation.expires_at(Some(0), now).is_err());
+        assert!(delegation.expires_at(Some(3601), now).is_err());
+    }
+
+    #[test]
+    fn allowed_actions() {
+        assert_eq!(parse_allowed_actions(None).unwrap(), vec!["read"]);
+        assert!(parse_allowed_actions(Some(vec![])).is_err());
+        assert!(parse_allowed_actions(Some(vec!["read".into(), "update".into()])).is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures::{future, stream, Future, Stream};
+use http::StatusCode;
+use log::{error, info};
+use svc_authn::AccountId;
+
+use crate::app::audit::{AuditEntry, AuditLog};
+use crate::app::config::ExpiryConfig;
+use crate::app::util::{delete_unless_held, legal_hold_active};
+use crate::s3::{Client, ClientRouter};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Deletes expired objects of the configured buckets every `interval_secs` in background,
+/// the backup lifecycle rule is put on the buckets first if `lifecycle_days` is configured.
+pub(crate) fn spawn(
+    s3: Arc<ClientRouter>,
+    audit: AuditLog,
+    subject: AccountId,
+    config: ExpiryConfig,
+) {
+    if config.buckets.is_empty() {
+        return;
+    }
+
+    std::thread::spawn(move || {
+        let mut rt =
+            tokio::runtime::Runtime::new().expect("Error creating an expiry cleanup runtime");
+
+        if let Some(days) = config.lifecycle_days {
+            for bucket in &config.buckets {
+                match rt.block_on(s3.client().put_expiry_lifecycle_rule(bucket, days)) {
+                    Ok(()) => info!(
+                        "Expiry lifecycle rule is put, bucket = '{}', days = {}",
+                        bucket, days
+                    ),
+                    Err(err) => error!(
+                        "Error putting the expiry lifecycle rule, bucket = '{}': {:#}",
+                        bucket, err
+                    ),
+                }
+            }
+        }
+
+        let interval = Duration::from_secs(config.interval_secs.max(1));
+        let page_size = config.page_size.clamp(1, 1000);
+        loop {
+            for bucket in &config.buckets {
+                let cleanup = cleanup_bucket(
+                    s3.client(),
+                    audit.clone(),
+                    subject.clone(),
+                    bucket.to_owned(),
+                    page_size,
+                );
+                match rt.block_on(cleanup) {
+                    Ok(deleted) => info!(
+                        "Expired objects are deleted, bucket = '{}', count = {}",
+                        bucket, deleted
+                    ),
+                    Err(err) => error!(
+                        "Error deleting expired objects, bucket = '{}': {:#}",
+                        bucket, err
+                    ),
+                }
+            }
+
+            std::thread::sleep(interval);
+        }
+    });
+}
+
+/// Pages through objects of the bucket deleting expired ones, returns the number of deleted objects.
+fn cleanup_bucket(
+    s3: Arc<Client>,
+    audit: AuditLog,
+    subject: AccountId,
+    bucket: String,
+    page_size: i64,
+) -> impl Future<Item = usize, Error = anyhow::Error> {
+    let now = Utc::now();
+
+    future::loop_fn((0, None), move |(deleted, continuation_token)| {
+        let (s3, audit, subject, bucket) =
+            (s3.clone(), audit.clone(), subject.clone(), bucket.clone());
+
+        s3.list_objects(&bucket, page_size, continuation_token)
+            .and_then(move |page| {
+                let next_continuation_token = page.next_continuation_token;
+
+                // Objects are checked one at a time, failures are logged and skipped
+                stream::iter_ok(page.objects)
+                    .and_then(move |object| {
+                        delete_expired(
+                            s3.clone(),
+                            audit.clone(),
+                            subject.clone(),
+                            bucket.clone(),
+                            object.key,
+                            now,
+                        )
+                    })
+                    .fold(deleted, |acc, is_deleted| {
+                        Ok::<_, anyhow::Error>(acc + usize::from(is_deleted))
+                    })
+                    .map(move |deleted| match next_continuation_token {
+                        Some(token) => future::Loop::Continue((deleted, Some(token))),
+                        None => future::Loop::Break(deleted),
+                    })
+            })
+    })
+}
+
+/// Deletes the object if its expiry annotation is in the past, returns whether it's deleted.
+fn delete_expired(
+    s3: Arc<Client>,
+    audit: AuditLog,
+    subject: AccountId,
+    bucket: String,
+    object: String,
+    now: DateTime<Utc>,
+) -> impl Future<Item = bool, Error = anyhow::Error> {
+    let location = format!("bucket = '{}', object = '{}'", bucket, object);
+    let held_location = location.clone();
+    let (audit_bucket, audit_object) = (bucket.clone(), object.clone());
+
+    s3.object_expiry(&bucket, &object)
+        .and_then(move |expires_at| match expires_at {
+            Some(expires_at) if expires_at <= now => {
+                // Held objects are kept until their legal hold is released
+                let legal_hold = legal_hold_active(&s3, &bucket, &object);
+                let delete =
+                    delete_unless_held(legal_hold, move || s3.delete_object(&bucket, &object));
+                future::Either::A(delete.then(move |result| {
+                    let status = match result {
+                        Ok(true) => StatusCode::NO_CONTENT,
+                        Ok(false) => {
+                            info!(
+                                "Expired object is kept under a legal hold, {}",
+                                held_location
+                            );
+                            StatusCode::FORBIDDEN
+                        }
+                        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                    };
+                    let entry =
+                        AuditEntry::new(&subject, &audit_bucket, "DELETE", "delete", status)
+                            .object(&audit_object);
+                    audit.record(&entry, status);
+
+                    result
+                }))
+            }
+            _ => future::Either::B(future::ok(false)),
+        })
+        .or_else(move |err| {
+            error!("Error deleting an expired object, {}: {:#}", location, err);
+            Ok(false)
+        })
+}
@@ -0,0 +1,278 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::Write;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use uuid::Uuid;
+
+use crate::app::config::AuditS3ExportConfig;
+use crate::s3::Client;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Records are kept while the bucket is unavailable, the oldest ones are dropped beyond the limit.
+const MAX_BUFFERED_BATCHES: usize = 10;
+
+const CONTENT_TYPE: &str = "application/gzip";
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Default)]
+struct Buffer {
+    records: VecDeque<String>,
+    dropped: u64,
+}
+
+struct Inner {
+    buffer: Mutex<Buffer>,
+    full: Condvar,
+    max_records: usize,
+}
+
+/// Exports audit records to an S3 bucket for long-term retention. Records are accumulated
+/// in memory and flushed in background in the order they're recorded, a file per batch.
+#[derive(Clone)]
+pub(crate) struct AuditExporter {
+    inner: Arc<Inner>,
+}
+
+impl fmt::Debug for AuditExporter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("AuditExporter")
+            .field("max_records", &self.inner.max_records)
+            .finish()
+    }
+}
+
+impl AuditExporter {
+    fn new(max_records: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                buffer: Mutex::new(Buffer::default()),
+                full: Condvar::new(),
+                max_records: max_records.max(1),
+            }),
+        }
+    }
+
+    /// The bucket is accessed with a client of its own, so failures of exports don't open
+    /// the circuit of the backend.
+    pub(crate) fn spawn(config: AuditS3ExportConfig) -> Self {
+        let exporter = Self::new(config.max_records);
+        let s3 = Client::new(
+            &config.access_key_id,
+            &config.secret_access_key,
+            &config.region,
+            &config.endpoint(),
+            Duration::from_secs(300),
+        );
+        let instance_id = config
+            .instance_id
+            .clone()
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        // Files of a restarted instance don't overwrite the ones exported before
+        let mut sequence = Utc::now().timestamp_millis() as u64;
+
+        let worker = exporter.clone();
+        std::thread::spawn(move || {
+            let mut rt =
+                tokio::runtime::Runtime::new().expect("Error creating an audit export runtime");
+            let interval = Duration::from_secs(config.flush_interval_secs.max(1));
+
+            loop {
+                worker.wait(interval);
+
+                while let Some(batch) = worker.take() {
+                    let key = object_key(&config.prefix, Utc::now(), &instance_id, sequence);
+                    let upload = encode(&batch).and_then(|body| {
+                        rt.block_on(s3.put_object(
+                            &config.bucket,
+                            &key,
+                            body,
+                            Some(CONTENT_TYPE.to_owned()),
+                            None,
+                            HashMap::new(),
+                        ))
+                    });
+
+                    match upload {
+                        Ok(_) => {
+                            sequence += 1;
+                            info!(
+                                "Audit records are exported, bucket = '{}', key = '{}', count = {}",
+                                config.bucket,
+                                key,
+                                batch.len()
+                            );
+                        }
+                        Err(err) => {
+                            error!(
+                                "Error exporting audit records, bucket = '{}', key = '{}': {:#}",
+                                config.bucket, key, err
+                            );
+                            // Retried on the next flush
+                            worker.restore(batch);
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        exporter
+    }
+
+    pub(crate) fn push(&self, record: String) {
+        let mut buffer = self.lock();
+        if buffer.records.len() >= self.inner.max_records * MAX_BUFFERED_BATCHES {
+            buffer.records.pop_front();
+            buffer.dropped += 1;
+        }
+        buffer.records.push_back(record);
+
+        if buffer.records.len() >= self.inner.max_records {
+            self.inner.full.notify_one();
+        }
+    }
+
+    /// Blocks until the interval elapses or a batch of records is accumulated.
+    fn wait(&self, interval: Duration) {
+        let started_at = Instant::now();
+        let mut buffer = self.lock();
+        while buffer.records.len() < self.inner.max_records {
+            let elapsed = started_at.elapsed();
+            if elapsed >= interval {
+                break;
+            }
+
+            buffer = self
+                .inner
+                .full
+                .wait_timeout(buffer, interval - elapsed)
+                .expect("Audit export buffer lock is poisoned")
+                .0;
+        }
+    }
+
+    /// Takes the oldest records, at most `max_records` of them.
+    fn take(&self) -> Option<Vec<String>> {
+        let mut buffer = self.lock();
+        if buffer.dropped > 0 {
+            error!(
+                "Audit records are dropped before being exported, count = {}",
+                buffer.dropped
+            );
+            buffer.dropped = 0;
+        }
+        if buffer.records.is_empty() {
+            return None;
+        }
+
+        let count = buffer.records.len().min(self.inner.max_records);
+        Some(buffer.records.drain(..count).collect())
+    }
+
+    /// Puts the batch back in front of the records accumulated since it's been taken.
+    fn restore(&self, batch: Vec<String>) {
+        let mut buffer = self.lock();
+        for record in batch.into_iter().rev() {
+            buffer.records.push_front(record);
+        }
+
+        let max_buffered = self.inner.max_records * MAX_BUFFERED_BATCHES;
+        while buffer.records.len() > max_buffered {
+            buffer.records.pop_front();
+            buffer.dropped += 1;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Buffer> {
+        self.inner
+            .buffer
+            .lock()
+            .expect("Audit export buffer lock is poisoned")
+    }
+}
+
+/// `<prefix>YYYY/MM/DD/HH/<instance_id>_<sequence>.ndjson.gz`
+fn object_key(prefix: &str, now: DateTime<Utc>, instance_id: &str, sequence: u64) -> String {
+    format!(
+        "{}{}/{}_{:020}.ndjson.gz",
+        prefix,
+        now.format("%Y/%m/%d/%H"),
+        instance_id,
+        sequence
+    )
+}
+
+fn encode(records: &[String]) -> anyhow::Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    for record in records {
+        encoder.write_all(record.as_bytes())?;
+        encoder.write_all(b"\n")?;
+    }
+
+    encoder.finish().context("failed to compress audit records")
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn object_keys() {
+        let now = "2024-03-05T07:30:00Z".parse().unwrap();
+        assert_eq!(
+            object_key("audit/", now, "storage-1", 42),
+            "audit/2024/03/05/07/storage-1_00000000000000000042.ndjson.gz"
+        );
+    }
+
+    #[test]
+    fn encode_records() {
+        let records = vec![r#"{"a":1}"#.to_owned(), r#"{"b":2}"#.to_owned()];
+
+        let mut ndjson = String::new();
+        flate2::read::GzDecoder::new(&encode(&records).unwrap()[..])
+            .read_to_string(&mut ndjson)
+            .unwrap();
+        assert_eq!(ndjson, "{\"a\":1}\n{\"b\":2}\n");
+    }
+
+    #[test]
+    fn take_batches_in_order() {
+        let exporter = AuditExporter::new(2);
+        for i in 0..5 {
+            exporter.push(i.to_string());
+        }
+
+        assert_eq!(exporter.take().unwrap(), vec!["0", "1"]);
+        let batch = exporter.take().unwrap();
+        assert_eq!(batch, vec!["2", "3"]);
+
+        // Failed batches are taken again before the rest of records
+        exporter.restore(batch);
+        assert_eq!(exporter.take().unwrap(), vec!["2", "3"]);
+        assert_eq!(exporter.take().unwrap(), vec!["4"]);
+        assert_eq!(exporter.take(), None);
+    }
+
+    #[test]
+    fn push_drops_oldest_records() {
+        let exporter = AuditExporter::new(1);
+        for i in 0..(MAX_BUFFERED_BATCHES + 2) {
+            exporter.push(i.to_string());
+        }
+
+        assert_eq!(exporter.lock().dropped, 2);
+        assert_eq!(exporter.take().unwrap(), vec!["2"]);
+        assert_eq!(exporter.lock().dropped, 0);
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use anyhow::format_err;
+use log::info;
+use openssl::sha::sha256;
+
+use crate::app::config::{FeatureFlagConfig, FeaturesConfig};
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) const CONTENT_NEGOTIATION: &str = "content_negotiation";
+pub(crate) const PROXY_UPLOAD: &str = "proxy_upload";
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// State of the flag as it's configured.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct FeatureFlagState {
+    pub(crate) name: String,
+    pub(crate) enabled_fraction: f64,
+    pub(crate) enabled_subjects: Vec<String>,
+}
+
+/// Features rolled out to subjects gradually: a feature is enabled for subjects of the allowlist
+/// and for the fraction of the rest of them. Subjects are assigned to the fraction by the hash
+/// of the name of the flag and the subject, so that they keep the feature across requests
+/// and instances of the application. Features without a flag are enabled for everyone.
+#[derive(Clone)]
+pub(crate) struct FeatureFlags {
+    flags: Arc<RwLock<Arc<BTreeMap<String, FeatureFlagState>>>>,
+}
+
+impl fmt::Debug for FeatureFlags {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("FeatureFlags")
+            .field("flags", &self.current())
+            .finish()
+    }
+}
+
+impl FeatureFlags {
+    pub(crate) fn new(config: &FeaturesConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            flags: Arc::new(RwLock::new(Arc::new(parse_flags(&config.flags)?))),
+        })
+    }
+
+    /// Replaces the flags, they're kept if the config is invalid.
+    pub(crate) fn reload(&self, config: &FeaturesConfig) -> anyhow::Result<()> {
+        let flags = parse_flags(&config.flags)?;
+        info!("Feature flags are reloaded, count = {}", flags.len());
+        *self.flags.write().expect("Feature flags lock is poisoned") = Arc::new(flags);
+        Ok(())
+    }
+
+    pub(crate) fn is_enabled(&self, name: &str, subject: &impl fmt::Display) -> bool {
+        let flags = self.current();
+        let flag = match flags.get(name) {
+            Some(val) => val,
+            None => return true,
+        };
+
+        let subject = subject.to_string();
+        flag.enabled_subjects.contains(&subject) || sample(name, &subject) < flag.enabled_fraction
+    }
+
+    /// Flags in the order of their names.
+    pub(crate) fn state(&self) -> Vec<FeatureFlagState> {
+        self.current().values().cloned().collect()
+    }
+
+    fn current(&self) -> Arc<BTreeMap<String, FeatureFlagState>> {
+        self.flags
+            .read()
+            .expect("Feature flags lock is poisoned")
+            .clone()
+    }
+}
+
+fn parse_flags(flags: &[FeatureFlagConfig]) -> anyhow::Result<BTreeMap<String, FeatureFlagState>> {
+    let mut parsed = BTreeMap::new();
+    for flag in flags {
+        if !(0.0..=1.0).contains(&flag.enabled_fraction) {
+            return Err(format_err!(
+                "invalid enabled_fraction = {} of feature flag = '{}', it must be within 0.0..1.0",
+                flag.enabled_fraction,
+                flag.name
+            ));
+        }
+
+        let state = FeatureFlagState {
+            name: flag.name.clone(),
+            enabled_fraction: flag.enabled_fraction,
+            enabled_subjects: flag.enabled_subjects.clone(),
+        };
+        if parsed.insert(flag.name.clone(), state).is_some() {
+            return Err(format_err!("duplicate feature flag = '{}'", flag.name));
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Uniform position of the subject within `[0.0, 1.0)` for the flag.
+fn sample(name: &str, subject: &str) -> f64 {
+    let digest = sha256(format!("{}\n{}", name, subject).as_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    (u64::from_be_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(enabled_fraction: f64, enabled_subjects: &[&str]) -> FeaturesConfig {
+        FeaturesConfig {
+            flags: vec![FeatureFlagConfig {
+                name: PROXY_UPLOAD.into(),
+                enabled_fraction,
+                enabled_subjects: enabled_subjects.iter().map(|val| val.to_string()).collect(),
+            }],
+        }
+    }
+
+    #[test]
+    fn enabled_features() {
+        let flags = FeatureFlags::new(&config(0.0, &["john.usr.example.org"])).unwrap();
+        assert!(flags.is_enabled(PROXY_UPLOAD, &"john.usr.example.org"));
+        assert!(!flags.is_enabled(PROXY_UPLOAD, &"jane.usr.example.org"));
+        assert!(flags.is_enabled(CONTENT_NEGOTIATION, &"jane.usr.example.org"));
+
+        flags.reload(&config(1.0, &[])).unwrap();
+        assert!(flags.is_enabled(PROXY_UPLOAD, &"jane.usr.example.org"));
+        assert!(flags.reload(&config(1.5, &[])).is_err());
+        assert_eq!(flags.state()[0].enabled_fraction, 1.0);
+    }
+
+    #[test]
+    fn sample_fraction_of_subjects() {
+        let flags = FeatureFlags::new(&config(0.25, &[])).unwrap();
+        let subjects = (0..10000)
+            .map(|idx| format!("user{}.usr.example.org", idx))
+            .collect::<Vec<String>>();
+        let enabled = subjects
+            .iter()
+            .filter(|subject| flags.is_enabled(PROXY_UPLOAD, subject))
+            .count();
+        assert!((2300..2700).contains(&enabled), "enabled = {}", enabled);
+
+        // Subjects are assigned deterministically
+        let again = subjects
+            .iter()
+            .filter(|subject| flags.is_enabled(PROXY_UPLOAD, subject))
+            .count();
+        assert_eq!(enabled, again);
+        assert_ne!(
+            sample(PROXY_UPLOAD, "john.usr.example.org"),
+            sample(CONTENT_NEGOTIATION, "john.usr.example.org")
+        );
+    }
+}
@@ -0,0 +1,943 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{format_err, Context};
+use bytes::Bytes;
+use futures::{future, Async, Future, Poll, Stream};
+use http::header;
+use http::StatusCode;
+use hyper::server::conn::Http;
+use hyper::{Body, Request, Response, Server};
+use log::{debug, error};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::timer::Delay;
+use tower_web::util::buf_stream::BufStream;
+use tower_web::util::http::{HttpService, NewHttpService};
+use tower_web::util::tuple::Either2;
+use url::form_urlencoded;
+
+use crate::app::validation::ReplayBody;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// How requests are received: as is, or wrapped into events of API Gateway proxy integration.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum GatewayMode {
+    #[default]
+    Http,
+    ApiGateway,
+}
+
+/// Request of API Gateway proxy integration (REST API, payload format version 1.0).
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiGatewayEvent {
+    http_method: String,
+    path: String,
+    #[serde(default)]
+    headers: Option<HashMap<String, String>>,
+    #[serde(default)]
+    multi_value_headers: Option<HashMap<String, Vec<String>>>,
+    #[serde(default)]
+    query_string_parameters: Option<HashMap<String, String>>,
+    #[serde(default)]
+    multi_value_query_string_parameters: Option<HashMap<String, Vec<String>>>,
+    #[serde(default)]
+    body: Option<String>,
+    #[serde(default)]
+    is_base64_encoded: bool,
+    #[serde(default)]
+    request_context: Option<ApiGatewayRequestContext>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiGatewayRequestContext {
+    #[serde(default)]
+    identity: Option<ApiGatewayIdentity>,
+}
+
+/// Identity of the caller, the source address is the one API Gateway has been connected by.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiGatewayIdentity {
+    source_ip: Option<String>,
+}
+
+/// Response of API Gateway proxy integration, the body is base64 encoded unless it's UTF-8.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiGatewayResponse {
+    status_code: u16,
+    multi_value_headers: BTreeMap<String, Vec<String>>,
+    body: String,
+    is_base64_encoded: bool,
+}
+
+/// Body of the request reconstructed from the event.
+#[derive(Debug)]
+pub(crate) struct EventBody(Option<Bytes>);
+
+impl BufStream for EventBody {
+    type Item = io::Cursor<Bytes>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        Ok(Async::Ready(self.0.take().map(io::Cursor::new)))
+    }
+}
+
+impl ReplayBody for EventBody {
+    fn replay(bytes: Bytes) -> Self {
+        EventBody(Some(bytes))
+    }
+}
+
+fn event_request(event: ApiGatewayEvent) -> anyhow::Result<http::Request<EventBody>> {
+    // Multi-value parameters and headers contain all values, others only the last one
+    let query = match (
+        event.multi_value_query_string_parameters,
+        event.query_string_parameters,
+    ) {
+        (Some(params), _) => params
+            .into_iter()
+            .flat_map(|(key, values)| values.into_iter().map(move |value| (key.clone(), value)))
+            .collect::<Vec<(String, String)>>(),
+        (None, Some(params)) => params.into_iter().collect(),
+        (None, None) => Vec::new(),
+    };
+    let uri = if query.is_empty() {
+        event.path
+    } else {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(query)
+            .finish();
+        format!("{}?{}", event.path, query)
+    };
+
+    let mut builder = http::Request::builder();
+    builder.method(event.http_method.as_str()).uri(uri.as_str());
+    match (event.multi_value_headers, event.headers) {
+        (Some(headers), _) => {
+            for (key, values) in headers {
+                for value in values {
+                    builder.header(key.as_str(), value.as_str());
+                }
+            }
+        }
+        (None, Some(headers)) => {
+            for (key, value) in headers {
+                builder.header(key.as_str(), value.as_str());
+            }
+        }
+        (None, None) => (),
+    }
+
+    let body = match event.body {
+        Some(body) if event.is_base64_encoded => Some(Bytes::from(
+            base64::decode(&body).context("invalid base64 body")?,
+        )),
+        Some(body) => Some(Bytes::from(body)),
+        None => None,
+    };
+
+    let mut request = builder
+        .body(EventBody(body))
+        .map_err(|err| format_err!("invalid request: {}", err))?;
+    let source_ip = event
+        .request_context
+        .and_then(|context| context.identity)
+        .and_then(|identity| identity.source_ip)
+        .and_then(|ip| ip.parse().ok());
+    if let Some(ip) = source_ip {
+        request.extensions_mut().insert(ClientAddr(ip));
+    }
+    Ok(request)
+}
+
+fn event_response(response: http::Response<Vec<u8>>) -> ApiGatewayResponse {
+    let (parts, body) = response.into_parts();
+
+    let mut multi_value_headers = BTreeMap::new();
+    for (key, value) in &parts.headers {
+        if let Ok(value) = value.to_str() {
+            multi_value_headers
+                .entry(key.as_str().to_owned())
+                .or_insert_with(Vec::new)
+                .push(value.to_owned());
+        }
+    }
+
+    let (body, is_base64_encoded) = match String::from_utf8(body) {
+        Ok(body) => (body, false),
+        Err(err) => (base64::encode(err.as_bytes()), true),
+    };
+
+    ApiGatewayResponse {
+        status_code: parts.status.as_u16(),
+        multi_value_headers,
+        body,
+        is_base64_encoded,
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Address of the client the request is received from, it's never taken from headers
+/// the client is able to set.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ClientAddr(pub(crate) IpAddr);
+
+/// Address of the TCP peer of the connection the request is received over, e.g. the proxy
+/// in front of the application. It's unknown with API Gateway.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct PeerAddr(pub(crate) IpAddr);
+
+/// Body of a proxy upload passed on to the handler as it's received rather than read by
+/// the service, it's taken out of the extensions of the request once.
+#[derive(Clone)]
+pub(crate) struct StreamedBody(Arc<Mutex<Option<HttpBody>>>);
+
+impl StreamedBody {
+    pub(crate) fn take(&self) -> Option<HttpBody> {
+        self.0.lock().ok().and_then(|mut body| body.take())
+    }
+}
+
+impl std::fmt::Debug for StreamedBody {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("StreamedBody").finish()
+    }
+}
+
+fn is_streamed<B>(req: &Request<B>) -> bool {
+    req.method() == http::Method::PUT && req.uri().path().ends_with("/proxy-upload")
+}
+
+/// Each of the trusted proxies in front of the application appends the address of its peer
+/// to `x-forwarded-for` header, so the client is the one the outermost of them is connected by.
+/// Entries the client has sent on its own precede those ones and are never used.
+fn client_addr(
+    headers: &header::HeaderMap,
+    peer: IpAddr,
+    trusted_proxies: usize,
+) -> Option<IpAddr> {
+    if trusted_proxies == 0 {
+        return Some(peer);
+    }
+
+    let entries = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<&str>>();
+    entries
+        .len()
+        .checked_sub(trusted_proxies)
+        .and_then(|idx| entries[idx].trim().parse().ok())
+}
+
+/// Handles the request aside of the service, e.g. upgrades the connection to another protocol,
+/// or leaves the request to the service.
+pub(crate) type Intercept =
+    Arc<dyn Fn(&mut Request<Body>) -> Option<Response<Bytes>> + Send + Sync>;
+
+const REQUEST_TIMEOUT_MESSAGE: &str = r#"{"message":"Request timeout"}"#;
+
+/// Time limits of HTTP connections, there is no limit unless specified.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct HttpTimeouts {
+    /// From the first byte of the request until its headers are read.
+    pub(crate) request_header: Option<Duration>,
+    /// From the moment the headers are read until the whole body is read.
+    pub(crate) request_body: Option<Duration>,
+    /// While the keep-alive connection has no requests being handled.
+    pub(crate) idle_connection: Option<Duration>,
+}
+
+/// Body of a request received over HTTP. Services share request bodies between threads while
+/// the one of hyper isn't `Sync`, so it's only polled through the exclusive reference to the mutex.
+#[derive(Debug)]
+pub(crate) struct HttpBody {
+    body: Mutex<Body>,
+    deadline: Option<Delay>,
+    timed_out: Arc<AtomicBool>,
+}
+
+impl HttpBody {
+    fn new(body: Body, timeout: Option<Duration>, timed_out: Arc<AtomicBool>) -> Self {
+        Self {
+            body: Mutex::new(body),
+            deadline: timeout.map(|timeout| Delay::new(Instant::now() + timeout)),
+            timed_out,
+        }
+    }
+}
+
+impl BufStream for HttpBody {
+    type Item = hyper::Chunk;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let body = self
+            .body
+            .get_mut()
+            .map_err(|_| io::Error::other("request body is poisoned"))?;
+        match Stream::poll(body) {
+            Ok(Async::NotReady) => (),
+            Ok(ready) => return Ok(ready),
+            Err(err) => return Err(io::Error::other(err)),
+        }
+
+        if let Some(ref mut deadline) = self.deadline {
+            if elapsed(deadline)? {
+                self.timed_out.store(true, Ordering::SeqCst);
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "request body timeout",
+                ));
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+// The body has been read in time, so it's replayed without a deadline
+impl ReplayBody for HttpBody {
+    fn replay(bytes: Bytes) -> Self {
+        Self::new(Body::from(bytes), None, Arc::new(AtomicBool::new(false)))
+    }
+}
+
+fn elapsed(deadline: &mut Delay) -> io::Result<bool> {
+    deadline
+        .poll()
+        .map(|ready| ready.is_ready())
+        .map_err(io::Error::other)
+}
+
+fn request_timeout<B>() -> Response<HttpResponseBody<B>> {
+    Response::builder()
+        .status(StatusCode::REQUEST_TIMEOUT)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CONNECTION, "close")
+        .body(HttpResponseBody::Intercepted(Some(Bytes::from(
+            REQUEST_TIMEOUT_MESSAGE,
+        ))))
+        .expect("Error building a response")
+}
+
+/// Body of the response of the service or the one of an intercepted request.
+pub(crate) enum HttpResponseBody<B> {
+    Service(B),
+    Intercepted(Option<Bytes>),
+}
+
+impl<B> hyper::body::Payload for HttpResponseBody<B>
+where
+    B: BufStream + Send + 'static,
+    B::Item: Send,
+{
+    type Data = Either2<B::Item, io::Cursor<Bytes>>;
+    type Error = io::Error;
+
+    fn poll_data(&mut self) -> Poll<Option<Self::Data>, Self::Error> {
+        match self {
+            HttpResponseBody::Service(body) => body
+                .poll()
+                .map(|ready| ready.map(|item| item.map(Either2::A)))
+                .map_err(|_| io::Error::other("failed to read the body")),
+            HttpResponseBody::Intercepted(body) => Ok(Async::Ready(
+                body.take().map(|body| Either2::B(io::Cursor::new(body))),
+            )),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Requests of the connection the service has received and responded to,
+/// shared by the service and the transport of the connection.
+#[derive(Debug, Default)]
+struct ConnectionActivity {
+    started: AtomicUsize,
+    finished: AtomicUsize,
+}
+
+impl ConnectionActivity {
+    fn start(&self) {
+        self.started.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn finish(&self) {
+        self.finished.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Transport of the connection limiting the time of reading request headers and the one
+/// of the connection being idle. Requests with headers not read in time are answered with
+/// `408 Request Timeout` right on the transport, idle connections are closed.
+struct TimedConnection<T> {
+    io: T,
+    timeouts: HttpTimeouts,
+    activity: Arc<ConnectionActivity>,
+    started: usize,
+    header_deadline: Option<Delay>,
+    idle_deadline: Option<Delay>,
+}
+
+impl<T> TimedConnection<T>
+where
+    T: Read + Write,
+{
+    fn new(io: T, timeouts: HttpTimeouts, activity: Arc<ConnectionActivity>) -> Self {
+        Self {
+            io,
+            timeouts,
+            activity,
+            started: 0,
+            // Connections sending nothing are timed out as the ones sending headers slowly.
+            header_deadline: timeouts
+                .request_header
+                .map(|timeout| Delay::new(Instant::now() + timeout)),
+            idle_deadline: None,
+        }
+    }
+
+    /// Whether no request is being handled, once the service receives a request its headers
+    /// are read and the header deadline is over.
+    fn is_idle(&mut self) -> bool {
+        let started = self.activity.started.load(Ordering::SeqCst);
+        if started != self.started {
+            self.started = started;
+            self.header_deadline = None;
+        }
+
+        let idle = started == self.activity.finished.load(Ordering::SeqCst);
+        if !idle {
+            self.idle_deadline = None;
+        }
+        idle
+    }
+
+    fn on_transfer(&mut self, received: bool) {
+        if !self.is_idle() {
+            return;
+        }
+
+        let now = Instant::now();
+        if received && self.header_deadline.is_none() {
+            self.header_deadline = self
+                .timeouts
+                .request_header
+                .map(|timeout| Delay::new(now + timeout));
+        }
+        if let Some(timeout) = self.timeouts.idle_connection {
+            match self.idle_deadline {
+                Some(ref mut deadline) => deadline.reset(now + timeout),
+                None => self.idle_deadline = Some(Delay::new(now + timeout)),
+            }
+        }
+    }
+
+    /// Checks deadlines while waiting for data, returns `false` once the idle connection
+    /// is to be closed.
+    fn poll_deadlines(&mut self) -> io::Result<bool> {
+        if self.is_idle() && self.idle_deadline.is_none() {
+            self.on_transfer(false);
+        }
+
+        if let Some(ref mut deadline) = self.header_deadline {
+            if elapsed(deadline)? {
+                let resp = format!(
+                    "HTTP/1.1 408 Request Timeout\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    REQUEST_TIMEOUT_MESSAGE.len(),
+                    REQUEST_TIMEOUT_MESSAGE
+                );
+                // The response is sent at best effort since the connection is closed anyway.
+                let _ = self.io.write(resp.as_bytes());
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "request header timeout",
+                ));
+            }
+        }
+
+        if let Some(ref mut deadline) = self.idle_deadline {
+            if elapsed(deadline)? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl<T> Read for TimedConnection<T>
+where
+    T: Read + Write,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.io.read(buf) {
+            Ok(size) => {
+                self.on_transfer(size > 0);
+                Ok(size)
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if self.poll_deadlines()? {
+                    Err(io::ErrorKind::WouldBlock.into())
+                } else {
+                    debug!("Closing the idle HTTP connection");
+                    Ok(0)
+                }
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl<T> Write for TimedConnection<T>
+where
+    T: Read + Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.io.write(buf)?;
+        self.on_transfer(false);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T> AsyncRead for TimedConnection<T> where T: AsyncRead + AsyncWrite {}
+
+impl<T> AsyncWrite for TimedConnection<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct HttpConnection<S> {
+    service: S,
+    intercept: Intercept,
+    timeouts: HttpTimeouts,
+    activity: Arc<ConnectionActivity>,
+    peer: Option<IpAddr>,
+    trusted_proxies: usize,
+}
+
+impl<S> hyper::service::Service for HttpConnection<S>
+where
+    S: HttpService<RequestBody = HttpBody>,
+    S::Future: Send + 'static,
+    S::ResponseBody: Send + 'static,
+    <S::ResponseBody as BufStream>::Item: Send,
+{
+    type ReqBody = Body;
+    type ResBody = HttpResponseBody<S::ResponseBody>;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = Response<Self::ResBody>, Error = io::Error> + Send>;
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        self.activity.start();
+        if let Some(resp) = (self.intercept)(&mut req) {
+            // Upgraded connections are never idle for HTTP.
+            if resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+                self.activity.finish();
+            }
+            let resp = resp.map(|body| HttpResponseBody::Intercepted(Some(body)));
+            return Box::new(future::ok(resp));
+        }
+        let addr = self
+            .peer
+            .and_then(|peer| client_addr(req.headers(), peer, self.trusted_proxies));
+        if let Some(addr) = addr {
+            req.extensions_mut().insert(ClientAddr(addr));
+        }
+        if let Some(peer) = self.peer {
+            req.extensions_mut().insert(PeerAddr(peer));
+        }
+
+        let activity = self.activity.clone();
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let body_timeout = self.timeouts.request_body;
+        let mut req = {
+            let timed_out = timed_out.clone();
+            req.map(move |body| HttpBody::new(body, body_timeout, timed_out))
+        };
+        if is_streamed(&req) {
+            let body = std::mem::replace(req.body_mut(), HttpBody::replay(Bytes::new()));
+            req.extensions_mut()
+                .insert(StreamedBody(Arc::new(Mutex::new(Some(body)))));
+        }
+        Box::new(self.service.call_http(req).then(move |result| {
+            activity.finish();
+            if timed_out.load(Ordering::SeqCst) {
+                return Ok(request_timeout());
+            }
+
+            result
+                .map(|resp| resp.map(HttpResponseBody::Service))
+                .map_err(|_| io::Error::other("failed to handle the request"))
+        }))
+    }
+}
+
+/// Pause of accepting connections after a failure to accept one.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Serves HTTP requests, those `intercept` handles never reach the service.
+/// Addresses of clients are taken from `x-forwarded-for` header of `trusted_proxies` if any.
+pub(crate) fn run_http<T>(
+    addr: &SocketAddr,
+    new_service: T,
+    intercept: Intercept,
+    timeouts: HttpTimeouts,
+    trusted_proxies: usize,
+) -> anyhow::Result<()>
+where
+    T: NewHttpService<RequestBody = HttpBody> + Send + Sync + 'static,
+    T::Future: Send + 'static,
+    T::Service: Send + 'static,
+    <T::Service as HttpService>::Future: Send + 'static,
+    T::ResponseBody: Send + 'static,
+    <T::ResponseBody as BufStream>::Item: Send,
+{
+    let listener = TcpListener::bind(addr).context("failed to bind the listener")?;
+    let http = Http::new();
+    let server = listener
+        .incoming()
+        .then(|result| match result {
+            Ok(socket) => future::Either::A(future::ok(Some(socket))),
+            // Errors are mostly of running out of file descriptors, accepting right away
+            // would spin the loop until some of them are closed
+            Err(err) => {
+                error!("Error accepting an HTTP connection: {}", err);
+                future::Either::B(
+                    Delay::new(Instant::now() + ACCEPT_ERROR_BACKOFF).then(|_| Ok(None)),
+                )
+            }
+        })
+        .filter_map(|socket| socket)
+        .for_each(move |socket| {
+            let peer = socket.peer_addr().ok().map(|addr| addr.ip());
+            let activity = Arc::new(ConnectionActivity::default());
+            let io = TimedConnection::new(socket, timeouts, activity.clone());
+            let intercept = intercept.clone();
+            let http = http.clone();
+            new_service.new_http_service().then(move |result| {
+                match result {
+                    Ok(service) => {
+                        let service = HttpConnection {
+                            service,
+                            intercept,
+                            timeouts,
+                            activity,
+                            peer,
+                            trusted_proxies,
+                        };
+                        let conn = http
+                            .serve_connection(io, service)
+                            .with_upgrades()
+                            .map_err(|err| debug!("Error serving an HTTP connection: {}", err));
+                        hyper::rt::spawn(conn);
+                    }
+                    Err(_) => error!("Error creating a service for an HTTP connection"),
+                }
+                Ok(())
+            })
+        });
+
+    hyper::rt::run(server);
+    Ok(())
+}
+
+/// Serves API Gateway events: each request contains an event in its body,
+/// the response of the service to the request reconstructed from the event is sent back as an event.
+pub(crate) fn run<T>(addr: &SocketAddr, new_service: T) -> anyhow::Result<()>
+where
+    T: NewHttpService<RequestBody = EventBody> + Send + Sync + 'static,
+    T::Future: Send,
+    T::Service: Send,
+    <T::Service as HttpService>::Future: Send,
+    T::ResponseBody: Send,
+    <T::ResponseBody as BufStream>::Item: Send,
+{
+    let new_service = Arc::new(new_service);
+    let server = Server::try_bind(addr)
+        .context("failed to bind the listener")?
+        .serve(move || {
+            let new_service = new_service.clone();
+            future::ok::<_, hyper::Error>(hyper::service::service_fn(move |req: Request<Body>| {
+                handle(new_service.clone(), req)
+            }))
+        })
+        .map_err(|err| error!("Error serving API Gateway events: {}", err));
+
+    hyper::rt::run(server);
+    Ok(())
+}
+
+fn handle<T>(
+    new_service: Arc<T>,
+    req: Request<Body>,
+) -> impl Future<Item = Response<Body>, Error = hyper::Error>
+where
+    T: NewHttpService<RequestBody = EventBody>,
+{
+    req.into_body().concat2().and_then(move |body| {
+        let request = serde_json::from_slice::<ApiGatewayEvent>(&body)
+            .context("invalid api gateway event")
+            .and_then(event_request);
+        let request = match request {
+            Ok(val) => val,
+            Err(err) => {
+                let resp = Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from(format!("{:#}", err)))
+                    .expect("Error building a response");
+                return future::Either::A(future::ok(resp));
+            }
+        };
+
+        let resp = new_service
+            .new_http_service()
+            .map_err(|_| format_err!("failed to create a service"))
+            .and_then(move |mut service| {
+                service
+                    .call_http(request)
+                    .map_err(|_| format_err!("failed to handle the request"))
+            })
+            .and_then(|resp| {
+                let (parts, body) = resp.into_parts();
+                body.collect::<Vec<u8>>()
+                    .map(|body| http::Response::from_parts(parts, body))
+                    .map_err(|_| format_err!("failed to read the response body"))
+            })
+            .then(|resp| {
+                let resp = resp.and_then(|resp| {
+                    serde_json::to_vec(&event_response(resp))
+                        .context("failed to serialize the response")
+                });
+
+                let resp = match resp {
+                    Ok(body) => Response::builder()
+                        .header("content-type", "application/json")
+                        .body(Body::from(body)),
+                    Err(err) => {
+                        error!("Error handling an API Gateway event: {:#}", err);
+                        Response::builder()
+                            .status(StatusCode::INTERNAL_SERVER_ERROR)
+                            .body(Body::empty())
+                    }
+                };
+                Ok(resp.expect("Error building a response"))
+            });
+
+        future::Either::B(resp)
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_request_reconstruction() {
+        let event = serde_json::from_value::<ApiGatewayEvent>(serde_json::json!({
+            "httpMethod": "GET",
+            "path": "/api/v1/buckets/data.example.org/objects/foo",
+            "headers": {"authorization": "Bearer token"},
+            "multiValueQueryStringParameters": {"format": ["json"]},
+            "body": "aGVsbG8=",
+            "isBase64Encoded": true,
+            "requestContext": {"identity": {"sourceIp": "203.0.113.10"}},
+        }))
+        .unwrap();
+
+        let mut req = event_request(event).unwrap();
+        assert_eq!(req.method(), http::Method::GET);
+        assert_eq!(
+            req.uri(),
+            "/api/v1/buckets/data.example.org/objects/foo?format=json"
+        );
+        assert_eq!(req.headers()["authorization"], "Bearer token");
+        assert_eq!(
+            req.extensions().get::<ClientAddr>(),
+            Some(&ClientAddr("203.0.113.10".parse().unwrap()))
+        );
+
+        let chunk = req.body_mut().poll().unwrap();
+        match chunk {
+            Async::Ready(Some(chunk)) => assert_eq!(chunk.into_inner(), "hello"),
+            _ => panic!("expected the body"),
+        }
+    }
+
+    #[test]
+    fn client_address() {
+        let peer = "10.0.0.2".parse().unwrap();
+        let mut headers = header::HeaderMap::new();
+        headers.append(
+            "x-forwarded-for",
+            "192.0.2.1, 203.0.113.10".parse().unwrap(),
+        );
+        headers.append("x-forwarded-for", "10.0.0.1".parse().unwrap());
+
+        assert_eq!(client_addr(&headers, peer, 0), Some(peer));
+        assert_eq!(
+            client_addr(&headers, peer, 1),
+            Some("10.0.0.1".parse().unwrap())
+        );
+        assert_eq!(
+            client_addr(&headers, peer, 2),
+            Some("203.0.113.10".parse().unwrap())
+        );
+        assert_eq!(client_addr(&headers, peer, 4), None);
+        assert_eq!(client_addr(&header::HeaderMap::new(), peer, 1), None);
+    }
+
+    /// Transport receiving the data and then waiting for more forever.
+    #[derive(Default)]
+    struct PendingIo {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for PendingIo {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.input.read(buf)? {
+                0 => Err(io::ErrorKind::WouldBlock.into()),
+                size => Ok(size),
+            }
+        }
+    }
+
+    impl Write for PendingIo {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Reads the connection until it's closed.
+    fn read_to_end<T: Read + Write>(conn: &mut TimedConnection<T>) -> io::Result<()> {
+        let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+        rt.block_on(future::poll_fn(|| loop {
+            match conn.read(&mut [0; 64]) {
+                Ok(0) => return Ok(Async::Ready(())),
+                Ok(_) => (),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady)
+                }
+                Err(err) => return Err(err),
+            }
+        }))
+    }
+
+    #[test]
+    fn request_header_timeout() {
+        let timeouts = HttpTimeouts {
+            request_header: Some(Duration::from_millis(10)),
+            ..HttpTimeouts::default()
+        };
+        let io = PendingIo {
+            input: io::Cursor::new(b"GET / HTTP/1.1\r\nhost: example.org\r\n".to_vec()),
+            ..PendingIo::default()
+        };
+        let mut conn = TimedConnection::new(io, timeouts, Arc::default());
+
+        let err = read_to_end(&mut conn).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let resp = String::from_utf8(conn.io.output).unwrap();
+        assert!(
+            resp.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{}",
+            resp
+        );
+        assert!(resp.ends_with(REQUEST_TIMEOUT_MESSAGE), "{}", resp);
+    }
+
+    #[test]
+    fn idle_connection_timeout() {
+        let timeouts = HttpTimeouts {
+            idle_connection: Some(Duration::from_millis(10)),
+            ..HttpTimeouts::default()
+        };
+        let activity = Arc::new(ConnectionActivity::default());
+        let mut conn = TimedConnection::new(PendingIo::default(), timeouts, activity.clone());
+        read_to_end(&mut conn).unwrap();
+
+        // Connections handling requests aren't idle.
+        let mut conn = TimedConnection::new(PendingIo::default(), timeouts, activity.clone());
+        activity.start();
+        let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+        rt.block_on(Delay::new(Instant::now() + Duration::from_millis(30)))
+            .unwrap();
+        let err = rt
+            .block_on(future::lazy(|| conn.read(&mut [0; 64])))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(conn.idle_deadline.is_none());
+    }
+
+    #[test]
+    fn request_body_timeout() {
+        let (_sender, body) = Body::channel();
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let mut body = HttpBody::new(body, Some(Duration::from_millis(10)), timed_out.clone());
+
+        let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let err = rt.block_on(future::poll_fn(|| body.poll())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(timed_out.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn event_response_encoding() {
+        let resp = http::Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header("location", "https://s3.example.org/foo")
+            .body(Vec::new())
+            .unwrap();
+        assert_eq!(
+            event_response(resp),
+            ApiGatewayResponse {
+                status_code: 303,
+                multi_value_headers: vec![(
+                    "location".to_owned(),
+                    vec!["https://s3.example.org/foo".to_owned()]
+                )]
+                .into_iter()
+                .collect(),
+                body: String::new(),
+                is_base64_encoded: false,
+            }
+        );
+
+        let resp = http::Response::new(vec![0xff, 0xfe]);
+        let event = event_response(resp);
+        assert!(event.is_base64_encoded);
+        assert_eq!(event.body, "//4=");
+    }
+}
@@ -0,0 +1,391 @@
+use std::io;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::future::{self, Loop};
+use futures::stream::{self, SplitSink, SplitStream};
+use futures::sync::oneshot;
+use futures::{Future, Sink, Stream};
+use http::header;
+use http::{Request, Response, StatusCode};
+use hyper::upgrade::Upgraded;
+use hyper::Body;
+use log::{info, warn};
+use tokio::codec::Framed;
+use tokio::timer::{Delay, Timeout};
+
+use crate::app::authz::Authz;
+use crate::app::config::{AuthnConfig, MultitenancyConfig};
+use crate::app::maintenance::MaintenanceMode;
+use crate::app::util::{self, AudienceEstimator, Subject};
+use crate::app::websocket::{self, Codec, Message};
+use crate::s3::Client;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// The connection is closed if the client doesn't respond to the closing frame in time.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The connection is closed if the client doesn't send the access token in time.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+type Socket = Framed<Upgraded, Codec>;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// The first message of the client.
+#[derive(Debug, Deserialize)]
+struct AuthMessage {
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommandMessage {
+    command: String,
+}
+
+#[derive(Debug, Serialize)]
+struct DoneMessage {
+    done: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorMessage {
+    error: String,
+}
+
+fn text<T: serde::Serialize>(value: &T) -> Message {
+    Message::Text(serde_json::to_string(value).expect("Error serializing a websocket message"))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Streams objects of buckets to WebSocket clients as they're listed, page by page,
+/// so that clients could process inventories of large buckets right away.
+pub(crate) struct InventoryStreams {
+    authz: Authz,
+    aud_estm: Arc<AudienceEstimator>,
+    s3: Arc<util::S3Clients>,
+    authn: AuthnConfig,
+    multitenancy: MultitenancyConfig,
+    page_size: i64,
+    maintenance: MaintenanceMode,
+}
+
+impl InventoryStreams {
+    pub(crate) fn new(
+        authz: Authz,
+        aud_estm: Arc<AudienceEstimator>,
+        s3: Arc<util::S3Clients>,
+        authn: AuthnConfig,
+        multitenancy: MultitenancyConfig,
+        page_size: i64,
+        maintenance: MaintenanceMode,
+    ) -> Self {
+        Self {
+            authz,
+            aud_estm,
+            s3,
+            authn,
+            multitenancy,
+            page_size: page_size.clamp(1, 1000),
+            maintenance,
+        }
+    }
+
+    /// Upgrades connections of requests to the stream endpoint, the rest of requests are left untouched.
+    pub(crate) fn upgrade(self: &Arc<Self>, req: &mut Request<Body>) -> Option<Response<Bytes>> {
+        let (back, bucket) = match stream_target(req.uri().path()) {
+            Some(target) if req.method() == http::Method::GET => target,
+            _ => return None,
+        };
+
+        if self.maintenance.is_enabled() {
+            return Some(rejection(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Service is in maintenance mode",
+            ));
+        }
+        if !websocket::is_upgrade(req.headers()) {
+            let mut resp = rejection(
+                StatusCode::UPGRADE_REQUIRED,
+                "WebSocket upgrade is required",
+            );
+            resp.headers_mut().insert(
+                header::UPGRADE,
+                header::HeaderValue::from_static("websocket"),
+            );
+            return Some(resp);
+        }
+        let resp = match websocket::handshake(req.headers()) {
+            Ok(resp) => resp,
+            Err(detail) => return Some(rejection(StatusCode::BAD_REQUEST, &detail)),
+        };
+
+        let streams = self.clone();
+        let stream = mem::replace(req.body_mut(), Body::empty())
+            .on_upgrade()
+            .map_err(|err| warn!("Error upgrading an inventory stream connection: {}", err))
+            .and_then(move |upgraded| streams.serve(back, bucket, upgraded));
+        tokio::spawn(stream);
+
+        Some(resp)
+    }
+
+    fn serve(
+        self: Arc<Self>,
+        back: String,
+        bucket: String,
+        upgraded: Upgraded,
+    ) -> impl Future<Item = (), Error = ()> + Send {
+        let (sink, source) = Framed::new(upgraded, Codec::default()).split();
+        let streams = self.clone();
+
+        Timeout::new(source.into_future().map_err(|(err, _)| err), AUTH_TIMEOUT)
+            .then(move |result| match result {
+                Ok((message, source)) => {
+                    future::Either::A(streams.authorize(&back, &bucket, message).then(
+                        move |result| match result {
+                            Ok((s3, sub)) => {
+                                info!(
+                                    "Inventory stream is started, bucket = '{}', subject = '{}'",
+                                    bucket, &*sub
+                                );
+                                future::Either::A(streams.stream(s3, bucket, sink, source))
+                            }
+                            Err(detail) => future::Either::B(close(
+                                sink,
+                                Some(text(&ErrorMessage { error: detail })),
+                                websocket::CLOSE_POLICY_VIOLATION,
+                            )),
+                        },
+                    ))
+                }
+                Err(ref err) if err.is_elapsed() => {
+                    let detail = "the access token isn't sent in time";
+                    future::Either::B(future::Either::A(close(
+                        sink,
+                        Some(text(&ErrorMessage {
+                            error: detail.to_owned(),
+                        })),
+                        websocket::CLOSE_POLICY_VIOLATION,
+                    )))
+                }
+                Err(err) => future::Either::B(future::Either::B(future::err(
+                    err.into_inner()
+                        .unwrap_or_else(|| io::Error::other("auth timer failed")),
+                ))),
+            })
+            .map_err(|err| warn!("Error streaming an inventory: {}", err))
+    }
+
+    /// The client is authenticated by the access token of its first message.
+    fn authorize(
+        &self,
+        back: &str,
+        bucket: &str,
+        message: Option<Message>,
+    ) -> Box<dyn Future<Item = (Arc<Client>, Subject), Error = String> + Send> {
+        let token = match message {
+            Some(Message::Text(text)) => serde_json::from_str::<AuthMessage>(&text).ok(),
+            _ => None,
+        };
+        let sub = match token {
+            Some(AuthMessage { token }) => {
+                match util::authenticate_token(&token, &self.authn, &self.multitenancy) {
+                    Ok(sub) => sub,
+                    Err(err) => return Box::new(future::err(err.to_string())),
+                }
+            }
+            None => {
+                let detail = "the first message must contain an access token";
+                return Box::new(future::err(detail.to_owned()));
+            }
+        };
+        let s3 = match self.s3.get(back) {
+            Some(val) => util::tenant_client(val, &sub),
+            None => return Box::new(future::err(format!("Backend '{}' is not found", back))),
+        };
+        let audience = match self.aud_estm.estimate(bucket) {
+            Ok(audience) => audience,
+            Err(err) => return Box::new(future::err(err.to_string())),
+        };
+
+        let zobj = vec!["buckets", bucket, "objects"];
+        Box::new(self.authz.authorize(audience, &sub, zobj, "list").then(
+            move |zresp| match zresp {
+                Ok(Ok(())) => Ok((s3, sub)),
+                Ok(Err(err)) => Err(err.to_string()),
+                Err(()) => Err(String::from("failed to authorize the intent")),
+            },
+        ))
+    }
+
+    /// Objects are sent until the listing is complete or the client sends the `stop` command.
+    fn stream(
+        &self,
+        s3: Arc<Client>,
+        bucket: String,
+        sink: SplitSink<Socket>,
+        source: SplitStream<Socket>,
+    ) -> impl Future<Item = (), Error = io::Error> + Send {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let (done_tx, done_rx) = oneshot::channel::<()>();
+        tokio::spawn(read_commands(source, stopped.clone(), done_rx));
+
+        let page_size = self.page_size;
+        let flag = stopped.clone();
+        future::loop_fn((sink, None), move |(sink, token)| {
+            let stopped = flag.clone();
+            s3.list_objects(&bucket, page_size, token)
+                .then(move |result| {
+                    let page = match result {
+                        Ok(page) => page,
+                        Err(err) => {
+                            let message = text(&ErrorMessage {
+                                error: format!("{:#}", err),
+                            });
+                            return future::Either::A(future::ok(Loop::Break((
+                                sink,
+                                Some(message),
+                                CLOSE_INTERNAL_ERROR,
+                            ))));
+                        }
+                    };
+
+                    let next = page.next_continuation_token;
+                    let sending = stopped.clone();
+                    let sent = stream::iter_ok::<_, io::Error>(page.objects)
+                        .take_while(move |_| Ok(!sending.load(Ordering::SeqCst)))
+                        .fold(sink, |sink, object| sink.send(text(&object)));
+                    future::Either::B(sent.map(move |sink| match next {
+                        _ if stopped.load(Ordering::SeqCst) => {
+                            Loop::Break((sink, None, websocket::CLOSE_NORMAL))
+                        }
+                        Some(token) => Loop::Continue((sink, Some(token))),
+                        None => Loop::Break((
+                            sink,
+                            Some(text(&DoneMessage { done: true })),
+                            websocket::CLOSE_NORMAL,
+                        )),
+                    }))
+                })
+        })
+        .and_then(|(sink, message, code)| close(sink, message, code))
+        .then(move |result| {
+            // The reader stops waiting for the closing frame of the client
+            drop(done_tx);
+            result
+        })
+    }
+}
+
+/// Sends the message, if any, followed by the closing frame.
+fn close(
+    sink: SplitSink<Socket>,
+    message: Option<Message>,
+    code: u16,
+) -> impl Future<Item = (), Error = io::Error> + Send {
+    let messages = message
+        .into_iter()
+        .chain(std::iter::once(Message::Close(Some(code))));
+    stream::iter_ok::<_, io::Error>(messages)
+        .fold(sink, |sink, message| sink.send(message))
+        .map(|_| ())
+}
+
+/// Reads commands of the client until it closes the connection, or the stream is complete
+/// and the client hasn't responded to the closing frame in time.
+fn read_commands(
+    source: SplitStream<Socket>,
+    stopped: Arc<AtomicBool>,
+    done: oneshot::Receiver<()>,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let flag = stopped.clone();
+    let commands = source
+        .take_while(|message| Ok(!matches!(message, Message::Close(_))))
+        .for_each(move |message| {
+            if let Message::Text(text) = message {
+                match serde_json::from_str::<CommandMessage>(&text) {
+                    Ok(ref cmd) if cmd.command == "stop" => flag.store(true, Ordering::SeqCst),
+                    _ => warn!("Unknown inventory stream command: '{}'", text),
+                }
+            }
+            Ok(())
+        });
+    let timeout = done.then(|_| Delay::new(Instant::now() + CLOSE_TIMEOUT));
+
+    commands.select2(timeout).then(move |_| {
+        // The client is gone, there is no one to send the rest of objects to
+        stopped.store(true, Ordering::SeqCst);
+        Ok(())
+    })
+}
+
+fn rejection(status: StatusCode, detail: &str) -> Response<Bytes> {
+    let body = serde_json::to_vec(&ErrorMessage {
+        error: detail.to_owned(),
+    })
+    .expect("Error serializing an inventory stream rejection");
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Bytes::from(body))
+        .expect("Error building an inventory stream rejection")
+}
+
+/// Returns the backend and the bucket of `/api/v1/buckets/:bucket/inventory/stream`
+/// and `/api/v1/backends/:back/buckets/:bucket/inventory/stream` paths.
+fn stream_target(path: &str) -> Option<(String, String)> {
+    let segments = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+    let (back, bucket) = match segments.as_slice() {
+        ["api", "v1", "buckets", bucket, "inventory", "stream"] => {
+            (util::S3_DEFAULT_CLIENT, *bucket)
+        }
+        ["api", "v1", "backends", back, "buckets", bucket, "inventory", "stream"] => {
+            (*back, *bucket)
+        }
+        _ => return None,
+    };
+    if back.is_empty() || bucket.is_empty() {
+        return None;
+    }
+
+    Some((back.to_owned(), bucket.to_owned()))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_targets() {
+        assert_eq!(
+            stream_target("/api/v1/buckets/data.example.org/inventory/stream"),
+            Some((
+                util::S3_DEFAULT_CLIENT.to_owned(),
+                "data.example.org".to_owned()
+            ))
+        );
+        assert_eq!(
+            stream_target("/api/v1/backends/alt/buckets/data.example.org/inventory/stream"),
+            Some(("alt".to_owned(), "data.example.org".to_owned()))
+        );
+        assert_eq!(
+            stream_target("/api/v1/buckets/data.example.org/inventory"),
+            None
+        );
+        assert_eq!(stream_target("/api/v1/buckets//inventory/stream"), None);
+        assert_eq!(
+            stream_target("/api/v2/buckets/data.example.org/inventory/stream"),
+            None
+        );
+    }
+}
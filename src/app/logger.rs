@@ -0,0 +1,137 @@
+use std::io::Write;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::kv::{self, Key, Value, VisitSource, VisitValue};
+use log::Record;
+use serde_json::{Map, Value as JsonValue};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Initializes the logger, records are filtered by `RUST_LOG` environment variable.
+pub(crate) fn init(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", json_record(record, Utc::now())));
+    }
+    builder.init();
+}
+
+/// Serializes the record into a JSON object, key-values of the record become its fields.
+fn json_record(record: &Record, timestamp: DateTime<Utc>) -> JsonValue {
+    let mut fields = Map::new();
+    fields.insert(
+        "timestamp".to_owned(),
+        timestamp
+            .to_rfc3339_opts(SecondsFormat::Millis, true)
+            .into(),
+    );
+    fields.insert("level".to_owned(), record.level().as_str().into());
+    fields.insert("message".to_owned(), record.args().to_string().into());
+    fields.insert(
+        "module".to_owned(),
+        record
+            .module_path()
+            .unwrap_or_else(|| record.target())
+            .into(),
+    );
+
+    let _ = record.key_values().visit(&mut FieldVisitor(&mut fields));
+    JsonValue::Object(fields)
+}
+
+struct FieldVisitor<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for FieldVisitor<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let mut json = JsonValue::Null;
+        value.visit(ValueVisitor(&mut json))?;
+        self.0.insert(key.as_str().to_owned(), json);
+        Ok(())
+    }
+}
+
+struct ValueVisitor<'a>(&'a mut JsonValue);
+
+impl<'v> VisitValue<'v> for ValueVisitor<'_> {
+    fn visit_any(&mut self, value: Value) -> Result<(), kv::Error> {
+        *self.0 = value.to_string().into();
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        *self.0 = JsonValue::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        *self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        *self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), kv::Error> {
+        *self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        *self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_str(&mut self, value: &str) -> Result<(), kv::Error> {
+        *self.0 = value.into();
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_record_fields() {
+        let kvs: &[(&str, Value)] = &[
+            ("request_id", Value::from("4a8e1d0c")),
+            ("bucket", Value::from("data.example.org")),
+            ("object", Value::null()),
+            ("duration_ms", Value::from(42u64)),
+        ];
+        let record = Record::builder()
+            .args(format_args!("GET data.example.org 303"))
+            .level(log::Level::Info)
+            .target("storage::access")
+            .module_path(Some("storage::app::audit"))
+            .key_values(&kvs)
+            .build();
+        let timestamp = "2020-01-01T10:00:00.123Z".parse().unwrap();
+
+        assert_eq!(
+            json_record(&record, timestamp),
+            serde_json::json!({
+                "timestamp": "2020-01-01T10:00:00.123Z",
+                "level": "INFO",
+                "message": "GET data.example.org 303",
+                "module": "storage::app::audit",
+                "request_id": "4a8e1d0c",
+                "bucket": "data.example.org",
+                "object": null,
+                "duration_ms": 42,
+            })
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use futures::{future, Future, Poll};
+use http::header;
+use http::{Request, Response, StatusCode};
+use log::warn;
+use tower_service::Service;
+use tower_web::middleware::Middleware;
+use tower_web::util::buf_stream::{size_hint, BufStream, SizeHint};
+use tower_web::util::http::HttpService;
+use tower_web::util::tuple::Either2;
+
+use crate::app::config::MaintenanceConfig;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Paths served in maintenance mode, the rest are rejected.
+const EXEMPT_PATHS: &[&str] = &["/healthz", "/readyz", "/api/v1/admin/maintenance-mode"];
+
+const MESSAGE: &str = r#"{"message":"Service is in maintenance mode"}"#;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Flag of the maintenance mode shared by the middleware and the admin endpoint.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MaintenanceMode {
+    enabled: Arc<AtomicBool>,
+}
+
+impl MaintenanceMode {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    /// Returns whether the state has changed.
+    pub(crate) fn set(&self, enabled: bool, subject: &str) -> bool {
+        let changed = self.enabled.swap(enabled, Ordering::SeqCst) != enabled;
+        if changed {
+            let state = if enabled { "entered" } else { "exited" };
+            warn!("Maintenance mode is {}, subject = '{}'", state, subject);
+        }
+
+        changed
+    }
+}
+
+/// Rejects requests to all paths but the exempt ones with `503 Service Unavailable`
+/// while the maintenance mode is enabled.
+pub(crate) struct MaintenanceMiddleware {
+    mode: MaintenanceMode,
+    retry_after: String,
+}
+
+impl MaintenanceMiddleware {
+    pub(crate) fn new(mode: MaintenanceMode, config: &MaintenanceConfig) -> Self {
+        Self {
+            mode,
+            retry_after: config.retry_after_secs.to_string(),
+        }
+    }
+}
+
+impl<S> Middleware<S> for MaintenanceMiddleware
+where
+    S: HttpService,
+{
+    type Request = Request<S::RequestBody>;
+    type Response = Response<MaintenanceBody<S::ResponseBody>>;
+    type Error = S::Error;
+    type Service = MaintenanceService<S>;
+
+    fn wrap(&self, inner: S) -> Self::Service {
+        MaintenanceService {
+            inner,
+            mode: self.mode.clone(),
+            retry_after: self.retry_after.clone(),
+        }
+    }
+}
+
+pub(crate) struct MaintenanceService<S> {
+    inner: S,
+    mode: MaintenanceMode,
+    retry_after: String,
+}
+
+impl<S> Service for MaintenanceService<S>
+where
+    S: HttpService,
+{
+    type Request = Request<S::RequestBody>;
+    type Response = Response<MaintenanceBody<S::ResponseBody>>;
+    type Error = S::Error;
+    type Future = future::Either<
+        future::Map<S::Future, fn(Response<S::ResponseBody>) -> Self::Response>,
+        future::FutureResult<Self::Response, Self::Error>,
+    >;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_http_ready()
+    }
+
+    fn call(&mut self, request: Self::Request) -> Self::Future {
+        if self.mode.is_enabled() && !EXEMPT_PATHS.contains(&request.uri().path()) {
+            let response = Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(header::RETRY_AFTER, self.retry_after.as_str())
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(MaintenanceBody::Message(MESSAGE))
+                .expect("Error building a maintenance mode response");
+            return future::Either::B(future::ok(response));
+        }
+
+        future::Either::A(
+            self.inner
+                .call_http(request)
+                .map(passthrough as fn(Response<S::ResponseBody>) -> Self::Response),
+        )
+    }
+}
+
+fn passthrough<B>(response: Response<B>) -> Response<MaintenanceBody<B>> {
+    response.map(MaintenanceBody::Inner)
+}
+
+/// Body of the inner service or the one of the rejection.
+pub(crate) enum MaintenanceBody<B> {
+    Inner(B),
+    Message(&'static str),
+}
+
+impl<B> BufStream for MaintenanceBody<B>
+where
+    B: BufStream,
+{
+    type Item = Either2<B::Item, io::Cursor<&'static [u8]>>;
+    type Error = B::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self {
+            MaintenanceBody::Inner(body) => body
+                .poll()
+                .map(|ready| ready.map(|item| item.map(Either2::A))),
+            MaintenanceBody::Message(message) => {
+                if message.is_empty() {
+                    return Ok(None.into());
+                }
+
+                let item = io::Cursor::new(std::mem::take(message).as_bytes());
+                Ok(Some(Either2::B(item)).into())
+            }
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self {
+            MaintenanceBody::Inner(body) => body.size_hint(),
+            MaintenanceBody::Message(message) => size_hint::Builder::new()
+                .available(message.len())
+                .lower(message.len())
+                .upper(message.len())
+                .build(),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tower_web::util::buf_stream::{self, Empty};
+
+    type Body = Empty<Option<[u8; 1]>, ()>;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = Request<Body>;
+        type Response = Response<Body>;
+        type Error = ();
+        type Future = future::FutureResult<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(futures::Async::Ready(()))
+        }
+
+        fn call(&mut self, _request: Self::Request) -> Self::Future {
+            future::ok(Response::new(buf_stream::empty()))
+        }
+    }
+
+    fn request(path: &str) -> Request<Body> {
+        Request::builder()
+            .uri(path)
+            .body(buf_stream::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn maintenance_mode_rejects_requests() {
+        let mode = MaintenanceMode::default();
+        let config = MaintenanceConfig {
+            retry_after_secs: 120,
+        };
+        let mut service = MaintenanceMiddleware::new(mode.clone(), &config).wrap(Echo);
+        let mut call = |path| service.call(request(path)).wait().unwrap();
+
+        assert_eq!(call("/api/v2/sign").status(), StatusCode::OK);
+
+        assert!(mode.set(true, "admin.usr.example.org"));
+        assert!(!mode.set(true, "admin.usr.example.org"));
+        let resp = call("/api/v2/sign");
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "120");
+        match resp.into_body() {
+            MaintenanceBody::Message(message) => assert_eq!(message, MESSAGE),
+            MaintenanceBody::Inner(_) => panic!("expected the maintenance mode message"),
+        }
+        assert_eq!(call("/healthz").status(), StatusCode::OK);
+        assert_eq!(call("/readyz").status(), StatusCode::OK);
+        assert_eq!(
+            call("/api/v1/admin/maintenance-mode").status(),
+            StatusCode::OK
+        );
+
+        assert!(mode.set(false, "admin.usr.example.org"));
+        assert_eq!(call("/api/v2/sign").status(), StatusCode::OK);
+    }
+}
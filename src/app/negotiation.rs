@@ -0,0 +1,652 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{format_err, Context};
+use bytes::Bytes;
+use futures::sync::{mpsc as async_mpsc, oneshot};
+use futures::{future, Future, Stream};
+use linked_hash_map::LinkedHashMap;
+use tokio::timer::Timeout;
+use wasmtime::{Instance, TypedFunc};
+
+use crate::app::config::{ContentNegotiationConfig, ContentTransformConfig};
+use crate::app::wasm::{Sandbox, SandboxLimits};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Converts objects read through the application to representations requested by `Accept` header,
+/// converted objects are cached for `cache_ttl_secs`.
+pub(crate) struct ContentNegotiation {
+    transforms: Vec<Arc<ContentTransform>>,
+    max_bytes: usize,
+    timeout: Duration,
+    cache: ConversionCache,
+}
+
+impl fmt::Debug for ContentNegotiation {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ContentNegotiation")
+            .field("transforms", &self.transforms)
+            .field("max_bytes", &self.max_bytes)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ContentTransform {
+    from_type: String,
+    to_type: String,
+    converter: Converter,
+}
+
+impl ContentTransform {
+    pub(crate) fn to_type(&self) -> &str {
+        &self.to_type
+    }
+}
+
+impl ContentNegotiation {
+    pub(crate) fn new(config: &ContentNegotiationConfig) -> anyhow::Result<Self> {
+        let timeout = Duration::from_secs(config.timeout_secs.max(1));
+        let limits = SandboxLimits {
+            fuel: config.wasm_fuel,
+            max_memory_bytes: config.wasm_max_memory_bytes,
+        };
+        let transforms = config
+            .transforms
+            .iter()
+            .map(|transform| {
+                Ok(Arc::new(ContentTransform {
+                    from_type: transform.from_type.trim().to_lowercase(),
+                    to_type: transform.to_type.trim().to_lowercase(),
+                    converter: Converter::new(transform, timeout, limits)?,
+                }))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            transforms,
+            max_bytes: config.max_bytes,
+            timeout,
+            cache: ConversionCache::new(
+                config.cache_capacity,
+                Duration::from_secs(config.cache_ttl_secs),
+            ),
+        })
+    }
+
+    pub(crate) fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// Whether the client could prefer a converted representation, so that the type of the object
+    /// has to be known before it's read. Only types listed in `Accept` header explicitly are converted to.
+    pub(crate) fn requested(&self, accept: &str) -> bool {
+        let ranges = media_ranges(accept);
+        self.transforms
+            .iter()
+            .any(|transform| explicit_quality(&ranges, &transform.to_type) > 0.0)
+    }
+
+    /// The transform of the object's type to the most preferred one, if it's preferred
+    /// over the object's type itself.
+    pub(crate) fn select(&self, accept: &str, content_type: &str) -> Option<Arc<ContentTransform>> {
+        let content_type = essence(content_type);
+        let ranges = media_ranges(accept);
+        let original = quality(&ranges, &content_type);
+
+        let mut selected: Option<(&Arc<ContentTransform>, f32)> = None;
+        for transform in self
+            .transforms
+            .iter()
+            .filter(|transform| transform.from_type == content_type)
+        {
+            let q = explicit_quality(&ranges, &transform.to_type);
+            if q > original && selected.is_none_or(|(_, best)| q > best) {
+                selected = Some((transform, q));
+            }
+        }
+
+        selected.map(|(transform, _)| transform.clone())
+    }
+
+    pub(crate) fn cached(&self, key: &str) -> Option<Bytes> {
+        self.cache.get(key)
+    }
+
+    /// Converts the content and caches the result by the key.
+    pub(crate) fn convert(
+        &self,
+        transform: &ContentTransform,
+        key: String,
+        content: Vec<u8>,
+    ) -> impl Future<Item = Bytes, Error = anyhow::Error> + Send {
+        let cache = self.cache.clone();
+        let to_type = transform.to_type.clone();
+        transform
+            .converter
+            .convert(content, self.timeout)
+            .map(move |converted| {
+                let converted = Bytes::from(converted);
+                cache.insert(key, converted.clone());
+                converted
+            })
+            .map_err(move |err| err.context(format!("failed to convert to '{}'", to_type)))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// `type/subtype` in lowercase, parameters are dropped.
+fn essence(media_type: &str) -> String {
+    media_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase()
+}
+
+/// Media ranges of `Accept` header along with their quality values.
+fn media_ranges(accept: &str) -> Vec<(String, f32)> {
+    accept
+        .split(',')
+        .filter_map(|value| {
+            let mut parts = value.split(';');
+            let range = essence(parts.next()?);
+            if range.is_empty() {
+                return None;
+            }
+
+            let q = parts
+                .filter_map(|param| {
+                    let mut kv = param.splitn(2, '=');
+                    match (kv.next()?.trim(), kv.next()) {
+                        ("q", Some(value)) | ("Q", Some(value)) => value.trim().parse::<f32>().ok(),
+                        _ => None,
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+            Some((range, q.clamp(0.0, 1.0)))
+        })
+        .collect()
+}
+
+/// Quality of the media type, the most specific of matching ranges takes precedence
+/// (RFC 7231, section 5.3.2).
+fn quality(ranges: &[(String, f32)], media_type: &str) -> f32 {
+    let major = media_type.split('/').next().unwrap_or_default();
+    ranges
+        .iter()
+        .filter_map(|(range, q)| {
+            let specificity = match range.as_str() {
+                "*/*" => 0,
+                range if range == media_type => 2,
+                range if range.strip_suffix("/*") == Some(major) => 1,
+                _ => return None,
+            };
+            Some((specificity, *q))
+        })
+        .max_by_key(|(specificity, _)| *specificity)
+        .map_or(0.0, |(_, q)| q)
+}
+
+fn explicit_quality(ranges: &[(String, f32)], media_type: &str) -> f32 {
+    ranges
+        .iter()
+        .find(|(range, _)| range == media_type)
+        .map_or(0.0, |(_, q)| *q)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Converted objects, the least recently used ones are evicted first.
+#[derive(Clone)]
+struct ConversionCache {
+    capacity: usize,
+    ttl: Duration,
+    inner: Arc<Mutex<LinkedHashMap<String, (Instant, Bytes)>>>,
+}
+
+impl ConversionCache {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            inner: Arc::new(Mutex::new(LinkedHashMap::new())),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<Bytes> {
+        let mut inner = self
+            .inner
+            .lock()
+            .expect("Conversion cache lock is poisoned");
+        match inner.get_refresh(key) {
+            Some((valid_until, content)) if Instant::now() < *valid_until => Some(content.clone()),
+            Some(_) => {
+                inner.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, key: String, content: Bytes) {
+        if self.capacity == 0 || self.ttl == Duration::from_secs(0) {
+            return;
+        }
+
+        let mut inner = self
+            .inner
+            .lock()
+            .expect("Conversion cache lock is poisoned");
+        inner.insert(key, (Instant::now() + self.ttl, content));
+        while inner.len() > self.capacity {
+            inner.pop_front();
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// An executable reading the object from stdin and writing the converted one to stdout,
+/// or a WebAssembly module if the path ends with `.wasm`.
+#[derive(Debug)]
+enum Converter {
+    Command { program: PathBuf, args: Vec<String> },
+    Wasm(WasmConverter),
+}
+
+impl Converter {
+    fn new(
+        config: &ContentTransformConfig,
+        timeout: Duration,
+        limits: SandboxLimits,
+    ) -> anyhow::Result<Self> {
+        let is_wasm = config
+            .converter
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("wasm"));
+        if is_wasm {
+            return Ok(Converter::Wasm(WasmConverter::load(
+                &config.converter,
+                timeout,
+                limits,
+            )?));
+        }
+
+        Ok(Converter::Command {
+            program: config.converter.clone(),
+            args: config.args.clone(),
+        })
+    }
+
+    fn convert(
+        &self,
+        content: Vec<u8>,
+        timeout: Duration,
+    ) -> Box<dyn Future<Item = Vec<u8>, Error = anyhow::Error> + Send> {
+        match self {
+            Converter::Command { program, args } => {
+                let (tx, rx) = oneshot::channel();
+                let (program, args) = (program.clone(), args.clone());
+                // The process is waited for on a dedicated thread rather than blocking the reactor
+                std::thread::spawn(move || {
+                    let _ = tx.send(run_command(&program, &args, content, timeout));
+                });
+                Box::new(
+                    rx.map_err(|_| format_err!("converter thread has stopped"))
+                        .and_then(|result| result),
+                )
+            }
+            Converter::Wasm(converter) => Box::new(converter.convert(content)),
+        }
+    }
+}
+
+/// Runs the command feeding the content to its stdin, the process is killed on timeout.
+fn run_command(
+    program: &Path,
+    args: &[String],
+    content: Vec<u8>,
+    timeout: Duration,
+) -> anyhow::Result<Vec<u8>> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("failed to run '{}'", program.display()))?;
+
+    // Stdin and stdout are served by their own threads, so that neither of the pipes fills up
+    let mut stdin = child
+        .stdin
+        .take()
+        .context("stdin of the converter is missing")?;
+    std::thread::spawn(move || {
+        // The converter might exit without reading the whole input, its status tells the outcome
+        let _ = stdin.write_all(&content);
+    });
+    let mut stdout = child
+        .stdout
+        .take()
+        .context("stdout of the converter is missing")?;
+    let reader = std::thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).map(|_| output)
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child
+            .try_wait()
+            .context("failed to wait for the converter")?
+        {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format_err!("converter has timed out after {:?}", timeout));
+        }
+        std::thread::sleep(COMMAND_POLL_INTERVAL);
+    };
+    if !status.success() {
+        return Err(format_err!("converter has failed, {}", status));
+    }
+
+    reader
+        .join()
+        .map_err(|_| format_err!("converter output thread has panicked"))?
+        .context("failed to read output of the converter")
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+type Conversion = (Vec<u8>, oneshot::Sender<anyhow::Result<Vec<u8>>>);
+
+/// Converter compiled to WebAssembly, run in a sandbox limiting each conversion.
+///
+/// Besides `memory` and `alloc` exports of the sandbox, the module must export
+/// `convert(ptr: i32, len: i32) -> i64` returning the pointer to the converted content
+/// in the upper 32 bits and its length in the lower ones, or a negative value on failure.
+///
+/// The module is instantiated and run on a dedicated thread, contents are converted in order.
+/// Conversions fail once they run out of fuel, as well as unless they're done in time.
+pub(crate) struct WasmConverter {
+    path: PathBuf,
+    timeout: Duration,
+    tx: async_mpsc::UnboundedSender<Conversion>,
+}
+
+impl fmt::Debug for WasmConverter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("WasmConverter")
+            .field("path", &self.path)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl WasmConverter {
+    fn load(path: &Path, timeout: Duration, limits: SandboxLimits) -> anyhow::Result<Self> {
+        let (tx, rx) = async_mpsc::unbounded::<Conversion>();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let path = path.to_owned();
+        let thread_path = path.clone();
+
+        std::thread::Builder::new()
+            .name("wasm-converter".to_owned())
+            .spawn(move || {
+                let converter =
+                    match Sandbox::load("wasm converter", &thread_path, limits, convert_export) {
+                        Ok(val) => {
+                            let _ = ready_tx.send(Ok(()));
+                            val
+                        }
+                        Err(err) => {
+                            let _ = ready_tx.send(Err(err));
+                            return;
+                        }
+                    };
+
+                for (content, tx) in rx.wait().flatten() {
+                    // The conversion has timed out while it's been queued
+                    if tx.is_canceled() {
+                        continue;
+                    }
+                    let _ = tx.send(convert(&converter, &content));
+                }
+            })
+            .context("failed to spawn a wasm converter thread")?;
+
+        ready_rx
+            .recv()
+            .context("wasm converter thread has stopped")??;
+
+        Ok(Self { path, timeout, tx })
+    }
+
+    fn convert(&self, content: Vec<u8>) -> impl Future<Item = Vec<u8>, Error = anyhow::Error> {
+        let (tx, rx) = oneshot::channel();
+        if self.tx.unbounded_send((content, tx)).is_err() {
+            return future::Either::A(future::err(format_err!(
+                "wasm converter thread has stopped"
+            )));
+        }
+
+        let timeout = self.timeout;
+        future::Either::B(Timeout::new(rx, timeout).then(move |result| match result {
+            Ok(result) => result,
+            Err(ref err) if err.is_elapsed() => Err(format_err!(
+                "wasm converter has timed out after {:?}",
+                timeout
+            )),
+            Err(ref err) if err.is_inner() => Err(format_err!("wasm converter thread has stopped")),
+            Err(_) => Err(format_err!("wasm converter timer has failed")),
+        }))
+    }
+}
+
+type ConvertFunc = TypedFunc<(i32, i32), i64>;
+
+fn convert_export(instance: &Instance) -> anyhow::Result<ConvertFunc> {
+    instance
+        .get_typed_func("convert")
+        .context("invalid 'convert' export of wasm converter")
+}
+
+fn convert(converter: &Sandbox<ConvertFunc>, content: &[u8]) -> anyhow::Result<Vec<u8>> {
+    converter.call(|instance| {
+        let (ptr, len) = instance.write(content)?;
+        let result = instance.exports().call((ptr, len))?;
+        if result < 0 {
+            return Err(format_err!("wasm converter has failed, code = {}", result));
+        }
+
+        let (ptr, len) = ((result >> 32) as usize, (result & 0xffff_ffff) as usize);
+        instance.read(ptr, len)
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::wasm;
+    use wasmtime::Module;
+
+    fn negotiation(transforms: &[(&str, &str)]) -> ContentNegotiation {
+        let config = ContentNegotiationConfig {
+            transforms: transforms
+                .iter()
+                .map(|(from_type, to_type)| ContentTransformConfig {
+                    from_type: from_type.to_string(),
+                    to_type: to_type.to_string(),
+                    converter: PathBuf::from("tr"),
+                    args: vec!["a-z".to_owned(), "A-Z".to_owned()],
+                })
+                .collect(),
+            ..Default::default()
+        };
+        ContentNegotiation::new(&config).unwrap()
+    }
+
+    #[test]
+    fn select_transforms() {
+        let negotiation = negotiation(&[
+            ("image/jpeg", "image/avif"),
+            ("image/jpeg", "image/webp"),
+            ("image/png", "image/webp"),
+        ]);
+        let selected = |accept: &str, content_type: &str| {
+            negotiation
+                .select(accept, content_type)
+                .map(|transform| transform.to_type().to_owned())
+        };
+
+        // A typical header of browsers
+        let accept = "image/avif;q=0.9,image/webp,image/apng,*/*;q=0.8";
+        assert!(negotiation.requested(accept));
+        assert_eq!(
+            selected(accept, "image/jpeg"),
+            Some("image/webp".to_owned())
+        );
+        assert_eq!(
+            selected(accept, "Image/PNG; charset=binary"),
+            Some("image/webp".to_owned())
+        );
+        assert_eq!(selected(accept, "image/gif"), None);
+
+        // The object's type is preferred on ties
+        assert_eq!(selected("image/webp, image/jpeg", "image/jpeg"), None);
+        assert_eq!(selected("image/webp, image/*", "image/jpeg"), None);
+        assert_eq!(
+            selected("image/webp, image/*;q=0.5", "image/jpeg"),
+            Some("image/webp".to_owned())
+        );
+        assert_eq!(selected("image/webp;q=0, */*", "image/jpeg"), None);
+
+        // Types are converted to only if they're listed explicitly
+        assert!(!negotiation.requested("*/*"));
+        assert!(!negotiation.requested("image/*"));
+        assert_eq!(selected("image/*, image/jpeg;q=0.1", "image/jpeg"), None);
+    }
+
+    #[test]
+    fn convert_by_command() {
+        let negotiation = negotiation(&[("text/plain", "text/x-upper")]);
+        let transform = negotiation.select("text/x-upper", "text/plain").unwrap();
+
+        let converted = negotiation
+            .convert(&transform, "key".to_owned(), b"hello".to_vec())
+            .wait()
+            .unwrap();
+        assert_eq!(&converted[..], b"HELLO");
+        assert_eq!(negotiation.cached("key"), Some(Bytes::from("HELLO")));
+        assert_eq!(negotiation.cached("other"), None);
+
+        let failed = run_command(
+            Path::new("false"),
+            &[],
+            b"hello".to_vec(),
+            Duration::from_secs(5),
+        );
+        assert!(failed.is_err());
+        let timed_out = run_command(
+            Path::new("sleep"),
+            &["5".to_owned()],
+            Vec::new(),
+            Duration::from_millis(50),
+        );
+        assert!(timed_out.is_err());
+    }
+
+    // Reverses the content in place
+    const CONVERTER: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (func (export "alloc") (param $len i32) (result i32)
+                (i32.const 0))
+            (func (export "convert") (param $ptr i32) (param $len i32) (result i64)
+                (local $i i32)
+                (local $j i32)
+                (local $b i32)
+                (local.set $i (local.get $ptr))
+                (local.set $j (i32.sub (i32.add (local.get $ptr) (local.get $len)) (i32.const 1)))
+                (block $done
+                    (loop $swap
+                        (br_if $done (i32.ge_s (local.get $i) (local.get $j)))
+                        (local.set $b (i32.load8_u (local.get $i)))
+                        (i32.store8 (local.get $i) (i32.load8_u (local.get $j)))
+                        (i32.store8 (local.get $j) (local.get $b))
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (local.set $j (i32.sub (local.get $j) (i32.const 1)))
+                        (br $swap)))
+                (i64.or
+                    (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+                    (i64.extend_i32_u (local.get $len)))))
+    "#;
+
+    const LIMITS: SandboxLimits = SandboxLimits {
+        fuel: 1_000_000,
+        max_memory_bytes: 16 * 1024 * 1024,
+    };
+
+    fn sandboxed(wat: &str) -> anyhow::Result<Sandbox<ConvertFunc>> {
+        let module = Module::new(&wasm::engine().unwrap(), wat).unwrap();
+        Sandbox::new(
+            "wasm converter",
+            module,
+            Path::new("converter.wasm"),
+            LIMITS,
+            convert_export,
+        )
+    }
+
+    #[test]
+    fn wasm_converter_instance() {
+        let converter = sandboxed(CONVERTER).unwrap();
+        assert_eq!(convert(&converter, b"hello").unwrap(), b"olleh");
+        assert_eq!(convert(&converter, b"").unwrap(), b"");
+
+        assert!(sandboxed(r#"(module (memory (export "memory") 1))"#).is_err());
+    }
+
+    #[test]
+    fn wasm_converter_limits() {
+        // Converters running out of fuel fail, the trapped instance isn't run again
+        let converter = sandboxed(
+            r#"
+            (module
+                (memory (export "memory") 1)
+                (global $trapped (mut i32) (i32.const 0))
+                (func (export "alloc") (param $len i32) (result i32)
+                    (i32.const 0))
+                (func (export "convert") (param $ptr i32) (param $len i32) (result i64)
+                    (if (global.get $trapped) (then (return (i64.const -1))))
+                    (if (local.get $len)
+                        (then
+                            (global.set $trapped (i32.const 1))
+                            (loop $forever (br $forever))))
+                    (i64.const 0)))
+            "#,
+        )
+        .unwrap();
+        assert!(convert(&converter, b"loop").is_err());
+        assert_eq!(convert(&converter, b"").unwrap(), b"");
+    }
+}
@@ -0,0 +1,246 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use futures::{future, Future};
+use http::StatusCode;
+
+use crate::app::config::OidcConfig;
+
+////////////////////////////////////////////////////////////////////////////////
+
+const DISCOVERY_PATH: &str = "/.well-known/openid-configuration";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MAX_REFRESH_TOKEN_LEN: usize = 4096;
+
+/// Tokens issued by the identity provider in exchange for the refresh token.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct TokenSet {
+    pub(crate) access_token: String,
+    pub(crate) token_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) expires_in: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) refresh_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscoveryDocument {
+    token_endpoint: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) enum RefreshError {
+    /// The refresh token is malformed, or the identity provider has rejected it.
+    Rejected(String),
+    Unavailable(anyhow::Error),
+}
+
+impl RefreshError {
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            RefreshError::Rejected(_) => StatusCode::UNAUTHORIZED,
+            RefreshError::Unavailable(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    pub(crate) fn detail(&self) -> String {
+        match self {
+            RefreshError::Rejected(detail) => detail.to_owned(),
+            RefreshError::Unavailable(err) => format!("{:#}", err),
+        }
+    }
+}
+
+/// Exchanges refresh tokens for access tokens at the token endpoint of the OIDC identity provider,
+/// the endpoint is discovered by the issuer URL unless it's configured.
+#[derive(Clone)]
+pub(crate) struct OidcClient {
+    config: Arc<OidcConfig>,
+    http: reqwest::r#async::Client,
+    token_endpoint: Arc<Mutex<Option<String>>>,
+}
+
+impl std::fmt::Debug for OidcClient {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("OidcClient")
+            .field("issuer", &self.config.issuer)
+            .finish()
+    }
+}
+
+impl OidcClient {
+    pub(crate) fn new(config: OidcConfig) -> anyhow::Result<Self> {
+        let http = reqwest::r#async::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .context("failed to create an HTTP client")?;
+        let token_endpoint = config.token_endpoint.clone();
+
+        Ok(Self {
+            config: Arc::new(config),
+            http,
+            token_endpoint: Arc::new(Mutex::new(token_endpoint)),
+        })
+    }
+
+    pub(crate) fn refresh(
+        &self,
+        refresh_token: &str,
+    ) -> impl Future<Item = TokenSet, Error = RefreshError> + Send {
+        if let Err(detail) = validate_refresh_token(refresh_token) {
+            return future::Either::A(future::err(RefreshError::Rejected(detail.to_owned())));
+        }
+
+        let http = self.http.clone();
+        let config = self.config.clone();
+        let refresh_token = refresh_token.to_owned();
+        future::Either::B(
+            self.token_endpoint()
+                .map_err(RefreshError::Unavailable)
+                .and_then(move |token_endpoint| {
+                    let mut params = vec![
+                        ("grant_type", "refresh_token"),
+                        ("refresh_token", refresh_token.as_str()),
+                        ("client_id", config.client_id.as_str()),
+                    ];
+                    if let Some(ref scope) = config.scope {
+                        params.push(("scope", scope.as_str()));
+                    }
+                    let mut req = http.post(&token_endpoint).form(&params);
+                    if let Some(ref secret) = config.client_secret {
+                        req = req.basic_auth(&config.client_id, Some(secret));
+                    }
+
+                    req.send()
+                        .map_err(|err| {
+                            RefreshError::Unavailable(
+                                anyhow::Error::from(err)
+                                    .context("failed to call the token endpoint"),
+                            )
+                        })
+                        .and_then(parse_token_response)
+                }),
+        )
+    }
+
+    fn token_endpoint(&self) -> impl Future<Item = String, Error = anyhow::Error> + Send {
+        let cached = self
+            .token_endpoint
+            .lock()
+            .expect("OIDC token endpoint lock is poisoned")
+            .clone();
+        if let Some(token_endpoint) = cached {
+            return future::Either::A(future::ok(token_endpoint));
+        }
+
+        let url = format!(
+            "{}{}",
+            self.config.issuer.trim_end_matches('/'),
+            DISCOVERY_PATH
+        );
+        let cache = self.token_endpoint.clone();
+        future::Either::B(
+            self.http
+                .get(&url)
+                .send()
+                .and_then(|resp| resp.error_for_status())
+                .and_then(|mut resp| resp.json::<DiscoveryDocument>())
+                .map(move |doc| {
+                    *cache.lock().expect("OIDC token endpoint lock is poisoned") =
+                        Some(doc.token_endpoint.clone());
+                    doc.token_endpoint
+                })
+                .map_err(|err| {
+                    anyhow::Error::from(err).context("failed to discover the token endpoint")
+                }),
+        )
+    }
+}
+
+fn parse_token_response(
+    mut resp: reqwest::r#async::Response,
+) -> Box<dyn Future<Item = TokenSet, Error = RefreshError> + Send> {
+    let status = resp.status();
+    if status.is_success() {
+        return Box::new(resp.json::<TokenSet>().map_err(|err| {
+            RefreshError::Unavailable(
+                anyhow::Error::from(err).context("invalid response of the token endpoint"),
+            )
+        }));
+    }
+
+    // Invalid or expired refresh tokens are rejected with `invalid_grant` error
+    if status == StatusCode::BAD_REQUEST || status == StatusCode::UNAUTHORIZED {
+        return Box::new(resp.json::<ErrorResponse>().then(move |result| {
+            Err(match result {
+                Ok(err) => RefreshError::Rejected(match err.error_description {
+                    Some(description) => format!("{}: {}", err.error, description),
+                    None => err.error,
+                }),
+                Err(_) => RefreshError::Unavailable(anyhow::format_err!(
+                    "token endpoint responded with status = {}",
+                    status
+                )),
+            })
+        }));
+    }
+
+    Box::new(future::err(RefreshError::Unavailable(anyhow::format_err!(
+        "token endpoint responded with status = {}",
+        status
+    ))))
+}
+
+/// Rejects refresh tokens which can't be valid before calling the identity provider.
+fn validate_refresh_token(token: &str) -> Result<(), &'static str> {
+    if token.is_empty() {
+        return Err("missing refresh token");
+    }
+    if token.len() > MAX_REFRESH_TOKEN_LEN {
+        return Err("refresh token is too long");
+    }
+    if !token.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err("refresh token contains invalid characters");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refresh_token_validation() {
+        assert_eq!(validate_refresh_token("8xLOxBtZp8"), Ok(()));
+        assert_eq!(
+            validate_refresh_token("eyJhbGciOiJIUzI1NiJ9.eyJzdWIiOiIxIn0.c2ln"),
+            Ok(())
+        );
+        assert!(validate_refresh_token("").is_err());
+        assert!(validate_refresh_token("8xLO xBtZp8").is_err());
+        assert!(validate_refresh_token("8xLOxBtZp8\n").is_err());
+        assert!(validate_refresh_token(&"a".repeat(MAX_REFRESH_TOKEN_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn token_set_serialization() {
+        let tokens = serde_json::from_str::<TokenSet>(
+            r#"{"access_token": "abc", "token_type": "Bearer", "expires_in": 300, "id_token": "xyz"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(&tokens).unwrap(),
+            serde_json::json!({"access_token": "abc", "token_type": "Bearer", "expires_in": 300})
+        );
+    }
+}
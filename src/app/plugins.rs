@@ -0,0 +1,1054 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{format_err, Context};
+use bytes::Bytes;
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll};
+use http::header;
+use http::{Request, Response, StatusCode};
+use log::{error, warn};
+use serde_derive::Serialize;
+use svc_authn::AccountId;
+use tokio::timer::Delay;
+use tower_service::Service;
+use tower_web::middleware::Middleware;
+use tower_web::util::buf_stream::{size_hint, BufStream, SizeHint};
+use tower_web::util::http::HttpService;
+use tower_web::util::tuple::Either2;
+use wasmtime::{Instance, Module, TypedFunc};
+
+use crate::app::api_keys::ApiKeyStore;
+use crate::app::config::{AuthnConfig, MultitenancyConfig, PluginConfig, PluginLimitsConfig};
+use crate::app::util;
+use crate::app::wasm::{self, Sandbox, SandboxInstance, SandboxLimits};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Credentials of the subject aren't passed to plugins, the subject itself is.
+const REDACTED_HEADERS: &[&str] = &["authorization", "cookie", "x-api-key"];
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Metadata of the request passed to plugins.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct RequestContext {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) query: Option<String>,
+    pub(crate) headers: BTreeMap<String, String>,
+    /// Account id of the authenticated subject, `None` for anonymous requests.
+    pub(crate) subject: Option<String>,
+    pub(crate) authn_method: Option<String>,
+}
+
+impl RequestContext {
+    fn new<B>(request: &Request<B>, subject: Option<&util::Subject>) -> Self {
+        let headers = request
+            .headers()
+            .keys()
+            .filter(|key| !REDACTED_HEADERS.contains(&key.as_str()))
+            .map(|key| {
+                (
+                    key.as_str().to_owned(),
+                    joined_values(request.headers(), key),
+                )
+            })
+            .collect();
+
+        Self {
+            method: request.method().to_string(),
+            path: request.uri().path().to_owned(),
+            query: request.uri().query().map(ToOwned::to_owned),
+            headers,
+            subject: subject.map(|sub| (**sub).to_string()),
+            authn_method: subject.map(|sub| sub.authn_method().to_string()),
+        }
+    }
+}
+
+/// Metadata of the response passed to plugins once the request is handled.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ResponseContext {
+    pub(crate) status: u16,
+    pub(crate) headers: BTreeMap<String, String>,
+}
+
+impl ResponseContext {
+    fn new<B>(response: &Response<B>) -> Self {
+        let headers = response
+            .headers()
+            .keys()
+            .map(|key| {
+                (
+                    key.as_str().to_owned(),
+                    joined_values(response.headers(), key),
+                )
+            })
+            .collect();
+
+        Self {
+            status: response.status().as_u16(),
+            headers,
+        }
+    }
+}
+
+fn joined_values(headers: &http::HeaderMap, key: &header::HeaderName) -> String {
+    headers
+        .get_all(key)
+        .iter()
+        .filter_map(|val| val.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Failure of a plugin, the request is aborted with the status code.
+#[derive(Debug)]
+pub(crate) struct PluginError {
+    status: StatusCode,
+    detail: String,
+}
+
+impl PluginError {
+    pub(crate) fn new(status: StatusCode, detail: &str) -> Self {
+        Self {
+            status,
+            detail: detail.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}, status = {}", self.detail, self.status)
+    }
+}
+
+/// Custom logic of the deployment run before and after each request.
+pub(crate) trait Plugin {
+    fn pre_request(&self, req: &RequestContext) -> Result<(), PluginError>;
+
+    fn post_response(
+        &self,
+        req: &RequestContext,
+        resp: &ResponseContext,
+    ) -> Result<(), PluginError>;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+type HookSender = oneshot::Sender<Result<(), PluginError>>;
+
+enum Command {
+    PreRequest {
+        req: Arc<RequestContext>,
+        tx: HookSender,
+    },
+    PostResponse {
+        req: Arc<RequestContext>,
+        resp: ResponseContext,
+        tx: HookSender,
+    },
+}
+
+impl Command {
+    fn reply(self, result: Result<(), PluginError>) {
+        let tx = match self {
+            Command::PreRequest { tx, .. } | Command::PostResponse { tx, .. } => tx,
+        };
+        let _ = tx.send(result);
+    }
+
+    fn run(self, plugins: &[Box<dyn Plugin>]) {
+        match self {
+            // The request has timed out or gone while the hook has been queued
+            Command::PreRequest { ref tx, .. } | Command::PostResponse { ref tx, .. }
+                if tx.is_canceled() => {}
+            Command::PreRequest { req, tx } => {
+                let result = plugins
+                    .iter()
+                    .try_for_each(|plugin| plugin.pre_request(&req));
+                let _ = tx.send(result);
+            }
+            Command::PostResponse { req, resp, tx } => {
+                let result = plugins
+                    .iter()
+                    .try_for_each(|plugin| plugin.post_response(&req, &resp));
+                let _ = tx.send(result);
+            }
+        }
+    }
+}
+
+/// Outcome of hooks of the request, they fail once the timeout has elapsed.
+struct HookResult {
+    rx: oneshot::Receiver<Result<(), PluginError>>,
+    deadline: Delay,
+}
+
+impl HookResult {
+    fn poll(&mut self) -> Async<Result<(), PluginError>> {
+        match self.rx.poll() {
+            Ok(Async::Ready(result)) => return Async::Ready(result),
+            Ok(Async::NotReady) => (),
+            Err(_) => {
+                return Async::Ready(Err(PluginError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "plugins thread has stopped",
+                )))
+            }
+        }
+
+        match self.deadline.poll() {
+            Ok(Async::NotReady) => Async::NotReady,
+            // Failures of the timer time hooks out as well
+            Ok(Async::Ready(())) | Err(_) => Async::Ready(Err(PluginError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "plugins have timed out",
+            ))),
+        }
+    }
+}
+
+/// Plugins of `[[plugins]]` sections run in order, the first one failing aborts the request.
+///
+/// Plugins are run by a pool of worker threads, each of them with its own instances.
+/// Hooks wait for a worker in the bounded queue, they fail once it's full or their
+/// timeout has elapsed.
+#[derive(Clone, Default)]
+pub(crate) struct Plugins {
+    tx: Option<mpsc::SyncSender<Command>>,
+    timeout: Duration,
+}
+
+impl fmt::Debug for Plugins {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Plugins")
+            .field("enabled", &self.tx.is_some())
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl Plugins {
+    pub(crate) fn load(
+        config: &[PluginConfig],
+        limits: &PluginLimitsConfig,
+    ) -> anyhow::Result<Self> {
+        if config.is_empty() {
+            return Ok(Self::default());
+        }
+
+        // Modules are compiled once, each of the workers instantiates them on its own
+        let engine = wasm::engine()?;
+        let modules = config
+            .iter()
+            .map(|plugin| {
+                Module::from_file(&engine, &plugin.path)
+                    .with_context(|| {
+                        format!(
+                            "failed to load a wasm plugin, path = '{}'",
+                            plugin.path.display()
+                        )
+                    })
+                    .map(|module| (plugin.path.clone(), module))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let wasm_limits = SandboxLimits {
+            fuel: limits.fuel,
+            max_memory_bytes: limits.max_memory_bytes,
+        };
+        Self::spawn(limits, move || {
+            modules
+                .iter()
+                .map(|(path, module)| {
+                    WasmPlugin::new(module.clone(), path, wasm_limits)
+                        .map(|plugin| Box::new(plugin) as Box<dyn Plugin>)
+                })
+                .collect()
+        })
+    }
+
+    fn spawn<F>(limits: &PluginLimitsConfig, load: F) -> anyhow::Result<Self>
+    where
+        F: Fn() -> anyhow::Result<Vec<Box<dyn Plugin>>> + Send + Sync + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel::<Command>(limits.queue_capacity);
+        let rx = Arc::new(Mutex::new(rx));
+        let load = Arc::new(load);
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let workers = limits.workers.max(1);
+
+        for idx in 0..workers {
+            let rx = rx.clone();
+            let load = load.clone();
+            let ready_tx = ready_tx.clone();
+            std::thread::Builder::new()
+                .name(format!("plugins-{}", idx))
+                .spawn(move || {
+                    let plugins = match load() {
+                        Ok(val) => {
+                            let _ = ready_tx.send(Ok(()));
+                            val
+                        }
+                        Err(err) => {
+                            let _ = ready_tx.send(Err(err));
+                            return;
+                        }
+                    };
+
+                    loop {
+                        // The queue is unlocked once the command is received
+                        let command = rx.lock().expect("Plugins queue lock is poisoned").recv();
+                        match command {
+                            Ok(command) => command.run(&plugins),
+                            Err(_) => break,
+                        }
+                    }
+                })
+                .context("failed to spawn a plugins thread")?;
+        }
+
+        for _ in 0..workers {
+            ready_rx.recv().context("plugins thread has stopped")??;
+        }
+
+        Ok(Self {
+            tx: Some(tx),
+            timeout: Duration::from_millis(limits.timeout_ms),
+        })
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.tx.is_some()
+    }
+
+    fn send<F>(&self, command: F) -> HookResult
+    where
+        F: FnOnce(HookSender) -> Command,
+    {
+        let (tx, rx) = oneshot::channel();
+        let command = command(tx);
+        let result = match self.tx {
+            Some(ref tx) => tx.try_send(command),
+            None => {
+                command.reply(Ok(()));
+                Ok(())
+            }
+        };
+        match result {
+            Ok(()) => (),
+            Err(mpsc::TrySendError::Full(command)) => {
+                warn!("Plugins are overloaded, the request is rejected");
+                command.reply(Err(PluginError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "plugins are overloaded",
+                )))
+            }
+            Err(mpsc::TrySendError::Disconnected(command)) => {
+                error!("Error running plugins: the plugins thread has stopped");
+                command.reply(Err(PluginError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "plugins thread has stopped",
+                )))
+            }
+        }
+
+        HookResult {
+            rx,
+            deadline: Delay::new(Instant::now() + self.timeout),
+        }
+    }
+
+    fn pre_request(&self, req: Arc<RequestContext>) -> HookResult {
+        self.send(|tx| Command::PreRequest { req, tx })
+    }
+
+    fn post_response(&self, req: Arc<RequestContext>, resp: ResponseContext) -> HookResult {
+        self.send(|tx| Command::PostResponse { req, resp, tx })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Plugin compiled to WebAssembly, run in a sandbox limiting each call of its hooks.
+///
+/// Besides `memory` and `alloc` exports of the sandbox, the module must export at least one
+/// of the hooks: `pre_request(req_ptr: i32, req_len: i32) -> i32` and
+/// `post_response(req_ptr: i32, req_len: i32, resp_ptr: i32, resp_len: i32) -> i32`.
+/// Contexts are UTF-8 encoded JSON documents. Hooks return 0 to go on, or a status code
+/// from 400 to 599 to abort the request with.
+struct WasmPlugin {
+    sandbox: Sandbox<PluginHooks>,
+}
+
+struct PluginHooks {
+    pre_request: Option<TypedFunc<(i32, i32), i32>>,
+    post_response: Option<TypedFunc<(i32, i32, i32, i32), i32>>,
+}
+
+impl PluginHooks {
+    fn new(instance: &Instance) -> anyhow::Result<Self> {
+        let pre_request = match instance.get_func("pre_request") {
+            Some(func) => Some(
+                func.typed()
+                    .context("invalid 'pre_request' export of wasm plugin")?
+                    .clone(),
+            ),
+            None => None,
+        };
+        let post_response = match instance.get_func("post_response") {
+            Some(func) => Some(
+                func.typed()
+                    .context("invalid 'post_response' export of wasm plugin")?
+                    .clone(),
+            ),
+            None => None,
+        };
+        if pre_request.is_none() && post_response.is_none() {
+            return Err(format_err!(
+                "wasm plugin exports neither 'pre_request' nor 'post_response'"
+            ));
+        }
+
+        Ok(Self {
+            pre_request,
+            post_response,
+        })
+    }
+}
+
+impl WasmPlugin {
+    fn new(module: Module, path: &Path, limits: SandboxLimits) -> anyhow::Result<Self> {
+        let sandbox = Sandbox::new("wasm plugin", module, path, limits, PluginHooks::new)?;
+        Ok(Self { sandbox })
+    }
+
+    /// Hooks the plugin doesn't export go on.
+    fn call<F>(&self, hook: &str, call: F) -> Result<(), PluginError>
+    where
+        F: FnOnce(&SandboxInstance<PluginHooks>) -> anyhow::Result<Option<i32>>,
+    {
+        match self.sandbox.call(call) {
+            Ok(Some(code)) => self.outcome(hook, code),
+            Ok(None) => Ok(()),
+            Err(err) => Err(self.failure(hook, err)),
+        }
+    }
+
+    /// Failures of the plugin abort the request as well.
+    fn outcome(&self, hook: &str, code: i32) -> Result<(), PluginError> {
+        match code {
+            0 => Ok(()),
+            code => match StatusCode::from_u16(code as u16) {
+                Ok(status) if (400..600).contains(&code) => Err(PluginError::new(
+                    status,
+                    &format!(
+                        "request is aborted by plugin = '{}'",
+                        self.sandbox.path().display()
+                    ),
+                )),
+                _ => Err(self.failure(hook, format_err!("invalid result = {}", code))),
+            },
+        }
+    }
+
+    fn failure(&self, hook: &str, err: anyhow::Error) -> PluginError {
+        error!(
+            "Error running '{}' of the plugin, path = '{}': {:#}",
+            hook,
+            self.sandbox.path().display(),
+            err
+        );
+        PluginError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("plugin = '{}' has failed", self.sandbox.path().display()),
+        )
+    }
+}
+
+impl Plugin for WasmPlugin {
+    fn pre_request(&self, req: &RequestContext) -> Result<(), PluginError> {
+        self.call("pre_request", |instance| {
+            let hook = match instance.exports().pre_request {
+                Some(ref hook) => hook,
+                None => return Ok(None),
+            };
+            let (ptr, len) = instance.write_json(req)?;
+            Ok(Some(hook.call((ptr, len))?))
+        })
+    }
+
+    fn post_response(
+        &self,
+        req: &RequestContext,
+        resp: &ResponseContext,
+    ) -> Result<(), PluginError> {
+        self.call("post_response", |instance| {
+            let hook = match instance.exports().post_response {
+                Some(ref hook) => hook,
+                None => return Ok(None),
+            };
+            let (req_ptr, req_len) = instance.write_json(req)?;
+            let (resp_ptr, resp_len) = instance.write_json(resp)?;
+            Ok(Some(hook.call((req_ptr, req_len, resp_ptr, resp_len))?))
+        })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Runs plugins before requests are handled and after their responses are ready,
+/// requests are passed through if there are no plugins.
+pub(crate) struct PluginMiddleware {
+    plugins: Plugins,
+    authn: Arc<RequestAuthn>,
+}
+
+/// Settings to identify subjects of requests with, as handlers do.
+struct RequestAuthn {
+    id: AccountId,
+    authn: AuthnConfig,
+    multitenancy: MultitenancyConfig,
+    api_keys: ApiKeyStore,
+}
+
+impl PluginMiddleware {
+    pub(crate) fn new(
+        plugins: Plugins,
+        id: AccountId,
+        authn: AuthnConfig,
+        multitenancy: MultitenancyConfig,
+        api_keys: ApiKeyStore,
+    ) -> Self {
+        Self {
+            plugins,
+            authn: Arc::new(RequestAuthn {
+                id,
+                authn,
+                multitenancy,
+                api_keys,
+            }),
+        }
+    }
+}
+
+impl<S> Middleware<S> for PluginMiddleware
+where
+    S: HttpService,
+{
+    type Request = Request<S::RequestBody>;
+    type Response = Response<PluginBody<S::ResponseBody>>;
+    type Error = S::Error;
+    type Service = PluginService<S>;
+
+    fn wrap(&self, inner: S) -> Self::Service {
+        PluginService {
+            inner,
+            plugins: self.plugins.clone(),
+            authn: self.authn.clone(),
+        }
+    }
+}
+
+pub(crate) struct PluginService<S> {
+    inner: S,
+    plugins: Plugins,
+    authn: Arc<RequestAuthn>,
+}
+
+impl<S> Service for PluginService<S>
+where
+    S: HttpService,
+{
+    type Request = Request<S::RequestBody>;
+    type Response = Response<PluginBody<S::ResponseBody>>;
+    type Error = S::Error;
+    type Future = PluginFuture<S::Future, S::ResponseBody>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_http_ready()
+    }
+
+    fn call(&mut self, request: Self::Request) -> Self::Future {
+        if !self.plugins.is_enabled() {
+            return PluginFuture::passthrough(self.inner.call_http(request));
+        }
+
+        let authn = &self.authn;
+        let subject = util::request_subject(
+            &request,
+            &authn.id,
+            &authn.authn,
+            &authn.multitenancy,
+            Some(&authn.api_keys),
+        );
+        let req = Arc::new(RequestContext::new(&request, subject.as_ref()));
+        let pre = self.plugins.pre_request(req.clone());
+
+        // Handlers of the service do nothing until their futures are polled
+        PluginFuture {
+            inner: self.inner.call_http(request),
+            hooks: Some((self.plugins.clone(), req)),
+            pre: Some(pre),
+            post: None,
+        }
+    }
+}
+
+/// Response of the inner service once plugins have run before and after it.
+pub(crate) struct PluginFuture<F, B> {
+    inner: F,
+    hooks: Option<(Plugins, Arc<RequestContext>)>,
+    pre: Option<HookResult>,
+    post: Option<(HookResult, Response<B>)>,
+}
+
+impl<F, B> PluginFuture<F, B> {
+    fn passthrough(inner: F) -> Self {
+        Self {
+            inner,
+            hooks: None,
+            pre: None,
+            post: None,
+        }
+    }
+}
+
+impl<F, B> Future for PluginFuture<F, B>
+where
+    F: Future<Item = Response<B>>,
+{
+    type Item = Response<PluginBody<B>>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(ref mut pre) = self.pre {
+            match pre.poll() {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Ok(())) => (),
+                Async::Ready(Err(err)) => return Ok(Async::Ready(rejection(&err))),
+            }
+            self.pre = None;
+        }
+
+        if let Some((ref mut post, _)) = self.post {
+            return match post.poll() {
+                Async::NotReady => Ok(Async::NotReady),
+                Async::Ready(Ok(())) => {
+                    let (_, response) = self.post.take().expect("missing response");
+                    Ok(Async::Ready(response.map(PluginBody::Inner)))
+                }
+                Async::Ready(Err(err)) => Ok(Async::Ready(rejection(&err))),
+            };
+        }
+
+        let response = futures::try_ready!(self.inner.poll());
+        match self.hooks.take() {
+            Some((plugins, req)) => {
+                let post = plugins.post_response(req, ResponseContext::new(&response));
+                self.post = Some((post, response));
+                self.poll()
+            }
+            None => Ok(Async::Ready(response.map(PluginBody::Inner))),
+        }
+    }
+}
+
+fn rejection<B>(err: &PluginError) -> Response<PluginBody<B>> {
+    let body = serde_json::json!({
+        "type": "plugin_error",
+        "title": "Request is aborted by a plugin",
+        "detail": err.detail,
+    });
+    Response::builder()
+        .status(err.status)
+        .header(header::CONTENT_TYPE, "application/problem+json")
+        .body(PluginBody::Message(Some(Bytes::from(body.to_string()))))
+        .expect("Error building a plugin response")
+}
+
+/// Body of the inner service or the one of the plugin error.
+pub(crate) enum PluginBody<B> {
+    Inner(B),
+    Message(Option<Bytes>),
+}
+
+impl<B> BufStream for PluginBody<B>
+where
+    B: BufStream,
+{
+    type Item = Either2<B::Item, io::Cursor<Bytes>>;
+    type Error = B::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self {
+            PluginBody::Inner(body) => body
+                .poll()
+                .map(|ready| ready.map(|item| item.map(Either2::A))),
+            PluginBody::Message(message) => Ok(Async::Ready(
+                message.take().map(|body| Either2::B(io::Cursor::new(body))),
+            )),
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self {
+            PluginBody::Inner(body) => body.size_hint(),
+            PluginBody::Message(message) => {
+                let len = message.as_ref().map_or(0, Bytes::len);
+                size_hint::Builder::new()
+                    .available(len)
+                    .lower(len)
+                    .upper(len)
+                    .build()
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::future;
+    use tokio::runtime::current_thread::Runtime;
+    use tower_web::util::buf_stream::{self, Empty};
+
+    type Body = Empty<Option<[u8; 1]>, ()>;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = Request<Body>;
+        type Response = Response<Body>;
+        type Error = ();
+        type Future = future::FutureResult<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, request: Self::Request) -> Self::Future {
+            let status = match request.uri().path() {
+                "/missing" => StatusCode::NOT_FOUND,
+                _ => StatusCode::OK,
+            };
+            let mut response = Response::new(buf_stream::empty());
+            *response.status_mut() = status;
+            future::ok(response)
+        }
+    }
+
+    /// Records hooks it has run, aborts requests and responses of the paths.
+    struct Recorder {
+        name: &'static str,
+        abort_path: &'static str,
+        abort_status: u16,
+        hooks: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Plugin for Recorder {
+        fn pre_request(&self, req: &RequestContext) -> Result<(), PluginError> {
+            self.hooks.lock().unwrap().push(format!(
+                "{} pre {} {} {:?}",
+                self.name, req.method, req.path, req.headers
+            ));
+            if req.path == self.abort_path {
+                return Err(PluginError::new(
+                    StatusCode::FORBIDDEN,
+                    "watermark is missing",
+                ));
+            }
+            Ok(())
+        }
+
+        fn post_response(
+            &self,
+            req: &RequestContext,
+            resp: &ResponseContext,
+        ) -> Result<(), PluginError> {
+            self.hooks
+                .lock()
+                .unwrap()
+                .push(format!("{} post {} {}", self.name, req.path, resp.status));
+            if resp.status == self.abort_status {
+                return Err(PluginError::new(
+                    StatusCode::BAD_GATEWAY,
+                    "drm token is missing",
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    fn request(path: &str) -> Request<Body> {
+        Request::builder()
+            .uri(path)
+            .header("authorization", "Bearer secret")
+            .header("x-request-id", "42")
+            .body(buf_stream::empty())
+            .unwrap()
+    }
+
+    fn body<B: BufStream>(response: Response<PluginBody<B>>) -> Option<serde_json::Value> {
+        match response.into_body() {
+            PluginBody::Message(Some(message)) => serde_json::from_slice(&message).ok(),
+            _ => None,
+        }
+    }
+
+    fn limits(workers: usize, queue_capacity: usize, timeout_ms: u64) -> PluginLimitsConfig {
+        PluginLimitsConfig {
+            workers,
+            queue_capacity,
+            timeout_ms,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn plugins_run_in_order() {
+        let hooks = Arc::new(Mutex::new(Vec::new()));
+        let plugins = Plugins::spawn(&limits(1, 16, 5000), {
+            let hooks = hooks.clone();
+            move || {
+                let recorder = |name, abort_path, abort_status| Recorder {
+                    name,
+                    abort_path,
+                    abort_status,
+                    hooks: hooks.clone(),
+                };
+                Ok(vec![
+                    Box::new(recorder("first", "/first", 0)) as Box<dyn Plugin>,
+                    Box::new(recorder("second", "/second", 404)),
+                ])
+            }
+        })
+        .unwrap();
+        let middleware = PluginMiddleware::new(
+            plugins,
+            AccountId::new("storage", "svc.example.org"),
+            serde_json::from_str("{}").unwrap(),
+            MultitenancyConfig::default(),
+            ApiKeyStore::new(&Default::default(), None).unwrap(),
+        );
+        let mut service = middleware.wrap(Echo);
+        let mut rt = Runtime::new().unwrap();
+        let mut call = |path| rt.block_on(service.call(request(path))).unwrap();
+        let take = || std::mem::take(&mut *hooks.lock().unwrap());
+
+        assert_eq!(call("/api/v2/sign").status(), StatusCode::OK);
+        let headers = r#"{"x-request-id": "42"}"#;
+        assert_eq!(
+            take(),
+            vec![
+                format!("first pre GET /api/v2/sign {}", headers),
+                format!("second pre GET /api/v2/sign {}", headers),
+                "first post /api/v2/sign 200".to_owned(),
+                "second post /api/v2/sign 200".to_owned(),
+            ]
+        );
+
+        // The request isn't handled once it's aborted by a plugin, the rest of them don't run
+        let resp = call("/first");
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            body(resp).unwrap()["detail"],
+            serde_json::json!("watermark is missing")
+        );
+        assert_eq!(take(), vec![format!("first pre GET /first {}", headers)]);
+
+        let resp = call("/missing");
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(body(resp).unwrap()["type"], "plugin_error");
+        assert_eq!(take().len(), 4);
+    }
+
+    /// Blocks the worker running its hook until it's released.
+    struct Blocker {
+        started: mpsc::Sender<()>,
+        release: Arc<Mutex<mpsc::Receiver<()>>>,
+    }
+
+    impl Plugin for Blocker {
+        fn pre_request(&self, _req: &RequestContext) -> Result<(), PluginError> {
+            let _ = self.started.send(());
+            let _ = self.release.lock().unwrap().recv();
+            Ok(())
+        }
+
+        fn post_response(
+            &self,
+            _req: &RequestContext,
+            _resp: &ResponseContext,
+        ) -> Result<(), PluginError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn plugins_queue_is_bounded() {
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel();
+        let started_tx = Mutex::new(started_tx);
+        let release_rx = Arc::new(Mutex::new(release_rx));
+        let plugins = Plugins::spawn(&limits(1, 1, 100), move || {
+            let blocker = Blocker {
+                started: started_tx.lock().unwrap().clone(),
+                release: release_rx.clone(),
+            };
+            Ok(vec![Box::new(blocker) as Box<dyn Plugin>])
+        })
+        .unwrap();
+        let req = Arc::new(context(None));
+        let mut rt = Runtime::new().unwrap();
+
+        // The first hook occupies the worker, the second one waits in the queue
+        let first = plugins.pre_request(req.clone());
+        started_rx.recv().unwrap();
+        let mut second = plugins.pre_request(req.clone());
+        let mut third = plugins.pre_request(req);
+        let status = |result: Result<(), PluginError>| result.unwrap_err().status;
+        assert_eq!(
+            status(
+                rt.block_on(future::poll_fn(|| Ok::<_, ()>(third.poll())))
+                    .unwrap()
+            ),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        // Hooks fail once their timeout has elapsed
+        assert_eq!(
+            status(
+                rt.block_on(future::poll_fn(|| Ok::<_, ()>(second.poll())))
+                    .unwrap()
+            ),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        drop(first);
+        release_tx.send(()).unwrap();
+    }
+
+    #[test]
+    fn no_plugins_pass_requests_through() {
+        let middleware = PluginMiddleware::new(
+            Plugins::load(&[], &Default::default()).unwrap(),
+            AccountId::new("storage", "svc.example.org"),
+            serde_json::from_str("{}").unwrap(),
+            MultitenancyConfig::default(),
+            ApiKeyStore::new(&Default::default(), None).unwrap(),
+        );
+        let mut service = middleware.wrap(Echo);
+        let resp = service.call(request("/missing")).wait().unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(body(resp).is_none());
+    }
+
+    // Aborts requests with `403` unless their contexts mention "john", responses are unchecked
+    const PLUGIN: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (func (export "alloc") (param $len i32) (result i32)
+                (i32.const 0))
+            (func (export "pre_request") (param $ptr i32) (param $len i32) (result i32)
+                (local $i i32)
+                (block $done
+                    (loop $scan
+                        (br_if $done (i32.gt_s (i32.add (local.get $i) (i32.const 4)) (local.get $len)))
+                        ;; "john" in little-endian
+                        (if (i32.eq (i32.load (i32.add (local.get $ptr) (local.get $i))) (i32.const 0x6e686f6a))
+                            (then (return (i32.const 0))))
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br $scan)))
+                (i32.const 403)))
+    "#;
+
+    fn context(subject: Option<&str>) -> RequestContext {
+        RequestContext {
+            method: "GET".to_owned(),
+            path: "/api/v2/sets/data.example.org::foo/objects/bar".to_owned(),
+            query: None,
+            headers: BTreeMap::new(),
+            subject: subject.map(ToOwned::to_owned),
+            authn_method: None,
+        }
+    }
+
+    const LIMITS: SandboxLimits = SandboxLimits {
+        fuel: 1_000_000,
+        max_memory_bytes: 3 * 65536,
+    };
+
+    #[test]
+    fn wasm_plugin_hooks() {
+        let path = Path::new("plugin.wasm");
+        let engine = wasm::engine().unwrap();
+        let module = Module::new(&engine, PLUGIN).unwrap();
+        let plugin = WasmPlugin::new(module, path, LIMITS).unwrap();
+
+        assert!(plugin
+            .pre_request(&context(Some("john.usr.example.net")))
+            .is_ok());
+        let err = plugin.pre_request(&context(None)).unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        let resp = ResponseContext {
+            status: 303,
+            headers: BTreeMap::new(),
+        };
+        assert!(plugin.post_response(&context(None), &resp).is_ok());
+
+        let module = Module::new(&engine, r#"(module (memory (export "memory") 1))"#).unwrap();
+        assert!(WasmPlugin::new(module, path, LIMITS).is_err());
+    }
+
+    #[test]
+    fn wasm_plugin_limits() {
+        let path = Path::new("plugin.wasm");
+        let engine = wasm::engine().unwrap();
+
+        // Hooks running out of fuel fail
+        let module = Module::new(
+            &engine,
+            r#"
+            (module
+                (memory (export "memory") 1)
+                (func (export "alloc") (param $len i32) (result i32)
+                    (i32.const 0))
+                (func (export "pre_request") (param $ptr i32) (param $len i32) (result i32)
+                    (loop $forever (br $forever))
+                    (i32.const 0)))
+            "#,
+        )
+        .unwrap();
+        let plugin = WasmPlugin::new(module, path, LIMITS).unwrap();
+        let err = plugin.pre_request(&context(None)).unwrap_err();
+        assert_eq!(err.status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        // Memory leaked by allocations is reclaimed by replacing the instance
+        let module = Module::new(
+            &engine,
+            r#"
+            (module
+                (memory (export "memory") 1)
+                (func (export "alloc") (param $len i32) (result i32)
+                    (i32.mul (memory.grow (i32.const 1)) (i32.const 65536)))
+                (func (export "pre_request") (param $ptr i32) (param $len i32) (result i32)
+                    (i32.const 0)))
+            "#,
+        )
+        .unwrap();
+        let plugin = WasmPlugin::new(module, path, LIMITS).unwrap();
+        for _ in 0..10 {
+            assert!(plugin.pre_request(&context(None)).is_ok());
+            assert!(plugin.sandbox.memory_size() <= LIMITS.max_memory_bytes);
+        }
+    }
+}
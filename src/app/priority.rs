@@ -0,0 +1,649 @@
+use std::collections::VecDeque;
+use std::fmt::{self, Write};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use futures::sync::oneshot;
+use futures::{future, Async, Future, Poll};
+use http::header;
+use http::{Request, Response, StatusCode};
+use log::warn;
+use svc_authn::token::jws_compact::extract::decode_jws_compact_with_config;
+use tower_service::Service;
+use tower_web::middleware::Middleware;
+use tower_web::util::buf_stream::{size_hint, BufStream, SizeHint};
+use tower_web::util::http::HttpService;
+use tower_web::util::tuple::Either2;
+
+use crate::app::config::PriorityQueueConfig;
+use crate::app::util;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Paths served aside of the queue, so that probes and scrapes don't stall under load.
+const EXEMPT_PATHS: &[&str] = &["/healthz", "/readyz", METRICS_PATH];
+
+const METRICS_PATH: &str = "/metrics";
+
+const MESSAGE: &str = r#"{"message":"Too many requests"}"#;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Priority of requests of the subject from the `priority` claim of its access token,
+/// requests without a valid access token are of `normal` priority.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    fn from_claim(value: &str) -> Option<Self> {
+        match value {
+            "low" => Some(Priority::Low),
+            "normal" => Some(Priority::Normal),
+            "high" => Some(Priority::High),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(self.as_str())
+    }
+}
+
+#[derive(Deserialize)]
+struct PriorityClaims {
+    priority: Option<String>,
+}
+
+/// The access token is verified before its claim is trusted, unknown values are ignored.
+fn request_priority<B>(request: &Request<B>, audiences: &svc_authn::jose::ConfigMap) -> Priority {
+    let header = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.split_once(' ').map(|(_, token)| token.to_owned()));
+    let token = header.or_else(|| {
+        url::form_urlencoded::parse(request.uri().query().unwrap_or_default().as_bytes())
+            .find(|(key, _)| key == "access_token")
+            .map(|(_, val)| val.into_owned())
+    });
+
+    token
+        .filter(|token| decode_jws_compact_with_config::<String>(token, audiences).is_ok())
+        .and_then(|token| util::token_payload(&token).ok())
+        .and_then(|payload| serde_json::from_slice::<PriorityClaims>(&payload).ok())
+        .and_then(|claims| claims.priority)
+        .and_then(|priority| {
+            let parsed = Priority::from_claim(&priority);
+            if parsed.is_none() {
+                warn!("Unknown priority claim = '{}' is ignored", priority);
+            }
+            parsed
+        })
+        .unwrap_or(Priority::Normal)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Requests are handled up to `max_concurrency` at once, the rest of them wait in the queues
+/// of their priorities and are dequeued in priority order. Requests of `high` priority bypass
+/// the queue up to `high_concurrency` at once, then they're queued ahead of others.
+#[derive(Clone)]
+pub(crate) struct RequestQueue {
+    state: Arc<Mutex<QueueState>>,
+    max_concurrency: usize,
+    high_concurrency: usize,
+    max_depth: usize,
+    rejected: Arc<AtomicU64>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    in_flight: usize,
+    high_in_flight: usize,
+    waiting: [VecDeque<oneshot::Sender<()>>; 3],
+}
+
+impl QueueState {
+    fn depth(&self, priority: Priority) -> usize {
+        self.waiting[priority.index()]
+            .iter()
+            .filter(|tx| !tx.is_canceled())
+            .count()
+    }
+
+    fn discard_canceled(&mut self) {
+        for waiting in self.waiting.iter_mut() {
+            waiting.retain(|tx| !tx.is_canceled());
+        }
+    }
+}
+
+enum Admission {
+    Admitted(Permit),
+    Queued(Waiting),
+    Rejected,
+}
+
+impl RequestQueue {
+    pub(crate) fn new(config: &PriorityQueueConfig) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(QueueState::default())),
+            max_concurrency: config.max_concurrency.max(1),
+            high_concurrency: config.high_concurrency,
+            max_depth: config.max_depth,
+            rejected: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, QueueState> {
+        self.state.lock().expect("Request queue lock is poisoned")
+    }
+
+    /// Requests of `low` priority are rejected once the queue is full, others wait regardless.
+    fn admit(&self, priority: Priority) -> Admission {
+        let mut state = self.lock();
+        if priority == Priority::High && state.high_in_flight < self.high_concurrency {
+            state.high_in_flight += 1;
+            return Admission::Admitted(Permit::new(self.clone(), true));
+        }
+
+        state.discard_canceled();
+        let depth = state.waiting.iter().map(VecDeque::len).sum::<usize>();
+        if depth == 0 && state.in_flight < self.max_concurrency {
+            state.in_flight += 1;
+            return Admission::Admitted(Permit::new(self.clone(), false));
+        }
+        if priority == Priority::Low && depth >= self.max_depth {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Admission::Rejected;
+        }
+
+        let (tx, rx) = oneshot::channel();
+        state.waiting[priority.index()].push_back(tx);
+        Admission::Queued(Waiting {
+            queue: self.clone(),
+            rx: Some(rx),
+        })
+    }
+
+    /// The slot is handed over to the first of waiting requests of the highest priority.
+    fn release(&self) {
+        let mut state = self.lock();
+        for priority in Priority::ALL.iter() {
+            while let Some(tx) = state.waiting[priority.index()].pop_front() {
+                if tx.send(()).is_ok() {
+                    return;
+                }
+            }
+        }
+
+        state.in_flight -= 1;
+    }
+
+    fn release_high(&self) {
+        self.lock().high_in_flight -= 1;
+    }
+
+    /// Metrics of the queue in Prometheus text format.
+    pub(crate) fn metrics(&self) -> String {
+        let state = self.lock();
+        let mut metrics = String::new();
+
+        metrics.push_str("# HELP storage_request_queue_depth Requests waiting in the queue.\n");
+        metrics.push_str("# TYPE storage_request_queue_depth gauge\n");
+        for priority in Priority::ALL.iter() {
+            let _ = writeln!(
+                metrics,
+                "storage_request_queue_depth{{priority=\"{}\"}} {}",
+                priority,
+                state.depth(*priority)
+            );
+        }
+        metrics.push_str("# HELP storage_requests_in_flight Requests being handled.\n");
+        metrics.push_str("# TYPE storage_requests_in_flight gauge\n");
+        let _ = writeln!(
+            metrics,
+            "storage_requests_in_flight{{pool=\"queue\"}} {}",
+            state.in_flight
+        );
+        let _ = writeln!(
+            metrics,
+            "storage_requests_in_flight{{pool=\"high\"}} {}",
+            state.high_in_flight
+        );
+        metrics.push_str(
+            "# HELP storage_requests_rejected_total Requests rejected since the queue was full.\n",
+        );
+        metrics.push_str("# TYPE storage_requests_rejected_total counter\n");
+        let _ = writeln!(
+            metrics,
+            "storage_requests_rejected_total{{priority=\"low\"}} {}",
+            self.rejected.load(Ordering::Relaxed)
+        );
+
+        metrics
+    }
+}
+
+impl fmt::Debug for RequestQueue {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("RequestQueue")
+            .field("max_concurrency", &self.max_concurrency)
+            .field("high_concurrency", &self.high_concurrency)
+            .field("max_depth", &self.max_depth)
+            .finish()
+    }
+}
+
+/// Slot of a request being handled, it's released once the permit is dropped.
+struct Permit {
+    queue: RequestQueue,
+    high: bool,
+}
+
+impl Permit {
+    fn new(queue: RequestQueue, high: bool) -> Self {
+        Self { queue, high }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if self.high {
+            self.queue.release_high();
+        } else {
+            self.queue.release();
+        }
+    }
+}
+
+/// Request waiting for a slot, the queue forgets about it once it's dropped.
+struct Waiting {
+    queue: RequestQueue,
+    rx: Option<oneshot::Receiver<()>>,
+}
+
+impl Future for Waiting {
+    type Item = Permit;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Permit, ()> {
+        let rx = match self.rx {
+            Some(ref mut rx) => rx,
+            None => return Err(()),
+        };
+        match rx.poll() {
+            Ok(Async::Ready(())) => {
+                self.rx = None;
+                Ok(Async::Ready(Permit::new(self.queue.clone(), false)))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => Err(()),
+        }
+    }
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if let Some(mut rx) = self.rx.take() {
+            // The slot could have been handed over right before the request was cancelled
+            rx.close();
+            match rx.try_recv() {
+                Ok(Some(())) => self.queue.release(),
+                _ => self.queue.lock().discard_canceled(),
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Queues requests by priorities of subjects and serves metrics of the queue,
+/// requests are passed through if the queue is disabled.
+pub(crate) struct PriorityMiddleware {
+    queue: Option<RequestQueue>,
+    audiences: svc_authn::jose::ConfigMap,
+}
+
+impl PriorityMiddleware {
+    pub(crate) fn new(queue: Option<RequestQueue>, audiences: svc_authn::jose::ConfigMap) -> Self {
+        Self { queue, audiences }
+    }
+}
+
+impl<S> Middleware<S> for PriorityMiddleware
+where
+    S: HttpService,
+{
+    type Request = Request<S::RequestBody>;
+    type Response = Response<PriorityBody<S::ResponseBody>>;
+    type Error = S::Error;
+    type Service = PriorityService<S>;
+
+    fn wrap(&self, inner: S) -> Self::Service {
+        PriorityService {
+            inner,
+            queue: self.queue.clone(),
+            audiences: self.audiences.clone(),
+        }
+    }
+}
+
+pub(crate) struct PriorityService<S> {
+    inner: S,
+    queue: Option<RequestQueue>,
+    audiences: svc_authn::jose::ConfigMap,
+}
+
+impl<S> Service for PriorityService<S>
+where
+    S: HttpService,
+{
+    type Request = Request<S::RequestBody>;
+    type Response = Response<PriorityBody<S::ResponseBody>>;
+    type Error = S::Error;
+    type Future = future::Either<
+        PriorityFuture<S::Future>,
+        future::FutureResult<Self::Response, Self::Error>,
+    >;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_http_ready()
+    }
+
+    fn call(&mut self, request: Self::Request) -> Self::Future {
+        let queue = match self.queue {
+            Some(ref val) => val,
+            None => return future::Either::A(PriorityFuture::new(self.inner.call_http(request))),
+        };
+
+        let path = request.uri().path();
+        if path == METRICS_PATH && request.method() == http::Method::GET {
+            let response = message(
+                StatusCode::OK,
+                "text/plain; version=0.0.4",
+                Bytes::from(queue.metrics()),
+            );
+            return future::Either::B(future::ok(response));
+        }
+        if EXEMPT_PATHS.contains(&path) {
+            return future::Either::A(PriorityFuture::new(self.inner.call_http(request)));
+        }
+
+        // Handlers of the service do nothing until their futures are polled,
+        // so that queued requests are held by their permits
+        let (permit, waiting) = match queue.admit(request_priority(&request, &self.audiences)) {
+            Admission::Admitted(permit) => (Some(permit), None),
+            Admission::Queued(waiting) => (None, Some(waiting)),
+            Admission::Rejected => {
+                let response = message(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "application/json",
+                    Bytes::from_static(MESSAGE.as_bytes()),
+                );
+                return future::Either::B(future::ok(response));
+            }
+        };
+
+        future::Either::A(PriorityFuture {
+            inner: self.inner.call_http(request),
+            waiting,
+            permit,
+        })
+    }
+}
+
+fn message<B>(status: StatusCode, content_type: &str, body: Bytes) -> Response<PriorityBody<B>> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(PriorityBody::Message(Some(body)))
+        .expect("Error building a priority queue response")
+}
+
+/// Response of the inner service once the request is admitted, the permit is held until then.
+pub(crate) struct PriorityFuture<F> {
+    inner: F,
+    waiting: Option<Waiting>,
+    permit: Option<Permit>,
+}
+
+impl<F> PriorityFuture<F> {
+    fn new(inner: F) -> Self {
+        Self {
+            inner,
+            waiting: None,
+            permit: None,
+        }
+    }
+}
+
+impl<F, B> Future for PriorityFuture<F>
+where
+    F: Future<Item = Response<B>>,
+{
+    type Item = Response<PriorityBody<B>>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(ref mut waiting) = self.waiting {
+            match waiting.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(permit)) => self.permit = Some(permit),
+                // The queue is gone, there is nothing to wait for
+                Err(()) => (),
+            }
+            self.waiting = None;
+        }
+
+        let response = futures::try_ready!(self.inner.poll());
+        self.permit = None;
+        Ok(Async::Ready(response.map(PriorityBody::Inner)))
+    }
+}
+
+/// Body of the inner service or the one of the queue.
+pub(crate) enum PriorityBody<B> {
+    Inner(B),
+    Message(Option<Bytes>),
+}
+
+impl<B> BufStream for PriorityBody<B>
+where
+    B: BufStream,
+{
+    type Item = Either2<B::Item, io::Cursor<Bytes>>;
+    type Error = B::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self {
+            PriorityBody::Inner(body) => body
+                .poll()
+                .map(|ready| ready.map(|item| item.map(Either2::A))),
+            PriorityBody::Message(message) => Ok(Async::Ready(
+                message.take().map(|body| Either2::B(io::Cursor::new(body))),
+            )),
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self {
+            PriorityBody::Inner(body) => body.size_hint(),
+            PriorityBody::Message(message) => {
+                let len = message.as_ref().map_or(0, Bytes::len);
+                size_hint::Builder::new()
+                    .available(len)
+                    .lower(len)
+                    .upper(len)
+                    .build()
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tower_web::util::buf_stream::{self, Empty};
+
+    type Body = Empty<Option<[u8; 1]>, ()>;
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = Request<Body>;
+        type Response = Response<Body>;
+        type Error = ();
+        type Future = future::FutureResult<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _request: Self::Request) -> Self::Future {
+            future::ok(Response::new(buf_stream::empty()))
+        }
+    }
+
+    fn request(path: &str) -> Request<Body> {
+        Request::builder()
+            .uri(path)
+            .body(buf_stream::empty())
+            .unwrap()
+    }
+
+    fn queue(max_concurrency: usize, high_concurrency: usize, max_depth: usize) -> RequestQueue {
+        RequestQueue::new(&PriorityQueueConfig {
+            enabled: true,
+            max_concurrency,
+            high_concurrency,
+            max_depth,
+        })
+    }
+
+    fn admitted(admission: Admission) -> Permit {
+        match admission {
+            Admission::Admitted(permit) => permit,
+            _ => panic!("expected the request to be admitted"),
+        }
+    }
+
+    fn queued(admission: Admission) -> Waiting {
+        match admission {
+            Admission::Queued(waiting) => waiting,
+            _ => panic!("expected the request to be queued"),
+        }
+    }
+
+    fn ready(waiting: &mut Waiting) -> Option<Permit> {
+        futures::future::poll_fn(|| {
+            Ok::<_, ()>(Async::Ready(match waiting.poll() {
+                Ok(Async::Ready(permit)) => Some(permit),
+                _ => None,
+            }))
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn dequeue_by_priority() {
+        let queue = queue(1, 1, 2);
+
+        let first = admitted(queue.admit(Priority::Normal));
+        let mut low = queued(queue.admit(Priority::Low));
+        let mut normal = queued(queue.admit(Priority::Normal));
+        // The queue is full for low priority requests only
+        assert!(matches!(queue.admit(Priority::Low), Admission::Rejected));
+        let late_normal = queued(queue.admit(Priority::Normal));
+
+        // High priority requests bypass the queue up to their cap
+        let high = admitted(queue.admit(Priority::High));
+        let mut queued_high = queued(queue.admit(Priority::High));
+        drop(high);
+
+        drop(first);
+        let permit = ready(&mut queued_high).expect("high priority request is dequeued first");
+        assert!(ready(&mut normal).is_none());
+        drop(permit);
+        let permit = ready(&mut normal).expect("normal priority request is dequeued next");
+        assert!(ready(&mut low).is_none());
+
+        // Cancelled requests leave the queue
+        drop(late_normal);
+        drop(permit);
+        let permit = ready(&mut low).expect("low priority request is dequeued last");
+        drop(permit);
+
+        let state = queue.lock();
+        assert_eq!((state.in_flight, state.high_in_flight), (0, 0));
+        assert_eq!(state.waiting.iter().map(VecDeque::len).sum::<usize>(), 0);
+    }
+
+    #[test]
+    fn queue_metrics() {
+        let queue = queue(1, 0, 0);
+        let _permit = admitted(queue.admit(Priority::High));
+        let _waiting = queued(queue.admit(Priority::Normal));
+        assert!(matches!(queue.admit(Priority::Low), Admission::Rejected));
+
+        let metrics = queue.metrics();
+        assert!(metrics.contains("storage_request_queue_depth{priority=\"normal\"} 1\n"));
+        assert!(metrics.contains("storage_request_queue_depth{priority=\"low\"} 0\n"));
+        assert!(metrics.contains("storage_requests_in_flight{pool=\"queue\"} 1\n"));
+        assert!(metrics.contains("storage_requests_in_flight{pool=\"high\"} 0\n"));
+        assert!(metrics.contains("storage_requests_rejected_total{priority=\"low\"} 1\n"));
+    }
+
+    #[test]
+    fn middleware_serves_metrics() {
+        let audiences = svc_authn::jose::ConfigMap::new();
+        let mut service = PriorityMiddleware::new(None, audiences.clone()).wrap(Echo);
+        let resp = service.call(request("/metrics")).wait().unwrap();
+        assert!(matches!(resp.into_body(), PriorityBody::Inner(_)));
+
+        let queue = queue(1, 0, 0);
+        let mut service = PriorityMiddleware::new(Some(queue.clone()), audiences).wrap(Echo);
+        let resp = service.call(request("/api/v2/sign")).wait().unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(queue.lock().in_flight, 0);
+
+        let resp = service.call(request("/metrics")).wait().unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        match resp.into_body() {
+            PriorityBody::Message(Some(body)) => {
+                assert!(body.starts_with(b"# HELP storage_request_queue_depth"))
+            }
+            _ => panic!("expected the queue metrics"),
+        }
+    }
+}
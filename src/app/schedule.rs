@@ -0,0 +1,435 @@
+use anyhow::{format_err, Context};
+use chrono::{DateTime, Datelike, Duration, FixedOffset, Timelike, Utc, Weekday};
+use futures::future::{self, FutureResult};
+use http::StatusCode;
+use tower_web::Error;
+
+use crate::app::config::{AccessScheduleConfig, ScheduleConfig, ScheduleWindowConfig};
+use crate::app::util::wildcard_match;
+
+////////////////////////////////////////////////////////////////////////////////
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// Time windows access is allowed within, checked after authorization of the intent.
+#[derive(Debug)]
+pub(crate) struct AccessSchedule {
+    default: Schedule,
+    buckets: Vec<(String, Schedule)>,
+}
+
+impl AccessSchedule {
+    pub(crate) fn new(config: &AccessScheduleConfig) -> anyhow::Result<Self> {
+        let default = Schedule::parse(&config.schedule).context("invalid access schedule")?;
+        let buckets = config
+            .buckets
+            .iter()
+            .map(|entry| {
+                Schedule::parse(&entry.schedule)
+                    .map(|schedule| (entry.bucket_pattern.clone(), schedule))
+                    .with_context(|| {
+                        format!(
+                            "invalid access schedule of buckets = '{}'",
+                            entry.bucket_pattern
+                        )
+                    })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self { default, buckets })
+    }
+
+    /// Returns the error of the request if it's outside of the schedule of the bucket.
+    pub(crate) fn restriction(&self, bucket: &str, method: &str) -> Option<Error> {
+        self.next_allowed(bucket, method, Utc::now())
+            .map(restriction_error)
+    }
+
+    /// The time access is allowed from, `None` if it's allowed at the moment.
+    fn next_allowed(
+        &self,
+        bucket: &str,
+        method: &str,
+        now: DateTime<Utc>,
+    ) -> Option<DateTime<FixedOffset>> {
+        let schedule = self
+            .buckets
+            .iter()
+            .find(|(pattern, _)| wildcard_match(pattern, bucket))
+            .map(|(_, schedule)| schedule)
+            .unwrap_or(&self.default);
+
+        schedule.next_allowed(method, now)
+    }
+}
+
+fn restriction_error(retry_after: DateTime<FixedOffset>) -> Error {
+    Error::builder()
+        .kind("schedule_restriction", "Access is outside of the schedule")
+        .status(StatusCode::FORBIDDEN)
+        .detail(&format!(
+            "access is allowed from retry_after = '{}'",
+            retry_after.to_rfc3339()
+        ))
+        .build()
+}
+
+/// Renders errors as `DefaultCatch` does, with the `retry_after` member and
+/// the `Retry-After` header added to restrictions of the schedule.
+pub(crate) fn catch(
+    _request: &http::Request<()>,
+    error: Error,
+) -> FutureResult<http::Response<String>, Error> {
+    let status = error.status_code();
+    let mut problem = serde_json::to_value(&error).unwrap_or_else(|_| {
+        serde_json::to_value(Error::from(StatusCode::INTERNAL_SERVER_ERROR))
+            .expect("Error serializing a blank error to JSON")
+    });
+
+    let mut builder = http::Response::builder();
+    builder
+        .status(status)
+        .header("content-type", "application/problem+json");
+    if let Some(retry_after) = restriction_retry_after(&problem) {
+        builder.header(
+            "retry-after",
+            crate::app::http_date(retry_after.with_timezone(&Utc)),
+        );
+        problem["retry_after"] = serde_json::Value::String(retry_after.to_rfc3339());
+    }
+
+    future::ok(builder.body(problem.to_string()).unwrap())
+}
+
+fn restriction_retry_after(problem: &serde_json::Value) -> Option<DateTime<FixedOffset>> {
+    if problem["type"] != "schedule_restriction" {
+        return None;
+    }
+
+    let detail = problem["detail"].as_str()?;
+    let value = detail
+        .trim_start_matches("access is allowed from retry_after = '")
+        .trim_end_matches('\'');
+    DateTime::parse_from_rfc3339(value).ok()
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+struct Schedule {
+    offset: FixedOffset,
+    methods: Vec<String>,
+    windows: Vec<Window>,
+}
+
+impl Schedule {
+    fn parse(config: &ScheduleConfig) -> anyhow::Result<Self> {
+        let offset = config
+            .utc_offset
+            .as_deref()
+            .map(parse_utc_offset)
+            .transpose()?
+            .unwrap_or_else(|| FixedOffset::east(0));
+        let windows = config
+            .windows
+            .iter()
+            .map(Window::parse)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            offset,
+            methods: config.methods.iter().map(|m| m.to_uppercase()).collect(),
+            windows,
+        })
+    }
+
+    fn applies(&self, method: &str) -> bool {
+        !self.windows.is_empty()
+            && (self.methods.is_empty() || self.methods.iter().any(|m| m == method))
+    }
+
+    fn next_allowed(&self, method: &str, now: DateTime<Utc>) -> Option<DateTime<FixedOffset>> {
+        if !self.applies(method) {
+            return None;
+        }
+
+        let now = now.with_timezone(&self.offset);
+        if self.windows.iter().any(|window| window.contains(&now)) {
+            return None;
+        }
+
+        // Access is allowed from the start of the nearest window, there is one within a week
+        let today = now.date().and_hms(0, 0, 0);
+        (0..=7)
+            .flat_map(|days| {
+                let day = today + Duration::days(days);
+                self.windows
+                    .iter()
+                    .filter(move |window| window.weekdays.contains(&day.weekday()))
+                    .map(move |window| day + Duration::minutes(i64::from(window.start)))
+            })
+            .filter(|start| *start > now)
+            .min()
+    }
+}
+
+/// Days of week along with minutes of the day, from the start inclusive to the end exclusive.
+#[derive(Debug)]
+struct Window {
+    weekdays: Vec<Weekday>,
+    start: u32,
+    end: u32,
+}
+
+impl Window {
+    fn parse(config: &ScheduleWindowConfig) -> anyhow::Result<Self> {
+        let weekdays = parse_weekdays(&config.weekdays)?;
+        let invalid_hours = || format_err!("invalid hours = '{}'", config.hours);
+        let mut hours = config.hours.splitn(2, '-');
+        let (start, end) = match (hours.next(), hours.next()) {
+            (Some(start), Some(end)) => (
+                parse_time_of_day(start).ok_or_else(invalid_hours)?,
+                parse_time_of_day(end).ok_or_else(invalid_hours)?,
+            ),
+            _ => return Err(invalid_hours()),
+        };
+        if start == MINUTES_PER_DAY {
+            return Err(invalid_hours());
+        }
+
+        Ok(Self {
+            weekdays,
+            start,
+            end,
+        })
+    }
+
+    /// Windows over midnight are of the day they start on.
+    fn contains(&self, time: &DateTime<FixedOffset>) -> bool {
+        let minute = time.hour() * 60 + time.minute();
+        let weekday = time.weekday();
+
+        if self.start < self.end {
+            self.weekdays.contains(&weekday) && self.start <= minute && minute < self.end
+        } else {
+            (self.weekdays.contains(&weekday) && minute >= self.start)
+                || (self.weekdays.contains(&weekday.pred()) && minute < self.end)
+        }
+    }
+}
+
+/// Parses `+03:00`, `-05:30` or `Z`.
+fn parse_utc_offset(value: &str) -> anyhow::Result<FixedOffset> {
+    let invalid = || format_err!("invalid utc_offset = '{}'", value);
+    if value == "Z" {
+        return Ok(FixedOffset::east(0));
+    }
+
+    let (sign, rest) = match value.chars().next() {
+        Some('+') => (1, &value[1..]),
+        Some('-') => (-1, &value[1..]),
+        _ => return Err(invalid()),
+    };
+    let minutes = parse_time_of_day(rest).ok_or_else(invalid)?;
+    FixedOffset::east_opt(sign * minutes as i32 * 60).ok_or_else(invalid)
+}
+
+/// Minutes of the day of `HH:MM`, `24:00` is the end of the day.
+fn parse_time_of_day(value: &str) -> Option<u32> {
+    let mut parts = value.trim().splitn(2, ':');
+    let hours = parts.next()?.parse::<u32>().ok()?;
+    let minutes = parts.next()?.parse::<u32>().ok()?;
+
+    match hours * 60 + minutes {
+        val if minutes < 60 && val <= MINUTES_PER_DAY => Some(val),
+        _ => None,
+    }
+}
+
+/// Parses comma-separated days of week and ranges of them, e.g. `mon-fri,sun`.
+fn parse_weekdays(value: &str) -> anyhow::Result<Vec<Weekday>> {
+    let invalid = || format_err!("invalid weekdays = '{}'", value);
+    let mut weekdays = Vec::new();
+
+    for part in value.split(',') {
+        let mut range = part.splitn(2, '-');
+        let first = range
+            .next()
+            .and_then(|day| day.trim().parse::<Weekday>().ok())
+            .ok_or_else(invalid)?;
+        let last = match range.next() {
+            Some(day) => day.trim().parse::<Weekday>().map_err(|_| invalid())?,
+            None => first,
+        };
+
+        // Ranges could wrap around the week, e.g. `fri-mon`
+        let mut day = first;
+        loop {
+            if !weekdays.contains(&day) {
+                weekdays.push(day);
+            }
+            if day == last {
+                break;
+            }
+            day = day.succ();
+        }
+    }
+
+    Ok(weekdays)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::config::BucketScheduleConfig;
+    use futures::Future;
+
+    fn window(weekdays: &str, hours: &str) -> ScheduleWindowConfig {
+        ScheduleWindowConfig {
+            weekdays: weekdays.into(),
+            hours: hours.into(),
+        }
+    }
+
+    fn time(value: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(value)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn next_allowed_times() {
+        let config = AccessScheduleConfig {
+            schedule: ScheduleConfig {
+                utc_offset: Some("+03:00".into()),
+                methods: vec![],
+                windows: vec![window("mon-fri", "09:00-18:00")],
+            },
+            buckets: vec![
+                BucketScheduleConfig {
+                    bucket_pattern: "reports.*".into(),
+                    schedule: ScheduleConfig {
+                        utc_offset: None,
+                        methods: vec!["get".into()],
+                        windows: vec![window("sat,sun", "22:00-02:00")],
+                    },
+                },
+                BucketScheduleConfig {
+                    bucket_pattern: "public.*".into(),
+                    schedule: ScheduleConfig::default(),
+                },
+            ],
+        };
+        let schedule = AccessSchedule::new(&config).unwrap();
+        let next = |bucket: &str, method: &str, now: &str| {
+            schedule
+                .next_allowed(bucket, method, time(now))
+                .map(|val| val.to_rfc3339())
+        };
+
+        // 2026-10-15 is Thursday, 12:00 at +03:00
+        assert_eq!(next("videos", "GET", "2026-10-15T09:00:00Z"), None);
+        assert_eq!(
+            next("videos", "PUT", "2026-10-15T16:00:00Z"),
+            Some("2026-10-16T09:00:00+03:00".into())
+        );
+        assert_eq!(
+            next("videos", "GET", "2026-10-16T15:30:00Z"),
+            Some("2026-10-19T09:00:00+03:00".into())
+        );
+        assert_eq!(
+            next("videos", "GET", "2026-10-15T05:59:59Z"),
+            Some("2026-10-15T09:00:00+03:00".into())
+        );
+
+        // Buckets of overrides follow their own schedules, windows over midnight included
+        assert_eq!(
+            next("reports.example.org", "PUT", "2026-10-15T03:00:00Z"),
+            None
+        );
+        assert_eq!(
+            next("reports.example.org", "GET", "2026-10-15T12:00:00Z"),
+            Some("2026-10-17T22:00:00+00:00".into())
+        );
+        assert_eq!(
+            next("reports.example.org", "GET", "2026-10-19T01:59:00Z"),
+            None
+        );
+        assert_eq!(
+            next("reports.example.org", "GET", "2026-10-19T02:00:00Z"),
+            Some("2026-10-24T22:00:00+00:00".into())
+        );
+        assert_eq!(
+            next("public.example.org", "GET", "2026-10-18T03:00:00Z"),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_schedule_values() {
+        assert_eq!(parse_time_of_day("09:30"), Some(570));
+        assert_eq!(parse_time_of_day("24:00"), Some(MINUTES_PER_DAY));
+        assert_eq!(parse_time_of_day("24:01"), None);
+        assert_eq!(parse_time_of_day("09:60"), None);
+        assert_eq!(parse_time_of_day("9"), None);
+
+        assert_eq!(
+            parse_utc_offset("-05:30").unwrap(),
+            FixedOffset::west(19800)
+        );
+        assert_eq!(parse_utc_offset("Z").unwrap(), FixedOffset::east(0));
+        assert!(parse_utc_offset("03:00").is_err());
+        assert!(parse_utc_offset("Europe/Moscow").is_err());
+
+        assert_eq!(
+            parse_weekdays("fri-mon,wed").unwrap(),
+            vec![
+                Weekday::Fri,
+                Weekday::Sat,
+                Weekday::Sun,
+                Weekday::Mon,
+                Weekday::Wed
+            ]
+        );
+        assert!(parse_weekdays("mon-").is_err());
+        assert!(parse_weekdays("weekend").is_err());
+
+        assert!(Window::parse(&window("mon", "24:00-01:00")).is_err());
+        assert!(Window::parse(&window("mon", "09:00")).is_err());
+        assert!(Window::parse(&window("mon", "00:00-24:00")).is_ok());
+    }
+
+    #[test]
+    fn restriction_errors() {
+        let err =
+            restriction_error(DateTime::parse_from_rfc3339("2026-10-16T09:00:00+03:00").unwrap());
+        assert_eq!(err.status_code(), StatusCode::FORBIDDEN);
+        let body = serde_json::to_value(&err).unwrap();
+        assert_eq!(body["type"], "schedule_restriction");
+        assert_eq!(
+            body["detail"],
+            "access is allowed from retry_after = '2026-10-16T09:00:00+03:00'"
+        );
+
+        let req = http::Request::new(());
+        let resp = catch(&req, err).wait().unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            resp.headers()["retry-after"],
+            "Fri, 16 Oct 2026 06:00:00 GMT"
+        );
+        let body: serde_json::Value = serde_json::from_str(resp.body()).unwrap();
+        assert_eq!(body["retry_after"], "2026-10-16T09:00:00+03:00");
+
+        // Other errors are rendered as they are
+        let resp = catch(&req, Error::from(StatusCode::NOT_FOUND))
+            .wait()
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(resp.headers().get("retry-after").is_none());
+        let body: serde_json::Value = serde_json::from_str(resp.body()).unwrap();
+        assert!(body.get("retry_after").is_none());
+    }
+}
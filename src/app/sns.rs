@@ -0,0 +1,528 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::{SecondsFormat, Utc};
+use futures::{future, Future, Stream};
+use http::StatusCode;
+use log::{error, info};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{HasPublic, PKeyRef};
+use openssl::sign::Verifier;
+use openssl::x509::X509;
+use regex::Regex;
+use url::Url;
+
+use crate::app::config::{SnsWebhookConfig, UpstreamWebhookConfig};
+use crate::app::transcoding::Transcoder;
+
+////////////////////////////////////////////////////////////////////////////////
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const NOTIFICATION: &str = "Notification";
+const SUBSCRIPTION_CONFIRMATION: &str = "SubscriptionConfirmation";
+const UNSUBSCRIBE_CONFIRMATION: &str = "UnsubscribeConfirmation";
+
+/// Regional SNS endpoints, including the ones of China regions.
+const SNS_HOST_PATTERN: &str = r"^sns\.[a-z0-9-]+\.amazonaws\.com(\.cn)?$";
+/// Signing certificates are issued to the global SNS endpoint or to the regional ones.
+const SNS_CERTIFICATE_SUBJECT: &str = "sns.amazonaws.com";
+
+/// Message posted by SNS to HTTP(S) subscriptions.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SnsMessage {
+    #[serde(rename = "Type")]
+    kind: String,
+    message_id: String,
+    token: Option<String>,
+    topic_arn: String,
+    subject: Option<String>,
+    message: String,
+    timestamp: String,
+    signature_version: String,
+    signature: String,
+    #[serde(rename = "SigningCertURL")]
+    signing_cert_url: String,
+    #[serde(rename = "SubscribeURL")]
+    subscribe_url: Option<String>,
+}
+
+/// S3 event notification, test events have no records.
+#[derive(Debug, Deserialize)]
+struct S3EventMessage {
+    #[serde(rename = "Records", default)]
+    records: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct S3EventDelivery<'a> {
+    event: &'static str,
+    topic_arn: &'a str,
+    records: &'a [serde_json::Value],
+    at: String,
+}
+
+#[derive(Debug)]
+pub(crate) enum SnsError {
+    Malformed(String),
+    /// The topic isn't allowed, or the signature of the message is invalid.
+    Rejected(String),
+    Unavailable(anyhow::Error),
+}
+
+impl SnsError {
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            SnsError::Malformed(_) => StatusCode::BAD_REQUEST,
+            SnsError::Rejected(_) => StatusCode::FORBIDDEN,
+            SnsError::Unavailable(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    pub(crate) fn detail(&self) -> String {
+        match self {
+            SnsError::Malformed(detail) | SnsError::Rejected(detail) => detail.to_owned(),
+            SnsError::Unavailable(err) => format!("{:#}", err),
+        }
+    }
+}
+
+/// Receives messages of SNS topics S3 event notifications are published to. Messages are verified
+/// with signing certificates of SNS, subscriptions are confirmed, S3 event records are delivered
+/// to upstream webhooks.
+#[derive(Clone)]
+pub(crate) struct SnsWebhook {
+    config: Arc<SnsWebhookConfig>,
+    upstreams: Arc<Vec<UpstreamWebhookConfig>>,
+    http: reqwest::r#async::Client,
+    certificates: Arc<Mutex<HashMap<String, X509>>>,
+    transcoder: Option<Transcoder>,
+}
+
+impl std::fmt::Debug for SnsWebhook {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("SnsWebhook")
+            .field("config", &self.config)
+            .field("upstreams", &self.upstreams)
+            .field("transcoder", &self.transcoder)
+            .finish()
+    }
+}
+
+impl SnsWebhook {
+    pub(crate) fn new(
+        config: SnsWebhookConfig,
+        upstreams: Vec<UpstreamWebhookConfig>,
+    ) -> anyhow::Result<Self> {
+        let http = reqwest::r#async::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .context("failed to create an HTTP client")?;
+
+        Ok(Self {
+            config: Arc::new(config),
+            upstreams: Arc::new(upstreams),
+            http,
+            certificates: Arc::new(Mutex::new(HashMap::new())),
+            transcoder: None,
+        })
+    }
+
+    /// Created objects of notifications are passed to the transcoder as well.
+    pub(crate) fn set_transcoder(&mut self, transcoder: Transcoder) -> &mut Self {
+        self.transcoder = Some(transcoder);
+        self
+    }
+
+    pub(crate) fn handle(
+        &self,
+        body: &[u8],
+    ) -> Box<dyn Future<Item = (), Error = SnsError> + Send> {
+        let message = match serde_json::from_slice::<SnsMessage>(body) {
+            Ok(val) => val,
+            Err(err) => {
+                return Box::new(future::err(SnsError::Malformed(format!(
+                    "invalid sns message: {}",
+                    err
+                ))))
+            }
+        };
+        if !self.config.topic_arns.contains(&message.topic_arn) {
+            return Box::new(future::err(SnsError::Rejected(format!(
+                "topic = '{}' isn't allowed",
+                message.topic_arn
+            ))));
+        }
+        let cert_url = match validate_sns_url(&message.signing_cert_url) {
+            Ok(val) if val.path().ends_with(".pem") => val,
+            Ok(_) => {
+                return Box::new(future::err(SnsError::Rejected(String::from(
+                    "invalid signing certificate url",
+                ))))
+            }
+            Err(detail) => return Box::new(future::err(SnsError::Rejected(detail))),
+        };
+
+        let webhook = self.clone();
+        Box::new(
+            self.certificate(cert_url)
+                .and_then(move |cert| {
+                    let key = cert.public_key().map_err(|_| {
+                        SnsError::Rejected(String::from("invalid signing certificate"))
+                    })?;
+                    verify_signature(&message, &key).map_err(SnsError::Rejected)?;
+                    Ok(message)
+                })
+                .and_then(move |message| webhook.process(message)),
+        )
+    }
+
+    fn process(&self, message: SnsMessage) -> Box<dyn Future<Item = (), Error = SnsError> + Send> {
+        match message.kind.as_str() {
+            NOTIFICATION => {
+                let event = match serde_json::from_str::<S3EventMessage>(&message.message) {
+                    Ok(val) => val,
+                    Err(err) => {
+                        return Box::new(future::err(SnsError::Malformed(format!(
+                            "invalid s3 event notification: {}",
+                            err
+                        ))))
+                    }
+                };
+                if !event.records.is_empty() {
+                    if let Some(ref transcoder) = self.transcoder {
+                        transcoder.submit(&event.records);
+                    }
+                    deliver(self.upstreams.clone(), message.topic_arn, event.records);
+                }
+                Box::new(future::ok(()))
+            }
+            SUBSCRIPTION_CONFIRMATION => {
+                let url = match message.subscribe_url.as_deref().map(validate_sns_url) {
+                    Some(Ok(val)) => val,
+                    Some(Err(detail)) => return Box::new(future::err(SnsError::Rejected(detail))),
+                    None => {
+                        return Box::new(future::err(SnsError::Malformed(String::from(
+                            "missing subscribe url",
+                        ))))
+                    }
+                };
+                let topic_arn = message.topic_arn;
+                Box::new(
+                    self.http
+                        .get(url)
+                        .send()
+                        .map_err(|err| {
+                            SnsError::Unavailable(
+                                anyhow::Error::from(err)
+                                    .context("failed to confirm a subscription"),
+                            )
+                        })
+                        .and_then(move |resp| {
+                            if resp.status().is_success() {
+                                info!("SNS subscription is confirmed, topic = '{}'", topic_arn);
+                                Ok(())
+                            } else {
+                                Err(SnsError::Unavailable(anyhow::format_err!(
+                                    "subscription confirmation responded with status = {}",
+                                    resp.status()
+                                )))
+                            }
+                        }),
+                )
+            }
+            _ => {
+                info!(
+                    "SNS subscription is cancelled, topic = '{}'",
+                    message.topic_arn
+                );
+                Box::new(future::ok(()))
+            }
+        }
+    }
+
+    /// Signing certificates are cached by their URLs.
+    fn certificate(&self, url: Url) -> Box<dyn Future<Item = X509, Error = SnsError> + Send> {
+        let cached = self
+            .certificates
+            .lock()
+            .expect("SNS certificates lock is poisoned")
+            .get(url.as_str())
+            .cloned();
+        if let Some(cert) = cached {
+            return Box::new(future::ok(cert));
+        }
+
+        let certificates = self.certificates.clone();
+        Box::new(
+            self.http
+                .get(url.clone())
+                .send()
+                .and_then(|resp| resp.error_for_status())
+                .and_then(|resp| {
+                    resp.into_body().fold(Vec::new(), |mut acc, chunk| {
+                        acc.extend_from_slice(&chunk);
+                        Ok::<_, reqwest::Error>(acc)
+                    })
+                })
+                .map_err(|err| {
+                    SnsError::Unavailable(
+                        anyhow::Error::from(err).context("failed to fetch the signing certificate"),
+                    )
+                })
+                .and_then(move |pem| {
+                    let cert = X509::from_pem(&pem).map_err(|_| {
+                        SnsError::Rejected(String::from("invalid signing certificate"))
+                    })?;
+                    validate_certificate(&cert).map_err(SnsError::Rejected)?;
+                    certificates
+                        .lock()
+                        .expect("SNS certificates lock is poisoned")
+                        .insert(url.into_string(), cert.clone());
+                    Ok(cert)
+                }),
+        )
+    }
+}
+
+fn is_sns_host(host: &str) -> bool {
+    Regex::new(SNS_HOST_PATTERN)
+        .expect("invalid sns host pattern")
+        .is_match(host)
+}
+
+/// URLs of SNS messages must point to SNS over HTTPS, so that they can't be used to forge messages.
+fn validate_sns_url(value: &str) -> Result<Url, String> {
+    let url = Url::parse(value).map_err(|_| format!("invalid sns url = '{}'", value))?;
+    let valid =
+        url.scheme() == "https" && url.port().is_none() && url.host_str().is_some_and(is_sns_host);
+
+    if valid {
+        Ok(url)
+    } else {
+        Err(format!("sns url = '{}' doesn't point to sns", value))
+    }
+}
+
+/// Signing certificates must be issued to SNS, the common name of the subject is checked.
+fn validate_certificate(cert: &X509) -> Result<(), String> {
+    let subject = cert
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().as_utf8().ok())
+        .map(|name| name.to_string());
+
+    match subject {
+        Some(ref name) if name == SNS_CERTIFICATE_SUBJECT || is_sns_host(name) => Ok(()),
+        Some(name) => Err(format!(
+            "signing certificate subject = '{}' isn't sns",
+            name
+        )),
+        None => Err(String::from("signing certificate has no subject")),
+    }
+}
+
+/// Fields of the message in the order they're signed by SNS.
+fn string_to_sign(message: &SnsMessage) -> Result<String, String> {
+    let fields = match message.kind.as_str() {
+        NOTIFICATION => vec![
+            ("Message", Some(message.message.as_str())),
+            ("MessageId", Some(message.message_id.as_str())),
+            ("Subject", message.subject.as_deref()),
+            ("Timestamp", Some(message.timestamp.as_str())),
+            ("TopicArn", Some(message.topic_arn.as_str())),
+            ("Type", Some(message.kind.as_str())),
+        ],
+        SUBSCRIPTION_CONFIRMATION | UNSUBSCRIBE_CONFIRMATION => vec![
+            ("Message", Some(message.message.as_str())),
+            ("MessageId", Some(message.message_id.as_str())),
+            ("SubscribeURL", message.subscribe_url.as_deref()),
+            ("Timestamp", Some(message.timestamp.as_str())),
+            ("Token", message.token.as_deref()),
+            ("TopicArn", Some(message.topic_arn.as_str())),
+            ("Type", Some(message.kind.as_str())),
+        ],
+        kind => return Err(format!("unknown sns message type = '{}'", kind)),
+    };
+
+    Ok(fields
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|value| format!("{}\n{}\n", key, value)))
+        .collect())
+}
+
+fn verify_signature<T: HasPublic>(message: &SnsMessage, key: &PKeyRef<T>) -> Result<(), String> {
+    let digest = match message.signature_version.as_str() {
+        "1" => MessageDigest::sha1(),
+        "2" => MessageDigest::sha256(),
+        version => return Err(format!("unsupported signature version = '{}'", version)),
+    };
+    let signature =
+        base64::decode(&message.signature).map_err(|_| String::from("malformed signature"))?;
+    let data = string_to_sign(message)?;
+
+    let verified = Verifier::new(digest, key)
+        .and_then(|mut verifier| {
+            verifier.update(data.as_bytes())?;
+            verifier.verify(&signature)
+        })
+        .unwrap_or(false);
+    if verified {
+        Ok(())
+    } else {
+        Err(String::from("invalid signature of the sns message"))
+    }
+}
+
+/// Posts S3 event records to upstream webhooks in background, so SNS isn't kept waiting.
+fn deliver(
+    upstreams: Arc<Vec<UpstreamWebhookConfig>>,
+    topic_arn: String,
+    records: Vec<serde_json::Value>,
+) {
+    std::thread::spawn(move || {
+        let delivery = S3EventDelivery {
+            event: "s3_event",
+            topic_arn: &topic_arn,
+            records: &records,
+            at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        };
+        for upstream in upstreams.iter() {
+            if let Err(err) = post(upstream, &delivery) {
+                error!(
+                    "Error delivering S3 events, url = '{}': {:#}",
+                    upstream.url, err
+                );
+            }
+        }
+    });
+}
+
+/// Posts the delivery to the upstream webhook, the secret is sent as a bearer token.
+pub(crate) fn post<T: serde::Serialize>(
+    upstream: &UpstreamWebhookConfig,
+    delivery: &T,
+) -> anyhow::Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .context("failed to create an HTTP client")?;
+    let mut req = client.post(&upstream.url).json(delivery);
+    if let Some(ref secret) = upstream.secret {
+        req = req.bearer_auth(secret);
+    }
+
+    let resp = req.send().context("failed to call the upstream webhook")?;
+    if !resp.status().is_success() {
+        return Err(anyhow::format_err!(
+            "upstream webhook responded with status = {}",
+            resp.status()
+        ));
+    }
+
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::sign::Signer;
+    use openssl::x509::X509NameBuilder;
+
+    fn notification() -> SnsMessage {
+        SnsMessage {
+            kind: NOTIFICATION.into(),
+            message_id: "22b80b92-fdea-4c2c-8f9d-bdfb0c7bf324".into(),
+            token: None,
+            topic_arn: "arn:aws:sns:eu-west-1:123456789012:storage-events".into(),
+            subject: None,
+            message: r#"{"Records":[]}"#.into(),
+            timestamp: "2020-01-01T10:00:00.000Z".into(),
+            signature_version: "2".into(),
+            signature: String::new(),
+            signing_cert_url: "https://sns.eu-west-1.amazonaws.com/SimpleNotificationService-0000000000000000000000.pem".into(),
+            subscribe_url: None,
+        }
+    }
+
+    #[test]
+    fn string_to_sign_fields() {
+        let message = notification();
+        assert_eq!(
+            string_to_sign(&message).unwrap(),
+            "Message\n{\"Records\":[]}\nMessageId\n22b80b92-fdea-4c2c-8f9d-bdfb0c7bf324\nTimestamp\n2020-01-01T10:00:00.000Z\nTopicArn\narn:aws:sns:eu-west-1:123456789012:storage-events\nType\nNotification\n"
+        );
+
+        let message = SnsMessage {
+            kind: "Unknown".into(),
+            ..notification()
+        };
+        assert!(string_to_sign(&message).is_err());
+    }
+
+    #[test]
+    fn verify_signature_values() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut message = notification();
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+        signer
+            .update(string_to_sign(&message).unwrap().as_bytes())
+            .unwrap();
+        message.signature = base64::encode(&signer.sign_to_vec().unwrap());
+        assert!(verify_signature(&message, &key).is_ok());
+
+        message.message = r#"{"Records":[{}]}"#.into();
+        assert!(verify_signature(&message, &key).is_err());
+
+        let message = SnsMessage {
+            signature_version: "3".into(),
+            ..notification()
+        };
+        assert!(verify_signature(&message, &key).is_err());
+    }
+
+    #[test]
+    fn validate_sns_urls() {
+        assert!(validate_sns_url("https://sns.eu-west-1.amazonaws.com/cert.pem").is_ok());
+        assert!(validate_sns_url("https://sns.cn-north-1.amazonaws.com.cn/cert.pem").is_ok());
+        assert!(validate_sns_url("http://sns.eu-west-1.amazonaws.com/cert.pem").is_err());
+        assert!(
+            validate_sns_url("https://sns.eu-west-1.amazonaws.com.example.org/cert.pem").is_err()
+        );
+        assert!(validate_sns_url("https://example.org/sns.amazonaws.com").is_err());
+        assert!(validate_sns_url("https://sns.evil.example.amazonaws.com/cert.pem").is_err());
+        assert!(validate_sns_url("https://sns.eu-west-1.amazonaws.com:8443/cert.pem").is_err());
+        assert!(validate_sns_url("https://sns.amazonaws.com/cert.pem").is_err());
+        assert!(validate_sns_url("not a url").is_err());
+    }
+
+    #[test]
+    fn validate_certificate_subjects() {
+        let cert = |name: &str| {
+            let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+            let mut subject = X509NameBuilder::new().unwrap();
+            subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+            let subject = subject.build();
+            let mut builder = X509::builder().unwrap();
+            builder.set_subject_name(&subject).unwrap();
+            builder.set_issuer_name(&subject).unwrap();
+            builder.set_pubkey(&key).unwrap();
+            builder.sign(&key, MessageDigest::sha256()).unwrap();
+            builder.build()
+        };
+
+        assert!(validate_certificate(&cert("sns.amazonaws.com")).is_ok());
+        assert!(validate_certificate(&cert("sns.eu-west-1.amazonaws.com")).is_ok());
+        assert!(validate_certificate(&cert("example.org")).is_err());
+        assert!(validate_certificate(&cert("sns.amazonaws.com.example.org")).is_err());
+    }
+}
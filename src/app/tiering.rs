@@ -0,0 +1,534 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use anyhow::{format_err, Context};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, Timelike, Utc};
+use diesel::pg::PgConnection;
+use futures::{future, stream, Future, Stream};
+use http::StatusCode;
+use log::{error, info, warn};
+use svc_authn::AccountId;
+
+use crate::app::audit::{AuditEntry, AuditLog};
+use crate::app::config::{BucketTieringConfig, TieringConfig};
+use crate::app::util::wildcard_match;
+use crate::db::{audit_event, ConnectionPool};
+use crate::s3::{Client, ClientRouter, ObjectInfo};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const SOURCE_STORAGE_CLASS: &str = "STANDARD";
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Schedule in cron format: `MINUTE HOUR DAY_OF_MONTH MONTH DAY_OF_WEEK`. Each of the fields
+/// is `*` or a comma-separated list of values and ranges (`1-5`), optionally with a step (`*/15`).
+/// Sunday is either `0` or `7`. As in cron, if both days of month and week are restricted,
+/// either of them matches.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl CronSchedule {
+    pub(crate) fn parse(value: &str) -> anyhow::Result<Self> {
+        let fields = value.split_whitespace().collect::<Vec<&str>>();
+        if fields.len() != 5 {
+            return Err(format_err!(
+                "invalid cron schedule = '{}', expected 5 fields",
+                value
+            ));
+        }
+
+        let weekdays = parse_field(fields[4], 0, 7)
+            .with_context(|| format!("invalid cron schedule = '{}'", value))?;
+        let schedule = Self {
+            minutes: parse_field(fields[0], 0, 59)
+                .with_context(|| format!("invalid cron schedule = '{}'", value))?,
+            hours: parse_field(fields[1], 0, 23)
+                .with_context(|| format!("invalid cron schedule = '{}'", value))?,
+            days: parse_field(fields[2], 1, 31)
+                .with_context(|| format!("invalid cron schedule = '{}'", value))?,
+            months: parse_field(fields[3], 1, 12)
+                .with_context(|| format!("invalid cron schedule = '{}'", value))?,
+            // Sunday is the bit 0
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        };
+
+        if schedule.next_after(Utc::now()).is_none() {
+            return Err(format_err!(
+                "invalid cron schedule = '{}', it never fires",
+                value
+            ));
+        }
+
+        Ok(schedule)
+    }
+
+    /// The first minute after the time matching the schedule, `None` if there isn't one
+    /// within a few years (e.g. for `0 0 30 2 *`).
+    pub(crate) fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let time = time.naive_utc();
+        let mut next = time.date().and_hms(time.hour(), time.minute(), 0) + Duration::minutes(1);
+        let until = NaiveDate::from_ymd(time.year() + 5, 1, 1).and_hms(0, 0, 0);
+
+        while next < until {
+            if !has_bit(self.months, next.month()) {
+                next = first_day_of_next_month(next.date()).and_hms(0, 0, 0);
+            } else if !self.matches_day(next) {
+                next = next.date().succ().and_hms(0, 0, 0);
+            } else if !has_bit(self.hours, next.hour()) {
+                next = next.date().and_hms(next.hour(), 0, 0) + Duration::hours(1);
+            } else if !has_bit(self.minutes, next.minute()) {
+                next += Duration::minutes(1);
+            } else {
+                return Some(DateTime::from_utc(next, Utc));
+            }
+        }
+
+        None
+    }
+
+    fn matches_day(&self, time: NaiveDateTime) -> bool {
+        let day = has_bit(self.days, time.day());
+        let weekday = has_bit(self.weekdays, time.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+}
+
+/// Bits of the values of the field within `min..=max`.
+fn parse_field(value: &str, min: u32, max: u32) -> anyhow::Result<u64> {
+    let invalid = || format_err!("invalid field = '{}'", value);
+
+    let mut bits = 0;
+    for part in value.split(',') {
+        let mut parts = part.splitn(2, '/');
+        let range = parts.next().ok_or_else(invalid)?;
+        let step = match parts.next() {
+            Some(step) => step
+                .parse::<u32>()
+                .ok()
+                .filter(|step| *step > 0)
+                .ok_or_else(invalid)?,
+            None => 1,
+        };
+
+        let parse = |val: &str| val.parse::<u32>().map_err(|_| invalid());
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            // A single value with a step stands for a range up to the maximum
+            None if step > 1 => (parse(range)?, max),
+            None => (parse(range)?, parse(range)?),
+        };
+        if start < min || end > max || start > end {
+            return Err(invalid());
+        }
+
+        bits |= (start..=end)
+            .step_by(step as usize)
+            .fold(0, |acc, val| acc | (1 << val));
+    }
+
+    Ok(bits)
+}
+
+fn has_bit(bits: u64, value: u32) -> bool {
+    bits & (1 << value) != 0
+}
+
+fn first_day_of_next_month(date: NaiveDate) -> NaiveDate {
+    match date.month() {
+        12 => NaiveDate::from_ymd(date.year() + 1, 1, 1),
+        month => NaiveDate::from_ymd(date.year(), month + 1, 1),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Moves objects of buckets of `rules` in `STANDARD` storage class that haven't been read since
+/// the cutoff to cheaper storage classes on the schedule in background. Reads are looked up
+/// in the audit log, buckets are skipped if it doesn't cover the inactivity period yet.
+/// Only one of the instances sharing the audit log moves objects at a time.
+pub(crate) fn spawn(
+    s3: Arc<ClientRouter>,
+    audit: AuditLog,
+    db: ConnectionPool,
+    subject: AccountId,
+    config: TieringConfig,
+    rules: Vec<BucketTieringConfig>,
+) {
+    if rules.is_empty() {
+        return;
+    }
+
+    let schedule =
+        CronSchedule::parse(&config.schedule).expect("Error reading bucket tiering schedule");
+
+    std::thread::spawn(move || {
+        let mut rt =
+            tokio::runtime::Runtime::new().expect("Error creating a bucket tiering runtime");
+
+        let page_size = config.page_size.clamp(1, 1000);
+        loop {
+            let now = Utc::now();
+            let next = match schedule.next_after(now) {
+                Some(val) => val,
+                None => {
+                    error!("Bucket tiering is stopped, the schedule never fires again");
+                    return;
+                }
+            };
+            std::thread::sleep((next - now).to_std().unwrap_or_default());
+
+            // The lock is held by the connection until it's released after the run
+            let conn = match db.get() {
+                Ok(val) => val,
+                Err(err) => {
+                    error!("Error getting a db connection for tiering: {}", err);
+                    continue;
+                }
+            };
+            match audit_event::try_lock_tiering(&conn) {
+                Ok(true) => {}
+                Ok(false) => {
+                    info!("Bucket tiering is skipped, it's run by another instance");
+                    continue;
+                }
+                Err(err) => {
+                    error!("Error locking bucket tiering: {}", err);
+                    continue;
+                }
+            }
+
+            match rt.block_on(s3.client().list_buckets()) {
+                Ok(buckets) => tier_buckets(
+                    &mut rt, &s3, &audit, &conn, &subject, &rules, buckets, page_size,
+                ),
+                Err(err) => error!("Error listing buckets for tiering: {:#}", err),
+            }
+
+            if let Err(err) = audit_event::unlock_tiering(&conn) {
+                error!("Error unlocking bucket tiering: {}", err);
+            }
+        }
+    });
+}
+
+/// Moves inactive objects of the buckets matching the rules.
+#[allow(clippy::too_many_arguments)]
+fn tier_buckets(
+    rt: &mut tokio::runtime::Runtime,
+    s3: &ClientRouter,
+    audit: &AuditLog,
+    conn: &PgConnection,
+    subject: &AccountId,
+    rules: &[BucketTieringConfig],
+    buckets: Vec<String>,
+    page_size: i64,
+) {
+    for bucket in buckets {
+        let rule = match rules
+            .iter()
+            .find(|rule| wildcard_match(&rule.bucket_pattern, &bucket))
+        {
+            Some(val) => val,
+            None => continue,
+        };
+
+        let cutoff = Utc::now() - Duration::days(i64::from(rule.inactive_days));
+        let accessed = match accessed_keys(conn, &bucket, cutoff) {
+            Ok(Some(val)) => val,
+            Ok(None) => {
+                warn!(
+                            "Bucket tiering is skipped, the audit log doesn't cover {} days yet, bucket = '{}'",
+                            rule.inactive_days, bucket
+                        );
+                continue;
+            }
+            Err(err) => {
+                error!(
+                    "Error reading accesses of objects, bucket = '{}': {:#}",
+                    bucket, err
+                );
+                continue;
+            }
+        };
+
+        let downgrade = downgrade_bucket(
+            s3.client(),
+            audit.clone(),
+            subject.clone(),
+            bucket.clone(),
+            rule.target_class.clone(),
+            cutoff,
+            Arc::new(accessed),
+            page_size,
+        );
+        match rt.block_on(downgrade) {
+            Ok(moved) => info!(
+                "Inactive objects are moved, bucket = '{}', storage_class = '{}', count = {}",
+                bucket, rule.target_class, moved
+            ),
+            Err(err) => error!(
+                "Error moving inactive objects, bucket = '{}': {:#}",
+                bucket, err
+            ),
+        }
+    }
+}
+
+/// Keys of objects of the bucket read since the cutoff,
+/// `None` if the audit log has no events before the cutoff.
+fn accessed_keys(
+    conn: &PgConnection,
+    bucket: &str,
+    cutoff: DateTime<Utc>,
+) -> anyhow::Result<Option<HashSet<String>>> {
+    match audit_event::oldest_created_at(conn).context("failed to read the audit log")? {
+        Some(oldest) if oldest <= cutoff => {}
+        _ => return Ok(None),
+    }
+
+    let keys = audit_event::AccessedQuery::new(bucket, cutoff)
+        .execute(conn)
+        .context("failed to read accesses of objects")?;
+    Ok(Some(keys.into_iter().map(|entry| entry.key).collect()))
+}
+
+/// Objects in `STANDARD` storage class modified and read before the cutoff. Objects are listed
+/// without their storage class by some backends, they're considered to be `STANDARD` ones.
+fn is_inactive(object: &ObjectInfo, cutoff: DateTime<Utc>, accessed: &HashSet<String>) -> bool {
+    let modified_before = object
+        .last_modified
+        .as_ref()
+        .and_then(|value| DateTime::parse_from_rfc3339(value).ok())
+        .map(|value| value.with_timezone(&Utc) < cutoff)
+        .unwrap_or(false);
+
+    object
+        .storage_class
+        .as_deref()
+        .unwrap_or(SOURCE_STORAGE_CLASS)
+        == SOURCE_STORAGE_CLASS
+        && modified_before
+        && !accessed.contains(&object.key)
+}
+
+/// Pages through objects of the bucket moving inactive ones to the storage class,
+/// returns the number of moved objects.
+#[allow(clippy::too_many_arguments)]
+fn downgrade_bucket(
+    s3: Arc<Client>,
+    audit: AuditLog,
+    subject: AccountId,
+    bucket: String,
+    storage_class: String,
+    cutoff: DateTime<Utc>,
+    accessed: Arc<HashSet<String>>,
+    page_size: i64,
+) -> impl Future<Item = usize, Error = anyhow::Error> {
+    future::loop_fn((0, None), move |(moved, continuation_token)| {
+        let (s3, audit, subject, bucket, storage_class, accessed) = (
+            s3.clone(),
+            audit.clone(),
+            subject.clone(),
+            bucket.clone(),
+            storage_class.clone(),
+            accessed.clone(),
+        );
+
+        s3.list_objects(&bucket, page_size, continuation_token)
+            .and_then(move |page| {
+                let next_continuation_token = page.next_continuation_token;
+                let objects = page
+                    .objects
+                    .into_iter()
+                    .filter(|object| is_inactive(object, cutoff, &accessed))
+                    .collect::<Vec<ObjectInfo>>();
+
+                // Objects are moved one at a time, failures are logged and skipped
+                stream::iter_ok(objects)
+                    .and_then(move |object| {
+                        transition(
+                            s3.clone(),
+                            audit.clone(),
+                            subject.clone(),
+                            bucket.clone(),
+                            storage_class.clone(),
+                            object,
+                        )
+                    })
+                    .fold(moved, |acc, is_moved| {
+                        Ok::<_, anyhow::Error>(acc + usize::from(is_moved))
+                    })
+                    .map(move |moved| match next_continuation_token {
+                        Some(token) => future::Loop::Continue((moved, Some(token))),
+                        None => future::Loop::Break(moved),
+                    })
+            })
+    })
+}
+
+/// Moves the object to the storage class unless it's been changed since it's been listed,
+/// returns whether it's moved.
+fn transition(
+    s3: Arc<Client>,
+    audit: AuditLog,
+    subject: AccountId,
+    bucket: String,
+    storage_class: String,
+    object: ObjectInfo,
+) -> impl Future<Item = bool, Error = anyhow::Error> {
+    let ObjectInfo { key, etag, .. } = object;
+
+    s3.transition_object(&bucket, &key, &storage_class, etag)
+        .then(move |result| {
+            let status = match result {
+                Ok(()) => StatusCode::OK,
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let entry = AuditEntry::new(&subject, &bucket, "PUT", "update", status)
+                .object(&key)
+                .operation("tiering");
+            audit.record(&entry, status);
+
+            match result {
+                Ok(()) => {
+                    info!(
+                        "Inactive object is moved, bucket = '{}', object = '{}', storage_class = '{}'",
+                        bucket, key, storage_class
+                    );
+                    Ok(true)
+                }
+                Err(err) => {
+                    error!(
+                        "Error moving an inactive object, bucket = '{}', object = '{}': {:#}",
+                        bucket, key, err
+                    );
+                    Ok(false)
+                }
+            }
+        })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(value: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(value)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn cron_schedule_next() {
+        let schedule = CronSchedule::parse("0 3 * * *").unwrap();
+        assert_eq!(
+            schedule.next_after(time("2020-01-31T02:59:30Z")),
+            Some(time("2020-01-31T03:00:00Z"))
+        );
+        assert_eq!(
+            schedule.next_after(time("2020-12-31T03:00:00Z")),
+            Some(time("2021-01-01T03:00:00Z"))
+        );
+
+        let schedule = CronSchedule::parse("*/15 9-17 * * 1-5").unwrap();
+        // Saturday
+        assert_eq!(
+            schedule.next_after(time("2020-02-01T10:00:00Z")),
+            Some(time("2020-02-03T09:00:00Z"))
+        );
+        assert_eq!(
+            schedule.next_after(time("2020-02-03T09:01:00Z")),
+            Some(time("2020-02-03T09:15:00Z"))
+        );
+
+        // Either the day of month or the day of week, Sunday is 7
+        let schedule = CronSchedule::parse("30 0 1 * 7").unwrap();
+        assert_eq!(
+            schedule.next_after(time("2020-02-03T00:00:00Z")),
+            Some(time("2020-02-09T00:30:00Z"))
+        );
+        assert_eq!(
+            schedule.next_after(time("2020-02-24T00:00:00Z")),
+            Some(time("2020-03-01T00:30:00Z"))
+        );
+
+        let schedule = CronSchedule::parse("0 0 29 2 *").unwrap();
+        assert_eq!(
+            schedule.next_after(time("2021-01-01T00:00:00Z")),
+            Some(time("2024-02-29T00:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn cron_schedule_invalid() {
+        for value in &[
+            "0 3 * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "a * * * *",
+            "0 0 30 2 *",
+        ] {
+            assert!(CronSchedule::parse(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn inactive_objects() {
+        let object = |key: &str, last_modified: &str, storage_class: Option<&str>| ObjectInfo {
+            key: key.to_owned(),
+            size: Some(1),
+            last_modified: Some(last_modified.to_owned()),
+            etag: Some("\"abc\"".to_owned()),
+            storage_class: storage_class.map(ToOwned::to_owned),
+        };
+        let cutoff = time("2020-06-01T00:00:00Z");
+        let accessed = vec!["read".to_owned()].into_iter().collect();
+
+        assert!(is_inactive(
+            &object("stale", "2020-01-01T00:00:00.000Z", Some("STANDARD")),
+            cutoff,
+            &accessed
+        ));
+        assert!(is_inactive(
+            &object("stale", "2020-01-01T00:00:00.000Z", None),
+            cutoff,
+            &accessed
+        ));
+        assert!(!is_inactive(
+            &object("read", "2020-01-01T00:00:00.000Z", Some("STANDARD")),
+            cutoff,
+            &accessed
+        ));
+        assert!(!is_inactive(
+            &object("fresh", "2020-07-01T00:00:00.000Z", Some("STANDARD")),
+            cutoff,
+            &accessed
+        ));
+        assert!(!is_inactive(
+            &object("stale", "2020-01-01T00:00:00.000Z", Some("GLACIER")),
+            cutoff,
+            &accessed
+        ));
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::Context;
+use regex::Regex;
+use serde_json::Value;
+
+use crate::app::config::TransformUrlsConfig;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Object of the backend an URL embedded into a JSON object refers to.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct ObjectUrl {
+    pub(crate) url: String,
+    pub(crate) bucket: String,
+    pub(crate) object: String,
+}
+
+/// Finds URLs of objects of the backend in string values of JSON objects
+/// and replaces them with signed ones.
+#[derive(Debug)]
+pub(crate) struct UrlTransformer {
+    pattern: Regex,
+    max_bytes: usize,
+}
+
+impl UrlTransformer {
+    pub(crate) fn new(config: &TransformUrlsConfig) -> anyhow::Result<Self> {
+        let pattern = Regex::new(&config.pattern).context("invalid url pattern")?;
+        for group in &["bucket", "object"] {
+            if !pattern.capture_names().any(|name| name == Some(*group)) {
+                return Err(anyhow::format_err!(
+                    "url pattern must capture '{}' group",
+                    group
+                ));
+            }
+        }
+
+        Ok(Self {
+            pattern,
+            max_bytes: config.max_bytes,
+        })
+    }
+
+    pub(crate) fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// Distinct URLs of objects among string values of the document, keys aren't transformed.
+    pub(crate) fn object_urls(&self, document: &Value) -> Vec<ObjectUrl> {
+        let mut urls = BTreeSet::new();
+        self.collect(document, &mut urls);
+        urls.into_iter().collect()
+    }
+
+    fn collect(&self, value: &Value, urls: &mut BTreeSet<ObjectUrl>) {
+        match value {
+            Value::String(val) => {
+                if let Some(url) = self.parse(val) {
+                    urls.insert(url);
+                }
+            }
+            Value::Array(values) => values.iter().for_each(|value| self.collect(value, urls)),
+            Value::Object(map) => map.values().for_each(|value| self.collect(value, urls)),
+            _ => (),
+        }
+    }
+
+    fn parse(&self, value: &str) -> Option<ObjectUrl> {
+        let captures = self.pattern.captures(value)?;
+        let decode = |name: &str| {
+            let capture = captures.name(name)?.as_str();
+            url::percent_encoding::percent_decode(capture.as_bytes())
+                .decode_utf8()
+                .ok()
+                .map(|val| val.into_owned())
+        };
+
+        Some(ObjectUrl {
+            url: value.to_owned(),
+            bucket: decode("bucket")?,
+            object: decode("object")?,
+        })
+    }
+}
+
+/// Replaces string values of the document equal to the keys of replacements.
+pub(crate) fn replace_urls(value: &mut Value, replacements: &HashMap<String, String>) {
+    match value {
+        Value::String(val) => {
+            if let Some(replacement) = replacements.get(val.as_str()) {
+                *val = replacement.to_owned();
+            }
+        }
+        Value::Array(values) => values
+            .iter_mut()
+            .for_each(|value| replace_urls(value, replacements)),
+        Value::Object(map) => map
+            .values_mut()
+            .for_each(|value| replace_urls(value, replacements)),
+        _ => (),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn object_urls_of_document() {
+        let transformer = UrlTransformer::new(&TransformUrlsConfig::default()).unwrap();
+        let document = serde_json::json!({
+            "poster": "https://s3.eu-west-1.amazonaws.com/media.example.org/posters/1.jpg",
+            "https://s3.amazonaws.com/media.example.org/keys": "keys aren't transformed",
+            "tracks": [
+                {"url": "https://s3-eu-west-1.amazonaws.com/media.example.org/tracks/a%20b.mp4?versionId=1"},
+                {"url": "https://s3.eu-west-1.amazonaws.com/media.example.org/posters/1.jpg"},
+                {"url": "https://cdn.example.org/media.example.org/tracks/c.mp4"},
+            ],
+            "duration": 10,
+        });
+
+        let urls = transformer.object_urls(&document);
+        assert_eq!(
+            urls,
+            vec![
+                ObjectUrl {
+                    url: "https://s3-eu-west-1.amazonaws.com/media.example.org/tracks/a%20b.mp4?versionId=1".into(),
+                    bucket: "media.example.org".into(),
+                    object: "tracks/a b.mp4".into(),
+                },
+                ObjectUrl {
+                    url: "https://s3.eu-west-1.amazonaws.com/media.example.org/posters/1.jpg".into(),
+                    bucket: "media.example.org".into(),
+                    object: "posters/1.jpg".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn replace_urls_of_document() {
+        let mut document = serde_json::json!({
+            "poster": "https://s3.amazonaws.com/media.example.org/1.jpg",
+            "tracks": ["https://s3.amazonaws.com/media.example.org/1.jpg", "https://s3.amazonaws.com/media.example.org/2.mp4"],
+        });
+        let mut replacements = HashMap::new();
+        replacements.insert(
+            "https://s3.amazonaws.com/media.example.org/1.jpg".to_owned(),
+            "https://signed.example.org/1.jpg".to_owned(),
+        );
+
+        replace_urls(&mut document, &replacements);
+        assert_eq!(
+            document,
+            serde_json::json!({
+                "poster": "https://signed.example.org/1.jpg",
+                "tracks": ["https://signed.example.org/1.jpg", "https://s3.amazonaws.com/media.example.org/2.mp4"],
+            })
+        );
+    }
+
+    #[test]
+    fn new_requires_groups() {
+        let config = |pattern: &str| TransformUrlsConfig {
+            pattern: pattern.to_owned(),
+            ..TransformUrlsConfig::default()
+        };
+
+        assert!(UrlTransformer::new(&config(r"^https://(?P<bucket>[^/]+)/(?P<object>.+)")).is_ok());
+        assert!(UrlTransformer::new(&config(r"^https://(?P<bucket>[^/]+)/")).is_err());
+        assert!(UrlTransformer::new(&config(r"^https://(")).is_err());
+    }
+}
@@ -1,17 +1,48 @@
-use anyhow::format_err;
+use anyhow::{format_err, Context};
+use bytes::Bytes;
+use futures::future::Shared;
+use futures::{future, stream, Async, Future, Stream};
+use linked_hash_map::LinkedHashMap;
+use log::{error, warn};
+use r2d2_redis::{r2d2, redis, RedisConnectionManager};
 use radix_trie::Trie;
-use std::collections::BTreeMap;
+use rusoto_core::credential::{
+    AutoRefreshingProvider, AwsCredentials, ProvideAwsCredentials, StaticProvider,
+};
+use rusoto_core::{HttpClient, Region};
+use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use svc_authn::{AccountId, Authenticable};
+use url::Url;
 
+use crate::app::api_keys::ApiKeyStore;
+use crate::app::authz::IntentSubject;
+use crate::app::config::{
+    AudienceS3Credentials, AuthnConfig, AuthnFallbackConfig, AuthzPrewarmEntry, BucketLimitConfig,
+    BucketQuotaConfig, BucketsConfig, CachePolicy, CachePolicyConfig, MultitenancyConfig,
+    RateLimitConfig, RouteConfig, S3Config,
+};
+use crate::app::delegation::DelegationGrant;
+use crate::app::gateway::HttpBody;
 use crate::db::{Bucket, Set};
-use crate::s3::Client;
+use crate::s3::{
+    CircuitBreakerConfig, Client, ClientRouter, FailoverHook, GcsCredentials, ObjectVersion,
+    RequestV2, S3Provider, SignatureVersion, UrlStyle,
+};
 use crate::tower_web::Error;
 
 ////////////////////////////////////////////////////////////////////////////////
 
 pub(crate) const S3_DEFAULT_CLIENT: &str = "default";
-pub(crate) type S3Clients = BTreeMap<String, ::std::sync::Arc<crate::s3::Client>>;
+pub(crate) type S3Clients = BTreeMap<String, ::std::sync::Arc<crate::s3::ClientRouter>>;
 
 ////////////////////////////////////////////////////////////////////////////////
 
@@ -34,8 +65,22 @@ impl AltBackendConfig {
 
 ////////////////////////////////////////////////////////////////////////////////
 
-pub(crate) fn read_s3_config(config: Option<&BackendConfig>) -> anyhow::Result<S3Clients> {
+pub(crate) fn read_s3_config(
+    config: Option<&BackendConfig>,
+    buckets: &BucketsConfig,
+    s3_config: &S3Config,
+    circuit_breaker: Option<&CircuitBreakerConfig>,
+    failover_hook: Option<&FailoverHook>,
+) -> anyhow::Result<S3Clients> {
     let mut acc = S3Clients::new();
+    let gcs_credentials = s3_config.gcs_credentials()?;
+    let settings = S3ClientSettings {
+        buckets,
+        s3_config,
+        gcs_credentials: gcs_credentials.as_ref(),
+        circuit_breaker,
+        failover_hook,
+    };
 
     if let Some(back) = config {
         read_s3(
@@ -44,17 +89,25 @@ pub(crate) fn read_s3_config(config: Option<&BackendConfig>) -> anyhow::Result<S
             back.alt
                 .get(&back.default)
                 .ok_or_else(|| format_err!("Missing default backend configuration"))?,
+            &settings,
             &mut acc,
         );
 
         for (back, config) in back.alt.iter() {
-            read_s3(back, &format!("{}_", back.to_uppercase()), config, &mut acc);
+            read_s3(
+                back,
+                &format!("{}_", back.to_uppercase()),
+                config,
+                &settings,
+                &mut acc,
+            );
         }
     } else {
         read_s3(
             &String::from(S3_DEFAULT_CLIENT),
             "",
             &AltBackendConfig::new(),
+            &settings,
             &mut acc,
         );
     }
@@ -62,7 +115,22 @@ pub(crate) fn read_s3_config(config: Option<&BackendConfig>) -> anyhow::Result<S
     Ok(acc)
 }
 
-fn read_s3(back: &str, prefix: &str, alt: &AltBackendConfig, acc: &mut S3Clients) {
+/// Settings shared by clients of all backends and their standbys.
+struct S3ClientSettings<'a> {
+    buckets: &'a BucketsConfig,
+    s3_config: &'a S3Config,
+    gcs_credentials: Option<&'a GcsCredentials>,
+    circuit_breaker: Option<&'a CircuitBreakerConfig>,
+    failover_hook: Option<&'a FailoverHook>,
+}
+
+fn read_s3(
+    back: &str,
+    prefix: &str,
+    alt: &AltBackendConfig,
+    settings: &S3ClientSettings,
+    acc: &mut S3Clients,
+) {
     use std::env::var;
     let key = var(&format!("{}AWS_ACCESS_KEY_ID", prefix))
         .unwrap_or_else(|_| panic!("{}AWS_ACCESS_KEY_ID must be specified", prefix));
@@ -84,18 +152,81 @@ fn read_s3(back: &str, prefix: &str, alt: &AltBackendConfig, acc: &mut S3Clients
     if let Some(ref proxy_host) = alt.proxy_host {
         client.set_proxy_host(proxy_host);
     }
+    configure_s3(&mut client, settings);
+
+    let standbys = settings
+        .s3_config
+        .standby_backends(back)
+        .map(|standby| {
+            let mut client = crate::s3::Client::new(
+                &standby.access_key_id,
+                &standby.secret_access_key,
+                &standby.region,
+                &standby.endpoint,
+                ::std::time::Duration::from_secs(300),
+            );
+            if let Some(ref proxy_host) = standby.proxy_host {
+                client.set_proxy_host(proxy_host);
+            }
+            configure_s3(&mut client, settings);
+            client
+        })
+        .collect::<Vec<_>>();
+
+    let mut router = ClientRouter::new(back, client);
+    if !standbys.is_empty() {
+        router.set_standbys(standbys, settings.s3_config.failback_after());
+    }
+    if let Some(hook) = settings.failover_hook {
+        router.set_failover_hook(hook.clone());
+    }
+
+    acc.insert(back.to_owned(), ::std::sync::Arc::new(router));
+}
 
-    acc.insert(back.to_owned(), ::std::sync::Arc::new(client));
+fn configure_s3(client: &mut crate::s3::Client, settings: &S3ClientSettings) {
+    let s3_config = settings.s3_config;
+    client.set_bucket_affixes(&settings.buckets.name_prefix, &settings.buckets.name_suffix);
+    client.set_timeouts(s3_config.operation_timeouts());
+    if let Some(ref user_agent) = s3_config.user_agent {
+        client.set_user_agent(user_agent);
+    }
+    client.set_cross_account_roles(&s3_config.cross_account_roles);
+    client.set_outposts(&s3_config.outposts);
+    client.set_provider(s3_config.provider);
+    if let Some(credentials) = settings.gcs_credentials {
+        client.set_gcs_credentials(credentials.clone());
+    }
+    if let Some(circuit_breaker) = settings.circuit_breaker {
+        client.set_circuit_breaker(circuit_breaker);
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+/// Signed URI along with the method and the headers the request must be sent with,
+/// since they're part of the signature.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SignedUri {
+    pub(crate) uri: String,
+    pub(crate) method: String,
+    pub(crate) headers: BTreeMap<String, String>,
+    pub(crate) expires_at: chrono::DateTime<chrono::Utc>,
+}
+
 #[derive(Debug)]
 pub(crate) struct S3SignedRequestBuilder {
     method: Option<String>,
     bucket: Option<String>,
     object: Option<String>,
     headers: BTreeMap<String, String>,
+    params: BTreeMap<String, String>,
+    upload_part: Option<(String, u32)>,
+    credentials: Option<AwsCredentials>,
+    signature_version: Option<SignatureVersion>,
+    url_style: Option<UrlStyle>,
+    transfer_acceleration: bool,
+    config: Option<Arc<S3Config>>,
 }
 
 impl S3SignedRequestBuilder {
@@ -105,6 +236,20 @@ impl S3SignedRequestBuilder {
             bucket: None,
             object: None,
             headers: BTreeMap::new(),
+            params: BTreeMap::new(),
+            upload_part: None,
+            credentials: None,
+            signature_version: None,
+            url_style: None,
+            transfer_acceleration: false,
+            config: None,
+        }
+    }
+
+    pub(crate) fn config(self, value: Arc<S3Config>) -> Self {
+        Self {
+            config: Some(value),
+            ..self
         }
     }
 
@@ -135,7 +280,60 @@ impl S3SignedRequestBuilder {
         Self { headers, ..self }
     }
 
-    pub(crate) fn build(self, client: &Client) -> Result<String, Error> {
+    /// Adds a query parameter, subresources like `restore` have an empty value.
+    pub(crate) fn add_param(self, key: &str, value: &str) -> Self {
+        let mut params = self.params;
+        params.insert(key.to_string(), value.to_string());
+        Self { params, ..self }
+    }
+
+    /// Signs the request for the object starting from the byte offset, the `range` header
+    /// must be sent with the request since it's part of the signature.
+    pub(crate) fn range_from(self, byte_offset: u64) -> Self {
+        self.add_header("range", &format!("bytes={}-", byte_offset))
+    }
+
+    /// Signs a part of the multipart upload instead of the whole object.
+    pub(crate) fn upload_part(self, upload_id: &str, part_number: u32) -> Self {
+        Self {
+            upload_part: Some((upload_id.to_string(), part_number)),
+            ..self
+        }
+    }
+
+    /// Signs the request with the credentials instead of the ones of the backend.
+    pub(crate) fn credentials(self, value: AwsCredentials) -> Self {
+        Self {
+            credentials: Some(value),
+            ..self
+        }
+    }
+
+    /// Overrides the signature version of the configuration.
+    pub(crate) fn signature_version(self, value: SignatureVersion) -> Self {
+        Self {
+            signature_version: Some(value),
+            ..self
+        }
+    }
+
+    /// Overrides the URI style of the configuration.
+    pub(crate) fn url_style(self, value: UrlStyle) -> Self {
+        Self {
+            url_style: Some(value),
+            ..self
+        }
+    }
+
+    /// Signs the request to the Transfer Acceleration endpoint of the bucket.
+    pub(crate) fn transfer_acceleration(self, value: bool) -> Self {
+        Self {
+            transfer_acceleration: value,
+            ..self
+        }
+    }
+
+    pub(crate) fn build(self, client: &Client) -> Result<SignedUri, Error> {
         let unproc_error = || {
             Error::builder()
                 .kind(
@@ -145,24 +343,496 @@ impl S3SignedRequestBuilder {
                 .status(http::StatusCode::INTERNAL_SERVER_ERROR)
         };
 
-        let mut req = client.create_request(
-            &self
-                .method
-                .ok_or_else(|| unproc_error().detail("missing method").build())?,
-            &self
-                .bucket
-                .ok_or_else(|| unproc_error().detail("missing bucket").build())?,
-            &self
-                .object
-                .ok_or_else(|| unproc_error().detail("missing object").build())?,
-        );
-        for (key, val) in self.headers {
-            req.add_header(&key, &val);
+        let method = self
+            .method
+            .ok_or_else(|| unproc_error().detail("missing method").build())?;
+        let bucket = self
+            .bucket
+            .ok_or_else(|| unproc_error().detail("missing bucket").build())?;
+        let object = self
+            .object
+            .ok_or_else(|| unproc_error().detail("missing object").build())?;
+        let mut headers = self.headers;
+        let mut signature_version = self.signature_version.unwrap_or_default();
+        let mut url_style = self.url_style.unwrap_or_default();
+        if let Some(config) = self.config {
+            config.apply_metadata_policy(&method, &mut headers);
+            // Parts of multipart uploads are stored in the class chosen on initiating the upload
+            if self.upload_part.is_none() {
+                config.apply_storage_tier(&method, &bucket, &object, &mut headers);
+            }
+            signature_version = self.signature_version.unwrap_or(config.signature_version);
+            url_style = self.url_style.unwrap_or(config.url_style);
+        }
+        let expires_at =
+            chrono::Utc::now() + chrono::Duration::seconds(client.expires_in().as_secs() as i64);
+        let signed = |uri: String, method: String, headers: BTreeMap<String, String>| SignedUri {
+            uri,
+            method,
+            headers,
+            expires_at,
+        };
+
+        if client.outpost(&bucket).is_some()
+            && (signature_version == SignatureVersion::V2 || self.transfer_acceleration)
+        {
+            return Err(unproc_error()
+                .detail("buckets on Outposts require v4 signature version without transfer acceleration")
+                .build());
+        }
+
+        if client.provider() == S3Provider::Gcs {
+            if signature_version == SignatureVersion::V2 || self.transfer_acceleration {
+                return Err(unproc_error()
+                    .detail(
+                        "gcs provider requires v4 signature version without transfer acceleration",
+                    )
+                    .build());
+            }
+            if self.credentials.is_some() {
+                return Err(unproc_error()
+                    .detail("gcs provider doesn't support credentials of cross account roles")
+                    .build());
+            }
+
+            let mut params = self.params;
+            if let Some((upload_id, part_number)) = self.upload_part {
+                params.insert("uploadId".to_string(), upload_id);
+                params.insert("partNumber".to_string(), part_number.to_string());
+            }
+            let req = RequestV2 {
+                method,
+                bucket,
+                object,
+                headers,
+                params,
+                url_style,
+            };
+
+            return client
+                .sign_request_gcs(&req)
+                .map(|uri| signed(uri, req.method, req.headers))
+                .map_err(|err| unproc_error().detail(&err.to_string()).build());
+        }
+
+        if signature_version == SignatureVersion::V2 {
+            if self.transfer_acceleration {
+                return Err(unproc_error()
+                    .detail("transfer acceleration requires v4 signature version")
+                    .build());
+            }
+
+            let mut params = self.params;
+            if let Some((upload_id, part_number)) = self.upload_part {
+                params.insert("uploadId".to_string(), upload_id);
+                params.insert("partNumber".to_string(), part_number.to_string());
+            }
+            let req = RequestV2 {
+                method,
+                bucket,
+                object,
+                headers,
+                params,
+                url_style,
+            };
+
+            return client
+                .sign_request_v2(&req, self.credentials.as_ref())
+                .map(|uri| signed(uri, req.method, req.headers))
+                .map_err(|err| unproc_error().detail(&err.to_string()).build());
+        }
+
+        let mut req = if self.transfer_acceleration {
+            client.create_accelerated_request(&method, &bucket, &object)
+        } else if url_style == UrlStyle::VirtualHosted {
+            client.create_virtual_hosted_request(&method, &bucket, &object)
+        } else {
+            client.create_request(&method, &bucket, &object)
+        };
+        for (key, val) in &headers {
+            req.add_header(key, val);
+        }
+        for (key, val) in self.params {
+            req.add_param(key, val);
+        }
+        if let Some((upload_id, part_number)) = self.upload_part {
+            req.add_param("uploadId".to_string(), upload_id);
+            req.add_param("partNumber".to_string(), part_number.to_string());
+        }
+
+        if self.transfer_acceleration {
+            let uri = client.sign_accelerated_request(&mut req, self.credentials.as_ref());
+            return Ok(signed(uri, method, headers));
+        }
+
+        match self.credentials {
+            Some(ref credentials) => client.sign_request_with(&mut req, credentials),
+            None => client.sign_request(&mut req),
+        }
+        .map(|uri| signed(uri, method, headers))
+        .map_err(|err| unproc_error().detail(&err.to_string()).build())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+const TRANSFER_ACCELERATION_STATUS_TTL: Duration = Duration::from_secs(300);
+
+/// Transfer Acceleration statuses of buckets retrieved from backends,
+/// each of them expires after 5 minutes.
+#[derive(Debug, Default)]
+pub(crate) struct TransferAcceleration {
+    statuses: Mutex<HashMap<String, (Instant, bool)>>,
+}
+
+impl TransferAcceleration {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fails unless Transfer Acceleration is enabled for the bucket on the backend.
+    pub(crate) fn verify(
+        self: &Arc<Self>,
+        s3: &Client,
+        back: &str,
+        bucket: &str,
+    ) -> Box<dyn Future<Item = (), Error = anyhow::Error> + Send> {
+        let key = format!("{}\n{}", back, s3.bucket_name(bucket));
+        let name = bucket.to_owned();
+        let check = move |enabled: bool| {
+            if enabled {
+                Ok(())
+            } else {
+                Err(format_err!(
+                    "transfer acceleration isn't enabled for the bucket = '{}'",
+                    name
+                ))
+            }
+        };
+
+        let cached = {
+            let mut statuses = self
+                .statuses
+                .lock()
+                .expect("Transfer acceleration lock is poisoned");
+            statuses.retain(|_, (checked_at, _)| {
+                checked_at.elapsed() < TRANSFER_ACCELERATION_STATUS_TTL
+            });
+            statuses.get(&key).map(|(_, enabled)| *enabled)
+        };
+        if let Some(enabled) = cached {
+            return Box::new(future::result(check(enabled)));
+        }
+
+        let this = self.clone();
+        Box::new(
+            s3.transfer_acceleration_enabled(bucket)
+                .and_then(move |enabled| {
+                    this.statuses
+                        .lock()
+                        .expect("Transfer acceleration lock is poisoned")
+                        .insert(key, (Instant::now(), enabled));
+                    check(enabled)
+                }),
+        )
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Default)]
+struct BucketUsage {
+    bytes: u64,
+    updated_at: Option<Instant>,
+    refreshing: bool,
+}
+
+/// Quotas of buckets, the first entry with the bucket pattern matching the bucket is applied.
+///
+/// Usage of buckets is never retrieved while checking a quota: the cached one is used,
+/// and if it's older than the ttl of the quota, it's refreshed in background by summing sizes
+/// of all objects of the bucket. Until usage of the bucket is retrieved, uploads are admitted.
+#[derive(Debug)]
+pub(crate) struct BucketQuotas {
+    quotas: Vec<BucketQuotaConfig>,
+    usage: Arc<Mutex<HashMap<String, BucketUsage>>>,
+}
+
+impl BucketQuotas {
+    pub(crate) fn new(quotas: &[BucketQuotaConfig]) -> Self {
+        Self {
+            quotas: quotas.to_vec(),
+            usage: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Admits an upload of `size` bytes unless the bucket would exceed its quota,
+    /// the size is added to the cached usage of the bucket until it's refreshed.
+    pub(crate) fn admit(
+        &self,
+        s3: &Client,
+        back: &str,
+        bucket: &str,
+        size: u64,
+    ) -> anyhow::Result<()> {
+        let quota = match self
+            .quotas
+            .iter()
+            .find(|quota| wildcard_match(&quota.bucket_pattern, bucket))
+        {
+            Some(val) => val,
+            None => return Ok(()),
+        };
+
+        // Quotas apply to each of the tenants separately
+        let key = format!("{}\n{}", back, s3.bucket_name(bucket));
+        let mut usage = self.usage.lock().expect("Bucket usage lock is poisoned");
+        let entry = usage.entry(key.clone()).or_default();
+
+        let ttl = Duration::from_secs(quota.usage_ttl_secs);
+        let is_stale = entry
+            .updated_at
+            .map(|updated_at| updated_at.elapsed() >= ttl)
+            .unwrap_or(true);
+        if is_stale && !entry.refreshing {
+            entry.refreshing = true;
+            self.refresh(s3, bucket, key);
+        }
+
+        if entry.updated_at.is_none() {
+            return Ok(());
+        }
+
+        let total = entry.bytes.saturating_add(size);
+        if total > quota.max_total_bytes {
+            return Err(format_err!(
+                "the upload of {} bytes would exceed the quota of the bucket = '{}': {} of {} bytes are used",
+                size,
+                bucket,
+                entry.bytes,
+                quota.max_total_bytes
+            ));
+        }
+
+        entry.bytes = total;
+        Ok(())
+    }
+
+    fn refresh(&self, s3: &Client, bucket: &str, key: String) {
+        let usage = self.usage.clone();
+        let bucket = bucket.to_owned();
+        tokio::spawn(s3.bucket_usage(&bucket).then(move |result| {
+            let mut usage = usage.lock().expect("Bucket usage lock is poisoned");
+            let entry = usage.entry(key).or_default();
+            entry.refreshing = false;
+            match result {
+                Ok(bytes) => {
+                    entry.bytes = bytes;
+                    entry.updated_at = Some(Instant::now());
+                }
+                Err(err) => error!(
+                    "Error retrieving usage of the bucket = '{}': {:#}",
+                    bucket, err
+                ),
+            }
+
+            Ok(())
+        }));
+    }
+}
+
+#[derive(Debug, Default)]
+struct BucketObjectCount {
+    count: u64,
+    updated_at: Option<Instant>,
+    refreshing: bool,
+}
+
+/// Limits of the number of objects of buckets, the first entry with the bucket pattern matching
+/// the bucket is applied.
+///
+/// As with quotas, the count is never retrieved while checking a limit: the cached one is used
+/// and refreshed in background once it's older than the ttl of the limit. Until the count
+/// of the bucket is retrieved, uploads are admitted.
+#[derive(Debug)]
+pub(crate) struct BucketLimits {
+    limits: Vec<BucketLimitConfig>,
+    counts: Arc<Mutex<HashMap<String, BucketObjectCount>>>,
+}
+
+impl BucketLimits {
+    pub(crate) fn new(limits: &[BucketLimitConfig]) -> Self {
+        Self {
+            limits: limits.to_vec(),
+            counts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Admits an upload unless the bucket has reached its limit, the upload is added
+    /// to the cached count of the bucket until it's refreshed.
+    pub(crate) fn admit(&self, s3: &Client, back: &str, bucket: &str) -> anyhow::Result<()> {
+        let limit = match self
+            .limits
+            .iter()
+            .find(|limit| wildcard_match(&limit.bucket_pattern, bucket))
+        {
+            Some(val) => val,
+            None => return Ok(()),
+        };
+
+        // Limits apply to each of the tenants separately
+        let key = format!("{}\n{}", back, s3.bucket_name(bucket));
+        let mut counts = self
+            .counts
+            .lock()
+            .expect("Bucket object counts lock is poisoned");
+        let entry = counts.entry(key.clone()).or_default();
+
+        let ttl = Duration::from_secs(limit.count_ttl_secs);
+        let is_stale = entry
+            .updated_at
+            .map(|updated_at| updated_at.elapsed() >= ttl)
+            .unwrap_or(true);
+        if is_stale && !entry.refreshing {
+            entry.refreshing = true;
+            self.refresh(s3, bucket, key);
+        }
+
+        if entry.updated_at.is_none() {
+            return Ok(());
+        }
+
+        if entry.count >= limit.max_objects {
+            return Err(format_err!("Bucket object limit reached"));
+        }
+
+        entry.count += 1;
+        Ok(())
+    }
+
+    fn refresh(&self, s3: &Client, bucket: &str, key: String) {
+        let counts = self.counts.clone();
+        let bucket = bucket.to_owned();
+        tokio::spawn(s3.bucket_object_count(&bucket).then(move |result| {
+            let mut counts = counts
+                .lock()
+                .expect("Bucket object counts lock is poisoned");
+            let entry = counts.entry(key).or_default();
+            entry.refreshing = false;
+            match result {
+                Ok(count) => {
+                    entry.count = count;
+                    entry.updated_at = Some(Instant::now());
+                }
+                Err(err) => error!(
+                    "Error retrieving the object count of the bucket = '{}': {:#}",
+                    bucket, err
+                ),
+            }
+
+            Ok(())
+        }));
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+const STS_SESSION_NAME: &str = "storage";
+
+pub(crate) type CredentialsFuture =
+    Box<dyn Future<Item = Option<AwsCredentials>, Error = anyhow::Error> + Send>;
+
+enum CredentialsSource {
+    Static(AwsCredentials),
+    Role(Arc<AutoRefreshingProvider<StsAssumeRoleSessionCredentialsProvider>>),
+}
+
+/// Credentials used for signing requests to buckets of particular audiences,
+/// the first entry with the audience pattern matching the audience of the bucket is used.
+pub(crate) struct AudienceCredentials {
+    inner: Vec<(String, CredentialsSource)>,
+}
+
+impl fmt::Debug for AudienceCredentials {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_list()
+            .entries(self.inner.iter().map(|(pattern, _)| pattern))
+            .finish()
+    }
+}
+
+impl AudienceCredentials {
+    pub(crate) fn new(config: &[AudienceS3Credentials]) -> anyhow::Result<Self> {
+        let mut inner = Vec::with_capacity(config.len());
+
+        for entry in config {
+            let source = match entry.role_arn {
+                None => CredentialsSource::Static(AwsCredentials::new(
+                    entry.access_key_id.clone(),
+                    entry.secret_access_key.clone(),
+                    None,
+                    None,
+                )),
+                Some(ref role_arn) => {
+                    let region = match entry.region {
+                        Some(ref region) => region.parse::<Region>().map_err(|err| {
+                            format_err!("invalid sts region = '{}': {}", region, err)
+                        })?,
+                        None => Region::UsEast1,
+                    };
+                    let client = StsClient::new_with(
+                        HttpClient::new().context("failed to create an HTTP client for STS")?,
+                        StaticProvider::new(
+                            entry.access_key_id.clone(),
+                            entry.secret_access_key.clone(),
+                            None,
+                            None,
+                        ),
+                        region,
+                    );
+                    let provider = StsAssumeRoleSessionCredentialsProvider::new(
+                        client,
+                        role_arn.to_owned(),
+                        STS_SESSION_NAME.to_owned(),
+                        None,
+                        None,
+                        None,
+                        None,
+                    );
+                    CredentialsSource::Role(Arc::new(
+                        AutoRefreshingProvider::new(provider)
+                            .context("failed to create an STS credentials provider")?,
+                    ))
+                }
+            };
+
+            inner.push((entry.audience.clone(), source));
         }
 
-        client
-            .sign_request(&mut req)
-            .map_err(|err| unproc_error().detail(&err.to_string()).build())
+        Ok(Self { inner })
+    }
+
+    /// Resolves credentials for the audience, `None` if the ones of the backend should be used.
+    /// Session credentials of the assumed role are cached until they expire.
+    pub(crate) fn resolve(&self, audience: &str) -> CredentialsFuture {
+        let source = self
+            .inner
+            .iter()
+            .find(|(pattern, _)| wildcard_match(pattern, audience))
+            .map(|(_, source)| source);
+
+        match source {
+            None => Box::new(future::ok(None)),
+            Some(CredentialsSource::Static(credentials)) => {
+                Box::new(future::ok(Some(credentials.clone())))
+            }
+            Some(CredentialsSource::Role(provider)) => Box::new(
+                provider
+                    .credentials()
+                    .map(Some)
+                    .map_err(|err| anyhow::Error::from(err).context("failed to assume role")),
+            ),
+        }
     }
 }
 
@@ -237,122 +907,2162 @@ impl AudienceEstimator {
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
-pub struct Subject {
-    inner: AccountId,
+const COMPRESSIONS: &[(&str, &str)] = &[("gzip", "gzip"), ("brotli", "br")];
+
+/// Returns the `Content-Encoding` header value for the compression.
+pub(crate) fn content_encoding(compression: &str) -> anyhow::Result<&'static str> {
+    COMPRESSIONS
+        .iter()
+        .find(|(name, _)| *name == compression)
+        .map(|(_, encoding)| *encoding)
+        .ok_or_else(|| {
+            format_err!(
+                "invalid compression = '{}', expected one of: gzip, brotli",
+                compression
+            )
+        })
 }
 
-impl Subject {
-    pub fn new(inner: AccountId) -> Self {
-        Self { inner }
+pub(crate) fn compress(data: &[u8], compression: &str) -> anyhow::Result<Vec<u8>> {
+    use std::io::Write;
+
+    match content_encoding(compression)? {
+        "gzip" => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?)
+        }
+        _ => {
+            let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 9, 22);
+            encoder.write_all(data)?;
+            Ok(encoder.into_inner())
+        }
     }
 }
 
-impl Deref for Subject {
-    type Target = AccountId;
+/// Compresses the data on a thread of the pool allowed to block, so that other requests
+/// of the worker aren't held up, and in place outside of the pool, e.g. in tests.
+pub(crate) fn compress_blocking(
+    data: Vec<u8>,
+    compression: String,
+) -> impl Future<Item = Vec<u8>, Error = anyhow::Error> {
+    future::poll_fn(
+        move || match tokio_threadpool::blocking(|| compress(&data, &compression)) {
+            Ok(Async::Ready(result)) => result.map(Async::Ready),
+            // Every thread allowed to block is busy, the data is compressed once one is free
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => compress(&data, &compression).map(Async::Ready),
+        },
+    )
+}
 
-    fn deref(&self) -> &AccountId {
-        &self.inner
-    }
+////////////////////////////////////////////////////////////////////////////////
+
+/// Body of `text/event-stream` responses.
+pub(crate) struct EventStream {
+    inner: Box<dyn Stream<Item = Bytes, Error = ()> + Send>,
 }
 
-impl Authenticable for Subject {
-    fn as_account_id(&self) -> &AccountId {
-        &self.inner
+impl fmt::Debug for EventStream {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("EventStream").finish()
     }
 }
 
-////////////////////////////////////////////////////////////////////////////////
+/// Body of a request passed on to the backend as it's received.
+pub(crate) struct UploadStream {
+    inner: Box<dyn Stream<Item = Bytes, Error = anyhow::Error> + Send>,
+}
 
-mod jose {
-    use svc_authn::jose::Claims;
+impl UploadStream {
+    /// Streams the body of the HTTP listener, it fails once the body exceeds the limit.
+    fn streamed(mut body: HttpBody, max_size: usize) -> Self {
+        use ::tower_web::util::BufStream;
 
-    use super::Subject;
-    use svc_authn::AccountId;
+        let mut size = 0;
+        let inner = stream::poll_fn(move || {
+            let chunk = match BufStream::poll(&mut body)? {
+                Async::Ready(Some(chunk)) => Bytes::from(chunk),
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+            size += chunk.len();
+            if size > max_size {
+                return Err(format_err!(
+                    "request body must be at most {} bytes",
+                    max_size
+                ));
+            }
+            Ok(Async::Ready(Some(chunk)))
+        });
 
-    impl From<Claims<String>> for Subject {
-        fn from(value: Claims<String>) -> Self {
-            Self::new(AccountId::new(value.subject(), value.audience()))
+        Self {
+            inner: Box::new(inner),
         }
     }
+
+    pub(crate) fn into_inner(self) -> Box<dyn Stream<Item = Bytes, Error = anyhow::Error> + Send> {
+        self.inner
+    }
 }
 
-////////////////////////////////////////////////////////////////////////////////
+impl From<Vec<Bytes>> for UploadStream {
+    fn from(chunks: Vec<Bytes>) -> Self {
+        Self {
+            inner: Box::new(stream::iter_ok(chunks)),
+        }
+    }
+}
 
-mod tower_web {
-    use super::{S3SignedRequestBuilder, Subject};
+impl fmt::Debug for UploadStream {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("UploadStream").finish()
+    }
+}
 
-    mod extract {
-        use http::StatusCode;
-        use tower_web::extract::{Context, Error, Extract, Immediate};
-        use tower_web::util::BufStream;
+/// Body of a request compressed by the application before it's uploaded.
+pub(crate) struct UploadData {
+    data: Vec<u8>,
+}
 
-        use svc_authn::token::jws_compact::extract::{
-            decode_jws_compact_with_config, extract_jws_compact,
-        };
-        use svc_authn::AccountId;
+impl UploadData {
+    pub(crate) fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
 
-        use crate::app::config::Config;
+impl From<Vec<Bytes>> for UploadData {
+    fn from(chunks: Vec<Bytes>) -> Self {
+        Self {
+            data: chunks.concat(),
+        }
+    }
+}
 
-        use super::{S3SignedRequestBuilder, Subject};
+impl fmt::Debug for UploadData {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("UploadData")
+            .field("size", &self.data.len())
+            .finish()
+    }
+}
 
-        impl<B: BufStream> Extract<B> for S3SignedRequestBuilder {
-            type Future = Immediate<S3SignedRequestBuilder>;
+/// Polls the object and streams `created`, `deleted` and `modified` events
+/// until `max_duration` elapses.
+pub(crate) fn watch_object(
+    s3: Arc<Client>,
+    bucket: String,
+    object: String,
+    poll_interval: Duration,
+    max_duration: Duration,
+) -> EventStream {
+    use tokio::timer::Interval;
 
-            fn extract(context: &Context) -> Self::Future {
-                let mut builder = S3SignedRequestBuilder::new();
-                let headers = context.request().headers();
-                for (key, val) in headers {
-                    match val.to_str() {
-                        Ok(val) => builder = builder.add_header(key.as_str(), val),
-                        Err(err) => return Immediate::err(Error::invalid_argument(&err)),
-                    }
+    let deadline = Instant::now() + max_duration;
+    let retry = format!("retry: {}\n\n", poll_interval.as_millis());
+    let name = object.clone();
+    let mut last: Option<Option<ObjectVersion>> = None;
+
+    let events = Interval::new(Instant::now(), poll_interval)
+        .map_err(|err| warn!("Object events timer failed: {}", err))
+        .take_while(move |_| Ok(Instant::now() < deadline))
+        .and_then(move |_| s3.head_object(&bucket, &object).then(Ok))
+        .map(move |result| {
+            let event = match result {
+                Ok(version) => {
+                    let event = last
+                        .as_ref()
+                        .and_then(|prev| object_event(prev.as_ref(), version.as_ref()));
+                    last = Some(version);
+                    event
                 }
-                Immediate::ok(builder)
+                Err(err) => {
+                    warn!("Error polling the object = '{}': {:#}", &name, err);
+                    None
+                }
+            };
+
+            match event {
+                Some(event) => format!("event: {}\ndata: {}\n\n", event, &name),
+                None => String::from(": keep-alive\n\n"),
             }
-        }
+        });
 
-        impl<B: BufStream> Extract<B> for Subject {
-            type Future = Immediate<Subject>;
+    EventStream {
+        inner: Box::new(stream::once(Ok(retry)).chain(events).map(Bytes::from)),
+    }
+}
 
-            fn extract(context: &Context) -> Self::Future {
-                let config = context.config::<Config>().expect("missing config");
-                let h = context.request().headers().get(http::header::AUTHORIZATION);
-                let q = url::form_urlencoded::parse(
-                    context
-                        .request()
-                        .uri()
-                        .query()
-                        .unwrap_or_else(|| "")
-                        .as_bytes(),
-                )
-                .find(|(key, _)| key == "access_token")
-                .map(|(_, val)| val);
+fn object_event(
+    prev: Option<&ObjectVersion>,
+    next: Option<&ObjectVersion>,
+) -> Option<&'static str> {
+    match (prev, next) {
+        (None, Some(_)) => Some("created"),
+        (Some(_), None) => Some("deleted"),
+        (Some(prev), Some(next)) if prev != next => Some("modified"),
+        _ => None,
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+type CoalescedFuture<T> = Box<dyn Future<Item = T, Error = ()> + Send>;
+type CoalescedEntry<T> = (Instant, Shared<CoalescedFuture<T>>);
+
+/// Coalesces identical concurrent operations: the first one to arrive is performed,
+/// others started within the window share its result.
+pub(crate) struct Coalescer<T> {
+    window: Duration,
+    inner: Mutex<HashMap<String, CoalescedEntry<T>>>,
+}
+
+impl<T> fmt::Debug for Coalescer<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Coalescer")
+            .field("window", &self.window)
+            .finish()
+    }
+}
+
+impl<T> Coalescer<T>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            inner: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn run<F, R>(&self, key: String, f: F) -> CoalescedFuture<T>
+    where
+        F: FnOnce() -> R,
+        R: Future<Item = T, Error = ()> + Send + 'static,
+    {
+        if self.window == Duration::from_secs(0) {
+            return Box::new(f());
+        }
+
+        let now = Instant::now();
+        let window = self.window;
+        let shared = {
+            let mut inner = self.inner.lock().expect("Coalescer lock is poisoned");
+            inner.retain(|_, (started_at, _)| now.duration_since(*started_at) < window);
+            inner
+                .entry(key)
+                .or_insert_with(|| (now, (Box::new(f()) as CoalescedFuture<T>).shared()))
+                .1
+                .clone()
+        };
+
+        Box::new(shared.map(|item| (*item).clone()).map_err(|_| ()))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+type VersionFuture = Box<dyn Future<Item = Option<ObjectVersion>, Error = anyhow::Error> + Send>;
+
+/// Least recently used versions of objects, each of them expires after the ttl.
+pub(crate) struct ObjectVersionCache {
+    capacity: usize,
+    ttl: Duration,
+    inner: Mutex<LinkedHashMap<String, (Instant, Option<ObjectVersion>)>>,
+}
+
+impl fmt::Debug for ObjectVersionCache {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ObjectVersionCache")
+            .field("capacity", &self.capacity)
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+impl ObjectVersionCache {
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            inner: Mutex::new(LinkedHashMap::new()),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<Option<ObjectVersion>> {
+        let mut inner = self
+            .inner
+            .lock()
+            .expect("Object version cache lock is poisoned");
+        match inner.get_refresh(key) {
+            Some((cached_at, version)) if cached_at.elapsed() < self.ttl => Some(version.clone()),
+            Some(_) => {
+                inner.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, key: String, version: Option<ObjectVersion>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut inner = self
+            .inner
+            .lock()
+            .expect("Object version cache lock is poisoned");
+        inner.insert(key, (Instant::now(), version));
+        while inner.len() > self.capacity {
+            inner.pop_front();
+        }
+    }
+
+    /// Returns the cached version of the object, performing `f` and caching its result on a miss.
+    pub(crate) fn lookup<F, R>(self: &Arc<Self>, key: String, f: F) -> VersionFuture
+    where
+        F: FnOnce() -> R,
+        R: Future<Item = Option<ObjectVersion>, Error = anyhow::Error> + Send + 'static,
+    {
+        if let Some(version) = self.get(&key) {
+            return Box::new(future::ok(version));
+        }
+
+        let cache = self.clone();
+        Box::new(f().map(move |version| {
+            cache.insert(key, version.clone());
+            version
+        }))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Presigned URIs are reused until shortly before they expire.
+const SIGN_CACHE_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// Request a presigned URI is cached for.
+#[derive(Debug)]
+pub(crate) struct SignCacheKey<'a> {
+    pub(crate) backend: &'a str,
+    pub(crate) method: &'a str,
+    pub(crate) bucket: &'a str,
+    pub(crate) object: &'a str,
+    pub(crate) headers: &'a BTreeMap<String, String>,
+    pub(crate) signature_version: Option<SignatureVersion>,
+    pub(crate) url_style: Option<UrlStyle>,
+    pub(crate) accelerate: bool,
+}
+
+impl<'a> SignCacheKey<'a> {
+    /// Only requests without side effects are cached, uploads must be signed for each attempt.
+    /// Requests with user metadata are never cached either.
+    pub(crate) fn to_key(&self) -> Option<String> {
+        if self.method != "GET" && self.method != "HEAD" {
+            return None;
+        }
+        if self
+            .headers
+            .keys()
+            .any(|key| key.to_lowercase().starts_with("x-amz-meta-"))
+        {
+            return None;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        self.headers.hash(&mut hasher);
+        Some(format!(
+            "{}\n{}\n{}\n{}\n{:?}\n{:?}\n{}\n{:016x}",
+            self.backend,
+            self.method,
+            self.bucket,
+            self.object,
+            self.signature_version,
+            self.url_style,
+            self.accelerate,
+            hasher.finish()
+        ))
+    }
+}
+
+/// Least recently used presigned URIs, along with the ratio of cache hits.
+pub(crate) struct SignCache {
+    capacity: usize,
+    inner: Mutex<LinkedHashMap<String, (Instant, SignedUri)>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl fmt::Debug for SignCache {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("SignCache")
+            .field("capacity", &self.capacity)
+            .finish()
+    }
+}
+
+impl SignCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(LinkedHashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<SignedUri> {
+        if !self.enabled() {
+            return None;
+        }
+
+        let mut inner = self.inner.lock().expect("Sign cache lock is poisoned");
+        let uri = match inner.get_refresh(key) {
+            Some((valid_until, uri)) if Instant::now() < *valid_until => Some(uri.clone()),
+            Some(_) => {
+                inner.remove(key);
+                None
+            }
+            None => None,
+        };
+
+        let counter = if uri.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        uri
+    }
+
+    /// URIs expiring within the margin aren't cached.
+    pub(crate) fn insert(&self, key: String, uri: SignedUri, expires_in: Duration) {
+        if !self.enabled() {
+            return;
+        }
+        let ttl = match expires_in.checked_sub(SIGN_CACHE_EXPIRY_MARGIN) {
+            Some(val) if val > Duration::from_secs(0) => val,
+            _ => return,
+        };
+
+        let mut inner = self.inner.lock().expect("Sign cache lock is poisoned");
+        inner.insert(key, (Instant::now() + ttl, uri));
+        while inner.len() > self.capacity {
+            inner.pop_front();
+        }
+    }
+
+    /// Ratio of lookups served from the cache, `None` until the first lookup.
+    pub(crate) fn hit_ratio(&self) -> Option<f64> {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        match hits + misses {
+            0 => None,
+            total => Some(hits as f64 / total as f64),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Responses of requests completed recently, keyed by subjects and hashes clients derive
+/// from their requests, so that retries of the same request get the same response.
+/// Responses are returned only for requests with the same fingerprint, whatever the hash
+/// of the client is derived from.
+pub(crate) struct RecentResponses<T> {
+    ttl: Duration,
+    capacity: usize,
+    inner: Mutex<LinkedHashMap<(String, String), RecentResponse<T>>>,
+}
+
+/// Completion time of the request, its fingerprint and the response.
+type RecentResponse<T> = (Instant, u64, T);
+
+impl<T> fmt::Debug for RecentResponses<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("RecentResponses")
+            .field("ttl", &self.ttl)
+            .field("capacity", &self.capacity)
+            .finish()
+    }
+}
+
+impl<T: Clone> RecentResponses<T> {
+    pub(crate) fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            inner: Mutex::new(LinkedHashMap::new()),
+        }
+    }
+
+    pub(crate) fn get(&self, subject: &str, hash: &str, fingerprint: u64) -> Option<T> {
+        let mut inner = self
+            .inner
+            .lock()
+            .expect("Recent responses lock is poisoned");
+        // Entries are in order of completion, expired ones are at the front
+        while let Some((_, (completed_at, _, _))) = inner.front() {
+            if completed_at.elapsed() < self.ttl {
+                break;
+            }
+            inner.pop_front();
+        }
+
+        match inner.get(&(subject.to_owned(), hash.to_owned())) {
+            Some((_, val, resp)) if *val == fingerprint => Some(resp.clone()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn insert(&self, subject: String, hash: String, fingerprint: u64, resp: T) {
+        let key = (subject, hash);
+        let mut inner = self
+            .inner
+            .lock()
+            .expect("Recent responses lock is poisoned");
+        inner.remove(&key);
+        inner.insert(key, (Instant::now(), fingerprint, resp));
+        while inner.len() > self.capacity {
+            inner.pop_front();
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Outcome of moving an object: the copy is verified,
+/// the source object is deleted unless `delete_error` is set.
+#[derive(Debug)]
+pub(crate) struct ObjectMove {
+    pub(crate) etag: Option<String>,
+    pub(crate) delete_error: Option<anyhow::Error>,
+}
+
+type ObjectMoveFuture = Box<dyn Future<Item = Option<ObjectMove>, Error = anyhow::Error> + Send>;
+
+/// Copies the object to the destination within the bucket and deletes the source
+/// once entity tags confirm the copy. Returns `None` if the source object doesn't exist.
+pub(crate) fn move_object(
+    s3: Arc<Client>,
+    bucket: String,
+    source: String,
+    destination: String,
+) -> ObjectMoveFuture {
+    let head = s3.head_object(&bucket, &source);
+
+    Box::new(head.and_then(move |version| {
+        let source_etag = match version {
+            Some(version) => version.etag,
+            None => return future::Either::A(future::ok(None)),
+        };
+
+        let copy = s3.copy_object(&bucket, &source, &destination, source_etag.clone());
+        let verify = {
+            let (s3, bucket) = (s3.clone(), bucket.clone());
+            move |copy_etag: Option<String>| {
+                s3.head_object(&bucket, &destination).and_then(move |version| {
+                    let etag = version.and_then(|version| version.etag);
+                    if etag.is_some() && etag == copy_etag && copy_matches(&source_etag, &etag) {
+                        Ok(etag)
+                    } else {
+                        Err(format_err!(
+                            "failed to verify the copy, source etag = {:?}, copy etag = {:?}, destination etag = {:?}",
+                            source_etag,
+                            copy_etag,
+                            etag
+                        ))
+                    }
+                })
+            }
+        };
+
+        future::Either::B(copy.and_then(verify).and_then(move |etag| {
+            let legal_hold = legal_hold_active(&s3, &bucket, &source);
+            delete_unless_held(legal_hold, move || s3.delete_object(&bucket, &source)).then(
+                move |result| {
+                    let delete_error = match result {
+                        Ok(true) => None,
+                        Ok(false) => Some(format_err!("legal hold of the source object is active")),
+                        Err(err) => Some(err),
+                    };
+                    Ok(Some(ObjectMove { etag, delete_error }))
+                },
+            )
+        }))
+    }))
+}
+
+/// Objects under a legal hold can't be deleted regardless of the retention of the bucket,
+/// it's checked by each path deleting objects.
+pub(crate) fn legal_hold_active(
+    s3: &Client,
+    bucket: &str,
+    object: &str,
+) -> impl Future<Item = bool, Error = anyhow::Error> + Send {
+    s3.object_status(bucket, object)
+        .map(|status| status.is_some_and(|status| status.legal_hold))
+}
+
+/// Deletes the object once it's confirmed not to be under a legal hold,
+/// returns whether it's deleted.
+pub(crate) fn delete_unless_held<H, F, D>(
+    legal_hold: H,
+    delete: F,
+) -> impl Future<Item = bool, Error = anyhow::Error>
+where
+    H: Future<Item = bool, Error = anyhow::Error>,
+    F: FnOnce() -> D,
+    D: Future<Item = (), Error = anyhow::Error>,
+{
+    legal_hold.and_then(move |held| {
+        if held {
+            return future::Either::A(future::ok(false));
+        }
+
+        future::Either::B(delete().map(|()| true))
+    })
+}
+
+// Entity tags of multipart objects (having the number of parts after a dash)
+// change on copying, the content is confirmed by the conditional copy then.
+fn copy_matches(source_etag: &Option<String>, etag: &Option<String>) -> bool {
+    match source_etag {
+        Some(source_etag) if !source_etag.contains('-') => Some(source_etag) == etag.as_ref(),
+        _ => true,
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Removes requests out of the window, counts the rest and records the current one
+// unless the limit is reached. Returns the number of requests within the window.
+const RATE_LIMIT_SCRIPT: &str = r#"
+local now = tonumber(ARGV[1])
+local window = tonumber(ARGV[2])
+local limit = tonumber(ARGV[3])
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now - window)
+local count = redis.call('ZCARD', KEYS[1])
+if count < limit then
+    redis.call('ZADD', KEYS[1], now, now .. ':' .. ARGV[4])
+    redis.call('PEXPIRE', KEYS[1], window)
+end
+return count
+"#;
+
+/// Sliding window rate limiter shared by all instances of the application through Redis.
+pub(crate) struct RateLimiter {
+    pool: Arc<r2d2::Pool<RedisConnectionManager>>,
+    window: Duration,
+    max_requests: u64,
+    script: redis::Script,
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("RateLimiter")
+            .field("window", &self.window)
+            .field("max_requests", &self.max_requests)
+            .finish()
+    }
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            pool: svc_authz::cache::create_pool2(
+                &config.redis_url,
+                config.pool_size,
+                None,
+                config.pool_timeout_secs,
+            ),
+            window: Duration::from_secs(config.window_secs),
+            max_requests: config.max_requests,
+            script: redis::Script::new(RATE_LIMIT_SCRIPT),
+        }
+    }
+
+    /// Records a request of the subject unless it exceeds the limit.
+    /// Requests are allowed if Redis is unavailable.
+    pub(crate) fn allows(&self, sub: &AccountId) -> bool {
+        match self.hit(&format!("storage.rate_limit.{}", sub)) {
+            Ok(count) => count < self.max_requests,
+            Err(err) => {
+                error!(
+                    "Error checking the rate limit, subject = '{}': {:#}",
+                    sub, err
+                );
+                true
+            }
+        }
+    }
+
+    fn hit(&self, key: &str) -> anyhow::Result<u64> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .context("invalid system time")?;
+        let mut conn = self
+            .pool
+            .get()
+            .context("failed to get a redis connection")?;
+
+        self.script
+            .key(key)
+            .arg(now.as_millis() as u64)
+            .arg(self.window.as_millis() as u64)
+            .arg(self.max_requests)
+            .arg(uuid::Uuid::new_v4().to_string())
+            .invoke::<u64>(&mut *conn)
+            .context("failed to run the rate limit script")
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Response)]
+pub(crate) struct AuthzPrewarmReport {
+    total: usize,
+    failed: usize,
+}
+
+/// Authorizes each of the configured intents to populate the authz cache.
+pub(crate) fn prewarm_authz(
+    authz: &svc_authz::ClientMap,
+    entries: &[AuthzPrewarmEntry],
+) -> impl Future<Item = AuthzPrewarmReport, Error = ()> + Send {
+    let requests = entries
+        .iter()
+        .map(|entry| {
+            let object = entry.object.iter().map(String::as_str).collect();
+            let intent = format!(
+                "subject = '{}', object = '{:?}', action = '{}'",
+                entry.subject, entry.object, entry.action
+            );
+
+            authz
+                .authorize(&entry.audience, &entry.subject, object, &entry.action)
+                .map(move |result| match result {
+                    Ok(_) => true,
+                    Err(err) => {
+                        warn!("Authz prewarm failed for {}: {}", intent, err);
+                        false
+                    }
+                })
+        })
+        .collect::<Vec<_>>();
+
+    let total = requests.len();
+    future::join_all(requests).map(move |results| AuthzPrewarmReport {
+        total,
+        failed: results.into_iter().filter(|ok| !ok).count(),
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Redirects must not be cached longer than presigned URIs they point to are valid.
+const REDIRECT_EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+/// Caps `max-age` and `s-maxage` directives of the `cache-control` header value
+/// at the expiration time of presigned URIs minus a safety margin.
+pub(crate) fn redirect_cache_control(value: &str, expires_in: Duration) -> String {
+    let max_age = expires_in.saturating_sub(REDIRECT_EXPIRY_MARGIN).as_secs();
+
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .map(|directive| match directive.split_once('=') {
+            Some((name, val)) if is_max_age_directive(name) => {
+                match val.trim().trim_matches('"').parse::<u64>() {
+                    Ok(val) => format!("{}={}", name.trim(), val.min(max_age)),
+                    Err(_) => directive.to_owned(),
+                }
+            }
+            _ => directive.to_owned(),
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Returns a value of the `max-age` directive of the `cache-control` header value.
+pub(crate) fn cache_control_max_age(value: &str) -> Option<u64> {
+    value.split(',').find_map(|directive| {
+        directive.split_once('=').and_then(|(name, val)| {
+            if name.trim().eq_ignore_ascii_case("max-age") {
+                val.trim().trim_matches('"').parse().ok()
+            } else {
+                None
+            }
+        })
+    })
+}
+
+fn is_max_age_directive(name: &str) -> bool {
+    let name = name.trim();
+    name.eq_ignore_ascii_case("max-age") || name.eq_ignore_ascii_case("s-maxage")
+}
+
+/// Caching headers of a redirect to a presigned URI.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct RedirectCaching {
+    pub(crate) cache_control: Option<String>,
+    pub(crate) surrogate_control: Option<String>,
+    pub(crate) surrogate_key: Option<String>,
+    pub(crate) vary: Option<&'static str>,
+}
+
+/// Cache policies of buckets and sets, the first matching one is applied.
+#[derive(Debug)]
+pub(crate) struct CachePolicies {
+    policies: Vec<CachePolicyConfig>,
+}
+
+impl CachePolicies {
+    pub(crate) fn new(policies: &[CachePolicyConfig]) -> Self {
+        Self {
+            policies: policies.to_vec(),
+        }
+    }
+
+    /// Headers of redirects to objects of the bucket, or of the set of the bucket,
+    /// `cache-control` of the route is used unless there is a policy for them.
+    ///
+    /// Redirects of authenticated subjects depend on their access, e.g. scopes, security labels
+    /// or the tenant, so they're only cached by the clients. Public caching applies to anonymous
+    /// requests only.
+    pub(crate) fn caching(
+        &self,
+        bucket: &str,
+        set: Option<&str>,
+        sub: &Subject,
+        route: &RouteConfig,
+        expires_in: Duration,
+    ) -> RedirectCaching {
+        let shared = sub.is_anonymous();
+        let policy = self.policies.iter().find(|policy| {
+            wildcard_match(&policy.bucket_pattern, bucket)
+                && match (&policy.set_pattern, set) {
+                    (None, _) => true,
+                    (Some(pattern), Some(set)) => wildcard_match(pattern, set),
+                    (Some(_), None) => false,
+                }
+        });
+        let policy = match policy {
+            Some(val) => val.cache_policy,
+            None => {
+                return RedirectCaching {
+                    cache_control: route.cache_control(expires_in).map(|value| {
+                        if shared {
+                            value
+                        } else {
+                            private_cache_control(&value)
+                        }
+                    }),
+                    ..Default::default()
+                }
+            }
+        };
+        let policy = match policy {
+            CachePolicy::PublicImmutable | CachePolicy::PublicShort if !shared => {
+                CachePolicy::Private
+            }
+            policy => policy,
+        };
+
+        // Even immutable content is redirected to presigned URIs expiring in `expires_in`
+        let (cache_control, surrogate_control) = match policy {
+            CachePolicy::NoStore => ("no-store", "no-store"),
+            CachePolicy::Private => ("private, max-age=31536000", "no-store"),
+            CachePolicy::PublicImmutable => {
+                ("public, max-age=31536000, immutable", "max-age=31536000")
+            }
+            CachePolicy::PublicShort => ("public, max-age=60", "max-age=60"),
+        };
+        let surrogate_key = match set {
+            Some(set) => format!("{}/{}", bucket, set),
+            None => bucket.to_owned(),
+        };
+
+        RedirectCaching {
+            cache_control: Some(redirect_cache_control(cache_control, expires_in)),
+            surrogate_control: Some(redirect_cache_control(surrogate_control, expires_in)),
+            surrogate_key: Some(surrogate_key),
+            // Cached redirects of anonymous requests aren't served to authenticated ones
+            vary: if shared { Some("authorization") } else { None },
+        }
+    }
+}
+
+/// Replaces the `public` directive of the `cache-control` header value with `private`.
+fn private_cache_control(value: &str) -> String {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .map(|directive| {
+            if directive.eq_ignore_ascii_case("public") {
+                "private"
+            } else {
+                directive
+            }
+        })
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
+
+/// Hosts of AWS endpoints, including the Transfer Acceleration ones.
+const AWS_HOST_PATTERNS: &[&str] = &["*.amazonaws.com", "*.amazonaws.com.cn"];
+
+/// Reason a redirect URL is rejected.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ValidationError {
+    Malformed(String),
+    InsecureScheme(String),
+    UnknownHost(String),
+    DuplicateQueryParam(String),
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::Malformed(err) => write!(fmt, "malformed redirect url: {}", err),
+            ValidationError::InsecureScheme(scheme) => {
+                write!(fmt, "insecure scheme = '{}' of the redirect url", scheme)
+            }
+            ValidationError::UnknownHost(host) => write!(
+                fmt,
+                "host = '{}' of the redirect url isn't a known S3 endpoint",
+                host
+            ),
+            ValidationError::DuplicateQueryParam(key) => write!(
+                fmt,
+                "query parameter = '{}' of the redirect url is duplicated",
+                key
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Validates redirects to presigned URIs, so that a misconfigured backend results in an error
+/// rather than a redirect clients can't follow.
+#[derive(Debug)]
+pub(crate) struct RedirectValidator {
+    // Scheme and host of endpoints and proxy hosts of the backends
+    origins: Vec<(String, String)>,
+}
+
+impl RedirectValidator {
+    pub(crate) fn new(clients: &S3Clients) -> Self {
+        let mut origins = clients
+            .values()
+            .flat_map(|router| router.clients())
+            .flat_map(|client| client.redirect_origins())
+            .collect::<Vec<(String, String)>>();
+        origins.sort();
+        origins.dedup();
+        Self { origins }
+    }
+
+    /// Checks that the URL parses, has no duplicated query parameters and points to an AWS endpoint
+    /// over `https` or to an endpoint of a backend. Backends configured with `http` endpoints
+    /// are allowed to be redirected to over `http`.
+    pub(crate) fn validate_redirect_url(&self, url: &str) -> Result<(), ValidationError> {
+        let url = Url::parse(url).map_err(|err| ValidationError::Malformed(err.to_string()))?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| ValidationError::Malformed("missing host".to_owned()))?
+            .to_lowercase();
+
+        let is_backend = |scheme: &str| {
+            self.origins
+                .iter()
+                .any(|(origin_scheme, origin_host)| origin_scheme == scheme && origin_host == &host)
+        };
+        match url.scheme() {
+            "https" => {
+                let is_aws = AWS_HOST_PATTERNS
+                    .iter()
+                    .any(|pattern| wildcard_match(pattern, &host));
+                if !is_aws && !is_backend("https") {
+                    return Err(ValidationError::UnknownHost(host));
+                }
+            }
+            scheme if is_backend(scheme) => {}
+            scheme => return Err(ValidationError::InsecureScheme(scheme.to_owned())),
+        }
+
+        let mut keys = url
+            .query_pairs()
+            .map(|(key, _)| key.into_owned())
+            .collect::<Vec<String>>();
+        keys.sort();
+        if let Some(pair) = keys.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(ValidationError::DuplicateQueryParam(pair[0].clone()));
+        }
+
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) fn parse_qr_ec_level(value: &str) -> anyhow::Result<qrcode::EcLevel> {
+    use qrcode::EcLevel;
+
+    match value {
+        "L" => Ok(EcLevel::L),
+        "M" => Ok(EcLevel::M),
+        "Q" => Ok(EcLevel::Q),
+        "H" => Ok(EcLevel::H),
+        _ => Err(format_err!("invalid error correction level = {}", value)),
+    }
+}
+
+pub(crate) fn render_qr_png(
+    data: &str,
+    ec_level: qrcode::EcLevel,
+    size: u32,
+) -> anyhow::Result<Vec<u8>> {
+    use image::{DynamicImage, ImageOutputFormat, Luma};
+    use qrcode::QrCode;
+
+    let code = QrCode::with_error_correction_level(data.as_bytes(), ec_level)?;
+    let image = code.render::<Luma<u8>>().min_dimensions(size, size).build();
+
+    let mut acc = Vec::new();
+    DynamicImage::ImageLuma8(image).write_to(&mut acc, ImageOutputFormat::Png)?;
+    Ok(acc)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) const FINGERPRINT_QUERY_PARAM: &str = "x-storage-fingerprint";
+
+/// Identity of the client device a signed URI is generated for.
+///
+/// The fingerprint of the identity is added to signed URIs as a query string parameter
+/// starting with `x-`, such parameters are ignored by S3 while verifying a signature
+/// but still presented in access logs.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ClientIdentity {
+    user_agent: Option<String>,
+    ip: Option<IpAddr>,
+    ip_prefix: Option<String>,
+}
+
+impl ClientIdentity {
+    pub(crate) fn new(user_agent: Option<&str>, ip: Option<IpAddr>) -> Self {
+        Self {
+            user_agent: user_agent.map(ToOwned::to_owned),
+            ip,
+            ip_prefix: ip.map(ip_prefix),
+        }
+    }
+
+    pub(crate) fn ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+
+    pub(crate) fn fingerprint(&self, subject: &AccountId) -> String {
+        let data = format!(
+            "{}\n{}\n{}",
+            subject,
+            self.user_agent.as_deref().unwrap_or(""),
+            self.ip_prefix.as_deref().unwrap_or("")
+        );
+
+        openssl::sha::sha256(data.as_bytes())[..16]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    pub(crate) fn apply(&self, subject: &AccountId, uri: &str) -> anyhow::Result<String> {
+        let mut url = Url::parse(uri)?;
+        url.query_pairs_mut()
+            .append_pair(FINGERPRINT_QUERY_PARAM, &self.fingerprint(subject));
+        Ok(url.to_string())
+    }
+
+    pub(crate) fn verify(&self, subject: &AccountId, uri: &str) -> anyhow::Result<bool> {
+        let url = Url::parse(uri)?;
+        let fingerprint = url
+            .query_pairs()
+            .find(|(key, _)| key == FINGERPRINT_QUERY_PARAM)
+            .map(|(_, val)| val.into_owned())
+            .ok_or_else(|| format_err!("missing fingerprint of the uri"))?;
+
+        Ok(fingerprint == self.fingerprint(subject))
+    }
+}
+
+/// Appends parameters to the query string of the signed URI without signing them,
+/// an edge in front of the backend must strip them before the request reaches S3.
+pub(crate) fn append_query_params(
+    uri: &str,
+    params: &BTreeMap<String, String>,
+) -> anyhow::Result<String> {
+    if params.is_empty() {
+        return Ok(uri.to_owned());
+    }
+
+    let mut url = Url::parse(uri)?;
+    url.query_pairs_mut().extend_pairs(params);
+    Ok(url.to_string())
+}
+
+fn ip_prefix(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            format!("{}.{}.{}.0/24", octets[0], octets[1], octets[2])
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            format!(
+                "{:x}:{:x}:{:x}:{:x}::/64",
+                segments[0], segments[1], segments[2], segments[3]
+            )
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Matches an address against an IP address or a network in CIDR notation, e.g. `10.0.0.0/8`.
+pub(crate) fn ip_matches(pattern: &str, ip: IpAddr) -> bool {
+    let (addr, prefix_len) = match pattern.split_once('/') {
+        Some((addr, len)) => match len.parse::<u32>() {
+            Ok(len) => (addr, Some(len)),
+            Err(_) => return false,
+        },
+        None => (pattern, None),
+    };
+
+    match (addr.parse::<IpAddr>(), ip) {
+        (Ok(IpAddr::V4(net)), IpAddr::V4(ip)) => {
+            prefix_matches(u32::from(net).into(), u32::from(ip).into(), 32, prefix_len)
+        }
+        (Ok(IpAddr::V6(net)), IpAddr::V6(ip)) => {
+            prefix_matches(u128::from(net), u128::from(ip), 128, prefix_len)
+        }
+        _ => false,
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, bits: u32, prefix_len: Option<u32>) -> bool {
+    let prefix_len = prefix_len.unwrap_or(bits);
+    if prefix_len > bits {
+        return false;
+    }
+
+    let shift = bits - prefix_len;
+    net.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Mechanism the subject of the request has been authenticated by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AuthnMethod {
+    #[default]
+    Jwt,
+    ApiKey,
+    ClientCertificate,
+    TrustedIp,
+    DelegationToken,
+    Anonymous,
+}
+
+impl fmt::Display for AuthnMethod {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let value = match self {
+            AuthnMethod::Jwt => "jwt",
+            AuthnMethod::ApiKey => "api_key",
+            AuthnMethod::ClientCertificate => "client_certificate",
+            AuthnMethod::TrustedIp => "trusted_ip",
+            AuthnMethod::DelegationToken => "delegation_token",
+            AuthnMethod::Anonymous => "anonymous",
+        };
+        fmt.write_str(value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Subject {
+    inner: AccountId,
+    #[serde(skip)]
+    scope: Option<TokenScope>,
+    #[serde(skip)]
+    method: AuthnMethod,
+    #[serde(skip)]
+    security_level: Option<String>,
+    #[serde(skip)]
+    cost_center: Option<String>,
+    #[serde(skip)]
+    namespace: Option<String>,
+    #[serde(skip)]
+    resource_scopes: Option<ResourceScopes>,
+    #[serde(skip)]
+    delegation: Option<DelegationGrant>,
+}
+
+impl Subject {
+    pub fn new(inner: AccountId) -> Self {
+        Self {
+            inner,
+            scope: None,
+            method: AuthnMethod::Jwt,
+            security_level: None,
+            cost_center: None,
+            namespace: None,
+            resource_scopes: None,
+            delegation: None,
+        }
+    }
+
+    /// Subject of requests without an access token.
+    pub(crate) fn anonymous(audience: &str) -> Self {
+        Self {
+            method: AuthnMethod::Anonymous,
+            ..Self::new(AccountId::new("anonymous", audience))
+        }
+    }
+
+    pub(crate) fn authenticated_by(inner: AccountId, method: AuthnMethod) -> Self {
+        Self {
+            method,
+            ..Self::new(inner)
+        }
+    }
+
+    /// Holder of a delegation token, it's the subject the token is issued to within the tenant
+    /// of the grant.
+    pub(crate) fn delegated(grant: DelegationGrant) -> Self {
+        Self {
+            method: AuthnMethod::DelegationToken,
+            namespace: grant.namespace.clone(),
+            delegation: Some(grant.clone()),
+            ..Self::new(grant.issued_to)
+        }
+    }
+
+    pub(crate) fn authn_method(&self) -> AuthnMethod {
+        self.method
+    }
+
+    pub(crate) fn is_anonymous(&self) -> bool {
+        self.method == AuthnMethod::Anonymous
+    }
+
+    pub(crate) fn set_scope(&mut self, scope: Option<TokenScope>) -> &mut Self {
+        self.scope = scope;
+        self
+    }
+
+    /// Clearance of the subject from the `max_security_level` claim of its access token.
+    pub(crate) fn security_level(&self) -> Option<&str> {
+        self.security_level.as_deref()
+    }
+
+    /// Cost center operations of the subject are attributed to, from the `cost_center` claim
+    /// of its access token.
+    pub(crate) fn cost_center(&self) -> Option<&str> {
+        self.cost_center.as_deref()
+    }
+
+    pub(crate) fn set_claims(&mut self, claims: SubjectClaims) -> &mut Self {
+        self.security_level = claims.max_security_level;
+        self.cost_center = claims.cost_center;
+        self
+    }
+
+    /// Namespace of the tenant of the subject, buckets of the tenant are prefixed with it
+    /// on the backend.
+    pub(crate) fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    pub(crate) fn set_namespace(&mut self, namespace: Option<String>) -> &mut Self {
+        self.namespace = namespace;
+        self
+    }
+
+    pub(crate) fn set_resource_scopes(&mut self, scopes: Option<ResourceScopes>) -> &mut Self {
+        self.resource_scopes = scopes;
+        self
+    }
+
+    /// Whether the action on the object is granted by the `s3_scopes` claim of the access token
+    /// or by the delegation token, such intents aren't sent to the authorization backend.
+    /// Scopes are granted by issuers of the audience of the bucket only.
+    pub(crate) fn scope_grants(
+        &self,
+        bucket: &str,
+        object: &str,
+        action: &str,
+        audience: &str,
+    ) -> bool {
+        if let Some(ref grant) = self.delegation {
+            return grant.allows(bucket, object, action);
+        }
+
+        self.audience() == audience
+            && self
+                .resource_scopes
+                .as_ref()
+                .map(|scopes| scopes.grants(bucket, object, action))
+                .unwrap_or(false)
+    }
+
+    /// Holders of delegation tokens are denied anything beyond the grants of their tokens.
+    pub(crate) fn check_delegation(
+        &self,
+        bucket: &str,
+        object: &str,
+        action: &str,
+    ) -> Result<(), String> {
+        match self.delegation {
+            Some(ref grant) if !grant.allows(bucket, object, action) => Err(format!(
+                "{} of the object = '{}/{}' isn't allowed by the delegation token",
+                action, bucket, object
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Subjects without the scope claim in their access tokens aren't restricted.
+    pub(crate) fn check_sign_scope(
+        &self,
+        bucket: &str,
+        object: &str,
+        method: &str,
+    ) -> Result<(), String> {
+        match self.scope {
+            Some(ref scope) if !scope.allows_sign(bucket, object, method) => Err(format!(
+                "signing {} request for the object = '{}/{}' is out of the access token scope",
+                method, bucket, object
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Header of API keys of the fallback authentication.
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// Authenticates the request by the fallback mechanisms in order: an API key, a client certificate
+/// verified by the proxy, the IP address of a trusted service. Returns `None` if the request has
+/// no credentials of any of them, or the error of the first mechanism that has failed.
+///
+/// API keys are looked up among the plaintext keys of the fallback first, then among
+/// the hashed keys of the store.
+pub(crate) fn authenticate_fallback(
+    config: &AuthnFallbackConfig,
+    api_keys: Option<&ApiKeyStore>,
+    headers: &http::HeaderMap,
+    ip: Option<IpAddr>,
+    peer: Option<IpAddr>,
+) -> Result<Option<Subject>, String> {
+    let header = |name: &str| headers.get(name).and_then(|val| val.to_str().ok());
+    let mut failure = None;
+
+    if let Some(key) = header(API_KEY_HEADER) {
+        let entry = config.api_keys.iter().find(|entry| {
+            entry.key.len() == key.len()
+                && openssl::memcmp::eq(entry.key.as_bytes(), key.as_bytes())
+        });
+        match entry {
+            Some(entry) => {
+                return Ok(Some(Subject::authenticated_by(
+                    entry.account_id.clone(),
+                    AuthnMethod::ApiKey,
+                )))
+            }
+            None => match api_keys.map(|store| store.authenticate(key)) {
+                Some(Ok(subject)) => return Ok(Some(subject)),
+                Some(Err(err)) => failure = Some(err),
+                None => failure = Some(String::from("unknown api key")),
+            },
+        }
+    }
+
+    // Headers of the certificate are only set by the proxies terminating mutual TLS
+    let cert = config.client_certificate.as_ref().filter(|cert| {
+        peer.is_some_and(|peer| {
+            cert.trusted_proxies
+                .iter()
+                .any(|proxy| ip_matches(proxy, peer))
+        })
+    });
+    if let Some(cert) = cert {
+        if let Some(subject) = header(&cert.subject_header) {
+            let entry = cert.subjects.iter().find(|entry| entry.subject == subject);
+            match (header(&cert.verify_header), entry) {
+                (Some("SUCCESS"), Some(entry)) => {
+                    return Ok(Some(Subject::authenticated_by(
+                        entry.account_id.clone(),
+                        AuthnMethod::ClientCertificate,
+                    )))
+                }
+                (Some("SUCCESS"), None) => {
+                    failure = failure.or_else(|| {
+                        Some(format!(
+                            "unknown client certificate subject = '{}'",
+                            subject
+                        ))
+                    })
+                }
+                _ => {
+                    failure =
+                        failure.or_else(|| Some(String::from("client certificate isn't verified")))
+                }
+            }
+        }
+    }
+
+    if let Some(ip) = ip {
+        if let Some(entry) = config
+            .trusted_ips
+            .iter()
+            .find(|entry| ip_matches(&entry.ip, ip))
+        {
+            return Ok(Some(Subject::authenticated_by(
+                entry.account_id.clone(),
+                AuthnMethod::TrustedIp,
+            )));
+        }
+    }
+
+    match failure {
+        Some(err) => Err(err),
+        None => Ok(None),
+    }
+}
+
+/// Subject authenticated by an access token of the request. Unlike `Subject`, it's extracted
+/// without rate limiting and invalid or missing access tokens don't fail the request.
+#[derive(Debug)]
+pub(crate) struct OptionalSubject(pub(crate) Option<Subject>);
+
+impl OptionalSubject {
+    pub(crate) fn is_authenticated(&self) -> bool {
+        self.0.is_some()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Operations allowed by the `scope` claim of an access token,
+/// e.g. `sign:bucket=videos.example.org/*:method=GET`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TokenScope {
+    entries: Vec<ScopeEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ScopeEntry {
+    operation: String,
+    bucket: Option<String>,
+    object: Option<String>,
+    method: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScopeClaim {
+    List(Vec<String>),
+    Line(String),
+}
+
+#[derive(Deserialize)]
+struct ScopeClaims {
+    scope: Option<ScopeClaim>,
+}
+
+/// Permissions on objects embedded into the `s3_scopes` claim of an access token,
+/// e.g. `["r:videos.example.org/intro.mp4", "rw:docs.example.org/*"]`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ResourceScopes {
+    entries: Vec<ResourceScope>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ResourceScope {
+    writable: bool,
+    bucket: String,
+    object: String,
+}
+
+#[derive(Deserialize)]
+struct ResourceScopesClaims {
+    s3_scopes: Option<Vec<String>>,
+}
+
+/// Claims of an access token describing the subject.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+pub(crate) struct SubjectClaims {
+    max_security_level: Option<String>,
+    cost_center: Option<String>,
+}
+
+/// Decodes the payload of a compact JWS. The token must be verified beforehand.
+pub(crate) fn token_payload(token: &str) -> anyhow::Result<Vec<u8>> {
+    let payload = token
+        .split('.')
+        .nth(1)
+        .ok_or_else(|| format_err!("invalid access token"))?;
+    base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .map_err(|err| format_err!("invalid access token payload: {}", err))
+}
+
+impl SubjectClaims {
+    /// Reads the claims from the payload of a compact JWS. The token must be verified beforehand.
+    pub(crate) fn from_token(token: &str) -> anyhow::Result<Self> {
+        serde_json::from_slice::<Self>(&token_payload(token)?)
+            .map_err(|err| format_err!("invalid subject claims: {}", err))
+    }
+}
+
+/// Authenticates the subject by the access token sent aside of headers, e.g. in a WebSocket message.
+pub(crate) fn authenticate_token(
+    token: &str,
+    authn: &AuthnConfig,
+    multitenancy: &MultitenancyConfig,
+) -> anyhow::Result<Subject> {
+    use svc_authn::token::jws_compact::extract::decode_jws_compact_with_config;
+
+    let data = decode_jws_compact_with_config::<String>(token, &authn.audiences)
+        .map_err(|err| format_err!("{}", err))?;
+    scoped_subject(data.claims.into(), token, authn, multitenancy)
+}
+
+/// Access tokens must carry the namespace of the tenant if multitenancy is enabled.
+/// Resource scopes are ignored unless they're enabled.
+fn scoped_subject(
+    mut subject: Subject,
+    token: &str,
+    authn: &AuthnConfig,
+    multitenancy: &MultitenancyConfig,
+) -> anyhow::Result<Subject> {
+    let scope = TokenScope::from_token(token)?;
+    let resource_scopes = if authn.resource_scopes {
+        ResourceScopes::from_token(token)?
+    } else {
+        None
+    };
+    let claims = SubjectClaims::from_token(token)?;
+    let namespace = if multitenancy.enabled {
+        Some(namespace_from_token(token, &multitenancy.namespace_claim)?)
+    } else {
+        None
+    };
+    subject
+        .set_scope(scope)
+        .set_resource_scopes(resource_scopes)
+        .set_claims(claims)
+        .set_namespace(namespace);
+    Ok(subject)
+}
+
+/// Reads the namespace of the tenant from the claim of a verified compact JWS. Namespaces consist
+/// of lowercase letters and digits, so prefixed names of buckets of tenants never collide.
+pub(crate) fn namespace_from_token(token: &str, claim: &str) -> anyhow::Result<String> {
+    let payload = serde_json::from_slice::<serde_json::Value>(&token_payload(token)?)
+        .map_err(|err| format_err!("invalid access token payload: {}", err))?;
+    let namespace = payload
+        .get(claim)
+        .and_then(|value| value.as_str())
+        .ok_or_else(|| format_err!("missing '{}' claim of the tenant", claim))?;
+
+    let valid = !namespace.is_empty()
+        && namespace
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit());
+    if !valid {
+        return Err(format_err!(
+            "invalid namespace of the tenant = '{}'",
+            namespace
+        ));
+    }
+
+    Ok(namespace.to_owned())
+}
+
+/// Client of the backend for the subject, names of buckets are prefixed with the namespace
+/// of its tenant.
+pub(crate) fn tenant_client(router: &ClientRouter, sub: &Subject) -> Arc<Client> {
+    namespaced_client(router, sub.namespace())
+}
+
+pub(crate) fn namespaced_client(router: &ClientRouter, namespace: Option<&str>) -> Arc<Client> {
+    match namespace {
+        Some(namespace) => Arc::new(router.client().namespaced(namespace)),
+        None => router.client(),
+    }
+}
+
+impl TokenScope {
+    pub(crate) fn parse<S: AsRef<str>>(values: &[S]) -> anyhow::Result<Self> {
+        let entries = values
+            .iter()
+            .map(|value| ScopeEntry::parse(value.as_ref()))
+            .collect::<anyhow::Result<Vec<ScopeEntry>>>()?;
+
+        Ok(Self { entries })
+    }
+
+    /// Reads the scope claim from the payload of a compact JWS. The token must be verified beforehand.
+    pub(crate) fn from_token(token: &str) -> anyhow::Result<Option<Self>> {
+        let claims = serde_json::from_slice::<ScopeClaims>(&token_payload(token)?)
+            .map_err(|err| format_err!("invalid scope claim: {}", err))?;
+
+        match claims.scope {
+            None => Ok(None),
+            Some(ScopeClaim::List(values)) => Self::parse(&values).map(Some),
+            Some(ScopeClaim::Line(line)) => {
+                Self::parse(&line.split_whitespace().collect::<Vec<&str>>()).map(Some)
+            }
+        }
+    }
+
+    pub(crate) fn allows_sign(&self, bucket: &str, object: &str, method: &str) -> bool {
+        self.entries.iter().any(|entry| {
+            entry.operation == "sign"
+                && optional_match(&entry.bucket, bucket)
+                && optional_match(&entry.object, object)
+                && optional_match(&entry.method, method)
+        })
+    }
+}
+
+impl ScopeEntry {
+    fn parse(value: &str) -> anyhow::Result<Self> {
+        let invalid = || format_err!("invalid scope = '{}'", value);
+
+        let mut parts = value.split(':');
+        let operation = parts
+            .next()
+            .filter(|op| !op.is_empty())
+            .ok_or_else(invalid)?;
+        let mut entry = Self {
+            operation: operation.to_owned(),
+            bucket: None,
+            object: None,
+            method: None,
+        };
+
+        for part in parts {
+            let mut kv = part.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("bucket"), Some(val)) => {
+                    let mut path = val.splitn(2, '/');
+                    entry.bucket = path.next().map(ToOwned::to_owned);
+                    entry.object = path.next().map(ToOwned::to_owned);
+                }
+                (Some("method"), Some(val)) => entry.method = Some(val.to_uppercase()),
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(entry)
+    }
+}
+
+impl ResourceScopes {
+    pub(crate) fn parse<S: AsRef<str>>(values: &[S]) -> anyhow::Result<Self> {
+        let entries = values
+            .iter()
+            .map(|value| ResourceScope::parse(value.as_ref()))
+            .collect::<anyhow::Result<Vec<ResourceScope>>>()?;
+
+        Ok(Self { entries })
+    }
+
+    /// Reads the `s3_scopes` claim from the payload of a compact JWS. The token must be verified beforehand.
+    pub(crate) fn from_token(token: &str) -> anyhow::Result<Option<Self>> {
+        let claims = serde_json::from_slice::<ResourceScopesClaims>(&token_payload(token)?)
+            .map_err(|err| format_err!("invalid s3_scopes claim: {}", err))?;
+
+        claims
+            .s3_scopes
+            .map(|values| Self::parse(&values))
+            .transpose()
+    }
+
+    /// `r` scopes grant `read` action, `rw` scopes grant `read` and `update` actions.
+    pub(crate) fn grants(&self, bucket: &str, object: &str, action: &str) -> bool {
+        let writing = match action {
+            "read" => false,
+            "update" => true,
+            _ => return false,
+        };
+
+        self.entries.iter().any(|entry| {
+            (entry.writable || !writing)
+                && entry.bucket == bucket
+                && wildcard_match(&entry.object, object)
+        })
+    }
+}
+
+impl ResourceScope {
+    fn parse(value: &str) -> anyhow::Result<Self> {
+        let invalid = || format_err!("invalid s3 scope = '{}'", value);
+
+        let mut parts = value.splitn(2, ':');
+        let writable = match parts.next() {
+            Some("r") => false,
+            Some("rw") => true,
+            _ => return Err(invalid()),
+        };
+        let mut path = parts.next().ok_or_else(invalid)?.splitn(2, '/');
+        match (path.next(), path.next()) {
+            (Some(bucket), Some(object)) if !bucket.is_empty() && !object.is_empty() => Ok(Self {
+                writable,
+                bucket: bucket.to_owned(),
+                object: object.to_owned(),
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+fn optional_match(pattern: &Option<String>, value: &str) -> bool {
+    match pattern {
+        Some(pattern) => wildcard_match(pattern, value),
+        None => true,
+    }
+}
+
+/// Matches a value against a pattern where `*` stands for any sequence of characters.
+pub(crate) fn wildcard_match(pattern: &str, value: &str) -> bool {
+    match pattern.find('*') {
+        None => pattern == value,
+        Some(idx) => {
+            let (head, tail) = (&pattern[..idx], &pattern[idx + 1..]);
+            value.starts_with(head)
+                && (head.len()..=value.len())
+                    .any(|pos| value.is_char_boundary(pos) && wildcard_match(tail, &value[pos..]))
+        }
+    }
+}
+
+impl Deref for Subject {
+    type Target = AccountId;
+
+    fn deref(&self) -> &AccountId {
+        &self.inner
+    }
+}
+
+impl Authenticable for Subject {
+    fn as_account_id(&self) -> &AccountId {
+        &self.inner
+    }
+}
+
+impl IntentSubject for Subject {
+    fn is_delegated(&self) -> bool {
+        self.delegation.is_some()
+    }
+}
+
+pub(crate) use self::tower_web::extract::request_subject;
+
+////////////////////////////////////////////////////////////////////////////////
+
+mod jose {
+    use svc_authn::jose::Claims;
+
+    use super::Subject;
+    use svc_authn::AccountId;
+
+    impl From<Claims<String>> for Subject {
+        fn from(value: Claims<String>) -> Self {
+            Self::new(AccountId::new(value.subject(), value.audience()))
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+mod tower_web {
+    use std::io;
+
+    use bytes::Bytes;
+    use futures::{Poll, Stream};
+    use tower_web::util::BufStream;
+
+    use super::{
+        authenticate_fallback, scoped_subject, ClientIdentity, EventStream, OptionalSubject,
+        RateLimiter, S3SignedRequestBuilder, Subject, UploadData, UploadStream,
+    };
+
+    impl BufStream for EventStream {
+        type Item = io::Cursor<Bytes>;
+        type Error = ();
+
+        fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+            self.inner
+                .poll()
+                .map(|chunk| chunk.map(|chunk| chunk.map(io::Cursor::new)))
+        }
+    }
+
+    pub(super) mod extract {
+        use std::marker::PhantomData;
+        use std::net::IpAddr;
+
+        use bytes::{Buf, Bytes};
+        use futures::{Async, Poll};
+        use http::StatusCode;
+        use log::debug;
+        use tower_web::codegen::CallSite;
+        use tower_web::extract::{Context, Error, Extract, ExtractFuture, Immediate};
+        use tower_web::util::BufStream;
+
+        use svc_authn::token::jws_compact::extract::{
+            decode_jws_compact_with_config, extract_jws_compact,
+        };
+
+        use svc_authn::AccountId;
+
+        use crate::app::api_keys::ApiKeyStore;
+        use crate::app::config::{AuthnConfig, Config, MultitenancyConfig};
+        use crate::app::gateway::{ClientAddr, PeerAddr, StreamedBody};
+
+        use super::{
+            authenticate_fallback, scoped_subject, ClientIdentity, OptionalSubject, RateLimiter,
+            S3SignedRequestBuilder, Subject, UploadData, UploadStream,
+        };
+
+        impl<B: BufStream> Extract<B> for ClientIdentity {
+            type Future = Immediate<ClientIdentity>;
+
+            fn extract(context: &Context) -> Self::Future {
+                let request = context.request();
+                let user_agent = request
+                    .headers()
+                    .get(http::header::USER_AGENT)
+                    .and_then(|val| val.to_str().ok());
+
+                Immediate::ok(ClientIdentity::new(user_agent, client_ip(request)))
+            }
+        }
+
+        // The address is resolved by the listener, see `gateway::ClientAddr`
+        fn client_ip<B>(request: &http::Request<B>) -> Option<IpAddr> {
+            request.extensions().get::<ClientAddr>().map(|addr| addr.0)
+        }
+
+        fn peer_ip<B>(request: &http::Request<B>) -> Option<IpAddr> {
+            request.extensions().get::<PeerAddr>().map(|addr| addr.0)
+        }
+
+        impl<B: BufStream> Extract<B> for S3SignedRequestBuilder {
+            type Future = Immediate<S3SignedRequestBuilder>;
+
+            fn extract(context: &Context) -> Self::Future {
+                let mut builder = S3SignedRequestBuilder::new();
+                let headers = context.request().headers();
+                for (key, val) in headers {
+                    match val.to_str() {
+                        Ok(val) => builder = builder.add_header(key.as_str(), val),
+                        Err(err) => return Immediate::err(Error::invalid_argument(&err)),
+                    }
+                }
+                Immediate::ok(builder)
+            }
+        }
+
+        impl<B: BufStream> Extract<B> for Subject {
+            type Future = Immediate<Subject>;
+
+            fn extract(context: &Context) -> Self::Future {
+                let subject = authenticate_context(context);
+
+                match context.config::<RateLimiter>() {
+                    Some(limiter) => match subject {
+                        Ok(ref sub) if !limiter.allows(sub) => Immediate::err(error(
+                            "rate limit exceeded",
+                            StatusCode::TOO_MANY_REQUESTS,
+                        )),
+                        _ => subject.into(),
+                    },
+                    None => subject.into(),
+                }
+            }
+        }
+
+        impl<B: BufStream> Extract<B> for OptionalSubject {
+            type Future = Immediate<OptionalSubject>;
+
+            fn extract(context: &Context) -> Self::Future {
+                let subject = authenticate_context(context)
+                    .ok()
+                    .filter(|subject| !subject.is_anonymous());
+                Immediate::ok(OptionalSubject(subject))
+            }
+        }
+
+        // Bodies of the HTTP listener are detached from requests and passed on as streams,
+        // bodies of other listeners are read before the upload starts.
+        impl<B: BufStream> Extract<B> for UploadStream {
+            type Future = UploadStreamFuture<B>;
+
+            fn extract(_context: &Context) -> Self::Future {
+                panic!("called `extract` but `body` is required");
+            }
+
+            fn extract_body(context: &Context, body: B) -> Self::Future {
+                let config = context.config::<Config>().expect("missing config");
+                let max_size = config.s3.proxy_upload_max_bytes();
+                let streamed = context
+                    .request()
+                    .extensions()
+                    .get::<StreamedBody>()
+                    .and_then(StreamedBody::take);
+                match streamed {
+                    Some(streamed) => {
+                        UploadStreamFuture::Streamed(UploadStream::streamed(streamed, max_size))
+                    }
+                    None => UploadStreamFuture::Buffered(UploadBody::new(body, max_size)),
+                }
+            }
 
-                match (h, q) {
-                    (Some(header), _) => match extract_jws_compact(header, &config.authn) {
-                        Ok(data) => Immediate::ok(data.claims.into()),
-                        Err(ref err) => {
-                            Immediate::err(error(&err.to_string(), StatusCode::UNAUTHORIZED))
-                        }
-                    },
-                    (_, Some(token)) => {
-                        match decode_jws_compact_with_config::<String>(&token, &config.authn) {
-                            Ok(data) => Immediate::ok(data.claims.into()),
-                            Err(ref err) => {
-                                Immediate::err(error(&err.to_string(), StatusCode::UNAUTHORIZED))
+            // The stream is the body of the request regardless of the argument
+            fn requires_body(_callsite: &CallSite) -> bool {
+                true
+            }
+        }
+
+        impl<B: BufStream> Extract<B> for UploadData {
+            type Future = UploadBody<B, UploadData>;
+
+            fn extract(_context: &Context) -> Self::Future {
+                panic!("called `extract` but `body` is required");
+            }
+
+            fn extract_body(context: &Context, body: B) -> Self::Future {
+                let config = context.config::<Config>().expect("missing config");
+                UploadBody::new(body, config.s3.upload_max_bytes())
+            }
+
+            fn requires_body(_callsite: &CallSite) -> bool {
+                true
+            }
+        }
+
+        pub(crate) enum UploadStreamFuture<B> {
+            Streamed(UploadStream),
+            Buffered(UploadBody<B, UploadStream>),
+        }
+
+        impl<B: BufStream> ExtractFuture for UploadStreamFuture<B> {
+            type Item = UploadStream;
+
+            fn poll(&mut self) -> Poll<(), Error> {
+                match self {
+                    UploadStreamFuture::Streamed(_) => Ok(Async::Ready(())),
+                    UploadStreamFuture::Buffered(body) => body.poll(),
+                }
+            }
+
+            fn extract(self) -> UploadStream {
+                match self {
+                    UploadStreamFuture::Streamed(stream) => stream,
+                    UploadStreamFuture::Buffered(body) => body.extract(),
+                }
+            }
+        }
+
+        /// Reads the body of an upload up to the limit.
+        pub(crate) struct UploadBody<B, T> {
+            body: B,
+            chunks: Vec<Bytes>,
+            size: usize,
+            max_size: usize,
+            item: PhantomData<T>,
+        }
+
+        impl<B, T> UploadBody<B, T> {
+            fn new(body: B, max_size: usize) -> Self {
+                Self {
+                    body,
+                    chunks: Vec::new(),
+                    size: 0,
+                    max_size,
+                    item: PhantomData,
+                }
+            }
+        }
+
+        impl<B: BufStream, T: From<Vec<Bytes>>> ExtractFuture for UploadBody<B, T> {
+            type Item = T;
+
+            fn poll(&mut self) -> Poll<(), Error> {
+                loop {
+                    match self.body.poll() {
+                        Ok(Async::Ready(Some(chunk))) => {
+                            let chunk = chunk.collect::<Bytes>();
+                            self.size += chunk.len();
+                            if self.size > self.max_size {
+                                let mut err = tower_web::Error::new(
+                                    "payload_too_large",
+                                    "Error reading the request body",
+                                    StatusCode::PAYLOAD_TOO_LARGE,
+                                );
+                                err.set_detail(&format!(
+                                    "request body must be at most {} bytes",
+                                    self.max_size
+                                ));
+                                return Err(err.into());
                             }
+                            self.chunks.push(chunk);
                         }
+                        Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Err(_) => {
+                            return Err(Error::invalid_argument(&"failed to read the request body"))
+                        }
+                    }
+                }
+            }
+
+            fn extract(self) -> T {
+                T::from(self.chunks)
+            }
+        }
+
+        fn authenticate_context(context: &Context) -> Result<Subject, Error> {
+            let config = context.config::<Config>().expect("missing config");
+            authenticate(
+                context.request(),
+                &config.id,
+                &config.authn,
+                &config.multitenancy,
+                context.config::<ApiKeyStore>(),
+            )
+        }
+
+        /// Identifies the subject of the request outside of handlers, e.g. for plugins.
+        /// Rate limits aren't applied, requests failing authentication have no subject.
+        pub(crate) fn request_subject<B>(
+            request: &http::Request<B>,
+            id: &AccountId,
+            authn: &AuthnConfig,
+            multitenancy: &MultitenancyConfig,
+            api_keys: Option<&ApiKeyStore>,
+        ) -> Option<Subject> {
+            authenticate(request, id, authn, multitenancy, api_keys)
+                .ok()
+                .filter(|subject| !subject.is_anonymous())
+        }
+
+        /// Access tokens are tried first, then the fallback mechanisms unless multitenancy
+        /// is enabled. Requests without credentials of any of them are anonymous.
+        fn authenticate<B>(
+            request: &http::Request<B>,
+            id: &AccountId,
+            authn: &AuthnConfig,
+            multitenancy: &MultitenancyConfig,
+            api_keys: Option<&ApiKeyStore>,
+        ) -> Result<Subject, Error> {
+            let headers = request.headers();
+            let subject = match authenticate_jwt(request, authn, multitenancy) {
+                Ok(Some(subject)) => Ok(Some(subject)),
+                // Subjects of the fallback mechanisms have no namespace
+                jwt if multitenancy.enabled => jwt,
+                jwt => {
+                    match authenticate_fallback(
+                        &authn.fallback,
+                        api_keys,
+                        headers,
+                        client_ip(request),
+                        peer_ip(request),
+                    ) {
+                        Ok(Some(subject)) => Ok(Some(subject)),
+                        Ok(None) => jwt,
+                        // Errors of access tokens take precedence
+                        Err(err) => match jwt {
+                            Err(jwt_err) => Err(jwt_err),
+                            Ok(_) => Err(error(&err, StatusCode::UNAUTHORIZED)),
+                        },
+                    }
+                }
+            };
+
+            match subject {
+                Ok(Some(subject)) => {
+                    debug!(
+                        "Request is authenticated by {}, subject = '{}'",
+                        subject.authn_method(),
+                        *subject
+                    );
+                    Ok(subject)
+                }
+                Ok(None) => Ok(Subject::anonymous(id.audience())),
+                Err(err) => Err(err),
+            }
+        }
+
+        /// Returns `None` if the request has no access token.
+        fn authenticate_jwt<B>(
+            request: &http::Request<B>,
+            authn: &AuthnConfig,
+            multitenancy: &MultitenancyConfig,
+        ) -> Result<Option<Subject>, Error> {
+            let h = request.headers().get(http::header::AUTHORIZATION);
+            let q = url::form_urlencoded::parse(request.uri().query().unwrap_or("").as_bytes())
+                .find(|(key, _)| key == "access_token")
+                .map(|(_, val)| val);
+
+            match (h, q) {
+                (Some(header), _) => match extract_jws_compact(header, &authn.audiences) {
+                    Ok(data) => {
+                        let token = header
+                            .to_str()
+                            .ok()
+                            .and_then(|val| val.split_once(' ').map(|(_, token)| token))
+                            .unwrap_or_default();
+                        scoped(data.claims.into(), token, authn, multitenancy).map(Some)
                     }
-                    (None, None) => {
-                        let audience = config.id.audience();
-                        let anonymous = AccountId::new("anonymous", audience);
-                        Immediate::ok(Subject::new(anonymous))
+                    Err(ref err) => Err(error(&err.to_string(), StatusCode::UNAUTHORIZED)),
+                },
+                (_, Some(token)) => {
+                    match decode_jws_compact_with_config::<String>(&token, &authn.audiences) {
+                        Ok(data) => {
+                            scoped(data.claims.into(), &token, authn, multitenancy).map(Some)
+                        }
+                        Err(ref err) => Err(error(&err.to_string(), StatusCode::UNAUTHORIZED)),
                     }
                 }
+                (None, None) => Ok(None),
             }
         }
 
+        fn scoped(
+            subject: Subject,
+            token: &str,
+            authn: &AuthnConfig,
+            multitenancy: &MultitenancyConfig,
+        ) -> Result<Subject, Error> {
+            scoped_subject(subject, token, authn, multitenancy)
+                .map_err(|err| error(&err.to_string(), StatusCode::UNAUTHORIZED))
+        }
+
         fn error(detail: &str, status: StatusCode) -> Error {
             let mut err = tower_web::Error::new(
                 "authn_error",
@@ -364,3 +3074,989 @@ mod tower_web {
         }
     }
 }
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip_matches_patterns() {
+        let ip = |value: &str| value.parse::<IpAddr>().unwrap();
+
+        assert!(ip_matches("10.0.0.1", ip("10.0.0.1")));
+        assert!(!ip_matches("10.0.0.1", ip("10.0.0.2")));
+        assert!(ip_matches("10.0.0.0/8", ip("10.20.30.40")));
+        assert!(!ip_matches("10.0.0.0/8", ip("11.0.0.1")));
+        assert!(ip_matches("0.0.0.0/0", ip("192.168.1.1")));
+        assert!(ip_matches("fd00::/8", ip("fd12:3456::1")));
+        assert!(!ip_matches("fd00::/8", ip("10.0.0.1")));
+        assert!(!ip_matches("10.0.0.0/33", ip("10.0.0.1")));
+        assert!(!ip_matches("localhost", ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn authenticate_fallback_chain() {
+        use crate::app::config::{
+            ApiKeyEntry, CertificateSubjectEntry, ClientCertificateConfig, TrustedIpEntry,
+        };
+
+        let account = |label: &str| AccountId::new(label, "svc.example.org");
+        let config = AuthnFallbackConfig {
+            api_keys: vec![ApiKeyEntry {
+                key: "secret".into(),
+                account_id: account("uploader"),
+            }],
+            client_certificate: Some(ClientCertificateConfig {
+                verify_header: "x-ssl-client-verify".into(),
+                subject_header: "x-ssl-client-s-dn".into(),
+                subjects: vec![CertificateSubjectEntry {
+                    subject: "CN=transcoder".into(),
+                    account_id: account("transcoder"),
+                }],
+                trusted_proxies: vec!["192.0.2.1".into()],
+            }),
+            trusted_ips: vec![TrustedIpEntry {
+                ip: "10.0.0.0/8".into(),
+                account_id: account("internal"),
+            }],
+        };
+        let authenticate_from =
+            |headers: &[(&'static str, &'static str)], ip: Option<&str>, peer: &str| {
+                let mut map = http::HeaderMap::new();
+                for (key, value) in headers {
+                    map.insert(*key, http::HeaderValue::from_static(value));
+                }
+                let ip = ip.map(|ip| ip.parse().unwrap());
+                authenticate_fallback(&config, None, &map, ip, Some(peer.parse().unwrap()))
+                    .map(|sub| sub.map(|sub| (sub.label().to_owned(), sub.authn_method())))
+            };
+        let authenticate = |headers: &[(&'static str, &'static str)], ip: Option<&str>| {
+            authenticate_from(headers, ip, "192.0.2.1")
+        };
+        let verified = [
+            ("x-ssl-client-verify", "SUCCESS"),
+            ("x-ssl-client-s-dn", "CN=transcoder"),
+        ];
+
+        assert_eq!(authenticate(&[], None), Ok(None));
+        assert_eq!(authenticate(&[], Some("192.168.0.1")), Ok(None));
+        assert_eq!(
+            authenticate(&[("x-api-key", "secret")], Some("10.0.0.1")),
+            Ok(Some(("uploader".into(), AuthnMethod::ApiKey)))
+        );
+        assert_eq!(
+            authenticate(&verified, None),
+            Ok(Some(("transcoder".into(), AuthnMethod::ClientCertificate)))
+        );
+        assert_eq!(
+            authenticate(&[], Some("10.1.2.3")),
+            Ok(Some(("internal".into(), AuthnMethod::TrustedIp)))
+        );
+        // The first mechanism that succeeds wins
+        assert_eq!(
+            authenticate(&[("x-api-key", "unknown")], Some("10.1.2.3")),
+            Ok(Some(("internal".into(), AuthnMethod::TrustedIp)))
+        );
+        assert!(authenticate(&[("x-api-key", "unknown")], None).is_err());
+        assert!(authenticate(&[("x-api-key", "secreT")], None).is_err());
+        // Headers of the certificate are ignored unless set by the trusted proxy
+        assert_eq!(authenticate_from(&verified, None, "198.51.100.1"), Ok(None));
+        assert!(authenticate(&[("x-ssl-client-s-dn", "CN=transcoder")], None).is_err());
+        assert!(authenticate(
+            &[
+                ("x-ssl-client-verify", "SUCCESS"),
+                ("x-ssl-client-s-dn", "CN=unknown")
+            ],
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn bucket_quotas_admit() {
+        let quotas = BucketQuotas::new(&[BucketQuotaConfig {
+            bucket_pattern: "*.example.org".into(),
+            max_total_bytes: 1000,
+            usage_ttl_secs: 300,
+        }]);
+        quotas.usage.lock().unwrap().insert(
+            "default\ndata.example.org".into(),
+            BucketUsage {
+                bytes: 600,
+                updated_at: Some(Instant::now()),
+                refreshing: false,
+            },
+        );
+        let s3 = Client::new(
+            "key",
+            "secret",
+            "us-east-1",
+            "https://s3.example.org",
+            Duration::from_secs(300),
+        );
+
+        assert!(quotas
+            .admit(&s3, "default", "data.example.org", 300)
+            .is_ok());
+        assert!(quotas
+            .admit(&s3, "default", "data.example.org", 200)
+            .is_err());
+        assert!(quotas
+            .admit(&s3, "default", "data.example.org", 100)
+            .is_ok());
+        assert!(quotas
+            .admit(&s3, "default", "data.example.net", 5000)
+            .is_ok());
+    }
+
+    #[test]
+    fn bucket_limits_admit() {
+        let limits = BucketLimits::new(&[BucketLimitConfig {
+            bucket_pattern: "*.example.org".into(),
+            max_objects: 10,
+            count_ttl_secs: 300,
+        }]);
+        limits.counts.lock().unwrap().insert(
+            "default\ndata.example.org".into(),
+            BucketObjectCount {
+                count: 8,
+                updated_at: Some(Instant::now()),
+                refreshing: false,
+            },
+        );
+        let s3 = Client::new(
+            "key",
+            "secret",
+            "us-east-1",
+            "https://s3.example.org",
+            Duration::from_secs(300),
+        );
+
+        assert!(limits.admit(&s3, "default", "data.example.org").is_ok());
+        assert!(limits.admit(&s3, "default", "data.example.org").is_ok());
+        assert_eq!(
+            limits
+                .admit(&s3, "default", "data.example.org")
+                .unwrap_err()
+                .to_string(),
+            "Bucket object limit reached"
+        );
+        assert!(limits.admit(&s3, "default", "data.example.net").is_ok());
+    }
+
+    #[test]
+    fn redirect_cache_control_caps_max_age() {
+        let expires_in = Duration::from_secs(300);
+        assert_eq!(
+            redirect_cache_control("private, max-age=600", expires_in),
+            "private, max-age=270"
+        );
+        assert_eq!(
+            redirect_cache_control("public,max-age=60, S-MAXAGE=\"3600\"", expires_in),
+            "public, max-age=60, S-MAXAGE=270"
+        );
+        assert_eq!(redirect_cache_control("no-store", expires_in), "no-store");
+        assert_eq!(
+            redirect_cache_control("max-age=60", Duration::from_secs(10)),
+            "max-age=0"
+        );
+
+        assert_eq!(cache_control_max_age("private, max-age=270"), Some(270));
+        assert_eq!(cache_control_max_age("public, s-maxage=270"), None);
+    }
+
+    #[test]
+    fn cache_policies_caching() {
+        let policy =
+            |bucket_pattern: &str, set_pattern: Option<&str>, cache_policy| CachePolicyConfig {
+                bucket_pattern: bucket_pattern.into(),
+                set_pattern: set_pattern.map(Into::into),
+                cache_policy,
+            };
+        let policies = CachePolicies::new(&[
+            policy("static.*", Some("assets*"), CachePolicy::PublicImmutable),
+            policy("static.*", None, CachePolicy::PublicShort),
+            policy("private.*", None, CachePolicy::NoStore),
+        ]);
+        let route = RouteConfig {
+            cache_control: Some("private, max-age=600".into()),
+            authz_mode: None,
+        };
+        let expires_in = Duration::from_secs(86400);
+        let anonymous = Subject::anonymous("example.org");
+        let user = Subject::new(AccountId::new("john", "example.org"));
+
+        assert_eq!(
+            policies.caching(
+                "static.example.org",
+                Some("assets.v2"),
+                &anonymous,
+                &route,
+                expires_in
+            ),
+            RedirectCaching {
+                cache_control: Some("public, max-age=86370, immutable".into()),
+                surrogate_control: Some("max-age=86370".into()),
+                surrogate_key: Some("static.example.org/assets.v2".into()),
+                vary: Some("authorization"),
+            }
+        );
+        assert_eq!(
+            policies.caching("static.example.org", None, &anonymous, &route, expires_in),
+            RedirectCaching {
+                cache_control: Some("public, max-age=60".into()),
+                surrogate_control: Some("max-age=60".into()),
+                surrogate_key: Some("static.example.org".into()),
+                vary: Some("authorization"),
+            }
+        );
+        assert_eq!(
+            policies
+                .caching(
+                    "private.example.org",
+                    Some("docs"),
+                    &anonymous,
+                    &route,
+                    expires_in
+                )
+                .cache_control,
+            Some("no-store".into())
+        );
+        assert_eq!(
+            policies.caching(
+                "data.example.org",
+                None,
+                &anonymous,
+                &route,
+                Duration::from_secs(300)
+            ),
+            RedirectCaching {
+                cache_control: Some("private, max-age=270".into()),
+                ..Default::default()
+            }
+        );
+
+        // Shared caches never store redirects of authenticated subjects
+        assert_eq!(
+            policies.caching("static.example.org", None, &user, &route, expires_in),
+            RedirectCaching {
+                cache_control: Some("private, max-age=86370".into()),
+                surrogate_control: Some("no-store".into()),
+                surrogate_key: Some("static.example.org".into()),
+                vary: None,
+            }
+        );
+        let route = RouteConfig {
+            cache_control: Some("public, max-age=600".into()),
+            authz_mode: None,
+        };
+        assert_eq!(
+            policies
+                .caching("data.example.org", None, &user, &route, expires_in)
+                .cache_control,
+            Some("private, max-age=600".into())
+        );
+    }
+
+    #[test]
+    fn signed_request_required_headers() {
+        let client = Client::new(
+            "key",
+            "secret",
+            "us-east-1",
+            "https://s3.amazonaws.com",
+            Duration::from_secs(300),
+        );
+
+        for signature_version in [SignatureVersion::V4, SignatureVersion::V2] {
+            let signed = S3SignedRequestBuilder::new()
+                .method("PUT")
+                .bucket("videos.example.org")
+                .object("movies/trailer.mp4")
+                .signature_version(signature_version)
+                .add_header("content-type", "video/mp4")
+                .build(&client)
+                .unwrap();
+            assert_eq!(signed.method, "PUT");
+            assert_eq!(
+                signed.headers.get("content-type").map(String::as_str),
+                Some("video/mp4")
+            );
+            let expires_in = signed.expires_at - chrono::Utc::now();
+            assert!(expires_in <= chrono::Duration::seconds(300));
+            assert!(expires_in > chrono::Duration::seconds(290));
+        }
+    }
+
+    #[test]
+    fn signed_request_range() {
+        let client = Client::new(
+            "key",
+            "secret",
+            "us-east-1",
+            "https://s3.amazonaws.com",
+            Duration::from_secs(300),
+        );
+
+        let signed = S3SignedRequestBuilder::new()
+            .method("GET")
+            .bucket("videos.example.org")
+            .object("movies/trailer.mp4")
+            .range_from(1048576)
+            .build(&client)
+            .unwrap();
+        assert_eq!(
+            signed.headers.get("range").map(String::as_str),
+            Some("bytes=1048576-")
+        );
+        let uri = Url::parse(&signed.uri).unwrap();
+        let signed_headers = uri
+            .query_pairs()
+            .find(|(key, _)| key == "X-Amz-SignedHeaders")
+            .map(|(_, value)| value.into_owned())
+            .unwrap();
+        assert!(signed_headers.split(';').any(|header| header == "range"));
+    }
+
+    #[test]
+    fn append_query_params_to_signed_request() {
+        use openssl::hash::MessageDigest;
+        use openssl::pkey::PKey;
+        use openssl::sign::Signer;
+
+        let hex = |bytes: &[u8]| {
+            bytes
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+        };
+        let hmac = |key: &[u8], data: &str| {
+            let key = PKey::hmac(key).unwrap();
+            let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+            signer.update(data.as_bytes()).unwrap();
+            signer.sign_to_vec().unwrap()
+        };
+        let encode = |value: &str| {
+            value
+                .bytes()
+                .map(|byte| match byte {
+                    b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                        (byte as char).to_string()
+                    }
+                    _ => format!("%{:02X}", byte),
+                })
+                .collect::<String>()
+        };
+
+        let client = Client::new(
+            "key",
+            "secret",
+            "us-east-1",
+            "https://s3.amazonaws.com",
+            Duration::from_secs(300),
+        );
+        let signed = S3SignedRequestBuilder::new()
+            .method("GET")
+            .bucket("videos.example.org")
+            .object("movies/trailer.mp4")
+            .build(&client)
+            .unwrap();
+        let mut extra = BTreeMap::new();
+        extra.insert("x-cdn-token".to_owned(), "a b&c".to_owned());
+        extra.insert("utm_source".to_owned(), "mail".to_owned());
+        let uri = append_query_params(&signed.uri, &extra).unwrap();
+
+        // The signature is verified the way S3 does it, over all parameters of the query
+        let verify = |uri: &Url| {
+            let mut params = uri
+                .query_pairs()
+                .map(|(key, val)| (key.into_owned(), val.into_owned()))
+                .collect::<Vec<_>>();
+            let param = |key: &str| {
+                params
+                    .iter()
+                    .find(|(name, _)| name == key)
+                    .map(|(_, val)| val.clone())
+                    .unwrap()
+            };
+            assert_eq!(param("X-Amz-SignedHeaders"), "host");
+            let signature = param("X-Amz-Signature");
+            let date = param("X-Amz-Date");
+            params.retain(|(key, _)| key != "X-Amz-Signature");
+            params.sort();
+            let query = params
+                .iter()
+                .map(|(key, val)| format!("{}={}", encode(key), encode(val)))
+                .collect::<Vec<_>>()
+                .join("&");
+            let canonical_request = format!(
+                "GET\n{}\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD",
+                uri.path(),
+                query,
+                uri.host_str().unwrap()
+            );
+            let scope = format!("{}/us-east-1/s3/aws4_request", &date[..8]);
+            let string_to_sign = format!(
+                "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+                date,
+                scope,
+                hex(&openssl::sha::sha256(canonical_request.as_bytes()))
+            );
+            let key = hmac(b"AWS4secret", &date[..8]);
+            let key = hmac(&key, "us-east-1");
+            let key = hmac(&key, "s3");
+            let key = hmac(&key, "aws4_request");
+            hex(&hmac(&key, &string_to_sign)) == signature
+        };
+
+        // Parameters follow the signature, they must be stripped before the request reaches S3
+        let mut url = Url::parse(&uri).unwrap();
+        let pairs = url
+            .query_pairs()
+            .map(|(key, val)| (key.into_owned(), val.into_owned()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pairs[pairs.len() - 2..],
+            [
+                ("utm_source".to_owned(), "mail".to_owned()),
+                ("x-cdn-token".to_owned(), "a b&c".to_owned())
+            ]
+        );
+        assert!(!verify(&url));
+        url.query_pairs_mut()
+            .clear()
+            .extend_pairs(pairs.iter().filter(|(key, _)| !extra.contains_key(key)));
+        assert!(verify(&url));
+        assert_eq!(url.as_str(), signed.uri);
+    }
+
+    #[test]
+    fn signed_request_url_styles() {
+        let mut client = Client::new(
+            "key",
+            "secret",
+            "us-east-1",
+            "https://s3.amazonaws.com",
+            Duration::from_secs(300),
+        );
+        let build = |client: &Client, signature_version, url_style| {
+            let uri = S3SignedRequestBuilder::new()
+                .method("GET")
+                .bucket("videos.example.org")
+                .object("movies/trailer.mp4")
+                .signature_version(signature_version)
+                .url_style(url_style)
+                .build(client)
+                .unwrap()
+                .uri;
+            let uri = Url::parse(&uri).unwrap();
+            (uri.host_str().unwrap().to_owned(), uri.path().to_owned())
+        };
+
+        for signature_version in [SignatureVersion::V4, SignatureVersion::V2] {
+            assert_eq!(
+                build(&client, signature_version, UrlStyle::Path),
+                (
+                    "s3.amazonaws.com".to_owned(),
+                    "/videos.example.org/movies/trailer.mp4".to_owned()
+                )
+            );
+            assert_eq!(
+                build(&client, signature_version, UrlStyle::VirtualHosted),
+                (
+                    "videos.example.org.s3.amazonaws.com".to_owned(),
+                    "/movies/trailer.mp4".to_owned()
+                )
+            );
+        }
+
+        // The bucket is kept in front of the proxy host
+        client.set_proxy_host("cdn.example.org");
+        assert_eq!(
+            build(&client, SignatureVersion::V4, UrlStyle::VirtualHosted),
+            (
+                "videos.example.org.cdn.example.org".to_owned(),
+                "/movies/trailer.mp4".to_owned()
+            )
+        );
+        assert_eq!(
+            build(&client, SignatureVersion::V4, UrlStyle::Path).0,
+            "cdn.example.org"
+        );
+    }
+
+    #[test]
+    fn validate_redirect_urls() {
+        let mut clients = S3Clients::new();
+        let client = |endpoint: &str| {
+            Client::new(
+                "key",
+                "secret",
+                "ru-central1",
+                endpoint,
+                Duration::from_secs(300),
+            )
+        };
+        let mut minio = ClientRouter::new("minio", client("http://minio:9000"));
+        minio.set_standbys(
+            vec![client("http://minio-standby:9000")],
+            Duration::from_secs(300),
+        );
+        clients.insert(
+            S3_DEFAULT_CLIENT.to_owned(),
+            Arc::new(ClientRouter::new(
+                S3_DEFAULT_CLIENT,
+                client("https://storage.yandexcloud.net"),
+            )),
+        );
+        clients.insert("minio".to_owned(), Arc::new(minio));
+        let redirects = RedirectValidator::new(&clients);
+
+        for url in &[
+            "https://storage.yandexcloud.net/example.org/foo?X-Amz-Signature=abc",
+            "https://example.org.s3-accelerate.amazonaws.com/foo?X-Amz-Signature=abc",
+            "http://minio:9000/example.org/foo",
+            "http://minio-standby:9000/example.org/foo",
+        ] {
+            assert_eq!(redirects.validate_redirect_url(url), Ok(()), "{}", url);
+        }
+        assert!(matches!(
+            redirects.validate_redirect_url("https://storage.yandexcloud.net:not-a-port/foo"),
+            Err(ValidationError::Malformed(_))
+        ));
+        assert_eq!(
+            redirects.validate_redirect_url("http://storage.yandexcloud.net/example.org/foo"),
+            Err(ValidationError::InsecureScheme("http".to_owned()))
+        );
+        assert_eq!(
+            redirects.validate_redirect_url("https://evil.example.com/example.org/foo"),
+            Err(ValidationError::UnknownHost("evil.example.com".to_owned()))
+        );
+        assert_eq!(
+            redirects.validate_redirect_url(
+                "https://storage.yandexcloud.net/example.org/foo?X-Amz-Date=1&fp=a&X-Amz-Date=2"
+            ),
+            Err(ValidationError::DuplicateQueryParam(
+                "X-Amz-Date".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn token_scope_allows_sign() {
+        let scope = TokenScope::parse(&[
+            "sign:bucket=videos.example.org/*:method=GET",
+            "sign:bucket=*.example.net/public.*:method=put",
+            "read:bucket=docs.example.org",
+        ])
+        .unwrap();
+
+        assert!(scope.allows_sign("videos.example.org", "foo.mp4", "GET"));
+        assert!(!scope.allows_sign("videos.example.org", "foo.mp4", "PUT"));
+        assert!(scope.allows_sign("data.example.net", "public.foo", "PUT"));
+        assert!(!scope.allows_sign("data.example.net", "private.foo", "PUT"));
+        assert!(!scope.allows_sign("docs.example.org", "foo", "GET"));
+
+        assert!(TokenScope::parse(&["sign:region=eu"]).is_err());
+        assert!(TokenScope::parse(&[":bucket=foo"]).is_err());
+    }
+
+    #[test]
+    fn resource_scopes_grant_objects() {
+        let scopes =
+            ResourceScopes::parse(&["r:videos/intro.mp4", "rw:docs/*", "r:media/public/*"])
+                .unwrap();
+
+        assert!(scopes.grants("videos", "intro.mp4", "read"));
+        assert!(!scopes.grants("videos", "intro.mp4", "update"));
+        assert!(!scopes.grants("videos", "outro.mp4", "read"));
+        assert!(scopes.grants("docs", "reports/2020.pdf", "read"));
+        assert!(scopes.grants("docs", "reports/2020.pdf", "update"));
+        assert!(!scopes.grants("docs", "reports/2020.pdf", "delete"));
+        assert!(scopes.grants("media", "public/foo.png", "read"));
+        assert!(!scopes.grants("media", "private/foo.png", "read"));
+        assert!(!scopes.grants("docs.example.org", "foo", "read"));
+
+        assert!(ResourceScopes::parse(&["w:docs/*"]).is_err());
+        assert!(ResourceScopes::parse(&["r:docs"]).is_err());
+        assert!(ResourceScopes::parse(&["rw"]).is_err());
+    }
+
+    #[test]
+    fn resource_scopes_of_bucket_audience() {
+        let mut subject = Subject::authenticated_by(
+            AccountId::new("player", "usr.example.net"),
+            AuthnMethod::Jwt,
+        );
+        subject.set_resource_scopes(Some(ResourceScopes::parse(&["r:videos/*"]).unwrap()));
+
+        assert!(subject.scope_grants("videos", "intro.mp4", "read", "usr.example.net"));
+        assert!(!subject.scope_grants("videos", "intro.mp4", "read", "example.org"));
+    }
+
+    #[test]
+    fn token_scope_from_token() {
+        let token = |payload: &str| {
+            format!(
+                "e30.{}.c2ln",
+                base64::encode_config(payload, base64::URL_SAFE_NO_PAD)
+            )
+        };
+
+        assert_eq!(
+            TokenScope::from_token(&token(r#"{"sub":"foo"}"#)).unwrap(),
+            None
+        );
+        let scope = TokenScope::from_token(&token(r#"{"scope":"sign:method=GET read"}"#))
+            .unwrap()
+            .unwrap();
+        assert!(scope.allows_sign("foo", "bar", "GET"));
+        assert!(!scope.allows_sign("foo", "bar", "PUT"));
+    }
+
+    #[test]
+    fn subject_claims_from_token() {
+        let token = |payload: &str| {
+            format!(
+                "e30.{}.c2ln",
+                base64::encode_config(payload, base64::URL_SAFE_NO_PAD)
+            )
+        };
+
+        assert_eq!(
+            SubjectClaims::from_token(&token(r#"{"sub":"foo"}"#)).unwrap(),
+            SubjectClaims::default()
+        );
+        let claims = SubjectClaims::from_token(&token(
+            r#"{"max_security_level":"SECRET","cost_center":"video"}"#,
+        ))
+        .unwrap();
+        let mut subject = Subject::new(AccountId::new("foo", "example.org"));
+        subject.set_claims(claims);
+        assert_eq!(subject.security_level(), Some("SECRET"));
+        assert_eq!(subject.cost_center(), Some("video"));
+        assert!(SubjectClaims::from_token(&token(r#"{"max_security_level":3}"#)).is_err());
+        assert!(SubjectClaims::from_token("foo").is_err());
+    }
+
+    #[test]
+    fn namespace_from_tokens() {
+        let token = |payload: &str| {
+            format!(
+                "e30.{}.c2ln",
+                base64::encode_config(payload, base64::URL_SAFE_NO_PAD)
+            )
+        };
+
+        assert_eq!(
+            namespace_from_token(&token(r#"{"tenant_id":"acme42"}"#), "tenant_id").unwrap(),
+            "acme42"
+        );
+        assert!(namespace_from_token(&token(r#"{"sub":"foo"}"#), "tenant_id").is_err());
+        assert!(namespace_from_token(&token(r#"{"tenant_id":42}"#), "tenant_id").is_err());
+        assert!(namespace_from_token(&token(r#"{"tenant_id":""}"#), "tenant_id").is_err());
+        // Hyphens would make prefixed names of buckets of tenants ambiguous
+        assert!(namespace_from_token(&token(r#"{"tenant_id":"acme-x"}"#), "tenant_id").is_err());
+        assert!(namespace_from_token(&token(r#"{"tenant_id":"Acme"}"#), "tenant_id").is_err());
+    }
+
+    #[test]
+    fn upload_stream_limit() {
+        use crate::app::validation::ReplayBody;
+
+        let read = |max_size| {
+            UploadStream::streamed(HttpBody::replay(Bytes::from("hello")), max_size)
+                .into_inner()
+                .concat2()
+                .wait()
+        };
+        assert_eq!(read(5).unwrap(), Bytes::from("hello"));
+        assert!(read(4).is_err());
+    }
+
+    #[test]
+    fn compress_roundtrip() {
+        use std::io::Read;
+
+        let data = "foo,bar,baz\n".repeat(100);
+
+        let gzipped = compress(data.as_bytes(), "gzip").unwrap();
+        assert!(gzipped.len() < data.len());
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&gzipped[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+
+        let brotlied = compress(data.as_bytes(), "brotli").unwrap();
+        assert!(brotlied.len() < data.len());
+        let mut decoded = String::new();
+        brotli::Decompressor::new(&brotlied[..], 4096)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+
+        assert!(compress(data.as_bytes(), "zstd").is_err());
+
+        // Outside of the pool allowed to block the data is compressed in place
+        let compressed = compress_blocking(data.clone().into_bytes(), "gzip".to_owned())
+            .wait()
+            .unwrap();
+        assert_eq!(compressed, gzipped);
+        assert!(compress_blocking(data.into_bytes(), "zstd".to_owned())
+            .wait()
+            .is_err());
+    }
+
+    #[test]
+    fn object_event_transitions() {
+        let version = |etag: &str| ObjectVersion {
+            etag: Some(etag.to_owned()),
+            last_modified: None,
+            checksum: None,
+            size: None,
+            content_type: None,
+        };
+        let (a, b) = (version("a"), version("b"));
+        assert_eq!(object_event(None, None), None);
+        assert_eq!(object_event(None, Some(&a)), Some("created"));
+        assert_eq!(object_event(Some(&a), None), Some("deleted"));
+        assert_eq!(object_event(Some(&a), Some(&a)), None);
+        assert_eq!(object_event(Some(&a), Some(&b)), Some("modified"));
+    }
+
+    #[test]
+    fn coalescer_run() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = AtomicUsize::new(0);
+        let call = || future::ok(calls.fetch_add(1, Ordering::SeqCst));
+
+        let c = Coalescer::new(Duration::from_secs(60));
+        assert_eq!(c.run("foo".into(), call).wait(), Ok(0));
+        assert_eq!(c.run("foo".into(), call).wait(), Ok(0));
+        assert_eq!(c.run("bar".into(), call).wait(), Ok(1));
+
+        let c = Coalescer::new(Duration::from_secs(0));
+        assert_eq!(c.run("foo".into(), call).wait(), Ok(2));
+        assert_eq!(c.run("foo".into(), call).wait(), Ok(3));
+    }
+
+    #[test]
+    fn client_identity_ip_prefix() {
+        let a = ClientIdentity::new(None, "192.168.1.10".parse().ok());
+        let b = ClientIdentity::new(None, "192.168.1.20".parse().ok());
+        let c = ClientIdentity::new(None, "192.168.2.10".parse().ok());
+        let sub = AccountId::new("foo", "example.org");
+        assert_eq!(a.fingerprint(&sub), b.fingerprint(&sub));
+        assert_ne!(a.fingerprint(&sub), c.fingerprint(&sub));
+    }
+
+    #[test]
+    fn client_identity_verify() {
+        let identity = ClientIdentity::new(Some("curl/7.64.1"), "10.0.0.1".parse().ok());
+        let alice = AccountId::new("alice", "example.org");
+        let bob = AccountId::new("bob", "example.org");
+        let uri = identity
+            .apply(
+                &alice,
+                "https://s3.example.org/example.org/foo.bar?X-Amz-Signature=abc",
+            )
+            .unwrap();
+        assert!(uri.starts_with("https://s3.example.org/example.org/foo.bar?X-Amz-Signature=abc&"));
+        assert!(identity.verify(&alice, &uri).unwrap());
+        assert!(!identity.verify(&bob, &uri).unwrap());
+        assert!(!ClientIdentity::default().verify(&alice, &uri).unwrap());
+        assert!(identity
+            .verify(&alice, "https://s3.example.org/example.org/foo.bar")
+            .is_err());
+    }
+
+    #[test]
+    fn append_query_params_unsigned() {
+        let uri = "https://s3.example.org/example.org/foo.bar?X-Amz-Signature=abc";
+        assert_eq!(append_query_params(uri, &BTreeMap::new()).unwrap(), uri);
+
+        let mut params = BTreeMap::new();
+        params.insert("x-cdn-token".to_owned(), "a b&c".to_owned());
+        params.insert("utm_source".to_owned(), "mail".to_owned());
+        assert_eq!(
+            append_query_params(uri, &params).unwrap(),
+            "https://s3.example.org/example.org/foo.bar?X-Amz-Signature=abc&utm_source=mail&x-cdn-token=a+b%26c"
+        );
+    }
+
+    #[test]
+    fn audience_credentials_resolve() {
+        let config = vec![AudienceS3Credentials {
+            audience: "*.example.org".into(),
+            access_key_id: "key".into(),
+            secret_access_key: "secret".into(),
+            role_arn: None,
+            region: None,
+        }];
+        let credentials = AudienceCredentials::new(&config).unwrap();
+
+        let resolved = credentials.resolve("usr.example.org").wait().unwrap();
+        assert_eq!(
+            resolved.map(|c| c.aws_access_key_id().to_owned()),
+            Some("key".into())
+        );
+        assert!(credentials.resolve("example.net").wait().unwrap().is_none());
+    }
+
+    #[test]
+    fn object_version_cache_lookup() {
+        let version = |etag: &str| {
+            Some(ObjectVersion {
+                etag: Some(etag.to_owned()),
+                last_modified: None,
+                checksum: None,
+                size: None,
+                content_type: None,
+            })
+        };
+        let cache = Arc::new(ObjectVersionCache::new(1, Duration::from_secs(60)));
+
+        let v = cache.lookup("a".into(), || future::ok(version("1"))).wait();
+        assert_eq!(v.unwrap(), version("1"));
+        let v = cache.lookup("a".into(), || future::ok(version("2"))).wait();
+        assert_eq!(v.unwrap(), version("1"));
+
+        // Evicts the least recently used entry
+        let v = cache.lookup("b".into(), || future::ok(version("3"))).wait();
+        assert_eq!(v.unwrap(), version("3"));
+        let v = cache.lookup("a".into(), || future::ok(version("4"))).wait();
+        assert_eq!(v.unwrap(), version("4"));
+
+        let cache = Arc::new(ObjectVersionCache::new(1, Duration::from_secs(0)));
+        cache
+            .lookup("a".into(), || future::ok(version("1")))
+            .wait()
+            .unwrap();
+        let v = cache.lookup("a".into(), || future::ok(version("2"))).wait();
+        assert_eq!(v.unwrap(), version("2"));
+    }
+
+    #[test]
+    fn sign_cache_keys() {
+        let mut headers = BTreeMap::new();
+        headers.insert("range".to_owned(), "bytes=0-1023".to_owned());
+        fn key(method: &str, headers: &BTreeMap<String, String>) -> Option<String> {
+            SignCacheKey {
+                backend: "default",
+                method,
+                bucket: "example.org",
+                object: "foo.bar",
+                headers,
+                signature_version: None,
+                url_style: None,
+                accelerate: false,
+            }
+            .to_key()
+        }
+
+        assert!(key("GET", &headers).is_some());
+        assert_eq!(key("HEAD", &headers), key("HEAD", &headers));
+        assert_ne!(key("GET", &headers), key("HEAD", &headers));
+        assert_ne!(key("GET", &headers), key("GET", &BTreeMap::new()));
+        assert_eq!(key("PUT", &headers), None);
+        assert_eq!(key("DELETE", &headers), None);
+
+        headers.insert("X-Amz-Meta-Owner".to_owned(), "john".to_owned());
+        assert_eq!(key("GET", &headers), None);
+    }
+
+    #[test]
+    fn sign_cache_lookup() {
+        let expires_in = Duration::from_secs(300);
+        let signed = |uri: &str| SignedUri {
+            uri: uri.to_owned(),
+            method: "GET".to_owned(),
+            headers: BTreeMap::new(),
+            expires_at: chrono::Utc::now() + chrono::Duration::seconds(300),
+        };
+        let cache = SignCache::new(1);
+        assert_eq!(cache.hit_ratio(), None);
+
+        assert_eq!(cache.get("a"), None);
+        let a = signed("https://s3.example.org/a");
+        cache.insert("a".into(), a.clone(), expires_in);
+        assert_eq!(cache.get("a"), Some(a.clone()));
+        assert_eq!(cache.hit_ratio(), Some(0.5));
+
+        // Evicts the least recently used entry
+        cache.insert("b".into(), signed("https://s3.example.org/b"), expires_in);
+        assert_eq!(cache.get("a"), None);
+
+        // URIs expiring within the margin aren't cached
+        cache.insert(
+            "c".into(),
+            signed("https://s3.example.org/c"),
+            SIGN_CACHE_EXPIRY_MARGIN,
+        );
+        assert_eq!(cache.get("c"), None);
+
+        let cache = SignCache::new(0);
+        cache.insert("a".into(), a, expires_in);
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.hit_ratio(), None);
+    }
+
+    #[test]
+    fn recent_responses_lookup() {
+        let responses = RecentResponses::new(Duration::from_secs(60), 2);
+        responses.insert("john".into(), "h1".into(), 1, "a");
+        assert_eq!(responses.get("john", "h1", 1), Some("a"));
+
+        // Other subjects and other requests with the same hash don't get the response
+        assert_eq!(responses.get("jane", "h1", 1), None);
+        assert_eq!(responses.get("john", "h1", 2), None);
+
+        // Evicts the oldest entry
+        responses.insert("john".into(), "h2".into(), 2, "b");
+        responses.insert("john".into(), "h3".into(), 3, "c");
+        assert_eq!(responses.get("john", "h1", 1), None);
+        assert_eq!(responses.get("john", "h2", 2), Some("b"));
+
+        let responses = RecentResponses::new(Duration::from_secs(0), 2);
+        responses.insert("john".into(), "h1".into(), 1, "a");
+        assert_eq!(responses.get("john", "h1", 1), None);
+    }
+
+    #[test]
+    fn copy_matches_etags() {
+        let etag = |val: &str| Some(val.to_owned());
+        assert!(copy_matches(&etag("\"a\""), &etag("\"a\"")));
+        assert!(!copy_matches(&etag("\"a\""), &etag("\"b\"")));
+        assert!(copy_matches(&etag("\"a-2\""), &etag("\"b\"")));
+        assert!(copy_matches(&None, &etag("\"b\"")));
+    }
+
+    #[test]
+    fn delete_unless_held_keeps_held_objects() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let deleted = Arc::new(AtomicBool::new(false));
+        let delete = |held: bool| {
+            let deleted = deleted.clone();
+            delete_unless_held(future::ok(held), move || {
+                deleted.store(true, Ordering::SeqCst);
+                future::ok(())
+            })
+        };
+
+        assert!(!delete(true).wait().unwrap());
+        assert!(!deleted.load(Ordering::SeqCst));
+        assert!(delete(false).wait().unwrap());
+        assert!(deleted.load(Ordering::SeqCst));
+
+        // Objects aren't deleted unless their legal hold is confirmed to be released
+        let failed = delete_unless_held(future::err(format_err!("failed")), || {
+            future::ok::<_, anyhow::Error>(())
+        });
+        assert!(failed.wait().is_err());
+    }
+}
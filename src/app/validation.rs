@@ -0,0 +1,442 @@
+use std::io;
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{Async, Future, Poll};
+use http::header;
+use http::request::Parts;
+use http::{Method, Request, Response, StatusCode};
+use tower_service::Service;
+use tower_web::middleware::Middleware;
+use tower_web::util::buf_stream::{size_hint, BufStream, SizeHint};
+use tower_web::util::http::HttpService;
+use tower_web::util::tuple::Either2;
+
+use crate::app::config::JsonValidationConfig;
+use crate::app::cors::SharedService;
+use crate::app::util::wildcard_match;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Validates bodies of requests to the configured routes before they're routed, so clients get
+/// the position of a syntax error rather than a generic error of the extractor.
+/// Requests of other routes and the ones without a body are passed through as is.
+/// Validated bodies are passed on as bodies of the same type, so the middleware could be
+/// composed with the services expecting bodies of the listener.
+pub(crate) struct JsonValidationMiddleware {
+    path_patterns: Vec<String>,
+    max_body_bytes: usize,
+}
+
+impl JsonValidationMiddleware {
+    pub(crate) fn new(config: &JsonValidationConfig) -> Self {
+        Self {
+            path_patterns: config.path_patterns.clone(),
+            max_body_bytes: config.max_body_bytes,
+        }
+    }
+}
+
+impl<S> Middleware<S> for JsonValidationMiddleware
+where
+    S: HttpService,
+    S::RequestBody: ReplayBody,
+{
+    type Request = Request<S::RequestBody>;
+    type Response = Response<EitherBody<S::ResponseBody>>;
+    type Error = S::Error;
+    type Service = JsonValidationService<S>;
+
+    fn wrap(&self, service: S) -> Self::Service {
+        JsonValidationService {
+            inner: SharedService::new(service),
+            path_patterns: self.path_patterns.clone(),
+            max_body_bytes: self.max_body_bytes,
+        }
+    }
+}
+
+pub(crate) struct JsonValidationService<S> {
+    inner: SharedService<S>,
+    path_patterns: Vec<String>,
+    max_body_bytes: usize,
+}
+
+impl<S> JsonValidationService<S> {
+    fn validates(&self, method: &Method, path: &str) -> bool {
+        (method == Method::POST || method == Method::PUT || method == Method::PATCH)
+            && self
+                .path_patterns
+                .iter()
+                .any(|pattern| wildcard_match(pattern, path))
+    }
+}
+
+impl<S> Service for JsonValidationService<S>
+where
+    S: HttpService,
+    S::RequestBody: ReplayBody,
+{
+    type Request = Request<S::RequestBody>;
+    type Response = Response<EitherBody<S::ResponseBody>>;
+    type Error = S::Error;
+    type Future = JsonValidationFuture<S>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, request: Self::Request) -> Self::Future {
+        let state = if !self.validates(request.method(), request.uri().path()) {
+            State::Calling(self.inner.call(request))
+        } else if let Err(rejection) = validate_head(&request, self.max_body_bytes) {
+            State::Rejected(Some(rejection))
+        } else {
+            let (parts, body) = request.into_parts();
+            State::Reading {
+                parts: Some(parts),
+                body,
+                buf: BytesMut::new(),
+            }
+        };
+
+        JsonValidationFuture {
+            inner: self.inner.clone(),
+            max_body_bytes: self.max_body_bytes,
+            state,
+        }
+    }
+}
+
+enum State<F, B> {
+    Reading {
+        parts: Option<Parts>,
+        body: B,
+        buf: BytesMut,
+    },
+    Calling(F),
+    Rejected(Option<Rejection>),
+}
+
+pub(crate) struct JsonValidationFuture<S>
+where
+    S: HttpService,
+{
+    inner: SharedService<S>,
+    max_body_bytes: usize,
+    state: State<S::Future, S::RequestBody>,
+}
+
+impl<S> Future for JsonValidationFuture<S>
+where
+    S: HttpService,
+    S::RequestBody: ReplayBody,
+{
+    type Item = Response<EitherBody<S::ResponseBody>>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let request = match self.state {
+                State::Calling(ref mut future) => {
+                    return match future.poll()? {
+                        Async::Ready(response) => {
+                            Ok(Async::Ready(response.map(EitherBody::Stream)))
+                        }
+                        Async::NotReady => Ok(Async::NotReady),
+                    };
+                }
+                State::Rejected(ref mut rejection) => {
+                    let rejection = rejection
+                        .take()
+                        .expect("Request validation future is polled after completion");
+                    return Ok(Async::Ready(rejection.into_response()));
+                }
+                State::Reading {
+                    ref mut parts,
+                    ref mut body,
+                    ref mut buf,
+                } => match body.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(Some(mut chunk))) => {
+                        while chunk.has_remaining() {
+                            let len = {
+                                let bytes = chunk.bytes();
+                                buf.extend_from_slice(bytes);
+                                bytes.len()
+                            };
+                            chunk.advance(len);
+                        }
+
+                        if buf.len() <= self.max_body_bytes {
+                            continue;
+                        }
+                        Err(too_large(self.max_body_bytes))
+                    }
+                    Ok(Async::Ready(None)) => validate_body(buf).map(|()| {
+                        let parts = parts
+                            .take()
+                            .expect("Request validation future is polled after completion");
+                        let body = ReplayBody::replay(buf.take().freeze());
+                        Request::from_parts(parts, body)
+                    }),
+                    Err(_) => Err(Rejection::new(
+                        StatusCode::BAD_REQUEST,
+                        "invalid_body",
+                        "failed to read the request body",
+                    )),
+                },
+            };
+
+            self.state = match request {
+                Ok(request) => State::Calling(self.inner.call(request)),
+                Err(rejection) => State::Rejected(Some(rejection)),
+            };
+        }
+    }
+}
+
+/// Content type and length are checked before the body is read.
+fn validate_head<B>(request: &Request<B>, max_body_bytes: usize) -> Result<(), Rejection> {
+    let is_json = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|mime| mime.trim().eq_ignore_ascii_case("application/json"))
+        .unwrap_or(false);
+    if !is_json {
+        return Err(Rejection::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_media_type",
+            "content-type of the request must be application/json",
+        ));
+    }
+
+    let content_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    match content_length {
+        Some(len) if len > max_body_bytes => Err(too_large(max_body_bytes)),
+        _ => Ok(()),
+    }
+}
+
+fn validate_body(body: &[u8]) -> Result<(), Rejection> {
+    serde_json::from_slice::<serde::de::IgnoredAny>(body)
+        .map(|_| ())
+        .map_err(|err| Rejection {
+            position: Some((err.line(), err.column())),
+            ..Rejection::new(StatusCode::BAD_REQUEST, "invalid_json", &err.to_string())
+        })
+}
+
+fn too_large(max_body_bytes: usize) -> Rejection {
+    Rejection::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "payload_too_large",
+        &format!("request body must be at most {} bytes", max_body_bytes),
+    )
+}
+
+/// Responded with the kind of the failure along with the position of JSON syntax errors.
+struct Rejection {
+    status: StatusCode,
+    kind: &'static str,
+    detail: String,
+    position: Option<(usize, usize)>,
+}
+
+impl Rejection {
+    fn new(status: StatusCode, kind: &'static str, detail: &str) -> Self {
+        Self {
+            status,
+            kind,
+            detail: detail.to_owned(),
+            position: None,
+        }
+    }
+
+    fn into_response<R>(self) -> Response<EitherBody<R>> {
+        let mut body = serde_json::json!({
+            "kind": self.kind,
+            "title": "Error validating the request",
+            "detail": self.detail,
+        });
+        if let Some((line, column)) = self.position {
+            body["line"] = line.into();
+            body["column"] = column.into();
+        }
+
+        Response::builder()
+            .status(self.status)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(EitherBody::Buffered(Some(Bytes::from(body.to_string()))))
+            .expect("Error building a request validation response")
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Request bodies which could be constructed from the bytes read out of another one.
+pub(crate) trait ReplayBody: BufStream {
+    fn replay(bytes: Bytes) -> Self;
+}
+
+/// Body of the wrapped response, or the one of a rejection.
+pub(crate) enum EitherBody<B> {
+    Stream(B),
+    Buffered(Option<Bytes>),
+}
+
+impl<B> BufStream for EitherBody<B>
+where
+    B: BufStream,
+{
+    type Item = Either2<B::Item, io::Cursor<Bytes>>;
+    type Error = B::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self {
+            EitherBody::Stream(body) => body
+                .poll()
+                .map(|ready| ready.map(|item| item.map(Either2::A))),
+            EitherBody::Buffered(bytes) => {
+                let item = bytes
+                    .take()
+                    .filter(|bytes| !bytes.is_empty())
+                    .map(|bytes| Either2::B(io::Cursor::new(bytes)));
+                Ok(Async::Ready(item))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self {
+            EitherBody::Stream(body) => body.size_hint(),
+            EitherBody::Buffered(bytes) => {
+                let len = bytes.as_ref().map(Bytes::len).unwrap_or(0);
+                size_hint::Builder::new()
+                    .available(len)
+                    .lower(len)
+                    .upper(len)
+                    .build()
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::future;
+
+    impl ReplayBody for EitherBody<&'static str> {
+        fn replay(bytes: Bytes) -> Self {
+            EitherBody::Buffered(Some(bytes))
+        }
+    }
+
+    /// Responds with the way the body has been passed.
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = Request<EitherBody<&'static str>>;
+        type Response = Response<&'static str>;
+        type Error = ();
+        type Future = future::FutureResult<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, request: Self::Request) -> Self::Future {
+            let body = match request.into_body() {
+                EitherBody::Stream(_) => "stream",
+                EitherBody::Buffered(_) => "buffered",
+            };
+            future::ok(Response::new(body))
+        }
+    }
+
+    fn request(
+        method: &str,
+        path: &str,
+        content_type: &str,
+        body: &'static str,
+    ) -> Request<EitherBody<&'static str>> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(EitherBody::Stream(body))
+            .unwrap()
+    }
+
+    fn rejection_body(response: Response<EitherBody<&'static str>>) -> serde_json::Value {
+        match response.into_body() {
+            EitherBody::Buffered(Some(bytes)) => serde_json::from_slice(&bytes).unwrap(),
+            _ => panic!("expected a rejection"),
+        }
+    }
+
+    #[test]
+    fn json_validation() {
+        let config = JsonValidationConfig {
+            path_patterns: vec![String::from("/api/*/sign")],
+            max_body_bytes: 32,
+        };
+        let mut service = JsonValidationMiddleware::new(&config).wrap(Echo);
+        let mut call = |request| service.call(request).wait().unwrap();
+
+        let resp = call(request(
+            "POST",
+            "/api/v2/sign",
+            "application/json; charset=utf-8",
+            r#"{"set": "foo"}"#,
+        ));
+        assert_eq!(resp.status(), StatusCode::OK);
+        match resp.into_body() {
+            EitherBody::Stream(body) => assert_eq!(body, "buffered"),
+            EitherBody::Buffered(_) => panic!("expected a response of the inner service"),
+        }
+
+        let resp = call(request(
+            "POST",
+            "/api/v2/sign",
+            "application/json",
+            "{\n\"set\" \"foo\"}",
+        ));
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = rejection_body(resp);
+        assert_eq!(body["kind"], "invalid_json");
+        assert_eq!(body["line"], 2);
+        assert_eq!(body["column"], 7);
+
+        let resp = call(request("POST", "/api/v1/sign", "text/plain", "{}"));
+        assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(rejection_body(resp)["kind"], "unsupported_media_type");
+
+        let resp = call(request(
+            "POST",
+            "/api/v2/backends/foo/sign",
+            "application/json",
+            r#"{"set": "0123456789012345678901234567890123456789"}"#,
+        ));
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(rejection_body(resp)["kind"], "payload_too_large");
+
+        // Requests of other routes or without a body aren't validated
+        for (method, path) in &[("POST", "/api/v1/check-access"), ("GET", "/api/v2/sign")] {
+            let resp = call(request(method, path, "text/plain", "{"));
+            match resp.into_body() {
+                EitherBody::Stream(body) => assert_eq!(body, "stream"),
+                EitherBody::Buffered(_) => panic!("expected a response of the inner service"),
+            }
+        }
+    }
+}
@@ -0,0 +1,319 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{format_err, Context};
+use log::error;
+use wasmtime::{Engine, Instance, Memory, Module, Store, TypedFunc};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Engine of sandboxed modules, their calls are metered by fuel.
+pub(crate) fn engine() -> anyhow::Result<Engine> {
+    let mut config = wasmtime::Config::new();
+    config.consume_fuel(true);
+    Engine::new(&config).context("failed to create a wasm engine")
+}
+
+/// Limits of a single call of a sandboxed module.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SandboxLimits {
+    /// Fuel of the call, roughly the number of instructions it's allowed to run.
+    pub(crate) fuel: u64,
+    /// Instances whose memory has grown larger are replaced with fresh ones after the call.
+    pub(crate) max_memory_bytes: usize,
+}
+
+/// Module run in isolation from the application.
+///
+/// The module must export `memory` and `alloc(len: i32) -> i32` returning a pointer to `len` bytes
+/// of the memory, arguments are written there. It can't import anything, so it has no access
+/// to the network or filesystem.
+///
+/// Calls run out of fuel once they exceed the limit. Memory allocated for arguments is never freed,
+/// so the instance is replaced with a fresh one once its memory grows larger than the limit,
+/// as well as after failures of its calls, so that a trapped instance is never called again.
+pub(crate) struct Sandbox<T> {
+    kind: &'static str,
+    path: PathBuf,
+    module: Module,
+    limits: SandboxLimits,
+    exports: fn(&Instance) -> anyhow::Result<T>,
+    instance: RefCell<SandboxInstance<T>>,
+}
+
+impl<T> fmt::Debug for Sandbox<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Sandbox")
+            .field("kind", &self.kind)
+            .field("path", &self.path)
+            .field("limits", &self.limits)
+            .finish()
+    }
+}
+
+impl<T> Sandbox<T> {
+    /// Instantiates the module, `exports` looks up functions of the module besides `alloc`.
+    /// The `kind` of the module names it in errors, e.g. `wasm plugin`.
+    pub(crate) fn new(
+        kind: &'static str,
+        module: Module,
+        path: &Path,
+        limits: SandboxLimits,
+        exports: fn(&Instance) -> anyhow::Result<T>,
+    ) -> anyhow::Result<Self> {
+        let instance = SandboxInstance::new(kind, &module, exports)?;
+        Ok(Self {
+            kind,
+            path: path.to_owned(),
+            module,
+            limits,
+            exports,
+            instance: RefCell::new(instance),
+        })
+    }
+
+    /// Compiles the module of the file with the engine of sandboxes and instantiates it.
+    pub(crate) fn load(
+        kind: &'static str,
+        path: &Path,
+        limits: SandboxLimits,
+        exports: fn(&Instance) -> anyhow::Result<T>,
+    ) -> anyhow::Result<Self> {
+        let module = Module::from_file(&engine()?, path)
+            .with_context(|| format!("failed to load a {}, path = '{}'", kind, path.display()))?;
+        Self::new(kind, module, path, limits, exports)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Calls the module with the fuel of a single call, the instance is replaced afterwards
+    /// if the call has failed or the memory has grown larger than the limit.
+    pub(crate) fn call<R, F>(&self, call: F) -> anyhow::Result<R>
+    where
+        F: FnOnce(&SandboxInstance<T>) -> anyhow::Result<R>,
+    {
+        let result = {
+            let instance = self.instance.borrow();
+            instance
+                .refuel(self.limits.fuel)
+                .and_then(|()| call(&instance))
+        };
+        self.recycle(result.is_err());
+        result
+    }
+
+    fn recycle(&self, failed: bool) {
+        let size = self.instance.borrow().memory.data_size();
+        if !failed && size <= self.limits.max_memory_bytes {
+            return;
+        }
+
+        match SandboxInstance::new(self.kind, &self.module, self.exports) {
+            Ok(instance) => *self.instance.borrow_mut() = instance,
+            Err(err) => error!(
+                "Error replacing the instance of the {}, path = '{}': {:#}",
+                self.kind,
+                self.path.display(),
+                err
+            ),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn memory_size(&self) -> usize {
+        self.instance.borrow().memory.data_size()
+    }
+}
+
+/// Instance of the module in a store of its own, along with functions it exports.
+pub(crate) struct SandboxInstance<T> {
+    kind: &'static str,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    exports: T,
+    fuel_added: Cell<u64>,
+    // The store must outlive the instance
+    store: Store,
+}
+
+impl<T> SandboxInstance<T> {
+    fn new(
+        kind: &'static str,
+        module: &Module,
+        exports: fn(&Instance) -> anyhow::Result<T>,
+    ) -> anyhow::Result<Self> {
+        if module.imports().len() > 0 {
+            return Err(format_err!("{} must not import anything", kind));
+        }
+
+        let store = Store::new(module.engine());
+        let instance = Instance::new(&store, module, &[])
+            .with_context(|| format!("failed to instantiate a {}", kind))?;
+        let memory = instance
+            .get_memory("memory")
+            .ok_or_else(|| format_err!("{} doesn't export 'memory'", kind))?;
+        let alloc = instance
+            .get_typed_func("alloc")
+            .with_context(|| format!("invalid 'alloc' export of {}", kind))?;
+        let exports = exports(&instance)?;
+
+        Ok(Self {
+            kind,
+            memory,
+            alloc,
+            exports,
+            fuel_added: Cell::new(0),
+            store,
+        })
+    }
+
+    pub(crate) fn exports(&self) -> &T {
+        &self.exports
+    }
+
+    /// Tops the fuel of the store up to the amount of a single call.
+    fn refuel(&self, fuel: u64) -> anyhow::Result<()> {
+        let consumed = self.store.fuel_consumed().unwrap_or_default();
+        let remaining = self.fuel_added.get().saturating_sub(consumed);
+        if remaining < fuel {
+            self.store.add_fuel(fuel - remaining)?;
+            self.fuel_added
+                .set(self.fuel_added.get() + fuel - remaining);
+        }
+        Ok(())
+    }
+
+    /// Copies the bytes to the memory allocated by the module, returns the pointer and the length.
+    pub(crate) fn write(&self, bytes: &[u8]) -> anyhow::Result<(i32, i32)> {
+        let len = bytes.len() as i32;
+        let ptr = self.alloc.call(len)?;
+
+        // Safe since the memory isn't accessed by the module while it's being written
+        let memory = unsafe { self.memory.data_unchecked_mut() };
+        let start = ptr as usize;
+        let end = start.saturating_add(bytes.len());
+        if ptr < 0 || end > memory.len() {
+            return Err(format_err!("{} allocated memory out of bounds", self.kind));
+        }
+        memory[start..end].copy_from_slice(bytes);
+
+        Ok((ptr, len))
+    }
+
+    pub(crate) fn write_json<V: serde::Serialize>(&self, value: &V) -> anyhow::Result<(i32, i32)> {
+        let bytes = serde_json::to_vec(value)
+            .with_context(|| format!("failed to serialize arguments of {}", self.kind))?;
+        self.write(&bytes)
+    }
+
+    /// Copies `len` bytes of the memory from the pointer returned by the module.
+    pub(crate) fn read(&self, ptr: usize, len: usize) -> anyhow::Result<Vec<u8>> {
+        // Safe since the module isn't running while the memory is being read
+        let memory = unsafe { self.memory.data_unchecked() };
+        memory
+            .get(ptr..ptr.saturating_add(len))
+            .map(|bytes| bytes.to_vec())
+            .ok_or_else(|| format_err!("{} returned memory out of bounds", self.kind))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: SandboxLimits = SandboxLimits {
+        fuel: 1_000_000,
+        max_memory_bytes: 3 * 65536,
+    };
+
+    fn sandboxed(wat: &str) -> anyhow::Result<Sandbox<TypedFunc<(i32, i32), i32>>> {
+        let module = Module::new(&engine().unwrap(), wat).unwrap();
+        Sandbox::new(
+            "wasm module",
+            module,
+            Path::new("module.wasm"),
+            LIMITS,
+            |instance| {
+                instance
+                    .get_typed_func("run")
+                    .context("invalid 'run' export of wasm module")
+            },
+        )
+    }
+
+    fn run(sandbox: &Sandbox<TypedFunc<(i32, i32), i32>>, bytes: &[u8]) -> anyhow::Result<i32> {
+        sandbox.call(|instance| {
+            let (ptr, len) = instance.write(bytes)?;
+            instance.exports().call((ptr, len)).map_err(Into::into)
+        })
+    }
+
+    #[test]
+    fn sandbox_exports() {
+        let sandbox = sandboxed(
+            r#"
+            (module
+                (memory (export "memory") 1)
+                (func (export "alloc") (param $len i32) (result i32)
+                    (i32.const 16))
+                (func (export "run") (param $ptr i32) (param $len i32) (result i32)
+                    (i32.load8_u (local.get $ptr))))
+            "#,
+        )
+        .unwrap();
+        assert_eq!(run(&sandbox, b"a").unwrap(), 97);
+        assert_eq!(sandbox.call(|instance| instance.read(16, 1)).unwrap(), b"a");
+        assert!(sandbox.call(|instance| instance.read(65536, 1)).is_err());
+
+        assert!(sandboxed(r#"(module (memory (export "memory") 1))"#).is_err());
+        assert!(sandboxed(
+            r#"(module (import "wasi_snapshot_preview1" "proc_exit" (func (param i32))))"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn sandbox_limits() {
+        // Calls running out of fuel fail, the instance is replaced after the trap
+        let sandbox = sandboxed(
+            r#"
+            (module
+                (memory (export "memory") 1)
+                (global $calls (mut i32) (i32.const 0))
+                (func (export "alloc") (param $len i32) (result i32)
+                    (i32.const 0))
+                (func (export "run") (param $ptr i32) (param $len i32) (result i32)
+                    (global.set $calls (i32.add (global.get $calls) (i32.const 1)))
+                    (if (local.get $len) (then (loop $forever (br $forever))))
+                    (global.get $calls)))
+            "#,
+        )
+        .unwrap();
+        assert_eq!(run(&sandbox, b"").unwrap(), 1);
+        assert_eq!(run(&sandbox, b"").unwrap(), 2);
+        assert!(run(&sandbox, b"loop").is_err());
+        assert_eq!(run(&sandbox, b"").unwrap(), 1);
+
+        // Memory leaked by allocations is reclaimed by replacing the instance
+        let sandbox = sandboxed(
+            r#"
+            (module
+                (memory (export "memory") 1)
+                (func (export "alloc") (param $len i32) (result i32)
+                    (i32.mul (memory.grow (i32.const 1)) (i32.const 65536)))
+                (func (export "run") (param $ptr i32) (param $len i32) (result i32)
+                    (i32.const 0)))
+            "#,
+        )
+        .unwrap();
+        for _ in 0..10 {
+            assert_eq!(run(&sandbox, b"a").unwrap(), 0);
+            assert!(sandbox.memory_size() <= LIMITS.max_memory_bytes);
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use std::io;
+use std::time::Duration;
+
+use anyhow::{format_err, Context};
+use bytes::Bytes;
+use futures::sync::mpsc;
+use futures::{Async, Future, Poll, Sink, Stream};
+use http::Response;
+use log::error;
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use tower_web::util::BufStream;
+
+use crate::app::config::WebsiteConfig;
+
+////////////////////////////////////////////////////////////////////////////////
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Headers of responses of the website endpoint passed to clients.
+const PROXIED_HEADERS: &[&str] = &[
+    "content-type",
+    "content-encoding",
+    "content-language",
+    "cache-control",
+    "etag",
+    "last-modified",
+    "expires",
+];
+
+/// Access to an object of a bucket in website mode, granted by the application.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct WebsiteToken {
+    pub(crate) backend: String,
+    pub(crate) bucket: String,
+    pub(crate) object: String,
+    pub(crate) expires_at: i64,
+    /// Namespace of the tenant the bucket belongs to, see multitenancy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) namespace: Option<String>,
+}
+
+/// Serves objects of buckets in website mode: signs tokens of redirect URIs and fetches objects
+/// from website endpoints, which don't support signed requests.
+#[derive(Clone)]
+pub(crate) struct Website {
+    config: WebsiteConfig,
+    http: reqwest::r#async::Client,
+}
+
+impl std::fmt::Debug for Website {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("Website")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl Website {
+    pub(crate) fn new(config: WebsiteConfig) -> anyhow::Result<Self> {
+        let http = reqwest::r#async::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .context("failed to create an HTTP client")?;
+
+        Ok(Self { config, http })
+    }
+
+    pub(crate) fn endpoint(&self) -> Option<&str> {
+        self.config.endpoint.as_deref()
+    }
+
+    /// Redirect URI of the object, `<public_url>/r/<token>`.
+    pub(crate) fn redirect_uri(&self, token: &WebsiteToken) -> anyhow::Result<String> {
+        Ok(format!(
+            "{}/r/{}",
+            self.config.public_url.trim_end_matches('/'),
+            encode_token(token, &self.config.token_secret)?
+        ))
+    }
+
+    pub(crate) fn verify(&self, token: &str, now: i64) -> anyhow::Result<WebsiteToken> {
+        decode_token(token, &self.config.token_secret, now)
+    }
+
+    /// Fetches the object from the website endpoint, the response is streamed through a buffer
+    /// of `buffered_chunks`, objects larger than `max_body_bytes` are rejected.
+    pub(crate) fn fetch(
+        &self,
+        url: &str,
+    ) -> impl Future<Item = Response<WebsiteBody>, Error = anyhow::Error> + Send {
+        let max_bytes = self.config.max_body_bytes;
+        let buffered_chunks = self.config.buffered_chunks;
+
+        self.http
+            .get(url)
+            .send()
+            .map_err(|err| {
+                anyhow::Error::from(err)
+                    .context("failed to fetch an object from the website endpoint")
+            })
+            .and_then(move |resp| {
+                if resp.content_length().is_some_and(|len| len > max_bytes) {
+                    return Err(too_large(max_bytes));
+                }
+
+                let mut builder = Response::builder();
+                builder.status(resp.status());
+                for name in PROXIED_HEADERS {
+                    if let Some(value) = resp.headers().get(*name) {
+                        builder.header(*name, value.clone());
+                    }
+                }
+
+                let chunks = resp.into_body().map(|chunk| Bytes::from(chunk.as_ref()));
+                let (body, forward) = stream_body(chunks, max_bytes, buffered_chunks);
+                // Reading from the endpoint is paused while the buffer is full
+                tokio::spawn(forward);
+                builder.body(body).context("failed to build a response")
+            })
+    }
+}
+
+fn too_large(max_bytes: u64) -> anyhow::Error {
+    format_err!(
+        "the object of the website endpoint exceeds {} bytes",
+        max_bytes
+    )
+}
+
+/// The body along with the future forwarding chunks of the stream to it, up to `buffered_chunks`
+/// of them are buffered. The body fails once the stream does or exceeds `max_bytes`.
+fn stream_body<S, E>(
+    chunks: S,
+    max_bytes: u64,
+    buffered_chunks: usize,
+) -> (WebsiteBody, impl Future<Item = (), Error = ()> + Send)
+where
+    S: Stream<Item = Bytes, Error = E> + Send + 'static,
+    E: Into<anyhow::Error>,
+{
+    let (tx, rx) = mpsc::channel(buffered_chunks.max(1) - 1);
+    let mut size = 0;
+    let forward = chunks
+        .map_err(|err| {
+            err.into()
+                .context("failed to fetch an object from the website endpoint")
+        })
+        .and_then(move |chunk| {
+            size += chunk.len() as u64;
+            if size > max_bytes {
+                return Err(too_large(max_bytes));
+            }
+            Ok(chunk)
+        })
+        .then(Ok)
+        // The client has gone once the body is dropped
+        .forward(tx.sink_map_err(|_| ()))
+        .map(|_| ());
+
+    (WebsiteBody { rx }, forward)
+}
+
+/// Body of responses of the website endpoint, it's read from the endpoint as it's sent.
+pub(crate) struct WebsiteBody {
+    rx: mpsc::Receiver<anyhow::Result<Bytes>>,
+}
+
+impl std::fmt::Debug for WebsiteBody {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("WebsiteBody").finish()
+    }
+}
+
+impl BufStream for WebsiteBody {
+    type Item = io::Cursor<Bytes>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.rx.poll()? {
+            Async::Ready(Some(Ok(chunk))) => Ok(Async::Ready(Some(io::Cursor::new(chunk)))),
+            Async::Ready(Some(Err(err))) => {
+                error!("Error streaming an object of the website: {:#}", err);
+                Err(())
+            }
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Tokens are the payload and its HMAC-SHA256 signature, both base64url encoded.
+fn encode_token(token: &WebsiteToken, secret: &str) -> anyhow::Result<String> {
+    let payload = base64::encode_config(
+        &serde_json::to_vec(token).context("failed to serialize a token")?,
+        base64::URL_SAFE_NO_PAD,
+    );
+    let signature = base64::encode_config(&sign(&payload, secret)?, base64::URL_SAFE_NO_PAD);
+    Ok(format!("{}.{}", payload, signature))
+}
+
+fn decode_token(token: &str, secret: &str, now: i64) -> anyhow::Result<WebsiteToken> {
+    let (payload, signature) = token
+        .split_once('.')
+        .ok_or_else(|| format_err!("malformed token"))?;
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| format_err!("malformed token signature"))?;
+    let expected = sign(payload, secret)?;
+    if signature.len() != expected.len() || !openssl::memcmp::eq(&signature, &expected) {
+        return Err(format_err!("invalid token signature"));
+    }
+
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| format_err!("malformed token payload"))?;
+    let token = serde_json::from_slice::<WebsiteToken>(&payload)
+        .map_err(|_| format_err!("malformed token payload"))?;
+    if token.expires_at <= now {
+        return Err(format_err!("token has expired"));
+    }
+
+    Ok(token)
+}
+
+fn sign(payload: &str, secret: &str) -> anyhow::Result<Vec<u8>> {
+    let key = PKey::hmac(secret.as_bytes()).context("failed to create an HMAC key")?;
+    let mut signer =
+        Signer::new(MessageDigest::sha256(), &key).context("failed to create a signer")?;
+    signer
+        .update(payload.as_bytes())
+        .context("failed to sign a token")?;
+    signer.sign_to_vec().context("failed to sign a token")
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{future, stream};
+
+    fn token() -> WebsiteToken {
+        WebsiteToken {
+            backend: "default".into(),
+            bucket: "site.example.org".into(),
+            object: "docs/index.html".into(),
+            expires_at: 1_600_000_300,
+            namespace: None,
+        }
+    }
+
+    #[test]
+    fn token_roundtrip() {
+        let encoded = encode_token(&token(), "secret").unwrap();
+        assert_eq!(
+            decode_token(&encoded, "secret", 1_600_000_000).unwrap(),
+            token()
+        );
+        assert!(decode_token(&encoded, "other", 1_600_000_000).is_err());
+        assert!(decode_token(&encoded, "secret", 1_600_000_300).is_err());
+    }
+
+    #[test]
+    fn token_tampered() {
+        let encoded = encode_token(&token(), "secret").unwrap();
+        let (_, signature) = encoded.split_once('.').unwrap();
+        let forged = WebsiteToken {
+            object: "private.html".into(),
+            ..token()
+        };
+        let payload = base64::encode_config(
+            &serde_json::to_vec(&forged).unwrap(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let tampered = format!("{}.{}", payload, signature);
+        assert!(decode_token(&tampered, "secret", 1_600_000_000).is_err());
+        assert!(decode_token("not a token", "secret", 1_600_000_000).is_err());
+    }
+
+    fn read_body(mut body: WebsiteBody) -> Result<Vec<u8>, ()> {
+        let mut acc = Vec::new();
+        future::poll_fn(move || loop {
+            match BufStream::poll(&mut body)? {
+                Async::Ready(Some(chunk)) => acc.extend_from_slice(chunk.get_ref()),
+                Async::Ready(None) => return Ok(Async::Ready(std::mem::take(&mut acc))),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        })
+        .wait()
+    }
+
+    #[test]
+    fn stream_body_backpressure() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let read = Arc::new(AtomicUsize::new(0));
+        let counter = read.clone();
+        let chunks = stream::iter_ok::<_, anyhow::Error>((0..10u8).map(move |idx| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Bytes::from(vec![idx])
+        }));
+        let (body, forward) = stream_body(chunks, 1024, 2);
+        let forward = std::thread::spawn(move || forward.wait());
+
+        // Reading is paused once the buffer is full, one chunk could be on its way to it
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(read.load(Ordering::SeqCst) <= 3);
+
+        assert_eq!(read_body(body).unwrap(), (0..10u8).collect::<Vec<_>>());
+        assert!(forward.join().unwrap().is_ok());
+        assert_eq!(read.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn stream_body_limits() {
+        let chunks =
+            stream::iter_ok::<_, anyhow::Error>(vec![Bytes::from("abc"), Bytes::from("def")]);
+        let (body, forward) = stream_body(chunks, 5, 4);
+        std::thread::spawn(move || forward.wait());
+        assert!(read_body(body).is_err());
+
+        let chunks = stream::iter_result(vec![Ok(Bytes::from("abc")), Err(format_err!("reset"))]);
+        let (body, forward) = stream_body(chunks, 1024, 4);
+        std::thread::spawn(move || forward.wait());
+        assert!(read_body(body).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+use std::io;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use http::header;
+use http::{HeaderMap, Response, StatusCode};
+use tokio::codec::{Decoder, Encoder};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Messages of clients are small commands, larger ones are rejected.
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+pub(crate) const CLOSE_NORMAL: u16 = 1000;
+pub(crate) const CLOSE_POLICY_VIOLATION: u16 = 1008;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Returns whether the request asks to upgrade the connection to the WebSocket protocol.
+pub(crate) fn is_upgrade(headers: &HeaderMap) -> bool {
+    let contains = |name, token: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+
+    contains(header::CONNECTION, "upgrade") && contains(header::UPGRADE, "websocket")
+}
+
+/// `101 Switching Protocols` response completing the opening handshake (RFC 6455, section 4.2.2).
+pub(crate) fn handshake(headers: &HeaderMap) -> Result<Response<Bytes>, String> {
+    let version = headers
+        .get(header::SEC_WEBSOCKET_VERSION)
+        .and_then(|value| value.to_str().ok());
+    if version != Some("13") {
+        return Err(String::from("unsupported websocket version"));
+    }
+    let key = headers
+        .get(header::SEC_WEBSOCKET_KEY)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| String::from("missing sec-websocket-key header"))?;
+
+    Ok(Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept_key(key).as_str())
+        .body(Bytes::new())
+        .expect("Error building a websocket handshake response"))
+}
+
+fn accept_key(key: &str) -> String {
+    let digest = openssl::sha::sha1(format!("{}{}", key.trim(), ACCEPT_GUID).as_bytes());
+    base64::encode(&digest)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Message {
+    Text(String),
+    Binary(Bytes),
+    Ping(Bytes),
+    Pong(Bytes),
+    Close(Option<u16>),
+}
+
+/// Frames of the server side of connections: frames of clients must be masked,
+/// frames of the server aren't. Fragmented messages are reassembled.
+#[derive(Debug, Default)]
+pub(crate) struct Codec {
+    fragments: Option<(u8, BytesMut)>,
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: BytesMut,
+}
+
+fn invalid_data(detail: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, detail)
+}
+
+impl Codec {
+    fn decode_frame(&mut self, src: &mut BytesMut) -> io::Result<Option<Frame>> {
+        if src.len() < 2 {
+            return Ok(None);
+        }
+
+        let fin = src[0] & 0x80 != 0;
+        if src[0] & 0x70 != 0 {
+            return Err(invalid_data("reserved bits of a frame are set"));
+        }
+        let opcode = src[0] & 0x0f;
+        if src[1] & 0x80 == 0 {
+            return Err(invalid_data("frames of clients must be masked"));
+        }
+
+        let (len, offset) = match src[1] & 0x7f {
+            126 if src.len() < 4 => return Ok(None),
+            126 => (u16::from_be_bytes([src[2], src[3]]) as u64, 4),
+            127 if src.len() < 10 => return Ok(None),
+            127 => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&src[2..10]);
+                (u64::from_be_bytes(bytes), 10)
+            }
+            len => (len as u64, 2),
+        };
+        if len > MAX_MESSAGE_LEN as u64 {
+            return Err(invalid_data("message is too large"));
+        }
+
+        let len = len as usize;
+        if src.len() < offset + 4 + len {
+            src.reserve(offset + 4 + len - src.len());
+            return Ok(None);
+        }
+
+        let header = src.split_to(offset + 4);
+        let mask = &header[offset..];
+        let mut payload = src.split_to(len);
+        for (idx, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[idx % 4];
+        }
+
+        Ok(Some(Frame {
+            fin,
+            opcode,
+            payload,
+        }))
+    }
+}
+
+impl Decoder for Codec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Message>> {
+        loop {
+            let frame = match self.decode_frame(src)? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+
+            let (opcode, payload) = match (frame.opcode, self.fragments.take()) {
+                // Control frames could be interleaved with fragments
+                (OPCODE_CLOSE, fragments) | (OPCODE_PING, fragments) | (OPCODE_PONG, fragments) => {
+                    self.fragments = fragments;
+                    if !frame.fin {
+                        return Err(invalid_data("control frames must not be fragmented"));
+                    }
+                    (frame.opcode, frame.payload)
+                }
+                (OPCODE_CONTINUATION, Some((opcode, mut payload))) => {
+                    if payload.len() + frame.payload.len() > MAX_MESSAGE_LEN {
+                        return Err(invalid_data("message is too large"));
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                    if !frame.fin {
+                        self.fragments = Some((opcode, payload));
+                        continue;
+                    }
+                    (opcode, payload)
+                }
+                (OPCODE_TEXT, None) | (OPCODE_BINARY, None) if !frame.fin => {
+                    self.fragments = Some((frame.opcode, frame.payload));
+                    continue;
+                }
+                (OPCODE_TEXT, None) | (OPCODE_BINARY, None) => (frame.opcode, frame.payload),
+                _ => return Err(invalid_data("unexpected frame")),
+            };
+
+            let message = match opcode {
+                OPCODE_TEXT => Message::Text(
+                    String::from_utf8(payload.to_vec())
+                        .map_err(|_| invalid_data("text message isn't valid UTF-8"))?,
+                ),
+                OPCODE_BINARY => Message::Binary(payload.freeze()),
+                OPCODE_PING => Message::Ping(payload.freeze()),
+                OPCODE_PONG => Message::Pong(payload.freeze()),
+                _ if payload.len() >= 2 => {
+                    Message::Close(Some(u16::from_be_bytes([payload[0], payload[1]])))
+                }
+                _ => Message::Close(None),
+            };
+            return Ok(Some(message));
+        }
+    }
+}
+
+impl Encoder for Codec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> io::Result<()> {
+        let (opcode, payload) = match message {
+            Message::Text(text) => (OPCODE_TEXT, Bytes::from(text)),
+            Message::Binary(payload) => (OPCODE_BINARY, payload),
+            Message::Ping(payload) => (OPCODE_PING, payload),
+            Message::Pong(payload) => (OPCODE_PONG, payload),
+            Message::Close(Some(code)) => (OPCODE_CLOSE, Bytes::from(&code.to_be_bytes()[..])),
+            Message::Close(None) => (OPCODE_CLOSE, Bytes::new()),
+        };
+
+        dst.reserve(payload.len() + 10);
+        dst.put_u8(0x80 | opcode);
+        match payload.len() {
+            len if len < 126 => dst.put_u8(len as u8),
+            len if len <= u16::MAX as usize => {
+                dst.put_u8(126);
+                dst.put_u16_be(len as u16);
+            }
+            len => {
+                dst.put_u8(127);
+                dst.put_u64_be(len as u64);
+            }
+        }
+        dst.extend_from_slice(&payload);
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn masked(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![first, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(idx, b)| b ^ mask[idx % 4]));
+        frame
+    }
+
+    #[test]
+    fn handshake_accept_key() {
+        // The example of RFC 6455, section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONNECTION, "keep-alive, Upgrade".parse().unwrap());
+        headers.insert(header::UPGRADE, "websocket".parse().unwrap());
+        assert!(is_upgrade(&headers));
+        assert!(handshake(&headers).is_err());
+
+        headers.insert(header::SEC_WEBSOCKET_VERSION, "13".parse().unwrap());
+        headers.insert(
+            header::SEC_WEBSOCKET_KEY,
+            "dGhlIHNhbXBsZSBub25jZQ==".parse().unwrap(),
+        );
+        let resp = handshake(&headers).unwrap();
+        assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(
+            resp.headers()[header::SEC_WEBSOCKET_ACCEPT],
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        headers.remove(header::UPGRADE);
+        assert!(!is_upgrade(&headers));
+    }
+
+    #[test]
+    fn decode_messages() {
+        let mut codec = Codec::default();
+        let mut src = BytesMut::new();
+
+        // Fragmented text message interleaved with a ping
+        let frame = masked(0x01, b"{\"command\":");
+        src.extend_from_slice(&frame[..3]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(&frame[3..]);
+        src.extend_from_slice(&masked(0x89, b"hi"));
+        src.extend_from_slice(&masked(0x80, b"\"stop\"}"));
+        src.extend_from_slice(&masked(0x88, &[0x03, 0xe8]));
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(Message::Ping(Bytes::from("hi")))
+        );
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(Message::Text(String::from("{\"command\":\"stop\"}")))
+        );
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(Message::Close(Some(CLOSE_NORMAL)))
+        );
+        assert!(src.is_empty());
+
+        // Unmasked frames of clients are rejected
+        let mut src = BytesMut::from(&b"\x81\x02hi"[..]);
+        assert!(codec.decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn encode_messages() {
+        let mut codec = Codec::default();
+        let mut dst = BytesMut::new();
+        codec
+            .encode(Message::Text(String::from("{\"done\":true}")), &mut dst)
+            .unwrap();
+        assert_eq!(&dst[..], &b"\x81\x0d{\"done\":true}"[..]);
+
+        let mut dst = BytesMut::new();
+        codec
+            .encode(Message::Close(Some(CLOSE_POLICY_VIOLATION)), &mut dst)
+            .unwrap();
+        assert_eq!(&dst[..], &[0x88, 0x02, 0x03, 0xf0][..]);
+
+        let mut dst = BytesMut::new();
+        codec
+            .encode(Message::Binary(Bytes::from(vec![0; 300])), &mut dst)
+            .unwrap();
+        assert_eq!(&dst[..4], &[0x82, 126, 0x01, 0x2c][..]);
+        assert_eq!(dst.len(), 304);
+    }
+}
@@ -0,0 +1,109 @@
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::result::Error;
+use uuid::Uuid;
+
+use crate::schema::api_key;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Identifiable, Queryable)]
+#[table_name = "api_key"]
+pub(crate) struct Object {
+    pub(crate) id: Uuid,
+    pub(crate) key_hash: String,
+    pub(crate) account_id: String,
+    pub(crate) scope: Option<Vec<String>>,
+    pub(crate) expires_at: Option<DateTime<Utc>>,
+    pub(crate) created_by: String,
+    pub(crate) created_at: DateTime<Utc>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Insertable)]
+#[table_name = "api_key"]
+pub(crate) struct InsertQuery<'a> {
+    id: Uuid,
+    key_hash: &'a str,
+    account_id: &'a str,
+    scope: Option<&'a [String]>,
+    expires_at: Option<DateTime<Utc>>,
+    created_by: &'a str,
+}
+
+impl<'a> InsertQuery<'a> {
+    pub(crate) fn new(
+        id: Uuid,
+        key_hash: &'a str,
+        account_id: &'a str,
+        created_by: &'a str,
+    ) -> Self {
+        Self {
+            id,
+            key_hash,
+            account_id,
+            scope: None,
+            expires_at: None,
+            created_by,
+        }
+    }
+
+    pub(crate) fn scope(self, value: Option<&'a [String]>) -> Self {
+        Self {
+            scope: value,
+            ..self
+        }
+    }
+
+    pub(crate) fn expires_at(self, value: Option<DateTime<Utc>>) -> Self {
+        Self {
+            expires_at: value,
+            ..self
+        }
+    }
+
+    pub(crate) fn execute(&self, conn: &PgConnection) -> Result<Object, Error> {
+        use diesel::RunQueryDsl;
+
+        diesel::insert_into(api_key::table)
+            .values(self)
+            .get_result(conn)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) struct FindQuery {
+    id: Uuid,
+}
+
+impl FindQuery {
+    pub(crate) fn new(id: Uuid) -> Self {
+        Self { id }
+    }
+
+    pub(crate) fn execute(&self, conn: &PgConnection) -> Result<Option<Object>, Error> {
+        use diesel::prelude::*;
+
+        api_key::table.find(self.id).get_result(conn).optional()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) struct DeleteQuery {
+    id: Uuid,
+}
+
+impl DeleteQuery {
+    pub(crate) fn new(id: Uuid) -> Self {
+        Self { id }
+    }
+
+    pub(crate) fn execute(&self, conn: &PgConnection) -> Result<usize, Error> {
+        use diesel::prelude::*;
+
+        diesel::delete(api_key::table.filter(api_key::id.eq(self.id))).execute(conn)
+    }
+}
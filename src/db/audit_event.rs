@@ -0,0 +1,411 @@
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::result::Error;
+use uuid::Uuid;
+
+use crate::schema::audit_event;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Identifiable, Queryable)]
+#[table_name = "audit_event"]
+pub(crate) struct Object {
+    pub(crate) id: Uuid,
+    pub(crate) request_id: Uuid,
+    pub(crate) subject: String,
+    pub(crate) bucket: String,
+    pub(crate) set: Option<String>,
+    pub(crate) object: Option<String>,
+    pub(crate) method: String,
+    pub(crate) authz_action: String,
+    pub(crate) authz_decision: String,
+    pub(crate) response_status: i32,
+    pub(crate) created_at: DateTime<Utc>,
+    pub(crate) operation: Option<String>,
+    pub(crate) authz_duration_ms: Option<i32>,
+    pub(crate) authn_method: Option<String>,
+    pub(crate) chain_seq: Option<i64>,
+    pub(crate) prev_hash: Option<String>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Insertable)]
+#[table_name = "audit_event"]
+pub(crate) struct InsertQuery<'a> {
+    request_id: Uuid,
+    subject: &'a str,
+    bucket: &'a str,
+    set: Option<&'a str>,
+    object: Option<&'a str>,
+    method: &'a str,
+    authz_action: &'a str,
+    authz_decision: &'a str,
+    response_status: i32,
+    operation: Option<&'a str>,
+    authz_duration_ms: Option<i32>,
+    authn_method: Option<&'a str>,
+    chain_seq: Option<i64>,
+    prev_hash: Option<&'a str>,
+}
+
+impl<'a> InsertQuery<'a> {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        request_id: Uuid,
+        subject: &'a str,
+        bucket: &'a str,
+        set: Option<&'a str>,
+        object: Option<&'a str>,
+        method: &'a str,
+        authz_action: &'a str,
+        authz_decision: &'a str,
+        response_status: i32,
+    ) -> Self {
+        Self {
+            request_id,
+            subject,
+            bucket,
+            set,
+            object,
+            method,
+            authz_action,
+            authz_decision,
+            response_status,
+            operation: None,
+            authz_duration_ms: None,
+            authn_method: None,
+            chain_seq: None,
+            prev_hash: None,
+        }
+    }
+
+    pub(crate) fn operation(self, value: Option<&'a str>) -> Self {
+        Self {
+            operation: value,
+            ..self
+        }
+    }
+
+    pub(crate) fn authz_duration_ms(self, value: Option<i32>) -> Self {
+        Self {
+            authz_duration_ms: value,
+            ..self
+        }
+    }
+
+    pub(crate) fn authn_method(self, value: Option<&'a str>) -> Self {
+        Self {
+            authn_method: value,
+            ..self
+        }
+    }
+
+    /// Position of the event in the hash chain along with the hash of the preceding event.
+    pub(crate) fn chain(self, seq: i64, prev_hash: &'a str) -> Self {
+        Self {
+            chain_seq: Some(seq),
+            prev_hash: Some(prev_hash),
+            ..self
+        }
+    }
+
+    pub(crate) fn execute(&self, conn: &PgConnection) -> Result<Object, Error> {
+        use diesel::RunQueryDsl;
+
+        diesel::insert_into(audit_event::table)
+            .values(self)
+            .get_result(conn)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Events within the time window ordered by the time they occurred,
+/// starting after the event with the specified time and identifier.
+pub(crate) struct ListQuery<'a> {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    bucket: Option<&'a str>,
+    subject: Option<&'a str>,
+    after: Option<(DateTime<Utc>, Uuid)>,
+    limit: i64,
+}
+
+impl<'a> ListQuery<'a> {
+    pub(crate) fn new(from: DateTime<Utc>, to: DateTime<Utc>, limit: i64) -> Self {
+        Self {
+            from,
+            to,
+            bucket: None,
+            subject: None,
+            after: None,
+            limit,
+        }
+    }
+
+    pub(crate) fn bucket(self, value: Option<&'a str>) -> Self {
+        Self {
+            bucket: value,
+            ..self
+        }
+    }
+
+    pub(crate) fn subject(self, value: Option<&'a str>) -> Self {
+        Self {
+            subject: value,
+            ..self
+        }
+    }
+
+    pub(crate) fn after(self, value: Option<(DateTime<Utc>, Uuid)>) -> Self {
+        Self {
+            after: value,
+            ..self
+        }
+    }
+
+    pub(crate) fn execute(&self, conn: &PgConnection) -> Result<Vec<Object>, Error> {
+        use diesel::prelude::*;
+
+        let mut q = audit_event::table
+            .filter(audit_event::created_at.ge(self.from))
+            .filter(audit_event::created_at.lt(self.to))
+            .into_boxed();
+
+        if let Some(bucket) = self.bucket {
+            q = q.filter(audit_event::bucket.eq(bucket));
+        }
+        if let Some(subject) = self.subject {
+            q = q.filter(audit_event::subject.eq(subject));
+        }
+        if let Some((created_at, id)) = self.after {
+            q = q.filter(
+                audit_event::created_at
+                    .gt(created_at)
+                    .or(audit_event::created_at
+                        .eq(created_at)
+                        .and(audit_event::id.gt(id))),
+            );
+        }
+
+        q.order_by((audit_event::created_at.asc(), audit_event::id.asc()))
+            .limit(self.limit)
+            .get_results(conn)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Key of the advisory lock serializing appends to the hash chain across instances.
+const CHAIN_LOCK_KEY: i64 = 0x6175_6469_7400;
+
+/// Locks the hash chain until the end of the transaction.
+pub(crate) fn lock_chain(conn: &PgConnection) -> Result<(), Error> {
+    use diesel::sql_types::BigInt;
+    use diesel::RunQueryDsl;
+
+    diesel::sql_query("select pg_advisory_xact_lock($1)")
+        .bind::<BigInt, _>(CHAIN_LOCK_KEY)
+        .execute(conn)
+        .map(|_| ())
+}
+
+/// Key of the advisory lock electing the instance moving inactive objects to cheaper storage classes.
+const TIERING_LOCK_KEY: i64 = 0x7469_6572_0000;
+
+/// Locks bucket tiering until it's unlocked or the connection is closed,
+/// returns `false` if another instance holds the lock.
+pub(crate) fn try_lock_tiering(conn: &PgConnection) -> Result<bool, Error> {
+    use diesel::dsl::sql;
+    use diesel::sql_types::{BigInt, Bool};
+    use diesel::RunQueryDsl;
+
+    diesel::select(
+        sql::<Bool>("pg_try_advisory_lock(")
+            .bind::<BigInt, _>(TIERING_LOCK_KEY)
+            .sql(")"),
+    )
+    .get_result(conn)
+}
+
+pub(crate) fn unlock_tiering(conn: &PgConnection) -> Result<(), Error> {
+    use diesel::sql_types::BigInt;
+    use diesel::RunQueryDsl;
+
+    diesel::sql_query("select pg_advisory_unlock($1)")
+        .bind::<BigInt, _>(TIERING_LOCK_KEY)
+        .execute(conn)
+        .map(|_| ())
+}
+
+/// The last event of the hash chain, events recorded before it's been introduced aren't chained.
+pub(crate) fn chain_head(conn: &PgConnection) -> Result<Option<Object>, Error> {
+    use diesel::prelude::*;
+
+    audit_event::table
+        .filter(audit_event::chain_seq.is_not_null())
+        .order_by(audit_event::chain_seq.desc())
+        .first(conn)
+        .optional()
+}
+
+/// Events of the hash chain ordered by their positions, starting from the specified one.
+pub(crate) struct ChainQuery {
+    from: i64,
+    limit: i64,
+}
+
+impl ChainQuery {
+    pub(crate) fn new(from: i64, limit: i64) -> Self {
+        Self { from, limit }
+    }
+
+    pub(crate) fn execute(&self, conn: &PgConnection) -> Result<Vec<Object>, Error> {
+        use diesel::prelude::*;
+
+        audit_event::table
+            .filter(audit_event::chain_seq.ge(self.from))
+            .order_by(audit_event::chain_seq.asc())
+            .limit(self.limit)
+            .get_results(conn)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Column events of an operation are grouped by.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum GroupColumn {
+    Subject,
+    Bucket,
+    Method,
+    Object,
+}
+
+impl GroupColumn {
+    fn expression(self) -> &'static str {
+        match self {
+            GroupColumn::Subject => "subject",
+            GroupColumn::Bucket => "bucket",
+            GroupColumn::Method => "method",
+            GroupColumn::Object => "bucket || '/' || coalesce(object, '')",
+        }
+    }
+}
+
+#[derive(Debug, QueryableByName)]
+pub(crate) struct GroupCount {
+    #[sql_type = "diesel::sql_types::Text"]
+    pub(crate) key: String,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    pub(crate) count: i64,
+}
+
+#[derive(Debug, QueryableByName)]
+pub(crate) struct Totals {
+    #[sql_type = "diesel::sql_types::BigInt"]
+    pub(crate) total: i64,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    pub(crate) errors: i64,
+    #[sql_type = "diesel::sql_types::Nullable<diesel::sql_types::Double>"]
+    pub(crate) avg_authz_duration_ms: Option<f64>,
+}
+
+/// Aggregates of events of the operation since the time,
+/// responses with `4xx` and `5xx` statuses are counted as errors.
+pub(crate) struct ActivityQuery<'a> {
+    operation: &'a str,
+    from: DateTime<Utc>,
+}
+
+impl<'a> ActivityQuery<'a> {
+    pub(crate) fn new(operation: &'a str, from: DateTime<Utc>) -> Self {
+        Self { operation, from }
+    }
+
+    pub(crate) fn totals(&self, conn: &PgConnection) -> Result<Totals, Error> {
+        use diesel::sql_types::{Text, Timestamptz};
+        use diesel::RunQueryDsl;
+
+        diesel::sql_query(
+            "select count(*) as total, \
+             count(*) filter (where response_status >= 400) as errors, \
+             avg(authz_duration_ms)::float8 as avg_authz_duration_ms \
+             from audit_event where operation = $1 and created_at >= $2",
+        )
+        .bind::<Text, _>(self.operation)
+        .bind::<Timestamptz, _>(self.from)
+        .get_result(conn)
+    }
+
+    /// Counts of events per value of the column, the most frequent first.
+    pub(crate) fn group_by(
+        &self,
+        conn: &PgConnection,
+        column: GroupColumn,
+        limit: i64,
+    ) -> Result<Vec<GroupCount>, Error> {
+        use diesel::sql_types::{BigInt, Text, Timestamptz};
+        use diesel::RunQueryDsl;
+
+        let query = format!(
+            "select {} as key, count(*) as count \
+             from audit_event where operation = $1 and created_at >= $2 \
+             group by 1 order by 2 desc, 1 limit $3",
+            column.expression()
+        );
+
+        diesel::sql_query(query)
+            .bind::<Text, _>(self.operation)
+            .bind::<Timestamptz, _>(self.from)
+            .bind::<BigInt, _>(limit)
+            .load(conn)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Time of the oldest event, the audit log doesn't cover accesses before it.
+pub(crate) fn oldest_created_at(conn: &PgConnection) -> Result<Option<DateTime<Utc>>, Error> {
+    use diesel::prelude::*;
+
+    audit_event::table
+        .select(audit_event::created_at)
+        .order_by(audit_event::created_at.asc())
+        .first(conn)
+        .optional()
+}
+
+#[derive(Debug, QueryableByName)]
+pub(crate) struct AccessedKey {
+    #[sql_type = "diesel::sql_types::Text"]
+    pub(crate) key: String,
+}
+
+/// Keys of objects of the bucket successfully read since the time,
+/// objects of sets are keyed by their names in the bucket (`SET.OBJECT`).
+pub(crate) struct AccessedQuery<'a> {
+    bucket: &'a str,
+    from: DateTime<Utc>,
+}
+
+impl<'a> AccessedQuery<'a> {
+    pub(crate) fn new(bucket: &'a str, from: DateTime<Utc>) -> Self {
+        Self { bucket, from }
+    }
+
+    pub(crate) fn execute(&self, conn: &PgConnection) -> Result<Vec<AccessedKey>, Error> {
+        use diesel::sql_types::{Text, Timestamptz};
+        use diesel::RunQueryDsl;
+
+        diesel::sql_query(
+            "select distinct coalesce(set || '.', '') || object as key \
+             from audit_event where bucket = $1 and created_at >= $2 \
+             and authz_action = 'read' and response_status < 400 and object is not null",
+        )
+        .bind::<Text, _>(self.bucket)
+        .bind::<Timestamptz, _>(self.from)
+        .load(conn)
+    }
+}
@@ -127,4 +127,6 @@ pub mod sql {
     }
 }
 
+pub(crate) mod api_key;
+pub(crate) mod audit_event;
 pub(crate) mod tag;
@@ -1,4 +1,4 @@
-#![recursion_limit = "128"]
+#![recursion_limit = "1024"]
 
 extern crate openssl;
 #[macro_use]
@@ -6,10 +6,9 @@ extern crate diesel;
 #[macro_use]
 extern crate tower_web;
 
-use svc_authz::cache::{create_pool2, Cache};
+use svc_authz::cache::create_pool2;
 
 fn main() {
-    env_logger::init();
     use std::env::var;
 
     let db = var("DATABASE_URL")
@@ -62,7 +61,7 @@ fn main() {
                     })
                     .unwrap_or_else(|_| 300);
 
-                Some(Cache::new(
+                Some((
                     create_pool2(&url, size, idle_size, timeout),
                     expiration_time,
                 ))
@@ -0,0 +1,398 @@
+use std::io::Read;
+
+use anyhow::{bail, format_err, Context, Result};
+use url::percent_encoding::percent_decode;
+
+use self::orc::{Column, OrcFile};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Format of the timestamp folder of a report, `<prefix>/<bucket>/<config id>/YYYY-MM-DDTHH-MMZ/`.
+const REPORT_TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H-%MZ";
+
+const MANIFEST_NAME: &str = "manifest.json";
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum InventoryFormat {
+    Csv,
+    Orc,
+}
+
+/// Manifest of an inventory report, listing files of the report in the destination bucket.
+#[derive(Debug)]
+pub(crate) struct InventoryManifest {
+    pub(crate) source_bucket: String,
+    pub(crate) format: InventoryFormat,
+    /// Names of columns of CSV files, ORC files describe themselves.
+    pub(crate) schema: Vec<String>,
+    pub(crate) files: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawManifest {
+    source_bucket: String,
+    file_format: String,
+    #[serde(default)]
+    file_schema: String,
+    files: Vec<RawManifestFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawManifestFile {
+    key: String,
+}
+
+pub(crate) fn parse_manifest(data: &[u8]) -> Result<InventoryManifest> {
+    let raw = serde_json::from_slice::<RawManifest>(data).context("invalid manifest")?;
+    let format = match raw.file_format.as_str() {
+        "CSV" => InventoryFormat::Csv,
+        "ORC" => InventoryFormat::Orc,
+        format => bail!("unsupported inventory format = '{}'", format),
+    };
+
+    Ok(InventoryManifest {
+        source_bucket: raw.source_bucket,
+        format,
+        schema: raw.file_schema.split(',').map(column_name).collect(),
+        files: raw.files.into_iter().map(|file| file.key).collect(),
+    })
+}
+
+/// Key of the manifest of the latest report among folders of reports under the prefix,
+/// other folders, such as `data/` or `hive/`, are skipped.
+pub(crate) fn latest_manifest(folders: &[String]) -> Option<String> {
+    folders
+        .iter()
+        .filter(|folder| {
+            let name = folder
+                .trim_end_matches('/')
+                .rsplit('/')
+                .next()
+                .unwrap_or("");
+            chrono::NaiveDateTime::parse_from_str(name, REPORT_TIMESTAMP_FORMAT).is_ok()
+        })
+        .max()
+        .map(|folder| format!("{}/{}", folder.trim_end_matches('/'), MANIFEST_NAME))
+}
+
+/// The prefix reports of the inventory configuration are delivered under,
+/// `<destination prefix>/<source bucket>/<config id>/`.
+pub(crate) fn report_prefix(destination_prefix: Option<&str>, bucket: &str, id: &str) -> String {
+    match destination_prefix
+        .map(|prefix| prefix.trim_end_matches('/'))
+        .filter(|prefix| !prefix.is_empty())
+    {
+        Some(prefix) => format!("{}/{}/{}/", prefix, bucket, id),
+        None => format!("{}/{}/", bucket, id),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Current version of an object listed in a report, delete markers are skipped.
+#[derive(Debug, PartialEq)]
+pub(crate) struct InventoryRecord {
+    pub(crate) key: String,
+    pub(crate) size: u64,
+    pub(crate) etag: String,
+}
+
+/// Parses a file of the report listed in the manifest.
+pub(crate) fn parse_file(
+    manifest: &InventoryManifest,
+    data: &[u8],
+) -> Result<Vec<InventoryRecord>> {
+    match manifest.format {
+        InventoryFormat::Csv => parse_csv(&manifest.schema, data),
+        InventoryFormat::Orc => parse_orc(data),
+    }
+}
+
+/// Names of columns differ between formats (`ETag` in CSV schemas, `e_tag` in ORC files).
+fn column_name(name: &str) -> String {
+    name.trim()
+        .chars()
+        .filter(|c| *c != '_')
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+/// Positions of columns of records in the schema.
+struct Columns {
+    key: usize,
+    size: usize,
+    etag: usize,
+    is_latest: Option<usize>,
+    is_delete_marker: Option<usize>,
+}
+
+impl Columns {
+    fn new<S: AsRef<str>>(schema: &[S]) -> Result<Self> {
+        let position = |name: &str| {
+            schema
+                .iter()
+                .position(|column| column_name(column.as_ref()) == name)
+        };
+        let required = |name: &str| {
+            position(name).ok_or_else(|| format_err!("missing '{}' column of the report", name))
+        };
+
+        Ok(Self {
+            key: required("key")?,
+            size: required("size")?,
+            etag: required("etag")?,
+            is_latest: position("islatest"),
+            is_delete_marker: position("isdeletemarker"),
+        })
+    }
+}
+
+/// CSV files are compressed with GZIP, keys are URL-encoded.
+fn parse_csv(schema: &[String], data: &[u8]) -> Result<Vec<InventoryRecord>> {
+    let columns = Columns::new(schema)?;
+    let mut text = String::new();
+    flate2::read::MultiGzDecoder::new(data)
+        .read_to_string(&mut text)
+        .context("failed to decompress a CSV file")?;
+
+    let mut records = Vec::new();
+    for (idx, line) in text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+    {
+        let values = csv_values(line).with_context(|| format!("invalid line = {}", idx + 1))?;
+        let value = |pos: usize| values.get(pos).map(String::as_str).unwrap_or("");
+        if columns.is_latest.map(value) == Some("false")
+            || columns.is_delete_marker.map(value) == Some("true")
+        {
+            continue;
+        }
+
+        let size = match value(columns.size) {
+            "" => continue,
+            size => size
+                .parse::<u64>()
+                .with_context(|| format!("invalid size = '{}' on line = {}", size, idx + 1))?,
+        };
+        let key = percent_decode(value(columns.key).as_bytes())
+            .decode_utf8()
+            .with_context(|| format!("invalid key on line = {}", idx + 1))?;
+        records.push(InventoryRecord {
+            key: key.into_owned(),
+            size,
+            etag: value(columns.etag).to_owned(),
+        });
+    }
+
+    Ok(records)
+}
+
+/// Values of the line, quoted ones could contain commas and doubled quotes.
+fn csv_values(line: &str) -> Result<Vec<String>> {
+    let mut values = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        value.push('"');
+                    }
+                    Some('"') => break,
+                    Some(c) => value.push(c),
+                    None => bail!("unterminated quoted value"),
+                }
+            }
+        }
+        while let Some(c) = chars.peek() {
+            if *c == ',' {
+                break;
+            }
+            value.push(*c);
+            chars.next();
+        }
+        values.push(value);
+
+        if chars.next().is_none() {
+            return Ok(values);
+        }
+    }
+}
+
+fn parse_orc(data: &[u8]) -> Result<Vec<InventoryRecord>> {
+    let orc = OrcFile::open(data).context("invalid ORC file")?;
+    let columns = Columns::new(orc.fields())?;
+
+    let keys = match orc.read_column(columns.key)? {
+        Column::Strings(values) => values,
+        _ => bail!("'key' column isn't a string"),
+    };
+    let sizes = match orc.read_column(columns.size)? {
+        Column::Integers(values) => values,
+        _ => bail!("'size' column isn't an integer"),
+    };
+    let etags = match orc.read_column(columns.etag)? {
+        Column::Strings(values) => values,
+        _ => bail!("'e_tag' column isn't a string"),
+    };
+    let flag = |column: Option<usize>| -> Result<Option<Vec<Option<bool>>>> {
+        match column.map(|column| orc.read_column(column)).transpose()? {
+            Some(Column::Booleans(values)) => Ok(Some(values)),
+            Some(_) => bail!("flag column isn't a boolean"),
+            None => Ok(None),
+        }
+    };
+    let is_latest = flag(columns.is_latest)?;
+    let is_delete_marker = flag(columns.is_delete_marker)?;
+    let is_set = |flags: &Option<Vec<Option<bool>>>, idx: usize| {
+        flags
+            .as_ref()
+            .and_then(|flags| flags.get(idx).cloned().flatten())
+    };
+
+    let mut records = Vec::with_capacity(keys.len());
+    for (idx, ((key, size), etag)) in keys.into_iter().zip(sizes).zip(etags).enumerate() {
+        if is_set(&is_latest, idx) == Some(false) || is_set(&is_delete_marker, idx) == Some(true) {
+            continue;
+        }
+
+        if let (Some(key), Some(size), Some(etag)) = (key, size, etag) {
+            records.push(InventoryRecord {
+                key,
+                size: size.max(0) as u64,
+                etag,
+            });
+        }
+    }
+
+    Ok(records)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+mod orc;
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn gzip(text: &str) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn parse_manifests() {
+        let manifest = parse_manifest(
+            br#"{
+                "sourceBucket": "data.example.org",
+                "destinationBucket": "arn:aws:s3:::reports.example.org",
+                "version": "2016-11-30",
+                "creationTimestamp": "1514944800000",
+                "fileFormat": "CSV",
+                "fileSchema": "Bucket, Key, VersionId, IsLatest, IsDeleteMarker, Size, ETag",
+                "files": [
+                    {
+                        "key": "inventory/data.example.org/daily/data/8ee5d8a2.csv.gz",
+                        "size": 2147483647,
+                        "MD5checksum": "f11166069f1990abeb9c97ace9cdfabc"
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(manifest.source_bucket, "data.example.org");
+        assert_eq!(manifest.format, InventoryFormat::Csv);
+        assert_eq!(
+            manifest.schema,
+            vec![
+                "bucket",
+                "key",
+                "versionid",
+                "islatest",
+                "isdeletemarker",
+                "size",
+                "etag"
+            ]
+        );
+        assert_eq!(
+            manifest.files,
+            vec!["inventory/data.example.org/daily/data/8ee5d8a2.csv.gz"]
+        );
+
+        let parquet = br#"{"sourceBucket":"a","fileFormat":"Parquet","files":[]}"#;
+        assert!(parse_manifest(parquet).is_err());
+    }
+
+    #[test]
+    fn latest_manifests() {
+        let folders = vec![
+            "inventory/data.example.org/daily/2024-01-02T01-00Z/".to_owned(),
+            "inventory/data.example.org/daily/2024-01-03T01-00Z/".to_owned(),
+            "inventory/data.example.org/daily/data/".to_owned(),
+            "inventory/data.example.org/daily/hive/".to_owned(),
+        ];
+        assert_eq!(
+            latest_manifest(&folders).as_deref(),
+            Some("inventory/data.example.org/daily/2024-01-03T01-00Z/manifest.json")
+        );
+        assert_eq!(latest_manifest(&folders[2..]), None);
+
+        assert_eq!(
+            report_prefix(Some("inventory/"), "data.example.org", "daily"),
+            "inventory/data.example.org/daily/"
+        );
+        assert_eq!(
+            report_prefix(Some(""), "data.example.org", "daily"),
+            "data.example.org/daily/"
+        );
+    }
+
+    #[test]
+    fn parse_csv_files() {
+        let schema = "Bucket, Key, IsLatest, IsDeleteMarker, Size, ETag"
+            .split(',')
+            .map(column_name)
+            .collect::<Vec<_>>();
+        let data = gzip(
+            "\"data.example.org\",\"foo%2Fbar%201.txt\",\"true\",\"false\",\"3\",\"acbd18db\"\n\
+             \"data.example.org\",\"foo\",\"false\",\"false\",\"3\",\"37b51d19\"\n\
+             \"data.example.org\",\"foo\",\"true\",\"true\",\"\",\"\"\n\
+             \"data.example.org\",\"say \"\"hi\"\",\",\"true\",\"false\",\"0\",\"d41d8cd9\"\n",
+        );
+        assert_eq!(
+            parse_csv(&schema, &data).unwrap(),
+            vec![
+                InventoryRecord {
+                    key: "foo/bar 1.txt".to_owned(),
+                    size: 3,
+                    etag: "acbd18db".to_owned(),
+                },
+                InventoryRecord {
+                    key: "say \"hi\",".to_owned(),
+                    size: 0,
+                    etag: "d41d8cd9".to_owned(),
+                },
+            ]
+        );
+
+        assert!(parse_csv(&schema[..2], &data).is_err());
+        assert!(parse_csv(&schema, b"not gzipped").is_err());
+    }
+
+    #[test]
+    fn parse_csv_values() {
+        assert_eq!(csv_values("a,\"b,c\",").unwrap(), vec!["a", "b,c", ""]);
+        assert!(csv_values("\"a").is_err());
+    }
+}
@@ -0,0 +1,989 @@
+use std::convert::TryFrom;
+use std::io::Read;
+
+use anyhow::{bail, format_err, Context, Result};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const MAGIC: &[u8] = b"ORC";
+
+const COMPRESSION_NONE: u64 = 0;
+const COMPRESSION_ZLIB: u64 = 1;
+
+const KIND_BOOLEAN: u64 = 0;
+const KIND_BYTE: u64 = 1;
+const KIND_SHORT: u64 = 2;
+const KIND_INT: u64 = 3;
+const KIND_LONG: u64 = 4;
+const KIND_STRING: u64 = 7;
+const KIND_STRUCT: u64 = 12;
+const KIND_VARCHAR: u64 = 16;
+const KIND_CHAR: u64 = 17;
+
+const STREAM_PRESENT: u64 = 0;
+const STREAM_DATA: u64 = 1;
+const STREAM_LENGTH: u64 = 2;
+const STREAM_DICTIONARY_DATA: u64 = 3;
+
+const ENCODING_DIRECT: u64 = 0;
+const ENCODING_DICTIONARY: u64 = 1;
+const ENCODING_DIRECT_V2: u64 = 2;
+const ENCODING_DICTIONARY_V2: u64 = 3;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Values of a column, `None` for nulls.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Column {
+    Booleans(Vec<Option<bool>>),
+    Integers(Vec<Option<i64>>),
+    Strings(Vec<Option<String>>),
+}
+
+/// Reader of ORC files limited to what S3 Inventory reports use: a struct of primitive
+/// columns, either uncompressed or compressed with ZLIB.
+pub(crate) struct OrcFile<'a> {
+    data: &'a [u8],
+    compression: u64,
+    stripes: Vec<Stripe>,
+    types: Vec<Type>,
+}
+
+#[derive(Debug)]
+struct Stripe {
+    offset: usize,
+    index_length: usize,
+    data_length: usize,
+    footer_length: usize,
+    rows: usize,
+}
+
+#[derive(Debug, Default)]
+struct Type {
+    kind: u64,
+    subtypes: Vec<usize>,
+    field_names: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Stream {
+    kind: u64,
+    column: usize,
+    offset: usize,
+    length: usize,
+}
+
+impl<'a> OrcFile<'a> {
+    pub(crate) fn open(data: &'a [u8]) -> Result<Self> {
+        if !data.starts_with(MAGIC) {
+            bail!("not an ORC file");
+        }
+
+        // The file ends with the postscript, followed by its length
+        let ps_length = *data.last().expect("checked above") as usize;
+        let ps_end = data.len() - 1;
+        let ps_start = ps_end
+            .checked_sub(ps_length)
+            .ok_or_else(|| format_err!("invalid postscript length"))?;
+        let (mut footer_length, mut compression) = (0, COMPRESSION_NONE);
+        for field in fields(&data[ps_start..ps_end]) {
+            match field? {
+                (1, Value::Varint(val)) => footer_length = val as usize,
+                (2, Value::Varint(val)) => compression = val,
+                _ => (),
+            }
+        }
+        if compression != COMPRESSION_NONE && compression != COMPRESSION_ZLIB {
+            bail!("unsupported compression = {}", compression);
+        }
+
+        let footer_start = ps_start
+            .checked_sub(footer_length)
+            .ok_or_else(|| format_err!("invalid footer length"))?;
+        let footer = decompress(compression, &data[footer_start..ps_start])?;
+        let (mut stripes, mut types) = (Vec::new(), Vec::new());
+        for field in fields(&footer) {
+            match field? {
+                (3, Value::Bytes(val)) => stripes.push(parse_stripe(val)?),
+                (4, Value::Bytes(val)) => types.push(parse_type(val)?),
+                _ => (),
+            }
+        }
+
+        match types.first() {
+            Some(root) if root.kind == KIND_STRUCT => (),
+            _ => bail!("the root type isn't a struct"),
+        }
+
+        Ok(Self {
+            data,
+            compression,
+            stripes,
+            types,
+        })
+    }
+
+    /// Names of columns of the root struct.
+    pub(crate) fn fields(&self) -> &[String] {
+        &self.types[0].field_names
+    }
+
+    /// Values of the column of the root struct, across all stripes.
+    pub(crate) fn read_column(&self, field: usize) -> Result<Column> {
+        let column = *self.types[0]
+            .subtypes
+            .get(field)
+            .ok_or_else(|| format_err!("missing field = {}", field))?;
+        let kind = self
+            .types
+            .get(column)
+            .map(|ty| ty.kind)
+            .ok_or_else(|| format_err!("missing type of column = {}", column))?;
+
+        let mut values = match kind {
+            KIND_BOOLEAN => Column::Booleans(Vec::new()),
+            KIND_BYTE | KIND_SHORT | KIND_INT | KIND_LONG => Column::Integers(Vec::new()),
+            KIND_STRING | KIND_VARCHAR | KIND_CHAR => Column::Strings(Vec::new()),
+            _ => bail!("unsupported type = {} of column = {}", kind, column),
+        };
+        for stripe in &self.stripes {
+            self.read_stripe(stripe, column, &mut values)
+                .with_context(|| format!("failed to read column = {}", column))?;
+        }
+
+        Ok(values)
+    }
+
+    fn read_stripe(&self, stripe: &Stripe, column: usize, values: &mut Column) -> Result<()> {
+        let footer = stripe
+            .offset
+            .checked_add(stripe.index_length)
+            .and_then(|start| start.checked_add(stripe.data_length))
+            .and_then(|start| Some(start..start.checked_add(stripe.footer_length)?))
+            .and_then(|range| self.data.get(range))
+            .ok_or_else(|| format_err!("stripe footer is out of the file"))?;
+        let footer = decompress(self.compression, footer)?;
+
+        let (mut streams, mut encodings) = (Vec::new(), Vec::new());
+        let mut offset = stripe.offset;
+        for field in fields(&footer) {
+            match field? {
+                (1, Value::Bytes(val)) => {
+                    let stream = parse_stream(val, offset)?;
+                    offset = offset.saturating_add(stream.length);
+                    streams.push(stream);
+                }
+                (2, Value::Bytes(val)) => encodings.push(parse_encoding(val)?),
+                _ => (),
+            }
+        }
+
+        let stream = |kind| -> Result<Option<Vec<u8>>> {
+            match streams
+                .iter()
+                .find(|stream| stream.column == column && stream.kind == kind)
+            {
+                Some(stream) => {
+                    let data = stream
+                        .offset
+                        .checked_add(stream.length)
+                        .and_then(|end| self.data.get(stream.offset..end))
+                        .ok_or_else(|| format_err!("stream is out of the file"))?;
+                    decompress(self.compression, data).map(Some)
+                }
+                None => Ok(None),
+            }
+        };
+        let required =
+            |kind| stream(kind)?.ok_or_else(|| format_err!("missing stream of kind = {}", kind));
+        let (encoding, dictionary_size) = encodings.get(column).cloned().unwrap_or_default();
+        let v2 = match encoding {
+            ENCODING_DIRECT | ENCODING_DICTIONARY => false,
+            ENCODING_DIRECT_V2 | ENCODING_DICTIONARY_V2 => true,
+            _ => bail!("unsupported encoding = {}", encoding),
+        };
+        let integers = |data: &[u8], count, signed| {
+            if v2 {
+                int_rle_v2(data, count, signed)
+            } else {
+                int_rle_v1(data, count, signed)
+            }
+        };
+
+        // Without the stream, all the values are present and they're counted by the data streams
+        let present = match stream(STREAM_PRESENT)? {
+            Some(data) => Some(booleans(&data, stripe.rows)?),
+            None => None,
+        };
+        let count = match present {
+            Some(ref present) => present.iter().filter(|is_present| **is_present).count(),
+            None => stripe.rows,
+        };
+        let present = present.as_deref();
+
+        match values {
+            Column::Booleans(values) => {
+                let data = booleans(&required(STREAM_DATA)?, count)?;
+                values.extend(with_nulls(present, data));
+            }
+            Column::Integers(values) => {
+                let data = integers(&required(STREAM_DATA)?, count, true)?;
+                values.extend(with_nulls(present, data));
+            }
+            Column::Strings(values) => {
+                let data = match encoding {
+                    ENCODING_DIRECT | ENCODING_DIRECT_V2 => {
+                        let lengths = integers(&required(STREAM_LENGTH)?, count, false)?;
+                        strings(&required(STREAM_DATA)?, &lengths)?
+                    }
+                    _ => {
+                        let lengths =
+                            integers(&required(STREAM_LENGTH)?, dictionary_size as usize, false)?;
+                        let dictionary = strings(&required(STREAM_DICTIONARY_DATA)?, &lengths)?;
+                        integers(&required(STREAM_DATA)?, count, false)?
+                            .into_iter()
+                            .map(|idx| {
+                                dictionary
+                                    .get(idx as usize)
+                                    .cloned()
+                                    .ok_or_else(|| format_err!("invalid dictionary index"))
+                            })
+                            .collect::<Result<Vec<_>>>()?
+                    }
+                };
+                values.extend(with_nulls(present, data));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn with_nulls<'a, T: 'a>(
+    present: Option<&'a [bool]>,
+    values: Vec<T>,
+) -> Box<dyn Iterator<Item = Option<T>> + 'a> {
+    let mut values = values.into_iter();
+    match present {
+        Some(present) => {
+            Box::new(
+                present
+                    .iter()
+                    .map(move |is_present| if *is_present { values.next() } else { None }),
+            )
+        }
+        None => Box::new(values.map(Some)),
+    }
+}
+
+fn strings(data: &[u8], lengths: &[i64]) -> Result<Vec<String>> {
+    let mut offset = 0usize;
+    lengths
+        .iter()
+        .map(|len| {
+            let value = usize::try_from(*len)
+                .ok()
+                .and_then(|len| offset.checked_add(len))
+                .and_then(|end| Some((end, data.get(offset..end)?)));
+            let (end, value) = value.ok_or_else(|| format_err!("string is out of the stream"))?;
+            offset = end;
+            String::from_utf8(value.to_vec()).context("string isn't valid UTF-8")
+        })
+        .collect()
+}
+
+/// Streams are split into chunks, each having a 3-byte header with its length
+/// and whether it's stored uncompressed.
+fn decompress(compression: u64, data: &[u8]) -> Result<Vec<u8>> {
+    if compression == COMPRESSION_NONE {
+        return Ok(data.to_vec());
+    }
+
+    let mut out = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        if rest.len() < 3 {
+            bail!("truncated compression chunk header");
+        }
+        let header = u32::from(rest[0]) | u32::from(rest[1]) << 8 | u32::from(rest[2]) << 16;
+        let len = (header >> 1) as usize;
+        let chunk = rest
+            .get(3..3 + len)
+            .ok_or_else(|| format_err!("truncated compression chunk"))?;
+        if header & 1 == 1 {
+            out.extend_from_slice(chunk);
+        } else {
+            flate2::read::DeflateDecoder::new(chunk)
+                .read_to_end(&mut out)
+                .context("failed to inflate a compression chunk")?;
+        }
+        rest = &rest[3 + len..];
+    }
+
+    Ok(out)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn parse_stripe(data: &[u8]) -> Result<Stripe> {
+    let mut stripe = Stripe {
+        offset: 0,
+        index_length: 0,
+        data_length: 0,
+        footer_length: 0,
+        rows: 0,
+    };
+    for field in fields(data) {
+        match field? {
+            (1, Value::Varint(val)) => stripe.offset = val as usize,
+            (2, Value::Varint(val)) => stripe.index_length = val as usize,
+            (3, Value::Varint(val)) => stripe.data_length = val as usize,
+            (4, Value::Varint(val)) => stripe.footer_length = val as usize,
+            (5, Value::Varint(val)) => stripe.rows = val as usize,
+            _ => (),
+        }
+    }
+
+    Ok(stripe)
+}
+
+fn parse_type(data: &[u8]) -> Result<Type> {
+    let mut ty = Type::default();
+    for field in fields(data) {
+        match field? {
+            (1, Value::Varint(val)) => ty.kind = val,
+            (2, val) => ty
+                .subtypes
+                .extend(varints(val)?.into_iter().map(|v| v as usize)),
+            (3, Value::Bytes(val)) => ty
+                .field_names
+                .push(String::from_utf8(val.to_vec()).context("field name isn't valid UTF-8")?),
+            _ => (),
+        }
+    }
+
+    Ok(ty)
+}
+
+fn parse_stream(data: &[u8], offset: usize) -> Result<Stream> {
+    let mut stream = Stream {
+        kind: 0,
+        column: 0,
+        offset,
+        length: 0,
+    };
+    for field in fields(data) {
+        match field? {
+            (1, Value::Varint(val)) => stream.kind = val,
+            (2, Value::Varint(val)) => stream.column = val as usize,
+            (3, Value::Varint(val)) => stream.length = val as usize,
+            _ => (),
+        }
+    }
+
+    Ok(stream)
+}
+
+fn parse_encoding(data: &[u8]) -> Result<(u64, u64)> {
+    let (mut kind, mut dictionary_size) = (ENCODING_DIRECT, 0);
+    for field in fields(data) {
+        match field? {
+            (1, Value::Varint(val)) => kind = val,
+            (2, Value::Varint(val)) => dictionary_size = val,
+            _ => (),
+        }
+    }
+
+    Ok((kind, dictionary_size))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Protocol Buffers value, metadata of ORC files is encoded with them.
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+fn fields(data: &[u8]) -> impl Iterator<Item = Result<(u64, Value<'_>)>> + '_ {
+    let mut cursor = Cursor::new(data);
+    std::iter::from_fn(move || {
+        if cursor.is_empty() {
+            return None;
+        }
+
+        let field = cursor.varint().and_then(|key| {
+            let value = match key & 0x7 {
+                0 => Value::Varint(cursor.varint()?),
+                1 => cursor.take(8).map(|_| Value::Fixed)?,
+                2 => {
+                    let len = cursor.varint()? as usize;
+                    Value::Bytes(cursor.take(len)?)
+                }
+                5 => cursor.take(4).map(|_| Value::Fixed)?,
+                wire_type => bail!("unsupported wire type = {}", wire_type),
+            };
+            Ok((key >> 3, value))
+        });
+        if field.is_err() {
+            // The rest can't be parsed after an error
+            cursor.skip_all();
+        }
+        Some(field)
+    })
+}
+
+/// Repeated varints are either packed or a separate field each.
+fn varints(value: Value) -> Result<Vec<u64>> {
+    match value {
+        Value::Varint(val) => Ok(vec![val]),
+        Value::Bytes(data) => {
+            let mut cursor = Cursor::new(data);
+            let mut values = Vec::new();
+            while !cursor.is_empty() {
+                values.push(cursor.varint()?);
+            }
+            Ok(values)
+        }
+        Value::Fixed => bail!("unexpected fixed-width value"),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn skip_all(&mut self) {
+        self.pos = self.data.len();
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let value = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| format_err!("unexpected end of data"))?;
+        self.pos += len;
+        Ok(value)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        self.take(1).map(|value| value[0])
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        bail!("varint is too long")
+    }
+
+    /// Big-endian unsigned integer of `len` bytes.
+    fn be(&mut self, len: usize) -> Result<u64> {
+        if len > 8 {
+            bail!("integer is too wide");
+        }
+
+        Ok(self
+            .take(len)?
+            .iter()
+            .fold(0, |acc, byte| acc << 8 | u64::from(*byte)))
+    }
+
+    /// Integers of `width` bits packed big-endian, the last byte is padded.
+    fn bits(&mut self, width: usize, count: usize) -> Result<Vec<u64>> {
+        let (mut current, mut left) = (0u8, 0usize);
+        let left_bits = self.data.len().saturating_sub(self.pos).saturating_mul(8);
+        let mut values = Vec::with_capacity(count.min(left_bits / width.max(1)));
+        for _ in 0..count {
+            let (mut value, mut need) = (0u64, width);
+            while need > 0 {
+                if left == 0 {
+                    current = self.byte()?;
+                    left = 8;
+                }
+                let take = need.min(left);
+                let bits = (u16::from(current) >> (left - take)) & ((1 << take) - 1);
+                value = value.checked_shl(take as u32).unwrap_or(0) | u64::from(bits);
+                left -= take;
+                need -= take;
+            }
+            values.push(value);
+        }
+
+        Ok(values)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn zigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+fn decode(value: u64, signed: bool) -> i64 {
+    if signed {
+        zigzag(value)
+    } else {
+        value as i64
+    }
+}
+
+/// Bit widths of RLEv2 runs are encoded with 5 bits.
+fn decode_width(encoded: u8) -> usize {
+    match encoded {
+        0..=23 => encoded as usize + 1,
+        24 => 26,
+        25 => 28,
+        26 => 30,
+        27 => 32,
+        28 => 40,
+        29 => 48,
+        30 => 56,
+        _ => 64,
+    }
+}
+
+fn closest_fixed_width(width: usize) -> usize {
+    match width {
+        0 => 1,
+        1..=24 => width,
+        25..=26 => 26,
+        27..=28 => 28,
+        29..=30 => 30,
+        31..=32 => 32,
+        33..=40 => 40,
+        41..=48 => 48,
+        49..=56 => 56,
+        _ => 64,
+    }
+}
+
+/// Capacity of decoded values, the count comes from the file, so only as many values
+/// as there are bytes of the stream are allocated upfront.
+fn capacity(data: &[u8], count: usize) -> usize {
+    count.min(data.len())
+}
+
+fn byte_rle(data: &[u8], count: usize) -> Result<Vec<u8>> {
+    let mut cursor = Cursor::new(data);
+    let mut values = Vec::with_capacity(capacity(data, count));
+    while values.len() < count {
+        let control = cursor.byte()?;
+        if control < 0x80 {
+            let value = cursor.byte()?;
+            values.extend(std::iter::repeat_n(value, control as usize + 3));
+        } else {
+            values.extend_from_slice(cursor.take(0x100 - control as usize)?);
+        }
+    }
+    values.truncate(count);
+
+    Ok(values)
+}
+
+fn booleans(data: &[u8], count: usize) -> Result<Vec<bool>> {
+    let bytes = byte_rle(data, count.div_ceil(8))?;
+    Ok((0..count)
+        .map(|idx| bytes[idx / 8] & (0x80 >> (idx % 8)) != 0)
+        .collect())
+}
+
+fn int_rle_v1(data: &[u8], count: usize, signed: bool) -> Result<Vec<i64>> {
+    let mut cursor = Cursor::new(data);
+    let mut values = Vec::with_capacity(capacity(data, count));
+    while values.len() < count {
+        let control = cursor.byte()?;
+        if control < 0x80 {
+            let delta = i64::from(cursor.byte()? as i8);
+            let base = decode(cursor.varint()?, signed);
+            values.extend(
+                (0..control as i64 + 3).map(|idx| base.wrapping_add(idx.wrapping_mul(delta))),
+            );
+        } else {
+            for _ in 0..0x100 - control as usize {
+                values.push(decode(cursor.varint()?, signed));
+            }
+        }
+    }
+    values.truncate(count);
+
+    Ok(values)
+}
+
+fn int_rle_v2(data: &[u8], count: usize, signed: bool) -> Result<Vec<i64>> {
+    let mut cursor = Cursor::new(data);
+    let mut values = Vec::with_capacity(capacity(data, count));
+    while values.len() < count {
+        let first = cursor.byte()?;
+        let run_length = |cursor: &mut Cursor| -> Result<usize> {
+            Ok(((first as usize & 1) << 8 | cursor.byte()? as usize) + 1)
+        };
+
+        match first >> 6 {
+            // Short repeat
+            0 => {
+                let width = ((first >> 3) & 0x7) as usize + 1;
+                let value = decode(cursor.be(width)?, signed);
+                values.extend(std::iter::repeat_n(value, (first & 0x7) as usize + 3));
+            }
+            // Direct
+            1 => {
+                let width = decode_width((first >> 1) & 0x1f);
+                let len = run_length(&mut cursor)?;
+                values.extend(
+                    cursor
+                        .bits(width, len)?
+                        .into_iter()
+                        .map(|value| decode(value, signed)),
+                );
+            }
+            // Patched base
+            2 => {
+                let width = decode_width((first >> 1) & 0x1f);
+                let len = run_length(&mut cursor)?;
+                let third = cursor.byte()?;
+                let base_width = (third >> 5) as usize + 1;
+                let patch_width = decode_width(third & 0x1f);
+                let fourth = cursor.byte()?;
+                let gap_width = (fourth >> 5) as usize + 1;
+                let patches_len = (fourth & 0x1f) as usize;
+
+                // The base is stored in sign-magnitude representation
+                let base = cursor.be(base_width)?;
+                let sign = 1 << (base_width * 8 - 1);
+                let base = if base & sign != 0 {
+                    -((base & !sign) as i64)
+                } else {
+                    base as i64
+                };
+
+                let mut unpacked = cursor.bits(width, len)?;
+                let patches =
+                    cursor.bits(closest_fixed_width(patch_width + gap_width), patches_len)?;
+                let patch_mask = u64::MAX.checked_shr(64 - patch_width as u32).unwrap_or(0);
+                let (mut pos, mut gap) = (0, 0);
+                for patch in patches {
+                    let (patch_gap, value) = (
+                        patch.checked_shr(patch_width as u32).unwrap_or(0) as usize,
+                        patch & patch_mask,
+                    );
+                    gap += patch_gap;
+                    // Gaps longer than 255 are split into entries without patches
+                    if patch_gap == 255 && value == 0 {
+                        continue;
+                    }
+
+                    pos += gap;
+                    gap = 0;
+                    let unpacked = unpacked
+                        .get_mut(pos)
+                        .ok_or_else(|| format_err!("patch is out of the run"))?;
+                    *unpacked |= value.checked_shl(width as u32).unwrap_or(0);
+                }
+
+                values.extend(
+                    unpacked
+                        .into_iter()
+                        .map(|value| base.wrapping_add(value as i64)),
+                );
+            }
+            // Delta
+            _ => {
+                let width = match (first >> 1) & 0x1f {
+                    0 => 0,
+                    encoded => decode_width(encoded),
+                };
+                let len = run_length(&mut cursor)?;
+                let mut value = decode(cursor.varint()?, signed);
+                let delta = zigzag(cursor.varint()?);
+                values.push(value);
+
+                if width == 0 {
+                    for _ in 1..len {
+                        value = value.wrapping_add(delta);
+                        values.push(value);
+                    }
+                } else if len > 1 {
+                    value = value.wrapping_add(delta);
+                    values.push(value);
+                    for step in cursor.bits(width, len - 2)? {
+                        value = if delta < 0 {
+                            value.wrapping_sub(step as i64)
+                        } else {
+                            value.wrapping_add(step as i64)
+                        };
+                        values.push(value);
+                    }
+                }
+            }
+        }
+    }
+    values.truncate(count);
+
+    Ok(values)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn varint(mut value: u64, out: &mut Vec<u8>) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn int_field(field: u64, value: u64, out: &mut Vec<u8>) {
+        varint(field << 3, out);
+        varint(value, out);
+    }
+
+    fn bytes_field(field: u64, value: &[u8], out: &mut Vec<u8>) {
+        varint(field << 3 | 2, out);
+        varint(value.len() as u64, out);
+        out.extend_from_slice(value);
+    }
+
+    fn message(fields: &[(u64, u64)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (field, value) in fields {
+            int_field(*field, *value, &mut out);
+        }
+        out
+    }
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let header = (compressed.len() as u32) << 1;
+        let mut out = vec![header as u8, (header >> 8) as u8, (header >> 16) as u8];
+        out.extend(compressed);
+        out
+    }
+
+    fn original(data: &[u8]) -> Vec<u8> {
+        let header = (data.len() as u32) << 1 | 1;
+        let mut out = vec![header as u8, (header >> 8) as u8, (header >> 16) as u8];
+        out.extend_from_slice(data);
+        out
+    }
+
+    /// A file of a single stripe with `key` string, `size` bigint, `e_tag` string
+    /// and `is_delete_marker` boolean columns, footers are compressed with ZLIB.
+    fn orc_file() -> Vec<u8> {
+        // (column, kind, data)
+        let streams: Vec<(u64, u64, Vec<u8>)> = vec![
+            // "a/1", "b/2", "c/3" as direct strings: lengths of 3 repeated, then the bytes
+            (1, STREAM_DATA, b"a/1b/2c/3".to_vec()),
+            (1, STREAM_LENGTH, vec![0x00, 0x03]),
+            // The second size is null: 0b101 in the present stream
+            (2, STREAM_PRESENT, vec![0xff, 0xa0]),
+            // 1024 and 2048 as direct signed integers of 16 bits (zigzag: 2048, 4096)
+            (2, STREAM_DATA, vec![0x5e, 0x01, 0x08, 0x00, 0x10, 0x00]),
+            // Indexes into the dictionary of "e1", "e2": 1, 0, 1
+            (3, STREAM_DATA, vec![0x40, 0x02, 0xa0]),
+            (3, STREAM_DICTIONARY_DATA, b"e1e2".to_vec()),
+            (3, STREAM_LENGTH, vec![0x42, 0x01, 0xa0]),
+            // false, false, true
+            (4, STREAM_DATA, vec![0xff, 0x20]),
+        ];
+
+        let mut file = MAGIC.to_vec();
+        let offset = file.len() as u64;
+        let mut stripe_footer = Vec::new();
+        let mut data_length = 0;
+        for (column, kind, data) in &streams {
+            // Streams are stored in chunks as is, as if compressing them hasn't paid off
+            let data = original(data);
+            file.extend_from_slice(&data);
+            data_length += data.len() as u64;
+            bytes_field(
+                1,
+                &message(&[(1, *kind), (2, *column), (3, data.len() as u64)]),
+                &mut stripe_footer,
+            );
+        }
+        for (kind, dictionary_size) in &[
+            (ENCODING_DIRECT, 0),
+            (ENCODING_DIRECT_V2, 0),
+            (ENCODING_DIRECT_V2, 0),
+            (ENCODING_DICTIONARY_V2, 2),
+            (ENCODING_DIRECT, 0),
+        ] {
+            bytes_field(
+                2,
+                &message(&[(1, *kind), (2, *dictionary_size)]),
+                &mut stripe_footer,
+            );
+        }
+        let stripe_footer = zlib(&stripe_footer);
+        file.extend_from_slice(&stripe_footer);
+
+        let mut footer = Vec::new();
+        let stripe = message(&[
+            (1, offset),
+            (2, 0),
+            (3, data_length),
+            (4, stripe_footer.len() as u64),
+            (5, 3),
+        ]);
+        bytes_field(3, &stripe, &mut footer);
+        let mut root = message(&[(1, KIND_STRUCT)]);
+        bytes_field(2, &[1, 2, 3, 4], &mut root);
+        for name in &["key", "size", "e_tag", "is_delete_marker"] {
+            bytes_field(3, name.as_bytes(), &mut root);
+        }
+        bytes_field(4, &root, &mut footer);
+        for kind in &[KIND_STRING, KIND_LONG, KIND_STRING, KIND_BOOLEAN] {
+            bytes_field(4, &message(&[(1, *kind)]), &mut footer);
+        }
+        int_field(6, 3, &mut footer);
+        let footer = zlib(&footer);
+        file.extend_from_slice(&footer);
+
+        let mut ps = message(&[(1, footer.len() as u64), (2, COMPRESSION_ZLIB), (3, 262144)]);
+        bytes_field(8000, MAGIC, &mut ps);
+        file.extend_from_slice(&ps);
+        file.push(ps.len() as u8);
+        file
+    }
+
+    #[test]
+    fn read_columns() {
+        let file = orc_file();
+        let orc = OrcFile::open(&file).unwrap();
+        assert_eq!(orc.fields(), &["key", "size", "e_tag", "is_delete_marker"]);
+        assert_eq!(
+            orc.read_column(0).unwrap(),
+            Column::Strings(vec![
+                Some("a/1".to_owned()),
+                Some("b/2".to_owned()),
+                Some("c/3".to_owned())
+            ])
+        );
+        assert_eq!(
+            orc.read_column(1).unwrap(),
+            Column::Integers(vec![Some(1024), None, Some(2048)])
+        );
+        assert_eq!(
+            orc.read_column(2).unwrap(),
+            Column::Strings(vec![
+                Some("e2".to_owned()),
+                Some("e1".to_owned()),
+                Some("e2".to_owned())
+            ])
+        );
+        assert_eq!(
+            orc.read_column(3).unwrap(),
+            Column::Booleans(vec![Some(false), Some(false), Some(true)])
+        );
+        assert!(orc.read_column(4).is_err());
+
+        assert!(OrcFile::open(b"PAR1").is_err());
+    }
+
+    // Examples of the ORC specification
+    #[test]
+    fn decode_int_rle_v2() {
+        assert_eq!(
+            int_rle_v2(&[0x0a, 0x27, 0x10], 5, false).unwrap(),
+            vec![10000; 5]
+        );
+        assert_eq!(
+            int_rle_v2(
+                &[0x5e, 0x03, 0x5c, 0xa1, 0xab, 0x1e, 0xde, 0xad, 0xbe, 0xef],
+                4,
+                false
+            )
+            .unwrap(),
+            vec![23713, 43806, 57005, 48879]
+        );
+        assert_eq!(
+            int_rle_v2(
+                &[
+                    0x8e, 0x13, 0x2b, 0x21, 0x07, 0xd0, 0x1e, 0x00, 0x14, 0x70, 0x28, 0x32, 0x3c,
+                    0x46, 0x50, 0x5a, 0x64, 0x6e, 0x78, 0x82, 0x8c, 0x96, 0xa0, 0xaa, 0xb4, 0xbe,
+                    0xfc, 0xe8
+                ],
+                20,
+                false
+            )
+            .unwrap(),
+            vec![
+                2030, 2000, 2020, 1000000, 2040, 2050, 2060, 2070, 2080, 2090, 2100, 2110, 2120,
+                2130, 2140, 2150, 2160, 2170, 2180, 2190
+            ]
+        );
+        assert_eq!(
+            int_rle_v2(&[0xc6, 0x09, 0x02, 0x02, 0x22, 0x42, 0x42, 0x46], 10, false).unwrap(),
+            vec![2, 3, 5, 7, 11, 13, 17, 19, 23, 29]
+        );
+        assert!(int_rle_v2(&[0x5e, 0x03, 0x5c], 4, false).is_err());
+    }
+
+    #[test]
+    fn decode_int_rle_v1() {
+        // A run of 100 values decreasing by 1 from 100, then literals 2, 3, 6, 7, 11
+        assert_eq!(
+            int_rle_v1(&[0x61, 0xff, 0x64], 100, false).unwrap(),
+            (1..=100).rev().collect::<Vec<i64>>()
+        );
+        assert_eq!(
+            int_rle_v1(&[0xfb, 0x02, 0x03, 0x06, 0x07, 0x0b], 5, false).unwrap(),
+            vec![2, 3, 6, 7, 11]
+        );
+    }
+
+    #[test]
+    fn decode_byte_rle() {
+        assert_eq!(byte_rle(&[0x61, 0x00], 100).unwrap(), vec![0; 100]);
+        assert_eq!(byte_rle(&[0xfe, 0x44, 0x45], 2).unwrap(), vec![0x44, 0x45]);
+        assert_eq!(
+            booleans(&[0xff, 0x80], 8).unwrap(),
+            vec![true, false, false, false, false, false, false, false]
+        );
+    }
+
+    #[test]
+    fn decode_malformed() {
+        // Counts of values come from the file, they aren't allocated upfront
+        assert!(byte_rle(&[0x61, 0x00], usize::MAX).is_err());
+        assert!(int_rle_v1(&[0x61, 0xff, 0x64], usize::MAX, false).is_err());
+        assert!(int_rle_v2(&[0x0a, 0x27, 0x10], usize::MAX, false).is_err());
+        assert!(booleans(&[0xff, 0x80], usize::MAX / 2).is_err());
+
+        assert_eq!(
+            strings(b"abcd", &[1, 3]).unwrap(),
+            vec!["a".to_owned(), "bcd".to_owned()]
+        );
+        assert!(strings(b"abcd", &[-1]).is_err());
+        assert!(strings(b"abcd", &[1, i64::MAX]).is_err());
+    }
+}
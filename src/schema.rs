@@ -1,3 +1,40 @@
+table! {
+    use diesel::sql_types::*;
+
+    api_key (id) {
+        id -> Uuid,
+        key_hash -> Text,
+        account_id -> Text,
+        scope -> Nullable<Array<Text>>,
+        expires_at -> Nullable<Timestamptz>,
+        created_by -> Text,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+
+    audit_event (id) {
+        id -> Uuid,
+        request_id -> Uuid,
+        subject -> Text,
+        bucket -> Text,
+        set -> Nullable<Text>,
+        object -> Nullable<Text>,
+        method -> Text,
+        authz_action -> Text,
+        authz_decision -> Text,
+        response_status -> Int4,
+        created_at -> Timestamptz,
+        operation -> Nullable<Text>,
+        authz_duration_ms -> Nullable<Int4>,
+        authn_method -> Nullable<Text>,
+        chain_seq -> Nullable<Int8>,
+        prev_hash -> Nullable<Text>,
+    }
+}
+
 table! {
     use diesel::sql_types::*;
     use crate::db::sql::*;
@@ -9,3 +46,5 @@ table! {
         created_at -> Timestamptz,
     }
 }
+
+allow_tables_to_appear_in_same_query!(api_key, audit_event, set_tag,);
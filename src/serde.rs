@@ -73,3 +73,33 @@ where
 {
     deserializer.deserialize_seq(AllowedOriginsVisitor)
 }
+
+pub(crate) fn optional_allowed_origins<'de, D>(
+    deserializer: D,
+) -> Result<Option<AllowedOrigins>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    allowed_origins(deserializer).map(Some)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+/// Deserializes either a single value or a list of them into a list.
+pub(crate) fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: de::Deserialize<'de>,
+{
+    match de::Deserialize::deserialize(deserializer)? {
+        OneOrMany::One(value) => Ok(vec![value]),
+        OneOrMany::Many(values) => Ok(values),
+    }
+}